@@ -0,0 +1,283 @@
+//! edulinker 헤드리스 데몬
+//!
+//! 창 없이 P2P 메신저, 디스커버리, tus/Durable Streams 서버를 돌린다.
+//! 교무실 PC를 릴레이 허브로 두거나, 서버에서 메시지를 보내고 받아 볼 때 쓴다.
+//! 같은 데이터 폴더를 쓰는 앱과 동시에 실행하면 포트가 겹치므로
+//! `INTERNAL_P2P_*_PORT` 환경 변수나 `--discovery-port`로 나눈다.
+//! Tauri 없이 빌드하려면 `cargo build --no-default-features --bin edulinker-daemon`.
+
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use edulinker_core::discovery_hub::DiscoveryHub;
+use edulinker_core::host::{CoreHost, DataDir, EventSink, NetConfig};
+use edulinker_core::internal_p2p::{self, InternalP2PManager};
+use edulinker_core::network_discovery::NetworkDiscoveryManager;
+use edulinker_core::schema;
+use edulinker_core::server::ServerManager;
+
+const USAGE: &str = "usage: edulinker-daemon <command> [options]
+
+commands:
+  serve                   run P2P, discovery and the local server, print every event
+  tail                    run P2P and print incoming messages
+  peers                   wait for discovery, then print known peers
+  send <userId> <text>    send a chat message and print the result
+
+options:
+  --data-dir <path>       data folder holding local.db (EDULINKER_DATA_DIR)
+  --user-id <id>          P2P user id (EDULINKER_USER_ID, default daemon-<hostname>)
+  --user-name <name>      display name (default: user id)
+  --school-id <id>        school id
+  --discovery-port <port> UDP discovery port (INTERNAL_P2P_DISCOVERY_PORT, default 41235)
+  --server-port <port>    tus/Durable Streams port for serve (default 41234)
+  --relay-hub             hold messages for offline peers (serve, tail)
+  --wait <secs>           how long peers/send wait for discovery (default 10)";
+
+enum Command {
+  Serve,
+  Tail,
+  Peers,
+  Send { receiver_id: String, content: String },
+}
+
+struct Options {
+  command: Command,
+  data_dir: PathBuf,
+  user_id: String,
+  user_name: String,
+  school_id: Option<String>,
+  discovery_port: u16,
+  server_port: u16,
+  relay_hub: bool,
+  wait: Duration,
+}
+
+/// 이벤트를 한 줄짜리 JSON으로 표준 출력에 쓴다.
+struct PrintSink {
+  filter: fn(&str) -> bool,
+}
+
+impl EventSink for PrintSink {
+  fn emit(&self, event: &str, payload: Value) {
+    if (self.filter)(event) {
+      println!("{}", json!({ "event": event, "payload": payload }));
+    }
+  }
+}
+
+struct Daemon {
+  internal: InternalP2PManager,
+  discovery: NetworkDiscoveryManager,
+  hub: DiscoveryHub,
+}
+
+impl Daemon {
+  async fn start(options: &Options, filter: fn(&str) -> bool) -> Result<Self, String> {
+    std::fs::create_dir_all(&options.data_dir).map_err(|e| e.to_string())?;
+    let host = CoreHost::new(
+      Arc::new(PrintSink { filter }),
+      Arc::new(DataDir::new(options.data_dir.clone())),
+    )
+    .with_net(NetConfig {
+      discovery_port: options.discovery_port,
+      ..NetConfig::from_env()
+    });
+    let conn = host.open_db().map_err(|e| e.to_string())?;
+    schema::init_db(&conn).map_err(|e| e.to_string())?;
+    drop(conn);
+
+    if options.relay_hub {
+      std::env::set_var("INTERNAL_P2P_RELAY_HUB", "1");
+    }
+
+    let internal = InternalP2PManager::new(host.clone());
+    let discovery = NetworkDiscoveryManager::new(host.clone());
+    let hub = DiscoveryHub::new(host);
+
+    let port = hub
+      .ensure_started(options.discovery_port, internal.clone(), discovery.clone())
+      .await?;
+    internal
+      .start(
+        options.user_id.clone(),
+        options.user_name.clone(),
+        options.school_id.clone(),
+        port,
+      )
+      .await?;
+    eprintln!("[Daemon] P2P started as {} (discovery port {port})", options.user_id);
+
+    Ok(Self { internal, discovery, hub })
+  }
+
+  async fn stop(&self) {
+    let _ = self.internal.stop().await;
+    let _ = self.discovery.stop().await;
+    self.hub.stop().await;
+  }
+
+  /// 대상이 직접 또는 경로로 보일 때까지 기다린다.
+  async fn wait_for_peer(&self, user_id: &str, wait: Duration) -> bool {
+    let deadline = Instant::now() + wait;
+    loop {
+      let peers = self.internal.get_peers().await;
+      let reachable = ["onlinePeers", "routes"].iter().any(|key| {
+        peers
+          .get(*key)
+          .and_then(|v| v.as_array())
+          .is_some_and(|list| list.iter().any(|peer| peer.get("userId").and_then(|v| v.as_str()) == Some(user_id)))
+      });
+      if reachable || Instant::now() >= deadline {
+        return reachable;
+      }
+      tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+  }
+}
+
+#[tokio::main]
+async fn main() {
+  let options = match parse_args(std::env::args().skip(1)) {
+    Ok(options) => options,
+    Err(err) => {
+      eprintln!("{err}\n\n{USAGE}");
+      std::process::exit(2);
+    }
+  };
+
+  if let Err(err) = run(options).await {
+    eprintln!("[Daemon] {err}");
+    std::process::exit(1);
+  }
+}
+
+async fn run(options: Options) -> Result<(), String> {
+  match &options.command {
+    Command::Serve => {
+      let daemon = Daemon::start(&options, |_| true).await?;
+      let server = ServerManager::new(options.server_port);
+      match server.start(options.data_dir.clone()).await {
+        Ok(message) => eprintln!("[Daemon] {message}"),
+        Err(err) => eprintln!("[Daemon] Server failed to start: {err}"),
+      }
+      wait_for_shutdown().await;
+      daemon.stop().await;
+    }
+    Command::Tail => {
+      let daemon = Daemon::start(&options, is_message_event).await?;
+      wait_for_shutdown().await;
+      daemon.stop().await;
+    }
+    Command::Peers => {
+      let daemon = Daemon::start(&options, |_| false).await?;
+      tokio::time::sleep(options.wait).await;
+      print_json(&daemon.internal.get_peers().await);
+      daemon.stop().await;
+    }
+    Command::Send { receiver_id, content } => {
+      let daemon = Daemon::start(&options, |_| false).await?;
+      if !daemon.wait_for_peer(receiver_id, options.wait).await {
+        eprintln!("[Daemon] {receiver_id} not found, message will be queued");
+      }
+      let result = daemon
+        .internal
+        .send_message(json!({ "receiverId": receiver_id, "content": content }))
+        .await;
+      // 릴레이 허브로 넘기는 작업이 끝날 시간을 준다.
+      tokio::time::sleep(Duration::from_secs(1)).await;
+      daemon.stop().await;
+      print_json(&result?);
+    }
+  }
+  Ok(())
+}
+
+fn is_message_event(event: &str) -> bool {
+  event.starts_with("messaging:") || event.starts_with("group:") || event.starts_with("p2p:message")
+}
+
+fn print_json(value: &Value) {
+  println!("{}", serde_json::to_string_pretty(value).unwrap_or_default());
+}
+
+async fn wait_for_shutdown() {
+  let _ = tokio::signal::ctrl_c().await;
+  eprintln!("[Daemon] Shutting down");
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+  let mut positional = Vec::new();
+  let mut data_dir = std::env::var("EDULINKER_DATA_DIR").ok().map(PathBuf::from);
+  let mut user_id = std::env::var("EDULINKER_USER_ID").ok();
+  let mut user_name = None;
+  let mut school_id = None;
+  let mut discovery_port = internal_p2p::requested_discovery_port();
+  let mut server_port = 41234;
+  let mut relay_hub = false;
+  let mut wait = Duration::from_secs(10);
+
+  while let Some(arg) = args.next() {
+    let mut value = |name: &str| args.next().ok_or_else(|| format!("{name} needs a value"));
+    match arg.as_str() {
+      "--data-dir" => data_dir = Some(PathBuf::from(value(&arg)?)),
+      "--user-id" => user_id = Some(value(&arg)?),
+      "--user-name" => user_name = Some(value(&arg)?),
+      "--school-id" => school_id = Some(value(&arg)?),
+      "--discovery-port" => discovery_port = parse_number(&arg, &value(&arg)?)?,
+      "--server-port" => server_port = parse_number(&arg, &value(&arg)?)?,
+      "--wait" => wait = Duration::from_secs(parse_number(&arg, &value(&arg)?)?),
+      "--relay-hub" => relay_hub = true,
+      "-h" | "--help" => return Err("edulinker headless daemon".to_string()),
+      flag if flag.starts_with("--") => return Err(format!("unknown option {flag}")),
+      _ => positional.push(arg),
+    }
+  }
+
+  let mut positional = positional.into_iter();
+  let command = match positional.next().as_deref() {
+    Some("serve") => Command::Serve,
+    Some("tail") => Command::Tail,
+    Some("peers") => Command::Peers,
+    Some("send") => {
+      let receiver_id = positional.next().ok_or("send needs a userId")?;
+      let content = positional.collect::<Vec<_>>().join(" ");
+      if content.is_empty() {
+        return Err("send needs a message".to_string());
+      }
+      Command::Send { receiver_id, content }
+    }
+    Some(other) => return Err(format!("unknown command {other}")),
+    None => return Err("missing command".to_string()),
+  };
+
+  // 앱과 같은 위치 (Tauri `app_data_dir`)
+  let data_dir = data_dir.unwrap_or_else(|| {
+    dirs::data_dir()
+      .unwrap_or_else(|| PathBuf::from("."))
+      .join("kr.schoolworks.edulinker")
+  });
+  let user_id = user_id.unwrap_or_else(|| {
+    let hostname = hostname::get().ok().and_then(|h| h.into_string().ok()).unwrap_or_default();
+    format!("daemon-{hostname}")
+  });
+  let user_name = user_name.unwrap_or_else(|| user_id.clone());
+
+  Ok(Options {
+    command,
+    data_dir,
+    user_id,
+    user_name,
+    school_id,
+    discovery_port,
+    server_port,
+    relay_hub,
+    wait,
+  })
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+  value.parse().map_err(|_| format!("invalid value for {name}: {value}"))
+}
@@ -1,31 +1,100 @@
+use async_trait::async_trait;
+use rusqlite::params;
 use serde_json::Value;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use tokio::net::UdpSocket;
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 
+use crate::host::CoreHost;
 use crate::internal_p2p::InternalP2PManager;
+use crate::mdns_discovery::MdnsBackend;
+use crate::net_interfaces;
 use crate::network_discovery::NetworkDiscoveryManager;
 
+/// `broadcast`, `mdns`, `both` 중 하나. 기본값은 `both`다.
+pub const BACKEND_SETTING: &str = "p2pDiscoveryBackend";
+const BACKEND_ENV: &str = "INTERNAL_P2P_DISCOVERY_BACKEND";
+
+/// 사용할 디스커버리 백엔드
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscoveryMode {
+  Broadcast,
+  Mdns,
+  Both,
+}
+
+impl DiscoveryMode {
+  /// 환경 변수가 있으면 설정보다 우선한다.
+  pub fn from_setting(value: Option<String>) -> Self {
+    let value = std::env::var(BACKEND_ENV).ok().or(value).unwrap_or_default();
+    match value.trim().to_lowercase().as_str() {
+      "broadcast" => Self::Broadcast,
+      "mdns" => Self::Mdns,
+      _ => Self::Both,
+    }
+  }
+
+  /// 브로드캐스트/멀티캐스트/유니캐스트 UDP 디스커버리를 쓰는지
+  pub fn uses_broadcast(self) -> bool {
+    self != Self::Mdns
+  }
+
+  pub fn uses_mdns(self) -> bool {
+    self != Self::Broadcast
+  }
+}
+
+/// 백엔드가 받은 디스커버리 알림을 두 관리자에게 넘긴다.
+#[derive(Clone)]
+pub struct DiscoverySink {
+  internal: InternalP2PManager,
+  discovery: NetworkDiscoveryManager,
+}
+
+impl DiscoverySink {
+  pub async fn deliver(&self, message: &Value, sender_ip: &str) {
+    self.internal.handle_discovery_message(message, sender_ip).await;
+    self.discovery.handle_discovery_message(message).await;
+  }
+
+  /// 이 설치를 알리는 서명된 알림. P2P가 실행 중이 아니면 `None`.
+  pub async fn announcement(&self) -> Option<Value> {
+    self.internal.discovery_announcement().await
+  }
+}
+
+/// 디스커버리 방식 하나. 허브가 취소할 때까지 알림을 받아 `sink`로 넘긴다.
+#[async_trait]
+pub trait DiscoveryBackend: Send {
+  fn name(&self) -> &'static str;
+  async fn run(self: Box<Self>, sink: DiscoverySink, token: CancellationToken);
+}
+
 struct DiscoveryHubState {
   port: Option<u16>,
+  backends: Vec<&'static str>,
   token: Option<CancellationToken>,
-  task: Option<tokio::task::JoinHandle<()>>,
+  tasks: Vec<tokio::task::JoinHandle<()>>,
 }
 
 #[derive(Clone)]
 pub struct DiscoveryHub {
+  app: CoreHost,
   state: std::sync::Arc<Mutex<DiscoveryHubState>>,
 }
 
 impl DiscoveryHub {
-  pub fn new() -> Self {
+  pub fn new(app: CoreHost) -> Self {
     let state = DiscoveryHubState {
       port: None,
+      backends: Vec::new(),
       token: None,
-      task: None,
+      tasks: Vec::new(),
     };
 
     Self {
+      app,
       state: std::sync::Arc::new(Mutex::new(state)),
     }
   }
@@ -41,15 +110,38 @@ impl DiscoveryHub {
       return Ok(port);
     }
 
-    let (socket, port) = bind_with_fallback(requested_port, 15).await?;
-    let token = CancellationToken::new();
-    state.token = Some(token.clone());
+    let mode = self.mode().await;
+    let mut backends: Vec<Box<dyn DiscoveryBackend>> = Vec::new();
+    let mut port = requested_port;
 
-    let task = tokio::spawn(async move {
-      discovery_loop(socket, token, internal, discovery).await;
-    });
+    if mode.uses_broadcast() {
+      let (backend, bound_port) = BroadcastBackend::bind(requested_port, self.app.net().bind_ip).await?;
+      backends.push(Box::new(backend));
+      port = bound_port;
+    }
+    if mode.uses_mdns() {
+      match MdnsBackend::new() {
+        Ok(backend) => backends.push(Box::new(backend)),
+        // 둘 다 쓰는 경우에는 브로드캐스트만으로 계속한다.
+        Err(err) if backends.is_empty() => return Err(err),
+        Err(_) => {}
+      }
+    }
 
-    state.task = Some(task);
+    let token = CancellationToken::new();
+    let sink = DiscoverySink { internal, discovery };
+    state.backends = backends.iter().map(|backend| backend.name()).collect();
+    state.tasks = backends
+      .into_iter()
+      .map(|backend| {
+        let sink = sink.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+          backend.run(sink, token).await;
+        })
+      })
+      .collect();
+    state.token = Some(token);
     state.port = Some(port);
 
     Ok(port)
@@ -60,7 +152,8 @@ impl DiscoveryHub {
     if let Some(token) = state.token.take() {
       token.cancel();
     }
-    state.task = None;
+    state.tasks.clear();
+    state.backends.clear();
     state.port = None;
   }
 
@@ -68,14 +161,73 @@ impl DiscoveryHub {
     let state = self.state.lock().await;
     state.port
   }
+
+  /// 실행 중인 백엔드 이름
+  pub async fn backends(&self) -> Vec<&'static str> {
+    let state = self.state.lock().await;
+    state.backends.clone()
+  }
+
+  async fn mode(&self) -> DiscoveryMode {
+    let app = self.app.clone();
+    let setting = tokio::task::spawn_blocking(move || app_setting(&app, BACKEND_SETTING))
+      .await
+      .unwrap_or(None);
+    DiscoveryMode::from_setting(setting)
+  }
+}
+
+/// IPv4 브로드캐스트/유니캐스트와 IPv6 멀티캐스트로 오는 디스커버리 패킷을 받는다.
+pub struct BroadcastBackend {
+  socket: UdpSocket,
+  socket_v6: Option<UdpSocket>,
 }
 
-async fn bind_with_fallback(start_port: u16, attempts: u16) -> Result<(UdpSocket, u16), String> {
+impl BroadcastBackend {
+  /// `bind_ip`가 있으면 그 주소로 오는 유니캐스트만 받는다.
+  pub async fn bind(requested_port: u16, bind_ip: Option<IpAddr>) -> Result<(Self, u16), String> {
+    let ip = bind_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let (socket, port) = bind_with_fallback(ip, requested_port, 15).await?;
+    // IPv6가 없는 네트워크에서는 IPv4 브로드캐스트만 받는다.
+    let socket_v6 = match bind_ip {
+      Some(_) => None,
+      None => net_interfaces::bind_discovery_v6(port).ok(),
+    };
+    Ok((Self { socket, socket_v6 }, port))
+  }
+}
+
+#[async_trait]
+impl DiscoveryBackend for BroadcastBackend {
+  fn name(&self) -> &'static str {
+    "broadcast"
+  }
+
+  async fn run(self: Box<Self>, sink: DiscoverySink, token: CancellationToken) {
+    let mut buf = vec![0u8; 8192];
+    let mut buf_v6 = vec![0u8; 8192];
+
+    loop {
+      let (res, payload) = tokio::select! {
+        _ = token.cancelled() => break,
+        res = self.socket.recv_from(&mut buf) => (res, &buf),
+        res = recv_optional(self.socket_v6.as_ref(), &mut buf_v6) => (res, &buf_v6),
+      };
+
+      let Ok((len, addr)) = res else { continue; };
+      if let Ok(message) = serde_json::from_slice::<Value>(&payload[..len]) {
+        sink.deliver(&message, &net_interfaces::peer_ip(&addr)).await;
+      }
+    }
+  }
+}
+
+async fn bind_with_fallback(ip: IpAddr, start_port: u16, attempts: u16) -> Result<(UdpSocket, u16), String> {
   let mut port = start_port;
   let mut remaining = attempts;
 
   loop {
-    match UdpSocket::bind(("0.0.0.0", port)).await {
+    match UdpSocket::bind((ip, port)).await {
       Ok(socket) => {
         let _ = socket.set_broadcast(true);
         return Ok((socket, port));
@@ -91,25 +243,16 @@ async fn bind_with_fallback(start_port: u16, attempts: u16) -> Result<(UdpSocket
   }
 }
 
-async fn discovery_loop(
-  socket: UdpSocket,
-  token: CancellationToken,
-  internal: InternalP2PManager,
-  discovery: NetworkDiscoveryManager,
-) {
-  let mut buf = vec![0u8; 8192];
-
-  loop {
-    tokio::select! {
-      _ = token.cancelled() => break,
-      res = socket.recv_from(&mut buf) => {
-        let Ok((len, addr)) = res else { continue; };
-        let payload = &buf[..len];
-        if let Ok(message) = serde_json::from_slice::<Value>(payload) {
-          internal.handle_discovery_message(&message, &addr.ip().to_string()).await;
-          discovery.handle_discovery_message(&message).await;
-        }
-      }
-    }
+async fn recv_optional(socket: Option<&UdpSocket>, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+  match socket {
+    Some(socket) => socket.recv_from(buf).await,
+    None => std::future::pending().await,
   }
 }
+
+fn app_setting(app: &CoreHost, key: &str) -> Option<String> {
+  let conn = app.open_db().ok()?;
+  conn
+    .query_row("SELECT value FROM app_settings WHERE key = ?1", params![key], |row| row.get::<_, String>(0))
+    .ok()
+}
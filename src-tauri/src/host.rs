@@ -0,0 +1,211 @@
+//! 코어가 바깥과 만나는 곳
+//!
+//! P2P 관리자와 디스커버리는 이벤트를 내보내고, 데이터 폴더의 로컬 DB를 열고, 포트를 연다.
+//! 앱에서는 이벤트가 Tauri 창으로, 헤드리스 데몬에서는 표준 출력으로, 테스트에서는
+//! [`RecordingSink`]로 간다. 한 프로세스에서 여러 인스턴스를 돌릴 수 있도록
+//! 데이터 폴더와 포트도 인스턴스마다 따로 둔다.
+
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json::Value;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::internal_p2p;
+
+/// 코어 이벤트를 받는 쪽
+pub trait EventSink: Send + Sync {
+  fn emit(&self, event: &str, payload: Value);
+}
+
+/// 데이터 폴더와 로컬 DB
+pub trait Storage: Send + Sync {
+  fn data_dir(&self) -> &Path;
+
+  /// 부를 때마다 새 연결을 연다. 스키마는 [`crate::schema::init_db`]로 만든다.
+  fn open_db(&self) -> rusqlite::Result<Connection>;
+
+  /// `downloadPath` 설정이 없을 때 받은 파일을 둘 곳. `None`이면 시스템 다운로드 폴더.
+  fn download_dir(&self) -> Option<PathBuf> {
+    None
+  }
+}
+
+/// 앱(Tauri `app_data_dir`)과 데몬이 쓰는 데이터 폴더. DB는 `local.db`다.
+pub struct DataDir {
+  dir: PathBuf,
+}
+
+impl DataDir {
+  pub fn new(dir: PathBuf) -> Self {
+    Self { dir }
+  }
+}
+
+impl Storage for DataDir {
+  fn data_dir(&self) -> &Path {
+    &self.dir
+  }
+
+  fn open_db(&self) -> rusqlite::Result<Connection> {
+    Connection::open(self.dir.join("local.db"))
+  }
+}
+
+/// 테스트용 임시 데이터 폴더. 받은 파일도 이 안에 두고, drop되면 통째로 지운다.
+pub struct TempDataDir {
+  dir: PathBuf,
+}
+
+impl TempDataDir {
+  pub fn new(name: &str) -> std::io::Result<Self> {
+    let dir = std::env::temp_dir().join(format!("edulinker-{name}-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    Ok(Self { dir })
+  }
+}
+
+impl Storage for TempDataDir {
+  fn data_dir(&self) -> &Path {
+    &self.dir
+  }
+
+  fn open_db(&self) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(self.dir.join("local.db"))?;
+    // 곧 지울 DB라 쓰기마다 fsync를 기다리지 않는다.
+    conn.pragma_update(None, "synchronous", "OFF")?;
+    Ok(conn)
+  }
+
+  fn download_dir(&self) -> Option<PathBuf> {
+    Some(self.dir.join("downloads"))
+  }
+}
+
+impl Drop for TempDataDir {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_dir_all(&self.dir);
+  }
+}
+
+/// 내보낸 이벤트를 메모리에 쌓아 둔다.
+#[derive(Default)]
+pub struct RecordingSink {
+  events: std::sync::Mutex<Vec<(String, Value)>>,
+}
+
+impl RecordingSink {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// 지금까지 받은 이벤트 (받은 순서)
+  pub fn events(&self) -> Vec<(String, Value)> {
+    self.events.lock().map(|events| events.clone()).unwrap_or_default()
+  }
+
+  /// `event` 이름으로 받은 페이로드들
+  pub fn payloads(&self, event: &str) -> Vec<Value> {
+    self
+      .events()
+      .into_iter()
+      .filter(|(name, _)| name == event)
+      .map(|(_, payload)| payload)
+      .collect()
+  }
+
+  pub fn clear(&self) {
+    if let Ok(mut events) = self.events.lock() {
+      events.clear();
+    }
+  }
+}
+
+impl EventSink for RecordingSink {
+  fn emit(&self, event: &str, payload: Value) {
+    if let Ok(mut events) = self.events.lock() {
+      events.push((event.to_string(), payload));
+    }
+  }
+}
+
+/// 인스턴스가 여는 포트와 주소. 기본값은 `INTERNAL_P2P_*_PORT` 환경 변수를 따른다.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetConfig {
+  pub discovery_port: u16,
+  pub udp_message_port: u16,
+  pub tcp_message_port: u16,
+  /// 이 주소에만 묶고 이 주소에서 보낸다. 한 호스트에서 127.0.0.x마다 인스턴스를 둘 때 쓴다.
+  pub bind_ip: Option<IpAddr>,
+  /// 메시지 포트로 받은 UDP 데이터그램과 TCP 연결을 이 비율(%)로 버린다.
+  /// 손실이 있는 링크를 흉내 내는 테스트용이며 기본값은 0이다.
+  #[cfg(any(test, feature = "test-harness"))]
+  pub inbound_loss_percent: u8,
+}
+
+impl NetConfig {
+  pub fn from_env() -> Self {
+    Self {
+      discovery_port: internal_p2p::requested_discovery_port(),
+      udp_message_port: internal_p2p::requested_udp_message_port(),
+      tcp_message_port: internal_p2p::requested_tcp_message_port(),
+      bind_ip: None,
+      #[cfg(any(test, feature = "test-harness"))]
+      inbound_loss_percent: 0,
+    }
+  }
+
+  /// 이번에 받은 패킷을 버릴지
+  #[cfg(any(test, feature = "test-harness"))]
+  pub fn drops_inbound(&self) -> bool {
+    self.inbound_loss_percent > 0 && uuid::Uuid::new_v4().as_u128() % 100 < u128::from(self.inbound_loss_percent)
+  }
+}
+
+/// 이벤트 전달처, 데이터 폴더, 포트
+#[derive(Clone)]
+pub struct CoreHost {
+  events: Arc<dyn EventSink>,
+  storage: Arc<dyn Storage>,
+  net: NetConfig,
+}
+
+impl CoreHost {
+  pub fn new(events: Arc<dyn EventSink>, storage: Arc<dyn Storage>) -> Self {
+    Self {
+      events,
+      storage,
+      net: NetConfig::from_env(),
+    }
+  }
+
+  /// 환경 변수 대신 이 포트를 쓴다.
+  pub fn with_net(mut self, net: NetConfig) -> Self {
+    self.net = net;
+    self
+  }
+
+  /// `tauri::Emitter::emit`과 같은 모양으로 이벤트를 내보낸다.
+  pub fn emit<S: Serialize>(&self, event: &str, payload: S) -> Result<(), String> {
+    let payload = serde_json::to_value(payload).map_err(|e| e.to_string())?;
+    self.events.emit(event, payload);
+    Ok(())
+  }
+
+  pub fn data_dir(&self) -> &Path {
+    self.storage.data_dir()
+  }
+
+  pub fn open_db(&self) -> rusqlite::Result<Connection> {
+    self.storage.open_db()
+  }
+
+  pub fn download_dir(&self) -> Option<PathBuf> {
+    self.storage.download_dir()
+  }
+
+  pub fn net(&self) -> NetConfig {
+    self.net
+  }
+}
@@ -1,15 +1,25 @@
-use serde::Serialize;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use sha2::Digest;
-use rusqlite::{params, Connection};
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use rusqlite::{params, Connection, TransactionBehavior};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tauri::{AppHandle, Emitter, Manager};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use tokio::sync::Mutex;
+use crate::p2p_crypto::{self, ChatKeys, IdentityKey};
+use crate::p2p_order::{self, ReorderBuffer, REORDER_WAIT};
+use crate::p2p_presence::{self, Presence, PresenceStatus};
+use crate::p2p_pool::ConnectionPool;
+use crate::p2p_udp::{self, Reassembler, Received};
+use crate::discovery_hub::{self, DiscoveryMode};
+use crate::host::CoreHost;
+use crate::net_interfaces;
+use crate::unicast_discovery::{self, UnicastTargets};
+use crate::p2p_protocol::{self, Incoming, P2PChannel, SecureConfig, CAPABILITIES, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::{Mutex, Notify};
 use tokio::time::timeout;
 use tokio_util::sync::CancellationToken;
 
@@ -19,12 +29,67 @@ pub struct PeerInfo {
   pub userId: String,
   pub userName: Option<String>,
   pub schoolId: Option<String>,
+  /// 연결에 쓰는 대표 주소
   pub ipAddress: String,
+  /// 최근에 이 피어를 확인한 주소들 (IPv4/IPv6, 최근 순)
+  pub addresses: Vec<String>,
+  /// 피어가 알린 UDP 메시지 포트
   pub port: u16,
+  pub tcpPort: u16,
+  pub discoveryPort: u16,
   pub lastSeen: String,
   pub isOnline: bool,
   pub hostname: Option<String>,
   pub platform: Option<String>,
+  pub protocolVersion: u16,
+  pub publicKey: Option<String>,
+  pub identityKey: Option<String>,
+  /// 오프라인 사용자 앞 메시지를 맡아 주는 중계 허브인지
+  pub isRelayHub: bool,
+  /// 피어가 마지막으로 알린 상태. 이전 버전 피어는 보내지 않는다.
+  pub presence: Option<Presence>,
+  /// 답이 오지 않은 연속 `ping` 수
+  #[serde(skip)]
+  missed_pongs: u32,
+  /// `presence`를 알린 패킷의 시각(피어 시계). 늦게 도착한 예전 상태로 덮어쓰지 않는다.
+  #[serde(skip)]
+  presence_at: i64,
+  /// 알림에서 `noise`를 광고했는지. 광고한 피어와는 암호화 없는 채널로 물러서지 않는다.
+  #[serde(skip)]
+  noise: bool,
+}
+
+impl PeerInfo {
+  fn has_address(&self, ip: &str) -> bool {
+    self.ipAddress == ip || self.addresses.iter().any(|address| address == ip)
+  }
+
+  /// 새로 확인한 주소를 기록한다. 대표 주소는 더 선호하는 주소가 보이거나
+  /// 목록에서 밀려났을 때만 바꿔서, 여러 경로로 들리는 피어의 연결이 흔들리지 않게 한다.
+  fn note_address(&mut self, ip: &str, was_online: bool) {
+    self.addresses.retain(|address| address != ip);
+    self.addresses.insert(0, ip.to_string());
+    self.addresses.truncate(MAX_PEER_ADDRESSES);
+
+    let replace = !was_online
+      || !self.addresses.contains(&self.ipAddress)
+      || net_interfaces::address_rank(ip) < net_interfaces::address_rank(&self.ipAddress);
+    if replace {
+      self.ipAddress = ip.to_string();
+    }
+  }
+
+  /// 대표 주소를 먼저, 나머지는 선호 순으로 돌려준다.
+  fn candidate_addresses(&self) -> Vec<String> {
+    let mut others = self
+      .addresses
+      .iter()
+      .filter(|address| **address != self.ipAddress)
+      .cloned()
+      .collect::<Vec<_>>();
+    others.sort_by_key(|address| net_interfaces::address_rank(address));
+    std::iter::once(self.ipAddress.clone()).chain(others).collect()
+  }
 }
 
 #[derive(Clone, Serialize)]
@@ -37,6 +102,145 @@ pub struct FileTransfer {
   pub status: String,
   pub direction: String,
   pub totalChunks: u64,
+  pub peerName: Option<String>,
+  pub filePath: Option<String>,
+  pub bytesTransferred: u64,
+  pub kind: String,
+  pub manifest: Option<Vec<ManifestEntry>>,
+}
+
+/// 다른 피어를 거쳐야 닿는 사용자로 가는 경로. 이웃들이 주고받은 도달 목록에서 배운다.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteInfo {
+  pub user_id: String,
+  pub user_name: Option<String>,
+  /// 다음 홉 이웃의 사용자 ID
+  pub via: String,
+  /// 목적지까지의 링크 수 (이웃을 한 번 거치면 2)
+  pub hops: u8,
+  pub public_key: Option<String>,
+  /// 목적지의 서명된 디스커버리 알림. 다른 이웃에게 그대로 전한다.
+  #[serde(skip)]
+  announcement: Value,
+  #[serde(skip)]
+  updated_at: i64,
+}
+
+/// 피어가 오프라인이라 아직 전달하지 못한 메시지. SQLite에 보관돼 재시작 후에도 재전송한다.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedMessage {
+  pub message_id: String,
+  pub receiver_id: String,
+  /// 받는 사람의 이 기기([`device_key`])에만 보낸다. 없으면 먼저 보이는 기기에 보낸다.
+  pub device: Option<String>,
+  pub message_type: String,
+  pub attempts: u32,
+  pub next_attempt_at: i64,
+  pub expires_at: i64,
+  pub last_error: Option<String>,
+  pub created_at: String,
+  /// 메시지를 맡아 둔 중계 허브의 사용자 ID. 허브가 받은 뒤에는 직접 재전송하지 않는다.
+  pub relay_holder: Option<String>,
+  pub message: Value,
+}
+
+/// 사용자별로 고정(TOFU)된 디스커버리 신원 키
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerKeyRecord {
+  pub user_id: String,
+  pub identity_key: String,
+  pub pending_key: Option<String>,
+  pub verified: bool,
+  /// 같은 사용자의 다른 기기로 받아들인 신원 키
+  pub device_keys: Vec<String>,
+}
+
+impl PeerKeyRecord {
+  fn accepts(&self, identity_key: &str) -> bool {
+    self.identity_key == identity_key || self.device_keys.iter().any(|key| key == identity_key)
+  }
+}
+
+/// 디스커버리 신원 키를 고정 기록과 비교한 결과
+#[derive(Default)]
+enum KeyPin {
+  /// DB를 쓸 수 없어 고정하지 못했다.
+  #[default]
+  Unpinned,
+  /// 처음 본 키를 고정했거나 고정된 키와 같다.
+  Pinned,
+  /// 고정된 키와 다르다. `first_seen`은 이 새 키를 처음 본 경우다.
+  Changed { previous: String, first_seen: bool },
+}
+
+/// 폴더 전송에 포함된 파일 하나. `path`는 폴더 기준 '/' 구분 상대 경로다.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+  pub path: String,
+  pub size: u64,
+  pub hash: String,
+}
+
+pub const DOWNLOAD_FOLDER_NAME: &str = "edulinker_file";
+
+const FILE_CHUNK_SIZE: u64 = 64 * 1024;
+const FILE_CHUNK_RETRIES: u32 = 3;
+
+const MAX_PEER_ADDRESSES: usize = 4;
+
+const QUEUE_MAX_ATTEMPTS: u32 = 10;
+const QUEUE_RETRY_BASE_MS: i64 = 5_000;
+const QUEUE_RETRY_MAX_MS: i64 = 10 * 60 * 1000;
+const DEFAULT_QUEUE_EXPIRY_HOURS: i64 = 72;
+
+/// 중계 허브가 디스커버리에 광고하는 기능
+const RELAY_CAPABILITY: &str = "relay";
+/// 중계 허브에 맡길 수 있는 메시지 종류
+const RELAY_MESSAGE_TYPES: &[&str] = &["chat", "read_receipt", "delivery_receipt"];
+
+/// 다른 피어를 거쳐 보낼 수 있는 메시지 종류. 파일 전송은 직접 연결만 쓴다.
+const ROUTABLE_MESSAGE_TYPES: &[&str] = &["chat", "read_receipt", "delivery_receipt", "typing"];
+const MAX_ROUTE_HOPS: u8 = 4;
+const ROUTE_GOSSIP_INTERVAL: Duration = Duration::from_secs(30);
+/// 광고가 세 번 끊기면 경로를 버린다.
+const ROUTE_TTL_MS: i64 = 3 * 30_000;
+
+/// 봉투 메시지는 중복 확인을 건너뛰고 안에 든 메시지로 확인한다.
+const DEDUP_EXEMPT_TYPES: &[&str] = &["ping", "pong", "goodbye", "relay", "relay_ack", "relay_deliver", "route", "route_advert"];
+/// 시계가 이만큼 빠른 피어의 메시지까지 받는다.
+const MAX_CLOCK_SKEW_MS: i64 = 10 * 60 * 1000;
+/// 서명된 디스커버리 알림을 받아들이는 시각 범위. 경로 광고로 몇 홉 건너온 알림도 이 안에 든다.
+const ANNOUNCEMENT_MAX_AGE_MS: i64 = 5 * 60 * 1000;
+/// 메모리에 둘 최근 메시지 ID 수. 넘치면 오래된 절반을 버리고 DB로 확인한다.
+const SEEN_CACHE_LIMIT: usize = 4096;
+/// 메모리 캐시는 재전송이 몰리는 동안만 들고 있는다. 그 뒤로는 DB로 확인한다.
+const SEEN_CACHE_TTL_MS: i64 = 10 * 60 * 1000;
+
+/// 봉인해서 보내는 메시지 종류
+const SEALED_MESSAGE_TYPES: &[&str] = &["chat", "sync_messages", "device_sync"];
+/// 다시 만났을 때 맞춰 보는 1:1 대화의 최근 메시지 수
+const SYNC_HISTORY_LIMIT: usize = 500;
+/// `sync_messages` 하나에 담는 기록 크기. 프레임 한도(1MB)보다 충분히 작게 둔다.
+const SYNC_BATCH_BYTES: usize = 256 * 1024;
+
+/// 하트비트(`ping`) 주기를 초로 담는 설정 키
+const HEARTBEAT_SETTING: &str = "p2pHeartbeatSeconds";
+/// 이만큼(초) `pong`이 없으면 오프라인으로 보는 설정 키
+const OFFLINE_SETTING: &str = "p2pOfflineSeconds";
+const DEFAULT_HEARTBEAT_SECS: u64 = 10;
+const DEFAULT_OFFLINE_SECS: u64 = 30;
+
+/// 받은 메시지를 처음 보는지
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Sighting {
+  #[default]
+  First,
+  Duplicate,
+  /// 신선도 창 밖의 타임스탬프. 캡처한 패킷을 다시 보낸 것으로 보고 버린다.
+  Stale,
 }
 
 struct InternalP2PState {
@@ -50,20 +254,43 @@ struct InternalP2PState {
   udp_message_port: u16,
   tcp_message_port: u16,
   peers: HashMap<String, PeerInfo>,
-  message_queue: HashMap<String, Vec<Value>>,
-  file_transfers: HashMap<String, FileTransfer>,
+  /// 직접 보이는 피어의 최근 서명된 디스커버리 알림 (사용자 ID별)
+  announcements: HashMap<String, Value>,
+  /// 피어 ID별로 마지막으로 받아들인 알림의 서명 시각. 같은 알림을 다시 보내면 거절한다.
+  announcement_times: HashMap<String, i64>,
+  routes: HashMap<String, RouteInfo>,
+  forwarding: bool,
+  chat_keys: Option<ChatKeys>,
+  identity_key: Option<IdentityKey>,
+  strict_transport: bool,
+  relay_hub: bool,
+  /// 최근 받은 메시지 (`보낸 사람|ID` → 받은 시각)
+  seen: HashMap<String, i64>,
+  reorder: ReorderBuffer,
+  /// 사용자가 고른 상태
+  chosen_presence: Presence,
+  /// 시간표를 반영해 지금 알리는 상태
+  presence: Presence,
+  heartbeat_interval: Duration,
+  /// 연속으로 이만큼 `pong`이 없으면 오프라인으로 본다.
+  missed_pong_limit: u32,
   cancel_token: Option<CancellationToken>,
   tasks: Vec<tokio::task::JoinHandle<()>>,
 }
 
 #[derive(Clone)]
 pub struct InternalP2PManager {
-  app: AppHandle,
+  app: CoreHost,
   state: std::sync::Arc<Mutex<InternalP2PState>>,
+  pool: std::sync::Arc<ConnectionPool>,
+  queue_flush: std::sync::Arc<Mutex<()>>,
+  /// 이웃이나 경로가 새로 생겼음을 경로 광고 루프에 알린다.
+  routes_changed: std::sync::Arc<Notify>,
 }
 
 impl InternalP2PManager {
-  pub fn new(app: AppHandle) -> Self {
+  pub fn new(app: CoreHost) -> Self {
+    let net = app.net();
     let state = InternalP2PState {
       running: false,
       my_peer_id: generate_peer_id(),
@@ -71,12 +298,24 @@ impl InternalP2PManager {
       my_user_name: String::new(),
       my_school_id: String::new(),
       my_ip: String::new(),
-      discovery_port: requested_discovery_port(),
-      udp_message_port: requested_udp_message_port(),
-      tcp_message_port: requested_tcp_message_port(),
+      discovery_port: net.discovery_port,
+      udp_message_port: net.udp_message_port,
+      tcp_message_port: net.tcp_message_port,
       peers: HashMap::new(),
-      message_queue: HashMap::new(),
-      file_transfers: HashMap::new(),
+      announcements: HashMap::new(),
+      announcement_times: HashMap::new(),
+      routes: HashMap::new(),
+      forwarding: true,
+      chat_keys: None,
+      identity_key: None,
+      strict_transport: false,
+      relay_hub: false,
+      seen: HashMap::new(),
+      reorder: ReorderBuffer::default(),
+      chosen_presence: Presence::default(),
+      presence: Presence::default(),
+      heartbeat_interval: Duration::from_secs(DEFAULT_HEARTBEAT_SECS),
+      missed_pong_limit: DEFAULT_OFFLINE_SECS.div_ceil(DEFAULT_HEARTBEAT_SECS) as u32,
       cancel_token: None,
       tasks: Vec::new(),
     };
@@ -84,6 +323,9 @@ impl InternalP2PManager {
     Self {
       app,
       state: std::sync::Arc::new(Mutex::new(state)),
+      pool: std::sync::Arc::new(ConnectionPool::new()),
+      queue_flush: std::sync::Arc::new(Mutex::new(())),
+      routes_changed: std::sync::Arc::new(Notify::new()),
     }
   }
 
@@ -94,6 +336,31 @@ impl InternalP2PManager {
     school_id: Option<String>,
     discovery_port: u16,
   ) -> Result<Value, String> {
+    let keys = self.with_app(load_or_create_keys).await;
+    let strict_transport = self.with_app(|app| app_setting(app, "p2pStrictTransport")).await.as_deref() == Some("true");
+    let discovery_mode = DiscoveryMode::from_setting(self.with_app(|app| app_setting(app, discovery_hub::BACKEND_SETTING)).await);
+    let relay_hub = self.with_app(|app| app_setting(app, "p2pRelayHub")).await.as_deref() == Some("true")
+      || matches!(std::env::var("INTERNAL_P2P_RELAY_HUB").as_deref(), Ok("1") | Ok("true"));
+    let forwarding = self.with_app(|app| app_setting(app, "p2pForwarding")).await.as_deref() != Some("false");
+    let chosen_presence = self
+      .with_app(|app| app_setting(app, p2p_presence::PRESENCE_SETTING))
+      .await
+      .and_then(|value| serde_json::from_str::<Presence>(&value).ok())
+      .unwrap_or_default();
+    let presence = chosen_presence.effective(self.with_app(current_class_period).await.as_deref());
+    let heartbeat_secs = self
+      .with_app(|app| app_setting(app, HEARTBEAT_SETTING))
+      .await
+      .and_then(|value| value.trim().parse::<u64>().ok())
+      .filter(|secs| *secs > 0)
+      .unwrap_or(DEFAULT_HEARTBEAT_SECS);
+    let offline_secs = self
+      .with_app(|app| app_setting(app, OFFLINE_SETTING))
+      .await
+      .and_then(|value| value.trim().parse::<u64>().ok())
+      .unwrap_or(DEFAULT_OFFLINE_SECS)
+      .max(heartbeat_secs);
+
     let mut state = self.state.lock().await;
     if state.running {
       return Ok(json!({
@@ -106,8 +373,17 @@ impl InternalP2PManager {
     state.my_user_id = user_id.clone();
     state.my_user_name = user_name.clone();
     state.my_school_id = school_id.unwrap_or_else(|| "default-school".to_string());
-    state.my_ip = get_local_ip();
+    state.my_ip = self.app.net().bind_ip.map(|ip| ip.to_string()).unwrap_or_else(get_local_ip);
     state.discovery_port = discovery_port;
+    state.chat_keys = keys.as_ref().map(|(chat_keys, _)| chat_keys.clone());
+    state.identity_key = keys.map(|(_, identity_key)| identity_key);
+    state.strict_transport = strict_transport;
+    state.relay_hub = relay_hub;
+    state.forwarding = forwarding;
+    state.chosen_presence = chosen_presence;
+    state.presence = presence;
+    state.heartbeat_interval = Duration::from_secs(heartbeat_secs);
+    state.missed_pong_limit = offline_secs.div_ceil(heartbeat_secs) as u32;
 
     let token = CancellationToken::new();
     state.cancel_token = Some(token.clone());
@@ -127,12 +403,16 @@ impl InternalP2PManager {
       manager.tcp_message_loop(tcp_port, token2).await;
     });
 
-    let token3 = token.clone();
-    let manager = self.clone();
-    let discovery_port = state.discovery_port;
-    let discovery_task = tokio::spawn(async move {
-      manager.discovery_broadcast_loop(discovery_port, token3).await;
-    });
+    // mDNS만 쓰면 UDP 디스커버리를 받는 쪽이 없으므로 보내지도 않는다.
+    let mut tasks = Vec::new();
+    if discovery_mode.uses_broadcast() {
+      let token3 = token.clone();
+      let manager = self.clone();
+      let discovery_port = state.discovery_port;
+      tasks.push(tokio::spawn(async move {
+        manager.discovery_broadcast_loop(discovery_port, token3).await;
+      }));
+    }
 
     let token4 = token.clone();
     let manager = self.clone();
@@ -146,7 +426,29 @@ impl InternalP2PManager {
       manager.heartbeat_loop(token5).await;
     });
 
-    state.tasks = vec![udp_task, tcp_task, discovery_task, cleanup_task, heartbeat_task];
+    let token6 = token.clone();
+    let manager = self.clone();
+    let queue_task = tokio::spawn(async move {
+      manager.queue_retry_loop(token6).await;
+    });
+
+    let token7 = token.clone();
+    let manager = self.clone();
+    let gossip_task = tokio::spawn(async move {
+      manager.route_gossip_loop(token7).await;
+    });
+
+    let token8 = token.clone();
+    let manager = self.clone();
+    let presence_task = tokio::spawn(async move {
+      manager.presence_loop(token8).await;
+    });
+
+    tasks.extend([udp_task, tcp_task, cleanup_task, heartbeat_task, queue_task, gossip_task, presence_task]);
+    state.tasks = tasks;
+
+    let app = self.app.clone();
+    tokio::task::spawn_blocking(move || interrupt_stale_file_transfers(&app));
 
     let info = self.info_from_state(&state);
     let _ = self.app.emit("p2p:started", info.clone());
@@ -167,9 +469,22 @@ impl InternalP2PManager {
     }
 
     state.tasks.clear();
-    state.peers.clear();
-    state.message_queue.clear();
+    drop(state);
+    // 피어들이 하트비트 타임아웃을 기다리지 않고 바로 오프라인으로 표시하도록 알린다.
+    // 수신을 멈춘 뒤에 보내야 그 사이 디스커버리 응답으로 다시 온라인이 되지 않는다.
+    self.send_goodbyes().await;
 
+    let mut state = self.state.lock().await;
+    state.peers.clear();
+    state.announcements.clear();
+    state.routes.clear();
+    let held = state.reorder.drain();
+    drop(state);
+    self.pool.clear();
+
+    for message in held {
+      self.emit_conversation_message(&message).await;
+    }
     let _ = self.app.emit("p2p:stopped", json!({}));
 
     Ok(json!({"success": true}))
@@ -181,6 +496,7 @@ impl InternalP2PManager {
       "running": state.running,
       "info": self.info_from_state(&state),
       "peers": state.peers.values().cloned().collect::<Vec<_>>(),
+      "routes": live_routes(&state),
       "onlinePeers": state
         .peers
         .values()
@@ -195,6 +511,7 @@ impl InternalP2PManager {
     json!({
       "success": true,
       "peers": state.peers.values().cloned().collect::<Vec<_>>(),
+      "routes": live_routes(&state),
       "onlinePeers": state
         .peers
         .values()
@@ -222,6 +539,8 @@ impl InternalP2PManager {
     } else {
       message_id.to_string()
     };
+    let conversation = format!("direct:{receiver_id}");
+    let (seq, prev_seq) = self.with_app(move |app| next_sequence(app, &conversation)).await;
 
     let message = json!({
       "id": id,
@@ -230,12 +549,15 @@ impl InternalP2PManager {
       "senderName": sender_name,
       "receiverId": receiver_id,
       "content": content,
-      "timestamp": now_iso()
+      "timestamp": now_iso(),
+      "seq": seq,
+      "prevSeq": prev_seq
     });
 
     let result = self.send_to_peer(receiver_id, &message).await;
     let delivered = result.get("error").is_none();
     self.persist_message(message.clone(), delivered, false).await;
+    self.spawn_device_sync(message);
     Ok(result)
   }
 
@@ -254,6 +576,7 @@ impl InternalP2PManager {
     });
 
     let _ = self.send_to_peer(sender_id, &receipt).await;
+    self.spawn_device_sync(receipt);
     Ok(json!({"success": true}))
   }
 
@@ -276,24 +599,40 @@ impl InternalP2PManager {
 
   pub async fn offer_file(&self, data: Value) -> Result<Value, String> {
     let receiver_id = data.get("receiverId").and_then(|v| v.as_str()).ok_or("missing receiverId")?;
-    let file_name = data.get("fileName").and_then(|v| v.as_str()).unwrap_or("unknown");
-    let file_size = data.get("fileSize").and_then(|v| v.as_u64()).unwrap_or(0);
+    let file_path = data.get("filePath").and_then(|v| v.as_str()).ok_or("missing filePath")?;
+
+    let metadata = tokio::fs::metadata(file_path)
+      .await
+      .map_err(|e| format!("cannot read {file_path}: {e}"))?;
+    if !metadata.is_file() {
+      return Err(format!("{file_path} is not a file"));
+    }
+
+    let file_name = data
+      .get("fileName")
+      .and_then(|v| v.as_str())
+      .map(|s| s.to_string())
+      .or_else(|| Path::new(file_path).file_name().map(|n| n.to_string_lossy().to_string()))
+      .unwrap_or_else(|| "unknown".to_string());
+    let file_size = metadata.len();
 
     let transfer = FileTransfer {
       id: uuid::Uuid::new_v4().to_string(),
       peerId: receiver_id.to_string(),
-      fileName: file_name.to_string(),
+      fileName: file_name.clone(),
       fileSize: file_size,
       progress: 0,
       status: "pending".to_string(),
       direction: "send".to_string(),
-      totalChunks: (file_size / (64 * 1024)).max(1),
+      totalChunks: total_chunks(file_size),
+      peerName: None,
+      filePath: Some(file_path.to_string()),
+      bytesTransferred: 0,
+      kind: "file".to_string(),
+      manifest: None,
     };
 
-    {
-      let mut state = self.state.lock().await;
-      state.file_transfers.insert(transfer.id.clone(), transfer.clone());
-    }
+    self.save_transfer(&transfer, None).await;
 
     let offer = json!({
       "id": transfer.id,
@@ -308,69 +647,250 @@ impl InternalP2PManager {
     });
 
     let _ = self.send_to_peer(receiver_id, &offer).await;
-    Ok(json!({"success": true, "transfer": transfer}))
+    Ok(json!({"success": true, "transfer": self.transfer_payload(&transfer).await}))
   }
 
-  pub async fn accept_file(&self, transfer_id: String) -> Result<Value, String> {
-    let (peer_id, accept) = {
-      let mut state = self.state.lock().await;
-      if let Some(transfer) = state.file_transfers.get_mut(&transfer_id) {
-        transfer.status = "accepted".to_string();
-        let peer_id = transfer.peerId.clone();
-        let accept = json!({
-          "id": uuid::Uuid::new_v4().to_string(),
-          "type": "file_accept",
-          "senderId": state.my_user_id,
-          "receiverId": peer_id,
-          "timestamp": now_iso(),
-          "messageId": transfer_id
-        });
-        (Some(peer_id), Some(accept))
-      } else {
-        (None, None)
-      }
+  /// 폴더 안의 파일 목록(상대 경로, 크기, SHA-256)을 매니페스트로 만들어 한 번의 전송으로 제안한다.
+  pub async fn offer_folder(&self, data: Value) -> Result<Value, String> {
+    let receiver_id = data.get("receiverId").and_then(|v| v.as_str()).ok_or("missing receiverId")?;
+    let folder_path = data.get("folderPath").and_then(|v| v.as_str()).ok_or("missing folderPath")?;
+
+    let root = PathBuf::from(folder_path);
+    let manifest = {
+      let root = root.clone();
+      tokio::task::spawn_blocking(move || collect_manifest(&root))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("cannot read {folder_path}: {e}"))?
+    };
+
+    let folder_name = root
+      .file_name()
+      .map(|n| n.to_string_lossy().to_string())
+      .unwrap_or_else(|| "folder".to_string());
+    let total_size = manifest.iter().map(|entry| entry.size).sum::<u64>();
+
+    let transfer = FileTransfer {
+      id: uuid::Uuid::new_v4().to_string(),
+      peerId: receiver_id.to_string(),
+      fileName: folder_name.clone(),
+      fileSize: total_size,
+      progress: 0,
+      status: "pending".to_string(),
+      direction: "send".to_string(),
+      totalChunks: total_chunks(total_size),
+      peerName: None,
+      filePath: Some(folder_path.to_string()),
+      bytesTransferred: 0,
+      kind: "folder".to_string(),
+      manifest: Some(manifest.clone()),
     };
 
-    if let (Some(peer_id), Some(accept)) = (peer_id, accept) {
-      let _ = self.send_to_peer(&peer_id, &accept).await;
+    self.save_transfer(&transfer, None).await;
+
+    let offer = json!({
+      "id": transfer.id,
+      "type": "folder_offer",
+      "senderId": self.my_user_id().await,
+      "senderName": self.my_user_name().await,
+      "receiverId": receiver_id,
+      "timestamp": now_iso(),
+      "fileName": folder_name,
+      "fileSize": total_size,
+      "totalChunks": transfer.totalChunks,
+      "manifest": manifest
+    });
+
+    let _ = self.send_to_peer(receiver_id, &offer).await;
+    Ok(json!({"success": true, "transfer": self.transfer_payload(&transfer).await}))
+  }
+
+  pub async fn accept_file(&self, transfer_id: String) -> Result<Value, String> {
+    let Some((mut transfer, _)) = self.load_transfer(&transfer_id).await else {
+      return Ok(json!({"success": false, "error": "Transfer not found"}));
+    };
+    if transfer.direction != "receive" || transfer.status != "pending" {
+      return Ok(json!({"success": false, "error": "Transfer is not awaiting acceptance"}));
     }
 
+    let download_dir = {
+      let app = self.app.clone();
+      tokio::task::spawn_blocking(move || download_dir_for(&app))
+        .await
+        .map_err(|e| e.to_string())?
+    };
+    tokio::fs::create_dir_all(&download_dir)
+      .await
+      .map_err(|e| format!("failed to create {}: {e}", download_dir.display()))?;
+
+    let destination = unique_destination(&download_dir, &transfer.fileName);
+    transfer.filePath = Some(destination.to_string_lossy().to_string());
+    prepare_partial(&transfer, &destination)
+      .await
+      .map_err(|e| format!("failed to create {}: {e}", destination.display()))?;
+
+    transfer.status = "accepted".to_string();
+    self
+      .save_transfer(&transfer, Some(new_chunk_bitmap(transfer.totalChunks)))
+      .await;
+
+    let accept = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "file_accept",
+      "senderId": self.my_user_id().await,
+      "receiverId": transfer.peerId,
+      "timestamp": now_iso(),
+      "messageId": transfer_id
+    });
+    let _ = self.send_to_peer(&transfer.peerId, &accept).await;
+
     Ok(json!({"success": true}))
   }
 
   pub async fn reject_file(&self, transfer_id: String) -> Result<Value, String> {
-    let (peer_id, reject) = {
-      let mut state = self.state.lock().await;
-      if let Some(transfer) = state.file_transfers.remove(&transfer_id) {
-        let peer_id = transfer.peerId.clone();
-        let reject = json!({
-          "id": uuid::Uuid::new_v4().to_string(),
-          "type": "file_reject",
-          "senderId": state.my_user_id,
-          "receiverId": peer_id,
-          "timestamp": now_iso(),
-          "messageId": transfer_id
-        });
-        (Some(peer_id), Some(reject))
-      } else {
-        (None, None)
-      }
+    let Some((transfer, _)) = self.load_transfer(&transfer_id).await else {
+      return Ok(json!({"success": true}));
     };
 
-    if let (Some(peer_id), Some(reject)) = (peer_id, reject) {
-      let _ = self.send_to_peer(&peer_id, &reject).await;
+    self.delete_transfer(&transfer_id).await;
+    if let (Some(destination), "receive") = (&transfer.filePath, transfer.direction.as_str()) {
+      remove_partial(&transfer, Path::new(destination)).await;
     }
 
+    let reject = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "file_reject",
+      "senderId": self.my_user_id().await,
+      "receiverId": transfer.peerId,
+      "timestamp": now_iso(),
+      "messageId": transfer_id
+    });
+    let _ = self.send_to_peer(&transfer.peerId, &reject).await;
+
     Ok(json!({"success": true}))
   }
 
+  /// 중단된 전송을 마지막으로 검증된 청크 이후부터 다시 받는다.
+  /// 수신 측은 받은 청크 비트맵을 보내고, 송신 측은 수신 측에 비트맵을 요청한다.
+  pub async fn resume_file(&self, transfer_id: String) -> Result<Value, String> {
+    let Some((mut transfer, received)) = self.load_transfer(&transfer_id).await else {
+      return Ok(json!({"success": false, "error": "Transfer not found"}));
+    };
+    if transfer.status == "pending" || transfer.status == "completed" {
+      return Ok(json!({"success": false, "error": format!("Transfer is {}", transfer.status)}));
+    }
+
+    let my_user_id = self.my_user_id().await;
+
+    if transfer.direction == "send" {
+      let request = json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "type": "file_resume_request",
+        "senderId": my_user_id,
+        "receiverId": transfer.peerId,
+        "timestamp": now_iso(),
+        "messageId": transfer_id
+      });
+      let _ = self.send_to_peer(&transfer.peerId, &request).await;
+      return Ok(json!({"success": true, "transfer": self.transfer_payload(&transfer).await}));
+    }
+
+    let Some(destination) = transfer.filePath.clone() else {
+      return Ok(json!({"success": false, "error": "Transfer has no destination"}));
+    };
+
+    // 부분 파일이 사라졌다면 처음부터 다시 받는다.
+    let partial = partial_path(Path::new(&destination));
+    let received = if tokio::fs::metadata(&partial).await.is_ok() {
+      received
+    } else {
+      prepare_partial(&transfer, Path::new(&destination))
+        .await
+        .map_err(|e| format!("failed to create {}: {e}", partial.display()))?;
+      new_chunk_bitmap(transfer.totalChunks)
+    };
+
+    transfer.status = "accepted".to_string();
+    transfer.bytesTransferred = received_bytes(&received, transfer.totalChunks, transfer.fileSize);
+    self.save_transfer(&transfer, Some(received.clone())).await;
+
+    let resume = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "file_resume",
+      "senderId": my_user_id,
+      "receiverId": transfer.peerId,
+      "timestamp": now_iso(),
+      "messageId": transfer_id,
+      "receivedChunks": base64::engine::general_purpose::STANDARD.encode(&received)
+    });
+    let _ = self.send_to_peer(&transfer.peerId, &resume).await;
+
+    Ok(json!({"success": true, "transfer": self.transfer_payload(&transfer).await}))
+  }
+
   pub async fn get_file_transfers(&self) -> Value {
-    let state = self.state.lock().await;
+    let transfers = self.with_app(list_file_transfers).await;
     json!({
       "success": true,
-      "transfers": state.file_transfers.values().cloned().collect::<Vec<_>>()
+      "transfers": transfers
     })
   }
+  pub async fn get_queued_messages(&self, data: Value) -> Value {
+    let receiver_id = data.get("receiverId").and_then(|v| v.as_str()).map(|s| s.to_string());
+    let messages = self
+      .with_app(move |app| list_queued_messages(app, receiver_id.as_deref()))
+      .await;
+    json!({"success": true, "messages": messages})
+  }
+
+  /// 대기 중인 메시지의 백오프를 풀고, 수신자가 온라인이면 바로 다시 보낸다.
+  pub async fn retry_queued_message(&self, message_id: String) -> Result<Value, String> {
+    let _flush = self.queue_flush.lock().await;
+    let entries = self
+      .with_app(move |app| load_queued_messages(app, &message_id))
+      .await;
+    if entries.is_empty() {
+      return Err("queued message not found".to_string());
+    }
+
+    // 기기마다 대기시킨 사본은 각각 보낸다.
+    let mut delivered = true;
+    for entry in entries {
+      let target_ip = {
+        let state = self.state.lock().await;
+        queued_target(&state, &entry)
+      };
+      delivered &= match target_ip {
+        Some(ip) => {
+          self.pool.reset_backoff(&ip);
+          self.attempt_queued(entry, &ip).await
+        }
+        None => {
+          let now = now_unix_ms();
+          self
+            .with_app(move |app| {
+              update_queued_message(app, &entry, entry.attempts, now, entry.last_error.as_deref())
+            })
+            .await;
+          false
+        }
+      };
+    }
+
+    Ok(json!({"success": true, "delivered": delivered}))
+  }
+
+  pub async fn cancel_queued_message(&self, message_id: String) -> Result<Value, String> {
+    let _flush = self.queue_flush.lock().await;
+    let deleted = self
+      .with_app(move |app| delete_queued_message(app, &message_id))
+      .await;
+    if !deleted {
+      return Err("queued message not found".to_string());
+    }
+    Ok(json!({"success": true}))
+  }
+
 
   pub async fn send_group_message(&self, data: Value) -> Result<Value, String> {
     let group_id = data.get("groupId").and_then(|v| v.as_str()).ok_or("missing groupId")?;
@@ -387,6 +907,26 @@ impl InternalP2PManager {
 
     let sender_id = self.my_user_id().await;
     let sender_name = self.my_user_name().await;
+    // 멤버마다 따로 보내도 그룹 대화에서는 한 메시지이므로 번호도 하나다.
+    let conversation = format!("group:{group_id}");
+    let (seq, prev_seq) = self.with_app(move |app| next_sequence(app, &conversation)).await;
+
+    let message_for = |receiver_id: &str| {
+      json!({
+        "id": id,
+        "type": "group_chat",
+        "senderId": sender_id,
+        "senderName": sender_name,
+        "receiverId": receiver_id,
+        "content": content,
+        "timestamp": now_iso(),
+        "groupId": group_id,
+        "groupName": group_name,
+        "memberIds": member_ids.clone(),
+        "seq": seq,
+        "prevSeq": prev_seq
+      })
+    };
 
     let mut failed = Vec::new();
 
@@ -399,24 +939,13 @@ impl InternalP2PManager {
         continue;
       }
 
-      let message = json!({
-        "id": id,
-        "type": "group_chat",
-        "senderId": sender_id,
-        "senderName": sender_name,
-        "receiverId": member_id,
-        "content": content,
-        "timestamp": now_iso(),
-        "groupId": group_id,
-        "groupName": group_name,
-        "memberIds": member_ids.clone()
-      });
-
+      let message = message_for(member_id);
       let result = self.send_to_peer(member_id, &message).await;
       if !result.get("success").and_then(|v| v.as_bool()).unwrap_or(false) {
         failed.push(member_id.to_string());
       }
     }
+    self.spawn_device_sync(message_for(&sender_id));
 
     Ok(json!({"success": true, "messageId": id, "failedRecipients": failed}))
   }
@@ -583,6 +1112,16 @@ impl InternalP2PManager {
 
     let school_id = message.get("schoolId").and_then(|v| v.as_str()).unwrap_or("default-school");
 
+    {
+      let state = self.state.lock().await;
+      // 디스커버리 허브는 P2P를 멈춘 뒤에도 알림을 넘긴다. 답하면 피어가 다시 온라인으로 본다.
+      if peer_id == state.my_peer_id || !state.running {
+        return;
+      }
+    }
+
+    let Some(identity_key) = self.check_announcement(message, user_id, sender_ip).await else { return; };
+
     let mut state = self.state.lock().await;
     if peer_id == state.my_peer_id {
       return;
@@ -599,34 +1138,293 @@ impl InternalP2PManager {
       .get(peer_id)
       .map(|peer| !peer.isOnline)
       .unwrap_or(false);
+    let (ip_address, addresses) = state
+      .peers
+      .get(peer_id)
+      .map(|peer| (peer.ipAddress.clone(), peer.addresses.clone()))
+      .unwrap_or_else(|| (sender_ip.to_string(), Vec::new()));
+    let previous_presence = state.peers.get(peer_id).and_then(|peer| peer.presence.clone());
+    let previous_presence_at = state.peers.get(peer_id).map(|peer| peer.presence_at).unwrap_or(i64::MIN);
+    // mDNS로 받은 알림에는 상태가 없다. 그때나 하트비트로 더 새 상태를 받았을 때는 아는 상태를 그대로 둔다.
+    let sent_at = packet_time(message);
+    let (presence, presence_at) = match Presence::from_value(message.get("presence")) {
+      Some(presence) if sent_at > previous_presence_at => (Some(presence), sent_at),
+      _ => (previous_presence.clone(), previous_presence_at),
+    };
 
-    let peer = PeerInfo {
+    let mut peer = PeerInfo {
       peerId: peer_id.to_string(),
       userId: user_id.to_string(),
       userName: message.get("userName").and_then(|v| v.as_str()).map(|s| s.to_string()),
       schoolId: Some(school_id.to_string()),
-      ipAddress: sender_ip.to_string(),
-      port: state.udp_message_port,
+      ipAddress: ip_address,
+      addresses,
+      port: announced_port(message, "udpPort", state.udp_message_port),
+      tcpPort: announced_port(message, "tcpPort", state.tcp_message_port),
+      discoveryPort: announced_port(message, "discoveryPort", state.discovery_port),
       lastSeen: now,
       isOnline: true,
       hostname: message.get("hostname").and_then(|v| v.as_str()).map(|s| s.to_string()),
       platform: message.get("platform").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      protocolVersion: message
+        .get("protocolVersion")
+        .and_then(|v| v.as_u64())
+        .map(|v| v.min(u16::MAX as u64) as u16)
+        .unwrap_or(LEGACY_PROTOCOL_VERSION),
+      publicKey: message.get("publicKey").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      identityKey: identity_key,
+      isRelayHub: advertises(message, RELAY_CAPABILITY),
+      presence,
+      missed_pongs: 0,
+      presence_at,
+      noise: advertises(message, "noise"),
     };
+    peer.note_address(sender_ip, !is_new && !was_offline);
 
     state.peers.insert(peer_id.to_string(), peer.clone());
+    if peer.identityKey.is_some() {
+      state.announcements.insert(user_id.to_string(), message.clone());
+    }
+    // 직접 닿게 되었으니 이웃을 거치는 경로는 더 쓰지 않는다.
+    state.routes.remove(user_id);
+    self.pool.reset_backoff(sender_ip);
 
     if is_new {
       let _ = self.app.emit("p2p:peer-discovered", peer.clone());
     } else if was_offline {
       let _ = self.app.emit("p2p:peer-online", peer.clone());
     }
+    if is_new || was_offline {
+      self.routes_changed.notify_one();
+    }
+    if !is_new && peer.presence != previous_presence {
+      self.emit_presence_changed(&peer);
+    }
 
     if msg_type == Some("discovery") {
       drop(state);
-      let _ = self.send_discovery_response(sender_ip).await;
+      let _ = self.send_discovery_response(sender_ip, peer.discoveryPort).await;
+    }
+
+    // 대기열 재전송이 디스커버리 처리를 막지 않도록 별도 태스크에서 보낸다.
+    // 새로 보이거나 다시 온라인이 된 피어와는 대기열을 비운 뒤 대화 기록을 맞춘다.
+    let manager = self.clone();
+    let user_id = user_id.to_string();
+    let device = device_key(&peer);
+    let sender_ip = sender_ip.to_string();
+    let reconnected = is_new || was_offline;
+    tokio::spawn(async move {
+      manager.deliver_queued_messages(&user_id, &device, &sender_ip).await;
+      manager.deliver_relayed_messages(&user_id, &sender_ip).await;
+      if reconnected {
+        manager.send_sync_summary(&user_id, &sender_ip).await;
+      }
+    });
+  }
+
+  /// 서명된 디스커버리 패킷을 검증하고, 사용자별로 처음 본 신원 키를 고정한다.
+  /// 고정된 키와 다르거나 키가 고정된 사용자가 서명 없이 광고하면 사칭으로 보고 `None`.
+  /// 서명하지 않는 이전 버전 피어는 `Some(None)`으로 받아들인다.
+  async fn check_announcement(&self, message: &Value, user_id: &str, sender_ip: &str) -> Option<Option<String>> {
+    let user = user_id.to_string();
+    if message.get("signature").is_none() && message.get("identityKey").is_none() {
+      let pinned = self.with_app(move |app| load_peer_key(app, &user)).await;
+      return if pinned.is_some() { None } else { Some(None) };
+    }
+
+    let identity_key = p2p_crypto::verify_announcement(message).ok()?;
+    // 캡처한 알림을 다른 주소에서 다시 보내 피어 주소를 가로채지 못하게 한다.
+    let signed_at = self.fresh_announcement_time(message).await?;
+    let key = identity_key.clone();
+    match self.with_app(move |app| pin_peer_key(app, &user, &key)).await {
+      KeyPin::Unpinned | KeyPin::Pinned => {
+        let peer_id = message.get("peerId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+        let mut state = self.state.lock().await;
+        // 검증하는 사이 더 새로운 알림이 먼저 기록됐을 수 있다.
+        if state.announcement_times.get(&peer_id).is_some_and(|last| *last >= signed_at) {
+          return None;
+        }
+        state.announcement_times.insert(peer_id, signed_at);
+        Some(Some(identity_key))
+      }
+      KeyPin::Changed { previous, first_seen } => {
+        if first_seen {
+          let _ = self.app.emit(
+            "p2p:peer-key-changed",
+            json!({
+              "userId": user_id,
+              "userName": message.get("userName").and_then(|v| v.as_str()),
+              "ipAddress": sender_ip,
+              "previousKey": previous,
+              "newKey": identity_key
+            }),
+          );
+        }
+        None
+      }
+    }
+  }
+
+  /// 알림의 서명 시각이 허용 범위 안이고 그 피어에게서 받아들인 마지막 알림보다 새로우면 그 시각
+  async fn fresh_announcement_time(&self, message: &Value) -> Option<i64> {
+    let peer_id = message.get("peerId").and_then(|v| v.as_str())?;
+    let signed_at = message
+      .get("timestamp")
+      .and_then(|v| v.as_str())
+      .and_then(|value| parse_iso(value).ok())?;
+    if (now_unix_ms() - signed_at).abs() > ANNOUNCEMENT_MAX_AGE_MS {
+      return None;
+    }
+    let state = self.state.lock().await;
+    match state.announcement_times.get(peer_id) {
+      Some(last) if *last >= signed_at => None,
+      _ => Some(signed_at),
+    }
+  }
+
+  /// 두 사용자가 직접 만나 비교할 수 있는 안전 번호
+  pub async fn get_safety_number(&self, user_id: String) -> Result<Value, String> {
+    let (my_user_id, my_key) = {
+      let state = self.state.lock().await;
+      (
+        state.my_user_id.clone(),
+        state.identity_key.as_ref().map(|key| key.public_key()),
+      )
+    };
+    let my_key = my_key.ok_or("P2P is not started")?;
+
+    let uid = user_id.clone();
+    let record = self
+      .with_app(move |app| load_peer_key(app, &uid))
+      .await
+      .ok_or("no key is pinned for this user")?;
+    let safety_number = p2p_crypto::safety_number(&my_user_id, &my_key, &user_id, &record.identity_key)?;
+
+    Ok(json!({
+      "success": true,
+      "userId": user_id,
+      "safetyNumber": safety_number,
+      "verified": record.verified,
+      "keyChanged": record.pending_key.is_some()
+    }))
+  }
+
+  /// 안전 번호를 직접 확인했음을 기록한다.
+  pub async fn verify_peer_key(&self, user_id: String, verified: bool) -> Result<Value, String> {
+    let updated = self
+      .with_app(move |app| set_peer_key_verified(app, &user_id, verified))
+      .await;
+    if !updated {
+      return Err("no key is pinned for this user".to_string());
+    }
+    Ok(json!({"success": true, "verified": verified}))
+  }
+
+  /// 바뀐 신원 키를 받아들여 새로 고정한다. 확인 표시는 초기화된다.
+  pub async fn trust_peer_key(&self, user_id: String) -> Result<Value, String> {
+    let updated = self
+      .with_app(move |app| accept_pending_peer_key(app, &user_id))
+      .await;
+    if !updated {
+      return Err("no key change is pending for this user".to_string());
+    }
+    Ok(json!({"success": true}))
+  }
+
+  /// 바뀐 것으로 보였던 키가 같은 사용자가 로그인한 다른 기기의 것이면 기존 키와 함께 고정한다.
+  /// 안전 번호와 확인 표시는 기존 키 기준 그대로다.
+  pub async fn trust_peer_device(&self, user_id: String) -> Result<Value, String> {
+    let updated = self
+      .with_app(move |app| accept_pending_peer_device(app, &user_id))
+      .await;
+    if !updated {
+      return Err("no key change is pending for this user".to_string());
+    }
+    // 그동안 무시한 기기가 다음 주기 알림(30초)을 기다리지 않고 바로 답해 보이도록 알린다.
+    let discovery_port = self.state.lock().await.discovery_port;
+    let _ = self.broadcast_discovery(discovery_port, false).await;
+    Ok(json!({"success": true}))
+  }
+
+  /// 내 상태를 바꾸고 바로 하트비트로 알린다. 바쁨과 퇴근이 아니면 수업 시간에는 수업 중으로 보인다.
+  pub async fn set_presence(&self, data: Value) -> Result<Value, String> {
+    let status = data.get("status").and_then(|v| v.as_str()).ok_or("missing status")?;
+    let status = PresenceStatus::parse(status).ok_or_else(|| format!("unknown presence status: {status}"))?;
+    let chosen = Presence::new(status, data.get("message").and_then(|v| v.as_str()));
+
+    let value = serde_json::to_string(&chosen).map_err(|e| e.to_string())?;
+    let saved = self
+      .with_app(move |app| save_setting(app, p2p_presence::PRESENCE_SETTING, &value))
+      .await;
+    if !saved {
+      return Err("failed to save presence".to_string());
+    }
+
+    {
+      let mut state = self.state.lock().await;
+      state.chosen_presence = chosen;
+    }
+    let presence = self.refresh_presence().await;
+    Ok(json!({"success": true, "presence": presence}))
+  }
+
+  /// 고른 상태에 시간표를 반영한다. 알리는 상태가 바뀌었으면 이벤트를 내고 하트비트를 보낸다.
+  async fn refresh_presence(&self) -> Presence {
+    let period = self.with_app(current_class_period).await;
+    let (presence, changed, info) = {
+      let mut state = self.state.lock().await;
+      let presence = state.chosen_presence.effective(period.as_deref());
+      let changed = presence != state.presence;
+      state.presence = presence.clone();
+      (presence, changed, self.info_from_state(&state))
+    };
+
+    if changed {
+      let _ = self.app.emit(
+        "p2p:presence-changed",
+        json!({
+          "peerId": info["peerId"],
+          "userId": info["userId"],
+          "userName": info["userName"],
+          "presence": presence,
+          "isSelf": true
+        }),
+      );
+      if info["userId"].as_str().is_some_and(|id| !id.is_empty()) {
+        self.send_heartbeats().await;
+      }
     }
+    presence
+  }
+
+  fn emit_presence_changed(&self, peer: &PeerInfo) {
+    let _ = self.app.emit(
+      "p2p:presence-changed",
+      json!({
+        "peerId": peer.peerId,
+        "userId": peer.userId,
+        "userName": peer.userName,
+        "presence": peer.presence,
+        "isSelf": false
+      }),
+    );
+  }
 
-    let _ = self.deliver_queued_messages(user_id, sender_ip).await;
+  /// 하트비트로 받은 상태를 그 주소의 피어(같은 사용자의 다른 기기와 구분)에 반영한다.
+  async fn note_peer_presence(&self, user_id: &str, ip_address: &str, message: &Value) {
+    let Some(presence) = Presence::from_value(message.get("presence")) else { return; };
+    let sent_at = packet_time(message);
+    let mut state = self.state.lock().await;
+    for peer in state.peers.values_mut() {
+      if peer.userId != user_id || !peer.has_address(ip_address) || sent_at <= peer.presence_at {
+        continue;
+      }
+      peer.presence_at = sent_at;
+      if peer.presence.as_ref() != Some(&presence) {
+        peer.presence = Some(presence.clone());
+        self.emit_presence_changed(peer);
+      }
+    }
   }
 
   pub async fn is_running(&self) -> bool {
@@ -639,7 +1437,9 @@ impl InternalP2PManager {
       "peerId": state.my_peer_id,
       "userId": state.my_user_id,
       "userName": state.my_user_name,
-      "ipAddress": state.my_ip
+      "ipAddress": state.my_ip,
+      "relayHub": state.relay_hub,
+      "presence": state.presence
     })
   }
 
@@ -653,44 +1453,71 @@ impl InternalP2PManager {
     state.my_user_name.clone()
   }
 
+  /// 받는 사람이 여러 기기에 로그인해 있으면 닿는 기기 모두에 보낸다. 한 기기에라도 닿으면 성공이다.
   async fn send_to_peer(&self, receiver_id: &str, message: &Value) -> Value {
-    let peer = {
+    let message_id = message.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let devices = {
       let state = self.state.lock().await;
-      state
-        .peers
-        .values()
-        .find(|peer| peer.userId == receiver_id)
-        .cloned()
+      user_devices(&state, receiver_id)
     };
 
-    if let Some(peer) = peer {
-      let tcp_success = self.send_tcp_message(&peer.ipAddress, message).await;
-      if tcp_success {
-        return json!({"success": true, "messageId": message.get("id").and_then(|v| v.as_str()).unwrap_or("")});
+    let mut missed = Vec::new();
+    for (device_ip, device) in &devices {
+      // 기기마다 채팅 키가 다르므로 따로 봉인한다.
+      let sealed = self.seal_outgoing(message, Some(device_ip)).await;
+      if self.send_tcp_message(device_ip, &sealed).await {
+        continue;
       }
 
-      let udp_success = self.send_udp_message(&peer.ipAddress, message).await;
-      if udp_success {
-        return json!({"success": true, "messageId": message.get("id").and_then(|v| v.as_str()).unwrap_or("")});
+      // 엄격 모드에서는 인증되지 않은 UDP로 보내지 않고 대기열에 남긴다.
+      if !self.strict_transport().await && self.send_udp_message(device_ip, &sealed).await {
+        continue;
       }
+      missed.push(device);
+    }
+    if missed.len() < devices.len() {
+      // 다른 기기가 받았어도 못 받은 기기 앞으로는 따로 대기시킨다.
+      for device in missed {
+        self.queue_message(receiver_id, Some(device), message.clone()).await;
+      }
+      return json!({"success": true, "messageId": message_id});
+    }
+
+    // 직접 닿지 않으면 이웃 피어를 거쳐 보낸다.
+    let sealed = self.seal_outgoing(message, None).await;
+    if self.send_routed(receiver_id, &sealed).await {
+      return json!({"success": true, "messageId": message_id, "routed": true});
     }
 
-    self.queue_message(receiver_id, message.clone()).await;
+    // 대기열은 먼저 보이는 기기에 맞춰 그때 봉인하도록 원문으로 둔다.
+    self.queue_message(receiver_id, None, message.clone()).await;
+    self.spawn_relay(receiver_id, sealed);
 
     json!({
       "success": true,
-      "messageId": message.get("id").and_then(|v| v.as_str()).unwrap_or(""),
+      "messageId": message_id,
       "error": "Message queued (peer offline)"
     })
   }
 
+  /// UDP로 메시지를 보내고 수신 측 ack를 받았을 때만 `true`를 돌려준다.
+  /// v2 미만 피어는 조각/ack를 모르므로 예전처럼 데이터그램 하나로 보낸다.
   async fn send_udp_message(&self, target_ip: &str, message: &Value) -> bool {
-    let port = {
-      let state = self.state.lock().await;
-      state.udp_message_port
-    };
+    if self.peer_protocol_version(target_ip).await < PROTOCOL_VERSION {
+      return self.send_udp_datagram(target_ip, message).await;
+    }
+
+    let (port, _) = self.peer_ports(target_ip).await;
+    let Some(target) = net_interfaces::socket_addr(target_ip, port) else { return false; };
+    p2p_udp::send_reliable(net_interfaces::local_for(&target, self.app.net().bind_ip), target, message).await
+  }
+
+  /// ack 없이 데이터그램 하나로 보낸다. 주기적인 ping/pong처럼 잃어도 되는 메시지에 쓴다.
+  async fn send_udp_datagram(&self, target_ip: &str, message: &Value) -> bool {
+    let (port, _) = self.peer_ports(target_ip).await;
+    let Some(target) = net_interfaces::socket_addr(target_ip, port) else { return false; };
 
-    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+    let socket = match UdpSocket::bind(net_interfaces::local_for(&target, self.app.net().bind_ip)).await {
       Ok(socket) => socket,
       Err(_) => return false,
     };
@@ -700,7 +1527,7 @@ impl InternalP2PManager {
       Err(_) => return false,
     };
 
-    timeout(Duration::from_secs(3), socket.send_to(&data, (target_ip, port)))
+    timeout(Duration::from_secs(3), socket.send_to(&data, target))
       .await
       .ok()
       .and_then(|res| res.ok())
@@ -708,54 +1535,122 @@ impl InternalP2PManager {
   }
 
   async fn send_tcp_message(&self, target_ip: &str, message: &Value) -> bool {
-    let port = {
+    self
+      .pool
+      .send(target_ip, message, || self.open_channel(target_ip))
+      .await
+      .is_ok()
+  }
+
+  /// 피어에 TCP 채널을 연다. 디스커버리에서 v2 이상을 광고한 피어와는 HELLO로
+  /// 버전과 기능을 협상하고, 그 외의 피어나 협상에 실패한 경우 줄바꿈 JSON을 쓴다.
+  /// Noise 세션이 맺어지면 상대 신원 키가 디스커버리에서 확인한 키와 같아야 한다.
+  /// 신원 키가 고정됐거나 `noise`를 광고한 피어와는 엄격 모드가 아니어도 평문으로 물러서지 않는다.
+  /// 그렇지 않으면 HELLO에서 `noise`를 지우는 것만으로 아는 피어와의 연결을 평문으로 낮출 수 있다.
+  async fn open_channel(&self, target_ip: &str) -> Result<P2PChannel, String> {
+    let peer_version = self.peer_protocol_version(target_ip).await;
+    let (port, hello, encrypted_only, expected_identity, secure) = {
       let state = self.state.lock().await;
-      state.tcp_message_port
+      let secure = secure_config(&state);
+      let peer = state.peers.values().find(|peer| peer.has_address(target_ip));
+      (
+        peer_ports(&state, target_ip).1,
+        p2p_protocol::hello_payload(&state.my_peer_id, &state.my_user_id, secure.is_some()),
+        state.strict_transport
+          || (secure.is_some() && peer.is_some_and(|peer| peer.identityKey.is_some() || peer.noise)),
+        peer.and_then(|peer| peer.identityKey.clone()),
+        secure,
+      )
     };
 
-    let addr = format!("{}:{}", target_ip, port);
-    let stream = match timeout(Duration::from_secs(5), TcpStream::connect(addr)).await {
-      Ok(Ok(stream)) => stream,
-      _ => return false,
-    };
+    let (stream, connected_ip) = self.connect_peer(target_ip, port).await?;
 
-    let payload = match serde_json::to_string(message) {
-      Ok(text) => format!("{}\n", text),
-      Err(_) => return false,
-    };
+    if peer_version >= PROTOCOL_VERSION {
+      match P2PChannel::connect(stream, hello, secure.as_ref()).await {
+        Ok(channel) if channel.is_secure() => {
+          if expected_identity.is_some() && channel.remote_identity != expected_identity {
+            return Err(format!("identity key mismatch for {target_ip}"));
+          }
+          return Ok(channel);
+        }
+        Ok(channel) if !encrypted_only => return Ok(channel),
+        Ok(_) => {}
+        Err(err) if encrypted_only => return Err(err.to_string()),
+        Err(_) => {}
+      }
+    }
 
-    let mut stream = stream;
-    timeout(Duration::from_secs(5), stream.write_all(payload.as_bytes()))
-      .await
-      .ok()
-      .and_then(|res| res.ok())
-      .is_some()
+    if encrypted_only {
+      return Err(format!("{target_ip} does not support an encrypted transport"));
+    }
+    P2PChannel::legacy(connect_tcp(&connected_ip, port, self.app.net().bind_ip).await?).map_err(|e| e.to_string())
   }
 
-  async fn send_discovery_response(&self, target_ip: &str) -> bool {
-    let (peer_id, user_id, user_name, school_id, port) = {
+  /// 대표 주소로 연결하고, 실패하면 피어의 다른 주소를 차례로 시도한다.
+  /// 다른 주소로 연결되면 그 주소를 대표 주소로 삼는다.
+  async fn connect_peer(&self, target_ip: &str, port: u16) -> Result<(TcpStream, String), String> {
+    let candidates = {
       let state = self.state.lock().await;
-      (
-        state.my_peer_id.clone(),
-        state.my_user_id.clone(),
-        state.my_user_name.clone(),
-        state.my_school_id.clone(),
-        state.discovery_port,
-      )
+      state
+        .peers
+        .values()
+        .find(|peer| peer.has_address(target_ip))
+        .map(|peer| peer.candidate_addresses())
+        .unwrap_or_else(|| vec![target_ip.to_string()])
     };
 
-    let message = json!({
-      "type": "discovery-response",
-      "peerId": peer_id,
-      "userId": user_id,
-      "userName": user_name,
-      "schoolId": school_id,
-      "hostname": get_hostname(),
-      "platform": std::env::consts::OS,
-      "timestamp": now_iso()
-    });
+    let mut last_err = format!("no address for {target_ip}");
+    for ip in candidates {
+      match connect_tcp(&ip, port, self.app.net().bind_ip).await {
+        Ok(stream) => {
+          if ip != target_ip {
+            let mut state = self.state.lock().await;
+            if let Some(peer) = state.peers.values_mut().find(|peer| peer.has_address(target_ip)) {
+              peer.ipAddress = ip.clone();
+            }
+          }
+          return Ok((stream, ip));
+        }
+        Err(err) => last_err = err,
+      }
+    }
+    Err(last_err)
+  }
+
+  async fn strict_transport(&self) -> bool {
+    let state = self.state.lock().await;
+    state.strict_transport
+  }
+
+  async fn peer_protocol_version(&self, target_ip: &str) -> u16 {
+    let state = self.state.lock().await;
+    state
+      .peers
+      .values()
+      .find(|peer| peer.has_address(target_ip))
+      .map(|peer| peer.protocolVersion)
+      .unwrap_or(LEGACY_PROTOCOL_VERSION)
+  }
 
-    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+  async fn peer_ports(&self, target_ip: &str) -> (u16, u16) {
+    let state = self.state.lock().await;
+    peer_ports(&state, target_ip)
+  }
+
+  /// 프레임 채널로 HELLO를 보낸 피어는 v2 이상을 지원하므로 피어 목록에 반영한다.
+  async fn note_peer_protocol(&self, channel: &P2PChannel) {
+    let Some(peer_id) = channel.peer_hello.get("peerId").and_then(|v| v.as_str()) else { return; };
+    let mut state = self.state.lock().await;
+    if let Some(peer) = state.peers.get_mut(peer_id) {
+      peer.protocolVersion = peer.protocolVersion.max(channel.version);
+    }
+  }
+
+  async fn send_discovery_response(&self, target_ip: &str, port: u16) -> bool {
+    let message = self.announcement("discovery-response", true).await;
+
+    let Some(target) = net_interfaces::socket_addr(target_ip, port) else { return false; };
+    let socket = match UdpSocket::bind(net_interfaces::local_for(&target, self.app.net().bind_ip)).await {
       Ok(socket) => socket,
       Err(_) => return false,
     };
@@ -765,519 +1660,3183 @@ impl InternalP2PManager {
       Err(_) => return false,
     };
 
-    socket.send_to(&data, (target_ip, port)).await.is_ok()
+    socket.send_to(&data, target).await.is_ok()
   }
 
-  async fn queue_message(&self, receiver_id: &str, message: Value) {
-    let mut state = self.state.lock().await;
-    let queue = state.message_queue.entry(receiver_id.to_string()).or_default();
-    queue.push(message);
+  async fn queue_message(&self, receiver_id: &str, device: Option<&str>, message: Value) {
+    let receiver_id = receiver_id.to_string();
+    let device = device.map(|device| device.to_string());
+    self
+      .with_app(move |app| insert_queued_message(app, &receiver_id, device.as_deref(), &message))
+      .await;
   }
 
-  async fn deliver_queued_messages(&self, user_id: &str, target_ip: &str) {
-    let queue = {
-      let mut state = self.state.lock().await;
-      state.message_queue.remove(user_id)
-    };
-
-    let Some(messages) = queue else { return; };
-
-    for message in messages {
-      let tcp_ok = self.send_tcp_message(target_ip, &message).await;
-      if !tcp_ok {
-        let _ = self.send_udp_message(target_ip, &message).await;
+  /// 피어가 다시 보이면 그 피어 앞으로 쌓인 메시지를 백오프와 관계없이 순서대로 보낸다.
+  /// 다른 기기 앞으로 대기시킨 사본은 건너뛴다.
+  async fn deliver_queued_messages(&self, user_id: &str, device: &str, target_ip: &str) {
+    let _flush = self.queue_flush.lock().await;
+    let receiver_id = user_id.to_string();
+    let entries = self
+      .with_app(move |app| list_queued_messages(app, Some(&receiver_id)))
+      .await;
+
+    for entry in entries {
+      if entry.device.as_deref().is_some_and(|queued_for| queued_for != device) {
+        continue;
+      }
+      if entry.expires_at <= now_unix_ms() {
+        self.fail_queued(entry, "expired").await;
+        continue;
+      }
+      if self.held_by_online_relay(&entry).await {
+        continue;
+      }
+      if !self.attempt_queued(entry, target_ip).await {
+        break;
       }
     }
   }
 
-  async fn udp_message_loop(&self, port: u16, token: CancellationToken) {
-    let socket = match UdpSocket::bind(("0.0.0.0", port)).await {
-      Ok(socket) => socket,
-      Err(_) => return,
-    };
+  /// 온라인인 중계 허브가 맡아 둔 메시지는 허브가 전달하도록 둔다. 허브가 사라졌으면 직접 보낸다.
+  async fn held_by_online_relay(&self, entry: &QueuedMessage) -> bool {
+    let Some(holder) = &entry.relay_holder else { return false; };
+    let state = self.state.lock().await;
+    state.peers.values().any(|peer| &peer.userId == holder && peer.isOnline)
+  }
 
-    let mut buf = vec![0u8; 8192];
-    loop {
-      tokio::select! {
-        _ = token.cancelled() => break,
-        res = socket.recv_from(&mut buf) => {
-          let Ok((len, addr)) = res else { continue; };
-          let payload = &buf[..len];
-          if let Ok(message) = serde_json::from_slice::<Value>(payload) {
-            self.handle_incoming_message(message, addr).await;
-          }
-        }
-      }
+  /// 대기열 메시지를 한 번 전송해 본다. 실패하면 재시도 횟수와 다음 시도 시각을 늘린다.
+  async fn attempt_queued(&self, entry: QueuedMessage, target_ip: &str) -> bool {
+    let sealed = self.seal_outgoing(&entry.message, Some(target_ip)).await;
+    if self.send_tcp_message(target_ip, &sealed).await {
+      self.with_app(move |app| delete_queued_entry(app, &entry)).await;
+      return true;
+    }
+
+    let attempts = entry.attempts + 1;
+    if attempts >= QUEUE_MAX_ATTEMPTS {
+      self.fail_queued(entry, "too many attempts").await;
+      return false;
     }
+
+    let backoff = QUEUE_RETRY_BASE_MS
+      .saturating_mul(1i64 << (attempts - 1).min(20))
+      .min(QUEUE_RETRY_MAX_MS);
+    let next_attempt_at = now_unix_ms() + backoff;
+    let error = format!("could not reach {target_ip}");
+    self
+      .with_app(move |app| update_queued_message(app, &entry, attempts, next_attempt_at, Some(&error)))
+      .await;
+    false
   }
 
-  async fn tcp_message_loop(&self, port: u16, token: CancellationToken) {
-    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
-      Ok(listener) => listener,
-      Err(_) => return,
-    };
+  async fn fail_queued(&self, entry: QueuedMessage, reason: &str) {
+    let queued = entry.clone();
+    self.with_app(move |app| delete_queued_entry(app, &queued)).await;
+    let _ = self.app.emit(
+      "p2p:message-failed",
+      json!({
+        "messageId": entry.message_id,
+        "receiverId": entry.receiver_id,
+        "type": entry.message_type,
+        "attempts": entry.attempts,
+        "reason": reason,
+        "lastError": entry.last_error
+      }),
+    );
+  }
+
+  /// 만료된 메시지를 정리하고, 재시도 시각이 된 메시지를 온라인 피어에게 다시 보낸다.
+  async fn queue_retry_loop(&self, token: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_secs(15));
 
     loop {
       tokio::select! {
         _ = token.cancelled() => break,
-        res = listener.accept() => {
-          let Ok((stream, _)) = res else { continue; };
-          let manager = self.clone();
-          tokio::spawn(async move {
-            manager.handle_tcp_stream(stream).await;
-          });
-        }
-      }
-    }
-  }
+        _ = interval.tick() => {
+          let _flush = self.queue_flush.lock().await;
+          let entries = self.with_app(|app| list_queued_messages(app, None)).await;
+          let now = now_unix_ms();
 
-  async fn handle_tcp_stream(&self, stream: TcpStream) {
-    let mut reader = BufReader::new(stream);
-    let mut line = String::new();
+          for entry in entries {
+            if entry.expires_at <= now {
+              self.fail_queued(entry, "expired").await;
+              continue;
+            }
+            if entry.next_attempt_at > now || self.held_by_online_relay(&entry).await {
+              continue;
+            }
 
-    loop {
-      line.clear();
-      let result = reader.read_line(&mut line).await;
-      if result.unwrap_or(0) == 0 {
-        break;
-      }
+            let target_ip = {
+              let state = self.state.lock().await;
+              queued_target(&state, &entry)
+            };
+            match target_ip {
+              Some(ip) => {
+                self.attempt_queued(entry, &ip).await;
+              }
+              // 기기를 정한 사본은 그 기기가 다시 보일 때 직접 보낸다.
+              None if entry.device.is_some() => {}
+              // 받는 사람이 오프라인이면 그 사이에 나타난 중계 허브에 맡긴다.
+              // 대기열에는 원문이 있으므로 봉인한 사본을 맡긴다.
+              None => {
+                let sealed = self.seal_outgoing(&entry.message, None).await;
+                self.relay_message(&entry.receiver_id, &sealed).await;
+              }
+            }
+          }
 
-      if let Ok(message) = serde_json::from_str::<Value>(&line) {
-        if let Ok(addr) = reader.get_ref().peer_addr() {
-          self.handle_incoming_message(message, addr).await;
+          let relay_hub = self.state.lock().await.relay_hub;
+          if relay_hub {
+            self.with_app(purge_expired_relayed_messages).await;
+          }
         }
       }
     }
   }
 
-  async fn handle_incoming_message(&self, message: Value, addr: SocketAddr) {
+  /// 받는 사람 대신 온라인 중계 허브에 메시지를 맡긴다. 허브는 `relay_ack`로 보관을 알린다.
+  async fn relay_message(&self, receiver_id: &str, message: &Value) -> bool {
     let msg_type = message.get("type").and_then(|v| v.as_str()).unwrap_or("");
-    let sender_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("");
-    let receiver_id = message.get("receiverId").and_then(|v| v.as_str()).unwrap_or("");
-
-    if !self.should_process_message(msg_type, receiver_id).await {
-      return;
+    if !RELAY_MESSAGE_TYPES.contains(&msg_type) {
+      return false;
+    }
+    // 봉인하지 못한 채팅은 허브가 읽을 수 있으므로 맡기지 않고 직접 재시도한다.
+    if SEALED_MESSAGE_TYPES.contains(&msg_type) && message.get("encrypted").is_none() {
+      return false;
     }
 
-    match msg_type {
-      "chat" => {
-        self.emit_message_received(&message).await;
-        self
-          .send_delivery_receipt(
-            sender_id,
-            message.get("id").and_then(|v| v.as_str()).unwrap_or(""),
-            addr.ip().to_string(),
-          )
-          .await;
-      }
-      "delivery_receipt" => {
-        let _ = self.app.emit("messaging:delivery-receipt", message.clone());
-        let message_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
-        self.update_delivered(message_id).await;
-      }
-      "read_receipt" => {
-        let _ = self.app.emit("messaging:read-receipt", message.clone());
-        let message_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
-        self.update_read(message_id).await;
-      }
-      "typing" => {
-        let is_typing = message.get("content").and_then(|v| v.as_str()).unwrap_or("") == "typing";
-        let payload = json!({"userId": sender_id, "isTyping": is_typing});
-        let _ = self.app.emit("messaging:typing", payload);
-      }
-      "group_chat" => {
-        let _ = self.app.emit("group:message-received", message.clone());
-        let receipt = json!({
-          "id": uuid::Uuid::new_v4().to_string(),
-          "type": "group_delivery_receipt",
-          "senderId": self.my_user_id().await,
-          "receiverId": sender_id,
-          "timestamp": now_iso(),
-          "messageId": message.get("id").and_then(|v| v.as_str()).unwrap_or(""),
-          "groupId": message.get("groupId").and_then(|v| v.as_str()).unwrap_or(""),
-          "deliveredAt": now_iso()
-        });
-        let _ = self.send_udp_message(&addr.ip().to_string(), &receipt).await;
-      }
-      "group_create" => {
-        let _ = self.app.emit("group:created", message.clone());
-      }
-      "group_join" | "group_leave" => {
-        let _ = self.app.emit("group:member-changed", message.clone());
-      }
-      "group_read_receipt" => {
-        let _ = self.app.emit("group:read-receipt", message.clone());
-      }
-      "group_delivery_receipt" => {
-        let _ = self.app.emit("group:delivery-receipt", message.clone());
-      }
-      "group_typing" => {
-        let _ = self.app.emit("group:typing", message.clone());
-      }
-      "file_offer" => {
-        self.handle_file_offer(&message).await;
-      }
-      "file_accept" => {
-        self.handle_file_accept(&message).await;
-      }
-      "file_reject" => {
-        self.handle_file_reject(&message).await;
-      }
-      "ping" => {
-        let _ = self.send_pong(sender_id, &addr.ip().to_string()).await;
-      }
-      "pong" => {
-        self.update_peer_presence(sender_id, &addr.ip().to_string()).await;
+    let (my_user_id, hubs) = {
+      let state = self.state.lock().await;
+      let hubs = state
+        .peers
+        .values()
+        .filter(|peer| peer.isRelayHub && peer.isOnline)
+        .filter(|peer| peer.userId != receiver_id && peer.userId != state.my_user_id)
+        .map(|peer| (peer.userId.clone(), peer.ipAddress.clone()))
+        .collect::<Vec<_>>();
+      (state.my_user_id.clone(), hubs)
+    };
+
+    for (hub_id, hub_ip) in hubs {
+      let envelope = json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "type": "relay",
+        "senderId": my_user_id,
+        "receiverId": hub_id,
+        "timestamp": now_iso(),
+        "payload": message
+      });
+      if self.send_tcp_message(&hub_ip, &envelope).await {
+        return true;
       }
-      _ => {}
     }
+    false
   }
 
-  async fn emit_message_received(&self, message: &Value) {
-    let payload = json!({
-      "id": message.get("id").and_then(|v| v.as_str()),
-      "messageId": message.get("id").and_then(|v| v.as_str()),
-      "senderId": message.get("senderId").and_then(|v| v.as_str()),
-      "senderName": message.get("senderName").and_then(|v| v.as_str()),
-      "receiverId": message.get("receiverId").and_then(|v| v.as_str()),
-      "content": message.get("content").and_then(|v| v.as_str()),
-      "timestamp": message.get("timestamp").and_then(|v| v.as_str()),
-      "type": "text",
-      "isRead": false,
-      "delivered": true,
-      "deliveredAt": now_iso()
+  fn spawn_relay(&self, receiver_id: &str, message: Value) {
+    let manager = self.clone();
+    let receiver_id = receiver_id.to_string();
+    tokio::spawn(async move {
+      manager.relay_message(&receiver_id, &message).await;
     });
-
-    let _ = self.app.emit("messaging:received", payload);
-    self.persist_message(message.clone(), true, false).await;
   }
 
-    async fn persist_message(&self, message: Value, delivered: bool, is_read: bool) {
-    let app = self.app.clone();
-    tokio::task::spawn_blocking(move || {
-      store_message(&app, message, delivered, is_read);
+  /// 중계로 받은 메시지의 수신 확인. 보낸 사람이 오프라인이면 대기열과 중계 허브로 돌려보낸다.
+  fn spawn_relayed_receipt(&self, receiver_id: &str, message_id: &str) {
+    if receiver_id.is_empty() || message_id.is_empty() {
+      return;
+    }
+
+    let manager = self.clone();
+    let receiver_id = receiver_id.to_string();
+    let message_id = message_id.to_string();
+    tokio::spawn(async move {
+      let receipt = json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "type": "delivery_receipt",
+        "senderId": manager.my_user_id().await,
+        "receiverId": receiver_id,
+        "timestamp": now_iso(),
+        "messageId": message_id,
+        "deliveredAt": now_iso()
+      });
+      manager.send_to_peer(&receiver_id, &receipt).await;
     });
   }
-async fn send_delivery_receipt(&self, receiver_id: &str, message_id: &str, target_ip: String) {
-    if receiver_id.is_empty() || message_id.is_empty() {
+
+  /// 허브 역할: 다른 피어가 맡긴 메시지를 보관하고, 받는 사람이 온라인이면 바로 전달한다.
+  async fn handle_relay(&self, message: &Value, addr: SocketAddr) {
+    let (relay_hub, my_user_id) = {
+      let state = self.state.lock().await;
+      (state.relay_hub, state.my_user_id.clone())
+    };
+    if !relay_hub {
       return;
     }
 
-    let receipt = json!({
+    let sender_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("");
+    let Some(payload) = message.get("payload") else { return; };
+    let message_id = payload.get("id").and_then(|v| v.as_str()).unwrap_or("");
+    let recipient_id = payload.get("receiverId").and_then(|v| v.as_str()).unwrap_or("");
+    let msg_type = payload.get("type").and_then(|v| v.as_str()).unwrap_or("");
+    // 다른 사람 이름으로 된 메시지는 맡지 않는다.
+    if payload.get("senderId").and_then(|v| v.as_str()) != Some(sender_id)
+      || message_id.is_empty()
+      || recipient_id.is_empty()
+      || recipient_id == my_user_id
+      || !RELAY_MESSAGE_TYPES.contains(&msg_type)
+    {
+      return;
+    }
+
+    let stored = {
+      let payload = payload.clone();
+      let sender = sender_id.to_string();
+      self.with_app(move |app| insert_relayed_message(app, &sender, &payload)).await
+    };
+    if !stored {
+      return;
+    }
+
+    let ack = json!({
       "id": uuid::Uuid::new_v4().to_string(),
-      "type": "delivery_receipt",
-      "senderId": self.my_user_id().await,
-      "receiverId": receiver_id,
+      "type": "relay_ack",
+      "senderId": my_user_id,
+      "receiverId": sender_id,
       "timestamp": now_iso(),
       "messageId": message_id,
-      "deliveredAt": now_iso()
+      "recipientId": recipient_id
     });
+    self.spawn_udp_message(net_interfaces::peer_ip(&addr), ack);
 
-    let _ = self.send_udp_message(&target_ip, &receipt).await;
+    let recipient_ip = {
+      let state = self.state.lock().await;
+      state
+        .peers
+        .values()
+        .find(|peer| peer.userId == recipient_id && peer.isOnline)
+        .map(|peer| peer.ipAddress.clone())
+    };
+    if let Some(ip) = recipient_ip {
+      let manager = self.clone();
+      let recipient_id = recipient_id.to_string();
+      tokio::spawn(async move {
+        manager.deliver_relayed_messages(&recipient_id, &ip).await;
+      });
+    }
   }
 
-  async fn send_pong(&self, receiver_id: &str, target_ip: &str) -> bool {
-    let pong = json!({
-      "id": uuid::Uuid::new_v4().to_string(),
-      "type": "pong",
-      "senderId": self.my_user_id().await,
-      "receiverId": receiver_id,
-      "timestamp": now_iso()
-    });
+  /// 허브가 메시지를 맡았다. 대기열 항목에 보관자를 기록해 직접 재전송을 멈춘다.
+  async fn handle_relay_ack(&self, message: &Value) {
+    let holder = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let message_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    if holder.is_empty() || message_id.is_empty() {
+      return;
+    }
 
-    self.send_udp_message(target_ip, &pong).await
+    let (id, hub) = (message_id.clone(), holder.clone());
+    if !self.with_app(move |app| set_queue_relay_holder(app, &id, &hub)).await {
+      return;
+    }
+
+    let _ = self.app.emit(
+      "p2p:message-relayed",
+      json!({
+        "messageId": message_id,
+        "receiverId": message.get("recipientId").and_then(|v| v.as_str()),
+        "relayHubId": holder
+      }),
+    );
   }
 
-  async fn should_process_message(&self, msg_type: &str, receiver_id: &str) -> bool {
-    if msg_type.starts_with("group_") {
-      return true;
+  /// 허브 역할: 받는 사람이 다시 보이면 맡아 둔 메시지를 순서대로 전달한다.
+  async fn deliver_relayed_messages(&self, user_id: &str, target_ip: &str) {
+    let (relay_hub, my_user_id) = {
+      let state = self.state.lock().await;
+      (state.relay_hub, state.my_user_id.clone())
+    };
+    if !relay_hub {
+      return;
     }
 
-    let my_user_id = self.my_user_id().await;
-    receiver_id == my_user_id || receiver_id.is_empty()
+    let _flush = self.queue_flush.lock().await;
+    let recipient_id = user_id.to_string();
+    let entries = self
+      .with_app(move |app| list_relayed_messages(app, &recipient_id))
+      .await;
+
+    for (message_id, payload) in entries {
+      let envelope = json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "type": "relay_deliver",
+        "senderId": my_user_id,
+        "receiverId": user_id,
+        "timestamp": now_iso(),
+        "payload": payload
+      });
+      if !self.send_tcp_message(target_ip, &envelope).await {
+        break;
+      }
+      self.with_app(move |app| delete_relayed_message(app, &message_id)).await;
+    }
   }
 
-    async fn update_delivered(&self, message_id: &str) {
-    if message_id.is_empty() {
-      return;
+  /// 경로 표에서 목적지로 가는 다음 홉을 찾아 `route` 봉투로 넘긴다.
+  /// 채팅 본문은 목적지 공개키로 이미 봉인돼 있어 중간 피어는 읽을 수 없다.
+  async fn send_routed(&self, receiver_id: &str, message: &Value) -> bool {
+    let msg_type = message.get("type").and_then(|v| v.as_str()).unwrap_or("");
+    if !ROUTABLE_MESSAGE_TYPES.contains(&msg_type) {
+      return false;
     }
-    let app = self.app.clone();
-    let id = message_id.to_string();
-    tokio::task::spawn_blocking(move || {
-      update_message_status(&app, &id, true, false);
+
+    let (my_user_id, hop) = {
+      let state = self.state.lock().await;
+      (state.my_user_id.clone(), next_hop(&state, receiver_id, None))
+    };
+    let Some((via, via_ip)) = hop else { return false; };
+
+    let envelope = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "route",
+      "senderId": my_user_id,
+      "receiverId": via,
+      "destinationId": receiver_id,
+      "ttl": MAX_ROUTE_HOPS,
+      "timestamp": now_iso(),
+      "payload": message
     });
+    self.send_tcp_message(&via_ip, &envelope).await
   }
 
-  async fn update_read(&self, message_id: &str) {
-    if message_id.is_empty() {
+  /// 목적지가 나면 안의 메시지를 처리하고, 아니면 다음 홉으로 넘긴다.
+  async fn handle_route(&self, message: &Value, addr: SocketAddr) {
+    let sender_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("");
+    let destination_id = message.get("destinationId").and_then(|v| v.as_str()).unwrap_or("");
+    let ttl = message.get("ttl").and_then(|v| v.as_u64()).unwrap_or(0);
+    let Some(mut payload) = message.get("payload").cloned() else { return; };
+    let payload_type = payload.get("type").and_then(|v| v.as_str()).unwrap_or("");
+    if !ROUTABLE_MESSAGE_TYPES.contains(&payload_type) || payload.get("receiverId").and_then(|v| v.as_str()) != Some(destination_id) {
       return;
     }
-    let app = self.app.clone();
-    let id = message_id.to_string();
-    tokio::task::spawn_blocking(move || {
-      update_message_status(&app, &id, true, true);
+
+    let (my_user_id, forwarding, hop) = {
+      let state = self.state.lock().await;
+      (state.my_user_id.clone(), state.forwarding, next_hop(&state, destination_id, Some(sender_id)))
+    };
+
+    if destination_id == my_user_id {
+      payload["routedVia"] = json!(sender_id);
+      Box::pin(self.handle_incoming_message(payload, addr)).await;
+      return;
+    }
+    if !forwarding || ttl <= 1 {
+      return;
+    }
+    let Some((via, via_ip)) = hop else { return; };
+
+    let envelope = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "route",
+      "senderId": my_user_id,
+      "receiverId": via,
+      "destinationId": destination_id,
+      "ttl": ttl - 1,
+      "timestamp": now_iso(),
+      "payload": payload
     });
+    let _ = self.send_tcp_message(&via_ip, &envelope).await;
   }
-async fn handle_file_offer(&self, message: &Value) {
-    let transfer = FileTransfer {
-      id: message.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-      peerId: message.get("senderId").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-      fileName: message.get("fileName").and_then(|v| v.as_str()).unwrap_or("unknown").to_string(),
-      fileSize: message.get("fileSize").and_then(|v| v.as_u64()).unwrap_or(0),
-      progress: 0,
-      status: "pending".to_string(),
-      direction: "receive".to_string(),
-      totalChunks: message.get("totalChunks").and_then(|v| v.as_u64()).unwrap_or(0),
+
+  /// 이웃이 보낸 도달 목록으로 경로 표를 갱신한다. 목록의 알림은 디스커버리와 같이 서명을 검증하고
+  /// 신원 키를 고정하므로, 중간 피어가 목적지의 공개키를 바꿔치기할 수 없다.
+  async fn handle_route_advert(&self, message: &Value, sender_ip: &str) {
+    let neighbor_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("");
+    let Some(entries) = message.get("routes").and_then(|v| v.as_array()) else { return; };
+
+    let (my_user_id, my_school_id, neighbor_online) = {
+      let state = self.state.lock().await;
+      (
+        state.my_user_id.clone(),
+        state.my_school_id.clone(),
+        state.peers.values().any(|peer| peer.userId == neighbor_id && peer.isOnline),
+      )
     };
+    if !neighbor_online {
+      return;
+    }
 
-    {
+    // 광고는 그 이웃이 닿는 전체 목록이다. 빠진 사용자로 가는 경로는 거둔다.
+    let listed = entries
+      .iter()
+      .filter_map(|entry| entry.pointer("/announcement/userId").and_then(|v| v.as_str()))
+      .collect::<HashSet<_>>();
+    self
+      .state
+      .lock()
+      .await
+      .routes
+      .retain(|user_id, route| route.via != neighbor_id || listed.contains(user_id.as_str()));
+
+    for entry in entries {
+      let Some(announcement) = entry.get("announcement") else { continue; };
+      let hops = entry.get("hops").and_then(|v| v.as_u64()).unwrap_or(u64::MAX).saturating_add(1);
+      let user_id = announcement.get("userId").and_then(|v| v.as_str()).unwrap_or("");
+      let school_id = announcement.get("schoolId").and_then(|v| v.as_str()).unwrap_or("default-school");
+      if hops > MAX_ROUTE_HOPS as u64 || user_id.is_empty() || user_id == my_user_id || user_id == neighbor_id {
+        continue;
+      }
+      if !my_school_id.is_empty() && school_id != my_school_id {
+        continue;
+      }
+      // 경로로 배운 사용자는 서명된 알림이 있어야 한다.
+      let Some(Some(_)) = self.check_announcement(announcement, user_id, sender_ip).await else { continue; };
+
+      let now = now_unix_ms();
       let mut state = self.state.lock().await;
-      state.file_transfers.insert(transfer.id.clone(), transfer.clone());
+      if state.peers.values().any(|peer| peer.userId == user_id && peer.isOnline) {
+        continue;
+      }
+      let replace = match state.routes.get(user_id) {
+        None => true,
+        Some(route) => route.via == neighbor_id || (hops as u8) < route.hops || now - route.updated_at > ROUTE_TTL_MS,
+      };
+      if replace {
+        if !state.routes.contains_key(user_id) {
+          self.routes_changed.notify_one();
+        }
+        state.routes.insert(
+          user_id.to_string(),
+          RouteInfo {
+            user_id: user_id.to_string(),
+            user_name: announcement.get("userName").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            via: neighbor_id.to_string(),
+            hops: hops as u8,
+            public_key: announcement.get("publicKey").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            announcement: announcement.clone(),
+            updated_at: now,
+          },
+        );
+      }
     }
+  }
+
+  /// 주기마다, 그리고 이웃이나 경로가 바뀌면 경로를 광고한다.
+  async fn route_gossip_loop(&self, token: CancellationToken) {
+    let mut interval = tokio::time::interval(ROUTE_GOSSIP_INTERVAL);
 
-    let _ = self.app.emit("p2p:file-offer", transfer);
+    loop {
+      tokio::select! {
+        _ = token.cancelled() => break,
+        _ = interval.tick() => self.advertise_routes().await,
+        // 이웃이 보이거나 사라지거나 새 경로를 배우면 다음 주기를 기다리지 않고 알린다.
+        _ = self.routes_changed.notified() => self.advertise_routes().await,
+      }
+    }
   }
 
-  async fn handle_file_accept(&self, message: &Value) {
-    let transfer_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
-    let mut state = self.state.lock().await;
-    if let Some(transfer) = state.file_transfers.get_mut(transfer_id) {
-      transfer.status = "accepted".to_string();
-      let _ = self
-        .app
-        .emit("p2p:file-progress", json!({"transferId": transfer_id, "progress": 0}));
+  /// 온라인 이웃마다 내가 닿을 수 있는 사용자 목록을 보낸다. 빈 목록도 보내야 이웃이 경로를 거둔다.
+  /// 그 이웃에게서 배운 경로는 다시 알려 주지 않는다(split horizon).
+  async fn advertise_routes(&self) {
+    let (my_user_id, forwarding, neighbors, direct, routes) = {
+      let mut state = self.state.lock().await;
+      let now = now_unix_ms();
+      state.routes.retain(|_, route| now - route.updated_at <= ROUTE_TTL_MS);
+
+      let neighbors = state
+        .peers
+        .values()
+        .filter(|peer| peer.isOnline && peer.protocolVersion >= PROTOCOL_VERSION)
+        .map(|peer| (peer.userId.clone(), peer.ipAddress.clone()))
+        .collect::<Vec<_>>();
+      let direct = neighbors
+        .iter()
+        .filter_map(|(user_id, _)| state.announcements.get(user_id).map(|a| (user_id.clone(), a.clone())))
+        .collect::<Vec<_>>();
+      (state.my_user_id.clone(), state.forwarding, neighbors, direct, live_routes(&state))
+    };
+    // 중계하지 않는 피어는 경로를 광고하지 않는다.
+    if !forwarding || my_user_id.is_empty() {
+      return;
+    }
+
+    for (neighbor_id, neighbor_ip) in neighbors {
+      let mut entries = direct
+        .iter()
+        .filter(|(user_id, _)| user_id != &neighbor_id)
+        .map(|(_, announcement)| json!({"hops": 1, "announcement": announcement}))
+        .collect::<Vec<_>>();
+      entries.extend(
+        routes
+          .iter()
+          .filter(|route| route.via != neighbor_id && route.user_id != neighbor_id && route.hops < MAX_ROUTE_HOPS)
+          .map(|route| json!({"hops": route.hops, "announcement": route.announcement})),
+      );
+
+      let advert = json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "type": "route_advert",
+        "senderId": my_user_id,
+        "receiverId": neighbor_id,
+        "timestamp": now_iso(),
+        "routes": entries
+      });
+      let _ = self.send_tcp_message(&neighbor_ip, &advert).await;
+    }
+  }
+
+  async fn udp_message_loop(&self, port: u16, token: CancellationToken) {
+    let net = self.app.net();
+    let socket = match net_interfaces::bind_udp(net.bind_ip, port).await {
+      Ok(socket) => socket,
+      Err(_) => return,
+    };
+
+    let strict = self.strict_transport().await;
+    let mut buf = vec![0u8; p2p_udp::MAX_DATAGRAM_SIZE];
+    let mut reassembler = Reassembler::default();
+    loop {
+      tokio::select! {
+        _ = token.cancelled() => break,
+        res = socket.recv_from(&mut buf) => {
+          let Ok((len, addr)) = res else { continue; };
+          #[cfg(any(test, feature = "test-harness"))]
+          if net.drops_inbound() {
+            continue;
+          }
+          let payload = &buf[..len];
+          let Ok(message) = serde_json::from_slice::<Value>(payload) else { continue; };
+          let msg_type = message.get("type").and_then(|v| v.as_str()).unwrap_or("");
+
+          // 엄격 모드에서는 인증되지 않은 UDP로 ping/pong/goodbye만 받는다.
+          if strict && !matches!(msg_type, "ping" | "pong" | "goodbye") {
+            continue;
+          }
+
+          match msg_type {
+            "udp_frag" => match reassembler.accept(addr.ip(), &message) {
+              Received::Complete(udp_id, payload) => {
+                let _ = socket.send_to(&p2p_udp::ack(&udp_id), addr).await;
+                if let Ok(message) = serde_json::from_slice::<Value>(&payload) {
+                  self.handle_incoming_message(message, addr).await;
+                }
+              }
+              Received::Duplicate(udp_id) => {
+                let _ = socket.send_to(&p2p_udp::ack(&udp_id), addr).await;
+              }
+              Received::Partial => {}
+            },
+            // ack는 송신용 임시 소켓으로 돌아오므로 여기로 오면 무시한다.
+            "udp_ack" => {}
+            _ => self.handle_incoming_message(message, addr).await,
+          }
+        }
+      }
+    }
+  }
+
+  async fn tcp_message_loop(&self, port: u16, token: CancellationToken) {
+    let net = self.app.net();
+    let listener = match net_interfaces::bind_tcp(net.bind_ip, port).await {
+      Ok(listener) => listener,
+      Err(_) => return,
+    };
+
+    loop {
+      tokio::select! {
+        _ = token.cancelled() => break,
+        res = listener.accept() => {
+          let Ok((stream, _)) = res else { continue; };
+          #[cfg(any(test, feature = "test-harness"))]
+          if net.drops_inbound() {
+            continue;
+          }
+          let manager = self.clone();
+          let token = token.clone();
+          tokio::spawn(async move {
+            manager.handle_tcp_stream(stream, token).await;
+          });
+        }
+      }
+    }
+  }
+
+  /// P2P를 멈추면 이미 받은 연결도 닫는다. 멈춘 뒤에 들어온 메시지를 처리하지 않도록.
+  async fn handle_tcp_stream(&self, stream: TcpStream, token: CancellationToken) {
+    let (hello, secure, strict) = {
+      let state = self.state.lock().await;
+      let secure = secure_config(&state);
+      (
+        p2p_protocol::hello_payload(&state.my_peer_id, &state.my_user_id, secure.is_some()),
+        secure,
+        state.strict_transport,
+      )
+    };
+    let Ok(mut channel) = P2PChannel::accept(stream, hello, secure.as_ref()).await else { return; };
+    if strict && !channel.is_secure() {
+      channel.shutdown().await;
+      return;
+    }
+    // 암호화된 채널에서는 HELLO의 userId가 핸드셰이크로 증명한 신원 키와 맞아야 하고,
+    // 이후 메시지의 senderId도 그 사용자여야 한다.
+    let authenticated_user = match self.authenticate_channel(&channel).await {
+      Ok(user_id) => user_id,
+      Err(_) => {
+        channel.shutdown().await;
+        return;
+      }
+    };
+    let addr = channel.peer_addr();
+    self.note_peer_protocol(&channel).await;
+    let mut receiving: Option<String> = None;
+
+    loop {
+      let incoming = tokio::select! {
+        _ = token.cancelled() => break,
+        incoming = channel.recv() => incoming,
+      };
+      let Ok(Some(incoming)) = incoming else { break; };
+
+      // 파일 청크는 같은 연결로 ack를 돌려줘야 하므로 일반 메시지 처리와 분리한다.
+      if let Some(user_id) = &authenticated_user {
+        let (Incoming::FileChunk(message, _) | Incoming::Message(message)) = &incoming;
+        let sender_id = message.get("senderId").and_then(|v| v.as_str());
+        if sender_id.is_some_and(|sender_id| sender_id != user_id) {
+          continue;
+        }
+      }
+
+      let ack = match incoming {
+        Incoming::FileChunk(header, data) => {
+          let receiver_id = header.get("receiverId").and_then(|v| v.as_str()).unwrap_or("");
+          if !self.should_process_message("file_chunk", receiver_id).await {
+            continue;
+          }
+          receiving = header.get("messageId").and_then(|v| v.as_str()).map(|s| s.to_string());
+          self.handle_file_chunk(&header, &data).await
+        }
+        Incoming::Message(message) => {
+          let msg_type = message.get("type").and_then(|v| v.as_str()).unwrap_or("");
+          if msg_type != "file_complete" {
+            self.handle_incoming_message(message, addr).await;
+            continue;
+          }
+
+          let receiver_id = message.get("receiverId").and_then(|v| v.as_str()).unwrap_or("");
+          if !self.should_process_message(msg_type, receiver_id).await {
+            continue;
+          }
+          receiving = None;
+          self.handle_file_complete(&message).await
+        }
+      };
+
+      if channel.send(&ack).await.is_err() {
+        break;
+      }
+    }
+
+    if let Some(transfer_id) = receiving {
+      self.interrupt_transfer(&transfer_id, "connection closed before the transfer completed").await;
+    }
+  }
+
+  /// 암호화된 채널의 상대 사용자를 확인한다. 고정된 신원 키와 다르면 거부한다.
+  async fn authenticate_channel(&self, channel: &P2PChannel) -> Result<Option<String>, String> {
+    let Some(remote_identity) = channel.remote_identity.clone() else { return Ok(None); };
+    let user_id = channel
+      .peer_hello
+      .get("userId")
+      .and_then(|v| v.as_str())
+      .unwrap_or("")
+      .to_string();
+    if user_id.is_empty() {
+      return Err("hello without userId".to_string());
+    }
+
+    let lookup = user_id.clone();
+    let record = self.with_app(move |app| load_peer_key(app, &lookup)).await;
+    match record {
+      Some(record) if !record.accepts(&remote_identity) => Err(format!("identity key mismatch for {user_id}")),
+      _ => Ok(Some(user_id)),
     }
   }
 
-  async fn handle_file_reject(&self, message: &Value) {
-    let transfer_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
-    let mut state = self.state.lock().await;
-    if let Some(transfer) = state.file_transfers.remove(transfer_id) {
-      let _ = self.app.emit("p2p:file-complete", transfer);
-    }
+  async fn handle_incoming_message(&self, message: Value, addr: SocketAddr) {
+    let Some(message) = self.open_sealed(message).await else { return; };
+    let msg_type = message.get("type").and_then(|v| v.as_str()).unwrap_or("");
+    let sender_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("");
+    let receiver_id = message.get("receiverId").and_then(|v| v.as_str()).unwrap_or("");
+
+    if !self.should_process_message(msg_type, receiver_id).await {
+      return;
+    }
+    if matches!(msg_type, "delivery_receipt" | "read_receipt") && !self.receipt_from_recipient(&message).await {
+      return;
+    }
+
+    match self.sighting(msg_type, &message).await {
+      Sighting::First => {}
+      // TCP, UDP, 대기열로 재시도하면 같은 메시지가 여러 번 온다. 보낸 쪽이 재시도를 멈추도록 확인만 다시 보낸다.
+      Sighting::Duplicate => {
+        match msg_type {
+          "chat" => self.acknowledge_chat(&message, addr).await,
+          "group_chat" => self.acknowledge_group_chat(&message, addr).await,
+          _ => {}
+        }
+        return;
+      }
+      Sighting::Stale => return,
+    }
+
+    match msg_type {
+      "chat" => {
+        let message = self.observe_sequence(message.clone()).await;
+        self.persist_message(message.clone(), true, false).await;
+        self.acknowledge_chat(&message, addr).await;
+        self.deliver_in_order(message).await;
+      }
+      "delivery_receipt" => {
+        let _ = self.app.emit("messaging:delivery-receipt", message.clone());
+        let message_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+        // 중계 허브가 전달한 메시지는 수신 확인이 올 때까지 대기열에 남아 있다.
+        // 전달됨으로 보이기 전에 대기열에서 빼야 그 사이 재시도가 다시 보내지 않는다.
+        let (id, receiver) = (message_id.clone(), sender_id.to_string());
+        self.with_app(move |app| delete_queued_message_to(app, &id, &receiver)).await;
+        self.update_delivered(&message_id).await;
+      }
+      "read_receipt" => {
+        let _ = self.app.emit("messaging:read-receipt", message.clone());
+        let message_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
+        self.update_read(message_id).await;
+      }
+      "typing" => {
+        let is_typing = message.get("content").and_then(|v| v.as_str()).unwrap_or("") == "typing";
+        let payload = json!({"userId": sender_id, "isTyping": is_typing});
+        let _ = self.app.emit("messaging:typing", payload);
+      }
+      "group_chat" => {
+        let message = self.observe_sequence(message.clone()).await;
+        self.acknowledge_group_chat(&message, addr).await;
+        self.deliver_in_order(message).await;
+      }
+      "group_create" => {
+        let _ = self.app.emit("group:created", message.clone());
+      }
+      "group_join" | "group_leave" => {
+        let _ = self.app.emit("group:member-changed", message.clone());
+      }
+      "group_read_receipt" => {
+        let _ = self.app.emit("group:read-receipt", message.clone());
+      }
+      "group_delivery_receipt" => {
+        let _ = self.app.emit("group:delivery-receipt", message.clone());
+      }
+      "group_typing" => {
+        let _ = self.app.emit("group:typing", message.clone());
+      }
+      "file_offer" => {
+        self.handle_file_offer(&message).await;
+      }
+      "folder_offer" => {
+        self.handle_folder_offer(&message).await;
+      }
+      "file_accept" => {
+        self.handle_file_accept(&message, addr).await;
+      }
+      "file_reject" => {
+        self.handle_file_reject(&message, addr).await;
+      }
+      "file_resume" => {
+        self.handle_file_resume(&message, addr).await;
+      }
+      "file_resume_request" => {
+        self.handle_file_resume_request(&message).await;
+      }
+      "ping" => {
+        let sender_ip = net_interfaces::peer_ip(&addr);
+        self.note_peer_presence(sender_id, &sender_ip, &message).await;
+        let _ = self.send_pong(sender_id, &sender_ip).await;
+      }
+      "goodbye" => {
+        self.handle_goodbye(sender_id, &message, &net_interfaces::peer_ip(&addr)).await;
+      }
+      "pong" => {
+        let sender_ip = net_interfaces::peer_ip(&addr);
+        self.update_peer_presence(sender_id, &sender_ip).await;
+        self.note_peer_presence(sender_id, &sender_ip, &message).await;
+      }
+      "relay" => {
+        self.handle_relay(&message, addr).await;
+      }
+      "relay_ack" => {
+        self.handle_relay_ack(&message).await;
+      }
+      "route" => {
+        self.handle_route(&message, addr).await;
+      }
+      "route_advert" => {
+        self.handle_route_advert(&message, &net_interfaces::peer_ip(&addr)).await;
+      }
+      "sync_summary" => {
+        self.handle_sync_summary(&message, addr).await;
+      }
+      "sync_request" => {
+        self.handle_sync_request(&message, addr).await;
+      }
+      "sync_messages" => {
+        self.handle_sync_messages(&message).await;
+      }
+      "device_sync" => {
+        self.handle_device_sync(&message).await;
+      }
+      "relay_deliver" => {
+        let Some(mut inner) = message.get("payload").cloned() else { return; };
+        let inner_type = inner.get("type").and_then(|v| v.as_str()).unwrap_or("");
+        if !RELAY_MESSAGE_TYPES.contains(&inner_type) {
+          return;
+        }
+        inner["relayedBy"] = json!(sender_id);
+        Box::pin(self.handle_incoming_message(inner, addr)).await;
+      }
+      _ => {}
+    }
+  }
+
+  /// 경로나 중계로 온 수신 확인은 안쪽 `senderId`를 확인할 수 없다.
+  /// 그 사람에게 보낸 메시지에 대한 확인일 때만 받는다.
+  async fn receipt_from_recipient(&self, message: &Value) -> bool {
+    if message.get("routedVia").is_none() && message.get("relayedBy").is_none() {
+      return true;
+    }
+    let sender_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let message_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    self.with_app(move |app| was_sent_to(app, &message_id, &sender_id)).await
+  }
+
+  async fn acknowledge_chat(&self, message: &Value, addr: SocketAddr) {
+    let sender_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("");
+    let message_id = message.get("id").and_then(|v| v.as_str()).unwrap_or("");
+    if message.get("relayedBy").is_some() || message.get("routedVia").is_some() {
+      // 보낸 사람이 아직 오프라인일 수 있으므로 수신 확인도 대기열과 중계 허브를 거친다.
+      self.spawn_relayed_receipt(sender_id, message_id);
+    } else {
+      self
+        .send_delivery_receipt(sender_id, message_id, net_interfaces::peer_ip(&addr))
+        .await;
+    }
+  }
+
+  async fn acknowledge_group_chat(&self, message: &Value, addr: SocketAddr) {
+    let receipt = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "group_delivery_receipt",
+      "senderId": self.my_user_id().await,
+      "receiverId": message.get("senderId").and_then(|v| v.as_str()).unwrap_or(""),
+      "timestamp": now_iso(),
+      "messageId": message.get("id").and_then(|v| v.as_str()).unwrap_or(""),
+      "groupId": message.get("groupId").and_then(|v| v.as_str()).unwrap_or(""),
+      "deliveredAt": now_iso()
+    });
+    self.spawn_udp_message(net_interfaces::peer_ip(&addr), receipt);
+  }
+
+  /// 보낸 사람과 메시지 ID로 이미 받은 메시지인지 확인하고 기록한다.
+  /// 최근 ID는 메모리에서, 재시작 전에 받은 것은 `p2p_seen_messages`와 저장된 채팅에서 찾는다.
+  /// 대기열과 중계 허브가 만료 전까지 원래 타임스탬프로 다시 보내므로, 신선도 창은 대기열 만료 시간이다.
+  async fn sighting(&self, msg_type: &str, message: &Value) -> Sighting {
+    let message_id = message.get("id").and_then(|v| v.as_str()).unwrap_or("");
+    if message_id.is_empty() || DEDUP_EXEMPT_TYPES.contains(&msg_type) {
+      return Sighting::First;
+    }
+
+    let now = now_unix_ms();
+    // 이전 버전 피어는 타임스탬프 없이 보낼 수 있다.
+    let sent_at = message
+      .get("timestamp")
+      .and_then(|v| v.as_str())
+      .and_then(|value| parse_iso(value).ok());
+    if sent_at.is_some_and(|sent_at| sent_at > now.saturating_add(MAX_CLOCK_SKEW_MS)) {
+      return Sighting::Stale;
+    }
+    // 기억하는 기간보다 오래된 메시지는 ID가 이미 지워졌을 수 있으므로 받지 않는다.
+    if let Some(sent_at) = sent_at {
+      let window = self.with_app(|app| app.open_db().ok().map(|conn| seen_window_ms(&conn))).await;
+      if window.is_some_and(|window| sent_at < now.saturating_sub(window)) {
+        return Sighting::Stale;
+      }
+    }
+
+    let sender_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let key = format!("{sender_id}|{message_id}");
+    {
+      let mut state = self.state.lock().await;
+      if state.seen.contains_key(&key) {
+        return Sighting::Duplicate;
+      }
+      remember_seen(&mut state.seen, key, now);
+    }
+
+    let message_id = message_id.to_string();
+    let is_chat = msg_type == "chat";
+    self
+      .with_app(move |app| record_seen_message(app, &sender_id, &message_id, is_chat, now))
+      .await
+  }
+
+  /// 다시 만난 피어에게 1:1 대화의 최근 기록 요약을 보낸다. 요약이 다르면 상대가 빠진 메시지를 요청한다.
+  async fn send_sync_summary(&self, user_id: &str, target_ip: &str) {
+    let my_user_id = self.my_user_id().await;
+    let (me, peer) = (my_user_id.clone(), user_id.to_string());
+    let history = self.with_app(move |app| conversation_history(app, &me, &peer)).await;
+
+    let summary = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "sync_summary",
+      "senderId": my_user_id,
+      "receiverId": user_id,
+      "timestamp": now_iso(),
+      "count": history.len(),
+      "highWater": history.iter().filter_map(p2p_order::seq_of).max().unwrap_or(0),
+      "digest": history_digest(&history)
+    });
+    let _ = self.send_tcp_message(target_ip, &summary).await;
+  }
+
+  async fn handle_sync_summary(&self, message: &Value, addr: SocketAddr) {
+    let peer_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    if peer_id.is_empty() {
+      return;
+    }
+
+    let (me, peer) = (self.my_user_id().await, peer_id.clone());
+    let history = self.with_app(move |app| conversation_history(app, &me, &peer)).await;
+    if message.get("digest").and_then(|v| v.as_str()) == Some(history_digest(&history).as_str()) {
+      return;
+    }
+    self
+      .send_sync_request(&peer_id, &net_interfaces::peer_ip(&addr), history_ids(&history), false)
+      .await;
+  }
+
+  /// 가진 메시지 ID를 보내 나머지를 요청한다. `reply`는 요청을 받고 되묻는 것이라 다시 되묻지 않는다.
+  async fn send_sync_request(&self, user_id: &str, target_ip: &str, have: Vec<String>, reply: bool) {
+    let request = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "sync_request",
+      "senderId": self.my_user_id().await,
+      "receiverId": user_id,
+      "timestamp": now_iso(),
+      "have": have,
+      "reply": reply
+    });
+    let _ = self.send_tcp_message(target_ip, &request).await;
+  }
+
+  /// 상대에게 없는 메시지 중 내가 보낸 것을 나눠 보낸다. 상대에게만 있는 메시지가 보이면 한 번 되묻는다.
+  async fn handle_sync_request(&self, message: &Value, addr: SocketAddr) {
+    let peer_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let Some(have) = message.get("have").and_then(|v| v.as_array()) else { return; };
+    if peer_id.is_empty() {
+      return;
+    }
+    let have = have.iter().filter_map(|id| id.as_str()).collect::<HashSet<_>>();
+    let target_ip = net_interfaces::peer_ip(&addr);
+
+    let my_user_id = self.my_user_id().await;
+    let (me, peer) = (my_user_id.clone(), peer_id.clone());
+    let history = self.with_app(move |app| conversation_history(app, &me, &peer)).await;
+
+    // 받는 쪽은 보낸 사람이 직접 보낸 기록만 받으므로 상대가 보낸 메시지는 돌려주지 않는다.
+    let missing = history
+      .iter()
+      .filter(|entry| entry.get("senderId").and_then(|v| v.as_str()) == Some(my_user_id.as_str()))
+      .filter(|entry| !have.contains(entry.get("id").and_then(|v| v.as_str()).unwrap_or("")))
+      .cloned()
+      .collect::<Vec<_>>();
+    for batch in sync_batches(missing) {
+      let envelope = json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "type": "sync_messages",
+        "senderId": my_user_id,
+        "receiverId": peer_id,
+        "timestamp": now_iso(),
+        "content": Value::Array(batch).to_string()
+      });
+      let sealed = self.seal_outgoing(&envelope, Some(&target_ip)).await;
+      if !self.send_tcp_message(&target_ip, &sealed).await {
+        return;
+      }
+    }
+
+    let mine = history_ids(&history);
+    let reply = message.get("reply").and_then(|v| v.as_bool()).unwrap_or(false);
+    if !reply && have.iter().any(|id| !mine.iter().any(|own| own == id)) {
+      self.send_sync_request(&peer_id, &target_ip, mine, true).await;
+    }
+  }
+
+  /// 받은 기록 중 상대가 나에게 보낸 채팅만 `p2p-sync`로 저장한다.
+  /// 내가 보낸 메시지는 상대가 지어낼 수 있으므로 받지 않는다. 내 다른 기기의 것은 `device_sync`로 온다.
+  /// 봉인되지 않은 묶음은 상대 키를 알면 `open_sealed`에서 이미 버려졌다.
+  async fn handle_sync_messages(&self, message: &Value) {
+    let peer_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let content = message.get("content").and_then(|v| v.as_str()).unwrap_or("");
+    let Ok(entries) = serde_json::from_str::<Vec<Value>>(content) else { return; };
+
+    let my_user_id = self.my_user_id().await;
+    let entries = entries
+      .into_iter()
+      .filter(|entry| is_sync_entry(entry, &my_user_id, &peer_id))
+      .collect::<Vec<_>>();
+    if entries.is_empty() {
+      return;
+    }
+
+    let peer = peer_id.clone();
+    let stored = self
+      .with_app(move |app| {
+        entries
+          .iter()
+          .filter(|entry| insert_synced_message(app, entry, &peer))
+          .filter_map(|entry| entry.get("id").and_then(|v| v.as_str()).map(|s| s.to_string()))
+          .collect::<Vec<_>>()
+      })
+      .await;
+    if stored.is_empty() {
+      return;
+    }
+
+    let _ = self.app.emit(
+      "messaging:history-synced",
+      json!({
+        "userId": peer_id,
+        "count": stored.len(),
+        "messageIds": stored
+      }),
+    );
+  }
+
+  /// 이 기기에서 보낸 메시지와 읽음 표시를 같은 사용자로 로그인한 다른 기기에 알린다.
+  /// 지금 닿는 기기에만 보낸다. 꺼져 있던 기기의 1:1 대화는 상대와 다시 만날 때 기록 동기화로 채워진다.
+  fn spawn_device_sync(&self, payload: Value) {
+    let manager = self.clone();
+    tokio::spawn(async move {
+      let (my_user_id, devices) = {
+        let state = manager.state.lock().await;
+        (state.my_user_id.clone(), user_devices(&state, &state.my_user_id))
+      };
+      for (device_ip, _) in devices {
+        let envelope = json!({
+          "id": uuid::Uuid::new_v4().to_string(),
+          "type": "device_sync",
+          "senderId": my_user_id,
+          "receiverId": my_user_id,
+          "timestamp": now_iso(),
+          "content": payload.to_string()
+        });
+        let sealed = manager.seal_outgoing(&envelope, Some(&device_ip)).await;
+        let _ = manager.send_tcp_message(&device_ip, &sealed).await;
+      }
+    });
+  }
+
+  /// 내 다른 기기가 보낸 메시지나 읽음 표시. 그 기기의 채팅 키로 봉인된 것만 받는다.
+  async fn handle_device_sync(&self, message: &Value) {
+    let my_user_id = self.my_user_id().await;
+    if message.get("senderId").and_then(|v| v.as_str()) != Some(my_user_id.as_str())
+      || message.get("encrypted") != Some(&json!(true))
+    {
+      return;
+    }
+    let content = message.get("content").and_then(|v| v.as_str()).unwrap_or("");
+    let Ok(payload) = serde_json::from_str::<Value>(content) else { return; };
+    if payload.get("senderId").and_then(|v| v.as_str()) != Some(my_user_id.as_str()) {
+      return;
+    }
+
+    match payload.get("type").and_then(|v| v.as_str()).unwrap_or("") {
+      "chat" => {
+        let payload = self.observe_sequence(payload).await;
+        self.persist_message(payload.clone(), false, false).await;
+        let _ = self.app.emit("messaging:device-sent", payload);
+      }
+      "group_chat" => {
+        let payload = self.observe_sequence(payload).await;
+        let _ = self.app.emit("group:device-sent", payload);
+      }
+      "read_receipt" => {
+        let message_id = payload.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
+        self.update_read(message_id).await;
+        let _ = self.app.emit(
+          "messaging:device-read",
+          json!({
+            "messageId": message_id,
+            "senderId": payload.get("receiverId").and_then(|v| v.as_str()),
+            "readAt": payload.get("readAt").and_then(|v| v.as_str())
+          }),
+        );
+      }
+      _ => {}
+    }
+  }
+
+  /// 상대 공개키를 알면 1:1 채팅과 대화 기록·기기 동기화의 `content`를 봉인한다.
+  /// `target_ip`에 있는 받는 사람의 기기 키가 우선이고, 모르면 아는 기기 아무 키나 쓴다.
+  /// 키를 광고하지 않는 이전 버전 피어에게는 평문 그대로 보낸다.
+  async fn seal_outgoing(&self, message: &Value, target_ip: Option<&str>) -> Value {
+    let msg_type = message.get("type").and_then(|v| v.as_str()).unwrap_or("");
+    if !SEALED_MESSAGE_TYPES.contains(&msg_type) || message.get("encrypted").is_some() {
+      return message.clone();
+    }
+
+    let receiver_id = message.get("receiverId").and_then(|v| v.as_str()).unwrap_or("");
+    let (keys, my_user_id, peer_key) = {
+      let state = self.state.lock().await;
+      (state.chat_keys.clone(), state.my_user_id.clone(), public_key_for(&state, receiver_id, target_ip))
+    };
+    let (Some(keys), Some(peer_key)) = (keys, peer_key) else { return message.clone(); };
+
+    let content = message.get("content").and_then(|v| v.as_str()).unwrap_or("");
+    let aad = p2p_crypto::message_aad(message);
+    match keys.seal(&peer_key, &my_user_id, receiver_id, &aad, content.as_bytes()) {
+      Ok(envelope) => {
+        let mut sealed = message.clone();
+        sealed["content"] = json!("");
+        sealed["encrypted"] = envelope;
+        sealed
+      }
+      Err(_) => message.clone(),
+    }
+  }
+
+  /// `encrypted` 봉투를 풀어 `content`를 채운다. 풀 수 없으면 `p2p:decrypt-failed`로 알리고 `None`.
+  /// 공개키를 아는 피어가 봉인해야 할 메시지를 평문으로 보냈으면 위조로 보고 마찬가지로 버린다.
+  async fn open_sealed(&self, mut message: Value) -> Option<Value> {
+    let msg_type = message.get("type").and_then(|v| v.as_str()).unwrap_or("");
+    let sender_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let receiver_id = message.get("receiverId").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    if message.get("encrypted").is_none() && !SEALED_MESSAGE_TYPES.contains(&msg_type) {
+      return Some(message);
+    }
+
+    let (keys, my_user_id, known_keys) = {
+      let state = self.state.lock().await;
+      (state.chat_keys.clone(), state.my_user_id.clone(), known_public_keys(&state, &sender_id))
+    };
+    if receiver_id != my_user_id {
+      return Some(message);
+    }
+    let Some(envelope) = message.get("encrypted").cloned() else {
+      // 키를 광고하지 않는 이전 버전 피어만 평문으로 보낼 수 있다.
+      if known_keys.is_empty() {
+        return Some(message);
+      }
+      self.emit_decrypt_failed(&message, &sender_id, "message is not encrypted");
+      return None;
+    };
+
+    let sender_key = envelope.get("senderKey").and_then(|v| v.as_str()).unwrap_or("");
+    let result = match (&keys, &known_keys) {
+      (None, _) => Err("encryption keys are not loaded".to_string()),
+      (_, known) if !known.is_empty() && !known.iter().any(|key| key == sender_key) => {
+        Err("sender key does not match the announced key".to_string())
+      }
+      (Some(keys), _) => keys
+        .open(&envelope, sender_key, &my_user_id, &sender_id, &p2p_crypto::message_aad(&message))
+        .and_then(|bytes| String::from_utf8(bytes).map_err(|_| "content is not valid UTF-8".to_string())),
+    };
+
+    match result {
+      Ok(content) => {
+        message["content"] = json!(content);
+        message["encrypted"] = json!(true);
+        Some(message)
+      }
+      Err(reason) => {
+        self.emit_decrypt_failed(&message, &sender_id, &reason);
+        None
+      }
+    }
+  }
+
+  fn emit_decrypt_failed(&self, message: &Value, sender_id: &str, reason: &str) {
+    let _ = self.app.emit(
+      "p2p:decrypt-failed",
+      json!({
+        "messageId": message.get("id").and_then(|v| v.as_str()).unwrap_or(""),
+        "senderId": sender_id,
+        "senderName": message.get("senderName").and_then(|v| v.as_str()),
+        "timestamp": message.get("timestamp").and_then(|v| v.as_str()),
+        "reason": reason
+      }),
+    );
+  }
+
+  async fn emit_message_received(&self, message: &Value) {
+    let payload = json!({
+      "id": message.get("id").and_then(|v| v.as_str()),
+      "messageId": message.get("id").and_then(|v| v.as_str()),
+      "senderId": message.get("senderId").and_then(|v| v.as_str()),
+      "senderName": message.get("senderName").and_then(|v| v.as_str()),
+      "receiverId": message.get("receiverId").and_then(|v| v.as_str()),
+      "content": message.get("content").and_then(|v| v.as_str()),
+      "timestamp": message.get("timestamp").and_then(|v| v.as_str()),
+      "seq": message.get("seq").and_then(|v| v.as_i64()),
+      "type": "text",
+      "isRead": false,
+      "delivered": true,
+      "deliveredAt": now_iso()
+    });
+
+    let _ = self.app.emit("messaging:received", payload);
+  }
+
+  async fn emit_conversation_message(&self, message: &Value) {
+    if message.get("type").and_then(|v| v.as_str()) == Some("group_chat") {
+      let _ = self.app.emit("group:message-received", message.clone());
+    } else {
+      self.emit_message_received(message).await;
+    }
+  }
+
+  /// 받은 `seq`로 대화 카운터를 앞당긴다. `seq`가 없는 이전 버전 피어의 메시지에는 받은 순서대로 번호를 붙인다.
+  async fn observe_sequence(&self, mut message: Value) -> Value {
+    let my_user_id = self.my_user_id().await;
+    let Some(conversation) = p2p_order::conversation_id(&message, &my_user_id) else { return message; };
+    let seq = p2p_order::seq_of(&message);
+    let seq = self.with_app(move |app| observe_sequence(app, &conversation, seq)).await;
+    if seq > 0 {
+      message["seq"] = json!(seq);
+    }
+    message
+  }
+
+  /// 저장은 이미 끝났고, 화면에 알리는 이벤트만 보낸 사람의 순서대로 내보낸다.
+  async fn deliver_in_order(&self, message: Value) {
+    let my_user_id = self.my_user_id().await;
+    let sender_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("");
+    let Some(conversation) = p2p_order::conversation_id(&message, &my_user_id) else {
+      self.emit_conversation_message(&message).await;
+      return;
+    };
+    let key = ReorderBuffer::key(&conversation, sender_id);
+
+    let ready = self.state.lock().await.reorder.push(&key, message);
+    if ready.is_empty() {
+      let manager = self.clone();
+      tokio::spawn(async move {
+        tokio::time::sleep(REORDER_WAIT).await;
+        let ready = manager.state.lock().await.reorder.flush(&key);
+        for message in ready {
+          manager.emit_conversation_message(&message).await;
+        }
+      });
+    }
+    for message in ready {
+      self.emit_conversation_message(&message).await;
+    }
+  }
+
+    async fn persist_message(&self, message: Value, delivered: bool, is_read: bool) {
+    let app = self.app.clone();
+    tokio::task::spawn_blocking(move || {
+      store_message(&app, message, delivered, is_read);
+    });
+  }
+async fn send_delivery_receipt(&self, receiver_id: &str, message_id: &str, target_ip: String) {
+    if receiver_id.is_empty() || message_id.is_empty() {
+      return;
+    }
+
+    let receipt = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "delivery_receipt",
+      "senderId": self.my_user_id().await,
+      "receiverId": receiver_id,
+      "timestamp": now_iso(),
+      "messageId": message_id,
+      "deliveredAt": now_iso()
+    });
+
+    self.spawn_udp_message(target_ip, receipt);
+  }
+
+  /// 수신 루프가 ack 대기로 멈추지 않도록 영수증은 별도 태스크에서 보낸다.
+  /// 엄격 모드에서는 UDP 대신 암호화된 TCP로 보낸다.
+  fn spawn_udp_message(&self, target_ip: String, message: Value) {
+    let manager = self.clone();
+    tokio::spawn(async move {
+      if manager.strict_transport().await {
+        let _ = manager.send_tcp_message(&target_ip, &message).await;
+      } else {
+        let _ = manager.send_udp_message(&target_ip, &message).await;
+      }
+    });
+  }
+
+  async fn send_pong(&self, receiver_id: &str, target_ip: &str) -> bool {
+    let (my_user_id, presence) = {
+      let state = self.state.lock().await;
+      (state.my_user_id.clone(), state.presence.clone())
+    };
+    let pong = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "pong",
+      "senderId": my_user_id,
+      "receiverId": receiver_id,
+      "timestamp": now_iso(),
+      "presence": presence
+    });
+
+    self.send_udp_datagram(target_ip, &pong).await
+  }
+
+  async fn should_process_message(&self, msg_type: &str, receiver_id: &str) -> bool {
+    if msg_type.starts_with("group_") {
+      return true;
+    }
+
+    let my_user_id = self.my_user_id().await;
+    receiver_id == my_user_id || receiver_id.is_empty()
+  }
+
+    async fn update_delivered(&self, message_id: &str) {
+    if message_id.is_empty() {
+      return;
+    }
+    let app = self.app.clone();
+    let id = message_id.to_string();
+    tokio::task::spawn_blocking(move || {
+      update_message_status(&app, &id, true, false);
+    });
+  }
+
+  async fn update_read(&self, message_id: &str) {
+    if message_id.is_empty() {
+      return;
+    }
+    let app = self.app.clone();
+    let id = message_id.to_string();
+    tokio::task::spawn_blocking(move || {
+      update_message_status(&app, &id, true, true);
+    });
+  }
+async fn handle_file_offer(&self, message: &Value) {
+    let file_size = message.get("fileSize").and_then(|v| v.as_u64()).unwrap_or(0);
+    let file_name = message.get("fileName").and_then(|v| v.as_str()).unwrap_or("unknown");
+
+    let transfer = FileTransfer {
+      id: message.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+      peerId: message.get("senderId").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+      fileName: sanitize_file_name(file_name),
+      fileSize: file_size,
+      progress: 0,
+      status: "pending".to_string(),
+      direction: "receive".to_string(),
+      totalChunks: total_chunks(file_size),
+      peerName: message.get("senderName").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      filePath: None,
+      bytesTransferred: 0,
+      kind: "file".to_string(),
+      manifest: None,
+    };
+
+    if transfer.id.is_empty() || self.load_transfer(&transfer.id).await.is_some() {
+      return;
+    }
+
+    self.save_transfer(&transfer, None).await;
+    let _ = self.app.emit("p2p:file-offer", self.transfer_payload(&transfer).await);
+  }
+
+  async fn handle_folder_offer(&self, message: &Value) {
+    let transfer_id = message.get("id").and_then(|v| v.as_str()).unwrap_or("");
+    let sender_id = message.get("senderId").and_then(|v| v.as_str()).unwrap_or("");
+    if transfer_id.is_empty() || self.load_transfer(transfer_id).await.is_some() {
+      return;
+    }
+
+    let manifest = message
+      .get("manifest")
+      .cloned()
+      .and_then(|v| serde_json::from_value::<Vec<ManifestEntry>>(v).ok())
+      .unwrap_or_default();
+    let total_size = manifest.iter().map(|entry| entry.size).sum::<u64>();
+    let declared_size = message.get("fileSize").and_then(|v| v.as_u64()).unwrap_or(0);
+
+    // 다운로드 폴더 밖으로 나가는 경로가 하나라도 있으면 제안 전체를 거절한다.
+    let unsafe_entry = manifest.iter().find(|entry| safe_relative_path(&entry.path).is_none());
+    if unsafe_entry.is_some() || total_size != declared_size {
+      let reject = json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "type": "file_reject",
+        "senderId": self.my_user_id().await,
+        "receiverId": sender_id,
+        "timestamp": now_iso(),
+        "messageId": transfer_id,
+        "content": "invalid folder manifest"
+      });
+      let _ = self.send_to_peer(sender_id, &reject).await;
+      let _ = self.app.emit(
+        "p2p:folder-rejected",
+        json!({
+          "transferId": transfer_id,
+          "senderId": sender_id,
+          "path": unsafe_entry.map(|entry| entry.path.clone()),
+          "error": "invalid folder manifest"
+        }),
+      );
+      return;
+    }
+
+    let folder_name = message.get("fileName").and_then(|v| v.as_str()).unwrap_or("folder");
+    let transfer = FileTransfer {
+      id: transfer_id.to_string(),
+      peerId: sender_id.to_string(),
+      fileName: sanitize_file_name(folder_name),
+      fileSize: total_size,
+      progress: 0,
+      status: "pending".to_string(),
+      direction: "receive".to_string(),
+      totalChunks: total_chunks(total_size),
+      peerName: message.get("senderName").and_then(|v| v.as_str()).map(|s| s.to_string()),
+      filePath: None,
+      bytesTransferred: 0,
+      kind: "folder".to_string(),
+      manifest: Some(manifest),
+    };
+
+    self.save_transfer(&transfer, None).await;
+    let _ = self.app.emit("p2p:file-offer", self.transfer_payload(&transfer).await);
+  }
+
+  async fn handle_file_accept(&self, message: &Value, addr: SocketAddr) {
+    let transfer_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
+    let Some((mut transfer, _)) = self.load_transfer(transfer_id).await else { return; };
+    if transfer.direction != "send" || transfer.status != "pending" || !self.is_transfer_peer(&transfer, message, &addr).await {
+      return;
+    }
+
+    transfer.status = "transferring".to_string();
+    self.save_transfer(&transfer, None).await;
+
+    let _ = self
+      .app
+      .emit("p2p:file-progress", json!({"transferId": transfer_id, "progress": 0}));
+
+    self.spawn_stream_file(transfer, net_interfaces::peer_ip(&addr), None);
+  }
+
+  async fn handle_file_reject(&self, message: &Value, addr: SocketAddr) {
+    let transfer_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
+    let Some((transfer, _)) = self.load_transfer(transfer_id).await else { return; };
+    if self.is_transfer_peer(&transfer, message, &addr).await {
+      self.delete_transfer(transfer_id).await;
+      let _ = self.app.emit("p2p:file-rejected", self.transfer_payload(&transfer).await);
+    }
+  }
+
+  async fn handle_file_resume(&self, message: &Value, addr: SocketAddr) {
+    let transfer_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
+    let Some((mut transfer, _)) = self.load_transfer(transfer_id).await else { return; };
+    if transfer.direction != "send"
+      || transfer.status == "pending"
+      || transfer.status == "completed"
+      || !self.is_transfer_peer(&transfer, message, &addr).await
+    {
+      return;
+    }
+
+    let received = message
+      .get("receivedChunks")
+      .and_then(|v| v.as_str())
+      .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok())
+      .filter(|bitmap| bitmap.len() == new_chunk_bitmap(transfer.totalChunks).len())
+      .unwrap_or_else(|| new_chunk_bitmap(transfer.totalChunks));
+
+    transfer.status = "transferring".to_string();
+    self.save_transfer(&transfer, None).await;
+    self.spawn_stream_file(transfer, net_interfaces::peer_ip(&addr), Some(received));
+  }
+
+  /// 전송 상대가 그 사용자의 알려진 주소에서 보낸 메시지인지. 전송 ID만 알아서는 파일을 받아 갈 수 없다.
+  async fn is_transfer_peer(&self, transfer: &FileTransfer, message: &Value, addr: &SocketAddr) -> bool {
+    if message.get("senderId").and_then(|v| v.as_str()) != Some(transfer.peerId.as_str()) {
+      return false;
+    }
+    let sender_ip = net_interfaces::peer_ip(addr);
+    let state = self.state.lock().await;
+    state
+      .peers
+      .values()
+      .any(|peer| peer.userId == transfer.peerId && peer.has_address(&sender_ip))
+  }
+
+  async fn handle_file_resume_request(&self, message: &Value) {
+    let transfer_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
+    let resumable = matches!(
+      self.load_transfer(transfer_id).await,
+      Some((transfer, _)) if transfer.direction == "receive" && transfer.filePath.is_some() && transfer.status != "completed"
+    );
+    if resumable {
+      let _ = self.resume_file(transfer_id.to_string()).await;
+    }
+  }
+
+  fn spawn_stream_file(&self, transfer: FileTransfer, target_ip: String, received: Option<Vec<u8>>) {
+    let manager = self.clone();
+    tokio::spawn(async move {
+      if let Err(err) = manager.stream_file(transfer.clone(), &target_ip, received).await {
+        manager.interrupt_transfer(&transfer.id, &err).await;
+      }
+    });
+  }
+
+  /// 수락된 파일을 64 KiB 청크로 나눠 하나의 TCP 연결로 전송한다.
+  /// 청크마다 SHA-256을 붙이고 수신 측 ack를 받은 뒤 다음 청크로 넘어간다.
+  /// `received`가 있으면 수신 측이 이미 검증한 청크는 건너뛴다.
+  async fn stream_file(&self, mut transfer: FileTransfer, target_ip: &str, received: Option<Vec<u8>>) -> Result<(), String> {
+    let source = transfer.filePath.clone().ok_or("missing source path")?;
+    let layout = transfer_layout(&transfer, Path::new(&source));
+    for (path, size) in &layout {
+      let current_size = tokio::fs::metadata(path)
+        .await
+        .map_err(|e| format!("failed to open {}: {e}", path.display()))?
+        .len();
+      if current_size != *size {
+        return Err(format!("{} changed size since it was offered", path.display()));
+      }
+    }
+
+    let mut channel = self.open_channel(target_ip).await?;
+
+    let received = received.unwrap_or_else(|| new_chunk_bitmap(transfer.totalChunks));
+    let sender_id = self.my_user_id().await;
+    let mut buf = vec![0u8; FILE_CHUNK_SIZE as usize];
+    let mut sent = received_bytes(&received, transfer.totalChunks, transfer.fileSize);
+
+    for index in 0..transfer.totalChunks {
+      if chunk_received(&received, index) {
+        continue;
+      }
+
+      let len = read_span(&layout, index * FILE_CHUNK_SIZE, &mut buf)
+        .await
+        .map_err(|e| e.to_string())?;
+      if len == 0 && transfer.fileSize > 0 {
+        return Err(format!("{source} ended after {sent} bytes"));
+      }
+
+      let chunk = json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "type": "file_chunk",
+        "senderId": sender_id,
+        "receiverId": transfer.peerId,
+        "timestamp": now_iso(),
+        "messageId": transfer.id,
+        "chunkIndex": index,
+        "hash": hex::encode(sha2::Sha256::digest(&buf[..len]))
+      });
+
+      let mut attempts = 0;
+      loop {
+        send_file_chunk(&mut channel, &chunk, &buf[..len]).await?;
+        let ack = recv_message(&mut channel).await?;
+        if ack.get("success").and_then(|v| v.as_bool()).unwrap_or(false) {
+          break;
+        }
+
+        attempts += 1;
+        if attempts >= FILE_CHUNK_RETRIES {
+          let error = ack.get("error").and_then(|v| v.as_str()).unwrap_or("chunk rejected");
+          return Err(format!("chunk {index} failed after {attempts} attempts: {error}"));
+        }
+      }
+
+      sent += len as u64;
+      if update_progress(&mut transfer, sent) {
+        self.save_transfer(&transfer, None).await;
+        self.emit_progress(&transfer);
+      }
+    }
+
+    let complete = json!({
+      "id": uuid::Uuid::new_v4().to_string(),
+      "type": "file_complete",
+      "senderId": sender_id,
+      "receiverId": transfer.peerId,
+      "timestamp": now_iso(),
+      "messageId": transfer.id,
+      "fileSize": transfer.fileSize
+    });
+    send_message(&mut channel, &complete).await?;
+    let ack = recv_message(&mut channel).await?;
+    channel.shutdown().await;
+
+    if !ack.get("success").and_then(|v| v.as_bool()).unwrap_or(false) {
+      let error = ack.get("error").and_then(|v| v.as_str()).unwrap_or("receiver rejected the file");
+      return Err(error.to_string());
+    }
+
+    self.finish_transfer(transfer).await;
+    Ok(())
+  }
+
+  /// 청크를 검증해 부분 파일에 기록하고 송신 측에 돌려줄 ack를 만든다.
+  async fn handle_file_chunk(&self, message: &Value, data: &[u8]) -> Value {
+    let transfer_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
+    let chunk_index = message.get("chunkIndex").and_then(|v| v.as_u64()).unwrap_or(u64::MAX);
+    let ack = |error: Option<&str>| {
+      json!({
+        "type": "file_chunk_ack",
+        "messageId": transfer_id,
+        "chunkIndex": chunk_index,
+        "success": error.is_none(),
+        "error": error
+      })
+    };
+
+    let Some((mut transfer, mut received)) = self.load_transfer(transfer_id).await else {
+      return ack(Some("unknown transfer"));
+    };
+    if transfer.direction != "receive" || (transfer.status != "accepted" && transfer.status != "transferring") {
+      return ack(Some("transfer is not accepting data"));
+    }
+    let Some(destination) = transfer.filePath.clone() else {
+      return ack(Some("transfer has no destination"));
+    };
+
+    if chunk_index >= transfer.totalChunks || data.len() as u64 > FILE_CHUNK_SIZE {
+      return ack(Some("chunk out of range"));
+    }
+
+    let hash = message.get("hash").and_then(|v| v.as_str()).unwrap_or("");
+    if hex::encode(sha2::Sha256::digest(data)) != hash {
+      return ack(Some("hash mismatch"));
+    }
+
+    if chunk_received(&received, chunk_index) {
+      return ack(None);
+    }
+
+    let layout = transfer_layout(&transfer, &partial_path(Path::new(&destination)));
+    if let Err(err) = write_span(&layout, chunk_index * FILE_CHUNK_SIZE, data).await {
+      return ack(Some(&err.to_string()));
+    }
+
+    mark_chunk_received(&mut received, chunk_index);
+    transfer.status = "transferring".to_string();
+    let bytes = received_bytes(&received, transfer.totalChunks, transfer.fileSize);
+    let changed = update_progress(&mut transfer, bytes);
+    self.save_transfer(&transfer, Some(received)).await;
+    if changed {
+      self.emit_progress(&transfer);
+    }
+
+    ack(None)
+  }
+
+  async fn handle_file_complete(&self, message: &Value) -> Value {
+    let transfer_id = message.get("messageId").and_then(|v| v.as_str()).unwrap_or("");
+    let ack = |error: Option<&str>| {
+      json!({
+        "type": "file_complete_ack",
+        "messageId": transfer_id,
+        "success": error.is_none(),
+        "error": error
+      })
+    };
+
+    let Some((transfer, mut received)) = self.load_transfer(transfer_id).await else {
+      return ack(Some("unknown transfer"));
+    };
+    if transfer.direction != "receive" {
+      return ack(Some("unknown transfer"));
+    }
+    if transfer.status == "completed" {
+      return ack(None);
+    }
+    let Some(destination) = transfer.filePath.clone() else {
+      return ack(Some("transfer has no destination"));
+    };
+
+    let missing = (0..transfer.totalChunks)
+      .filter(|index| !chunk_received(&received, *index))
+      .count();
+    if missing > 0 {
+      let error = format!("{missing} chunks missing");
+      self.interrupt_transfer(transfer_id, &error).await;
+      return ack(Some(&error));
+    }
+
+    let partial = partial_path(Path::new(&destination));
+
+    // 폴더는 청크 해시와 별개로 파일 단위 해시까지 확인하고, 어긋난 파일의 청크만 다시 받도록 비운다.
+    if let Some(manifest) = transfer.manifest.clone() {
+      let root = partial.clone();
+      let corrupted = tokio::task::spawn_blocking(move || corrupted_entries(&root, &manifest))
+        .await
+        .unwrap_or_default();
+      if !corrupted.is_empty() {
+        for (offset, size) in &corrupted {
+          clear_chunk_range(&mut received, *offset, *size);
+        }
+        let mut transfer = transfer.clone();
+        transfer.bytesTransferred = received_bytes(&received, transfer.totalChunks, transfer.fileSize);
+        self.save_transfer(&transfer, Some(received)).await;
+
+        let error = format!("{} files failed verification", corrupted.len());
+        self.interrupt_transfer(transfer_id, &error).await;
+        return ack(Some(&error));
+      }
+    }
+
+    if let Err(err) = tokio::fs::rename(&partial, &destination).await {
+      let error = err.to_string();
+      self.interrupt_transfer(transfer_id, &error).await;
+      return ack(Some(&error));
+    }
+
+    self.finish_transfer(transfer).await;
+    ack(None)
+  }
+
+  fn emit_progress(&self, transfer: &FileTransfer) {
+    let _ = self.app.emit(
+      "p2p:file-progress",
+      json!({
+        "transferId": transfer.id,
+        "progress": transfer.progress,
+        "bytesTransferred": transfer.bytesTransferred,
+        "direction": transfer.direction
+      }),
+    );
+  }
+
+  async fn finish_transfer(&self, mut transfer: FileTransfer) {
+    transfer.status = "completed".to_string();
+    transfer.progress = 100;
+    transfer.bytesTransferred = transfer.fileSize;
+    self.save_transfer(&transfer, None).await;
+
+    let _ = self.app.emit("p2p:file-complete", self.transfer_payload(&transfer).await);
+  }
+
+  /// 전송을 재개 가능한 상태로 표시한다. 부분 파일과 수신 비트맵은 그대로 둔다.
+  async fn interrupt_transfer(&self, transfer_id: &str, error: &str) {
+    let Some((mut transfer, _)) = self.load_transfer(transfer_id).await else { return; };
+    if transfer.status == "completed" || transfer.status == "interrupted" {
+      return;
+    }
+
+    transfer.status = "interrupted".to_string();
+    self.save_transfer(&transfer, None).await;
+
+    let mut payload = self.transfer_payload(&transfer).await;
+    payload["error"] = json!(error);
+    let _ = self.app.emit("p2p:file-interrupted", payload);
+  }
+
+  /// UI에서 사용하는 형태(transferId, senderId/receiverId, savedPath)로 전송 정보를 변환한다.
+  async fn transfer_payload(&self, transfer: &FileTransfer) -> Value {
+    let (my_user_id, my_user_name) = {
+      let state = self.state.lock().await;
+      (state.my_user_id.clone(), state.my_user_name.clone())
+    };
+
+    let (sender_id, sender_name, receiver_id) = if transfer.direction == "send" {
+      (my_user_id.clone(), Some(my_user_name), transfer.peerId.clone())
+    } else {
+      (transfer.peerId.clone(), transfer.peerName.clone(), my_user_id)
+    };
+
+    let mut payload = serde_json::to_value(transfer).unwrap_or_else(|_| json!({}));
+    payload["transferId"] = json!(transfer.id);
+    payload["senderId"] = json!(sender_id);
+    payload["senderName"] = json!(sender_name);
+    payload["receiverId"] = json!(receiver_id);
+    if transfer.direction == "receive" && transfer.status == "completed" {
+      payload["savedPath"] = json!(transfer.filePath);
+    }
+    payload
+  }
+
+  async fn load_transfer(&self, transfer_id: &str) -> Option<(FileTransfer, Vec<u8>)> {
+    let id = transfer_id.to_string();
+    self.with_app(move |app| load_file_transfer(app, &id)).await
+  }
+
+  /// `received`가 `None`이면 저장된 수신 비트맵은 유지한다.
+  async fn save_transfer(&self, transfer: &FileTransfer, received: Option<Vec<u8>>) {
+    let transfer = transfer.clone();
+    self
+      .with_app(move |app| save_file_transfer(app, &transfer, received.as_deref()))
+      .await;
+  }
+
+  async fn delete_transfer(&self, transfer_id: &str) {
+    let id = transfer_id.to_string();
+    self.with_app(move |app| delete_file_transfer(app, &id)).await;
+  }
+
+  async fn with_app<T, F>(&self, f: F) -> T
+  where
+    T: Default + Send + 'static,
+    F: FnOnce(&CoreHost) -> T + Send + 'static,
+  {
+    let app = self.app.clone();
+    tokio::task::spawn_blocking(move || f(&app)).await.unwrap_or_default()
+  }
+
+  /// `pong`을 보낸 피어를 온라인으로 두고 놓친 `pong` 수를 되돌린다.
+  /// 같은 사용자의 기기가 여럿이면 보낸 주소의 기기를 고른다.
+  async fn update_peer_presence(&self, user_id: &str, ip_address: &str) {
+    let mut state = self.state.lock().await;
+    let peer_id = state
+      .peers
+      .values()
+      .filter(|peer| peer.userId == user_id)
+      .max_by_key(|peer| peer.has_address(ip_address))
+      .map(|peer| peer.peerId.clone());
+    let Some(peer) = peer_id.and_then(|peer_id| state.peers.get_mut(&peer_id)) else { return; };
+
+    let was_online = peer.isOnline;
+    peer.isOnline = true;
+    peer.missed_pongs = 0;
+    peer.note_address(ip_address, was_online);
+    peer.lastSeen = now_iso();
+    if !was_online {
+      let _ = self.app.emit("p2p:peer-online", peer.clone());
+    }
+  }
+
+  /// 피어가 종료하며 보낸 `goodbye`. 그 주소에서 온 경우에만 오프라인으로 표시한다.
+  /// 그보다 먼저 서명된 알림은 늦게 도착해도 피어를 다시 온라인으로 만들지 않는다.
+  async fn handle_goodbye(&self, user_id: &str, message: &Value, ip_address: &str) {
+    let peer_id = message.get("peerId").and_then(|v| v.as_str());
+    let sent_at = message
+      .get("timestamp")
+      .and_then(|v| v.as_str())
+      .and_then(|value| parse_iso(value).ok())
+      .filter(|sent_at| (now_unix_ms() - sent_at).abs() <= ANNOUNCEMENT_MAX_AGE_MS);
+    let mut state = self.state.lock().await;
+    let mut departed = Vec::new();
+    for peer in state.peers.values_mut() {
+      let same_device = peer_id.is_none_or(|peer_id| peer.peerId == peer_id);
+      if peer.isOnline && peer.userId == user_id && same_device && peer.has_address(ip_address) {
+        self.mark_offline(peer);
+        departed.push(peer.peerId.clone());
+      }
+    }
+    if let Some(sent_at) = sent_at {
+      for peer_id in departed {
+        let last = state.announcement_times.entry(peer_id).or_insert(sent_at);
+        *last = (*last).max(sent_at);
+      }
+    }
+  }
+
+  fn mark_offline(&self, peer: &mut PeerInfo) {
+    peer.isOnline = false;
+    peer.missed_pongs = 0;
+    self.pool.remove(&peer.ipAddress);
+    self.routes_changed.notify_one();
+    let _ = self.app.emit("p2p:peer-offline", peer.clone());
+  }
+
+  async fn discovery_broadcast_loop(&self, discovery_port: u16, token: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    let mut sweep_interval = tokio::time::interval(unicast_discovery::SWEEP_INTERVAL);
+
+    loop {
+      tokio::select! {
+        _ = token.cancelled() => break,
+        _ = interval.tick() => {
+          let _ = self.broadcast_discovery(discovery_port, false).await;
+        }
+        _ = sweep_interval.tick() => {
+          let _ = self.broadcast_discovery(discovery_port, true).await;
+        }
+      }
+    }
+  }
+
+  /// 브로드캐스트와 함께 설정된 고정 피어/허브에도 유니캐스트로 보낸다.
+  /// `sweep`이면 설정된 대역의 모든 주소로도 보낸다.
+  async fn broadcast_discovery(&self, discovery_port: u16, sweep: bool) -> bool {
+    if self.my_user_id().await.is_empty() {
+      return false;
+    }
+
+    let message = self.announcement("discovery", true).await;
+
+    let data = match serde_json::to_vec(&message) {
+      Ok(data) => data,
+      Err(_) => return false,
+    };
+
+    let bind_ip = self.app.net().bind_ip.filter(|ip| ip.is_ipv4()).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let socket = match UdpSocket::bind((bind_ip, 0)).await {
+      Ok(socket) => socket,
+      Err(_) => return false,
+    };
+
+    let _ = socket.set_broadcast(true);
+
+    for addr in net_interfaces::broadcast_addresses() {
+      let _ = socket.send_to(&data, (addr, discovery_port)).await;
+    }
+    net_interfaces::send_multicast_v6(&data, discovery_port).await;
+
+    let unicast = UnicastTargets::load(&self.app).await;
+    unicast_discovery::send_to_hosts(&socket, &data, &unicast.hosts, discovery_port).await;
+    if sweep {
+      let own_ip = local_ip_address::local_ip().ok();
+      unicast_discovery::sweep(&socket, &data, &unicast.sweep, discovery_port, own_ip).await;
+    }
+
+    true
+  }
+
+  /// 서명된 디스커버리 알림. 브로드캐스트, 응답, mDNS TXT가 모두 이 내용을 쓴다.
+  /// 포트와 상태도 서명하므로 알림을 전하는 피어가 바꿀 수 없다.
+  async fn announcement(&self, msg_type: &str, with_presence: bool) -> Value {
+    let (peer_id, user_id, user_name, school_id, public_key, identity_key, relay_hub, ports, presence) = {
+      let state = self.state.lock().await;
+      (
+        state.my_peer_id.clone(),
+        state.my_user_id.clone(),
+        state.my_user_name.clone(),
+        state.my_school_id.clone(),
+        state.chat_keys.as_ref().map(|keys| keys.public_key()),
+        state.identity_key.clone(),
+        state.relay_hub,
+        (state.udp_message_port, state.tcp_message_port, state.discovery_port),
+        state.presence.clone(),
+      )
+    };
+
+    // HELLO와 마찬가지로 키가 없으면 `noise`를 광고하지 않는다.
+    let secure = identity_key.is_some() && public_key.is_some();
+    let mut capabilities = CAPABILITIES
+      .iter()
+      .copied()
+      .filter(|c| secure || *c != "noise")
+      .collect::<Vec<_>>();
+    if relay_hub {
+      capabilities.push(RELAY_CAPABILITY);
+    }
+
+    // 모든 인스턴스가 같은 포트를 쓰지 않는 경우(테스트, 데몬)를 위해 포트도 알린다.
+    let (udp_port, tcp_port, discovery_port) = ports;
+    let mut message = json!({
+      "type": msg_type,
+      "peerId": peer_id,
+      "userId": user_id,
+      "userName": user_name,
+      "schoolId": school_id,
+      "hostname": get_hostname(),
+      "platform": std::env::consts::OS,
+      "protocolVersion": PROTOCOL_VERSION,
+      "capabilities": capabilities,
+      "publicKey": public_key,
+      "udpPort": udp_port,
+      "tcpPort": tcp_port,
+      "discoveryPort": discovery_port,
+      "timestamp": now_iso()
+    });
+    if with_presence {
+      message["presence"] = json!(presence);
+    }
+    if let Some(identity_key) = &identity_key {
+      identity_key.sign_announcement(&mut message);
+    }
+    message
+  }
+
+  /// 디스커버리 백엔드가 광고할 알림. 실행 중이 아니면 `None`.
+  /// TXT 값은 255바이트로 잘리므로 상태 메시지는 싣지 않는다. 상태는 하트비트로 전해진다.
+  pub async fn discovery_announcement(&self) -> Option<Value> {
+    let (running, user_id) = {
+      let state = self.state.lock().await;
+      (state.running, state.my_user_id.clone())
+    };
+    if !running || user_id.is_empty() {
+      return None;
+    }
+
+    Some(self.announcement("discovery-response", false).await)
+  }
+
+  async fn cleanup_loop(&self, token: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+    loop {
+      tokio::select! {
+        _ = token.cancelled() => break,
+        _ = interval.tick() => {
+          self.pool.prune_idle();
+          let window = now_unix_ms().saturating_sub(SEEN_CACHE_TTL_MS);
+          self.state.lock().await.seen.retain(|_, seen_at| *seen_at > window);
+          self.with_app(purge_seen_messages).await;
+        }
+      }
+    }
+  }
+
+  async fn heartbeat_loop(&self, token: CancellationToken) {
+    let period = self.state.lock().await.heartbeat_interval;
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+      tokio::select! {
+        _ = token.cancelled() => break,
+        _ = interval.tick() => {
+          self.count_missed_pongs().await;
+          self.send_heartbeats().await;
+        }
+      }
+    }
+  }
+
+  /// 지난 `ping`에 답하지 않은 피어를 센다. 연속으로 한도만큼 놓치면 오프라인으로 표시한다.
+  async fn count_missed_pongs(&self) {
+    let mut state = self.state.lock().await;
+    let limit = state.missed_pong_limit;
+    for peer in state.peers.values_mut().filter(|peer| peer.isOnline) {
+      if peer.missed_pongs >= limit {
+        self.mark_offline(peer);
+      } else {
+        peer.missed_pongs += 1;
+      }
+    }
+  }
+
+  /// 온라인 피어마다 `goodbye`를 보낸다. 잃어도 피어가 하트비트로 알아채므로 한 번만 보낸다.
+  async fn send_goodbyes(&self) {
+    let (my_peer_id, my_user_id, peers) = {
+      let state = self.state.lock().await;
+      (
+        state.my_peer_id.clone(),
+        state.my_user_id.clone(),
+        state.peers.values().filter(|peer| peer.isOnline).cloned().collect::<Vec<_>>(),
+      )
+    };
+
+    for peer in peers {
+      let goodbye = json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "type": "goodbye",
+        "senderId": my_user_id,
+        "receiverId": peer.userId,
+        "peerId": my_peer_id,
+        "timestamp": now_iso()
+      });
+      let _ = self.send_udp_datagram(&peer.ipAddress, &goodbye).await;
+    }
+  }
+
+  /// 온라인 피어마다 내 상태를 실은 `ping`을 보낸다.
+  async fn send_heartbeats(&self) {
+    let (my_user_id, presence, peers) = {
+      let state = self.state.lock().await;
+      (
+        state.my_user_id.clone(),
+        state.presence.clone(),
+        state.peers.values().filter(|peer| peer.isOnline).cloned().collect::<Vec<_>>(),
+      )
+    };
+
+    for peer in peers {
+      let ping = json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "type": "ping",
+        "senderId": my_user_id,
+        "receiverId": peer.userId,
+        "timestamp": now_iso(),
+        "presence": presence
+      });
+      let _ = self.send_udp_datagram(&peer.ipAddress, &ping).await;
+    }
+  }
+
+  /// 수업 시작과 끝에 맞춰 상태를 바꾼다.
+  async fn presence_loop(&self, token: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+
+    loop {
+      tokio::select! {
+        _ = token.cancelled() => break,
+        _ = interval.tick() => {
+          self.refresh_presence().await;
+        }
+      }
+    }
+  }
+}
+
+pub fn requested_discovery_port() -> u16 {
+  parse_port(std::env::var("INTERNAL_P2P_DISCOVERY_PORT").ok(), 41235)
+}
+
+pub fn requested_udp_message_port() -> u16 {
+  parse_port(std::env::var("INTERNAL_P2P_MESSAGE_PORT").ok(), 41236)
+}
+
+pub fn requested_tcp_message_port() -> u16 {
+  parse_port(std::env::var("INTERNAL_P2P_TCP_PORT").ok(), 41237)
+}
+
+fn parse_port(value: Option<String>, fallback: u16) -> u16 {
+  value
+    .and_then(|v| v.parse::<u16>().ok())
+    .unwrap_or(fallback)
+}
+
+fn generate_peer_id() -> String {
+  let mac = mac_address::get_mac_address()
+    .ok()
+    .flatten()
+    .map(|m| m.to_string())
+    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+  let mut hasher = sha2::Sha256::new();
+  hasher.update(format!("{}{}", mac, now_unix_ms()));
+  let result = hasher.finalize();
+  let hex = hex::encode(result);
+  hex.chars().take(16).collect()
+}
+
+fn get_local_ip() -> String {
+  match local_ip_address::local_ip() {
+    Ok(ip) => ip.to_string(),
+    Err(_) => "127.0.0.1".to_string(),
+  }
+}
+
+fn get_hostname() -> String {
+  hostname::get()
+    .ok()
+    .and_then(|h| h.into_string().ok())
+    .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn now_iso() -> String {
+  chrono::Utc::now().to_rfc3339()
+}
+
+fn now_unix_ms() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_millis() as i64
+}
+
+fn parse_iso(value: &str) -> Result<i64, ()> {
+  let parsed = chrono::DateTime::parse_from_rfc3339(value).map_err(|_| ())?;
+  Ok(parsed.timestamp_millis())
+}
+
+/// 패킷의 `timestamp`(보낸 쪽 시계). 없거나 읽을 수 없으면 가장 오래된 것으로 본다.
+fn packet_time(message: &Value) -> i64 {
+  message
+    .get("timestamp")
+    .and_then(|v| v.as_str())
+    .and_then(|value| parse_iso(value).ok())
+    .unwrap_or(i64::MIN)
+}
+
+fn total_chunks(file_size: u64) -> u64 {
+  file_size.div_ceil(FILE_CHUNK_SIZE).max(1)
+}
+
+/// 상대방이 보낸 파일 이름에서 경로 구성 요소를 제거한다.
+fn sanitize_file_name(name: &str) -> String {
+  let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+  let trimmed = base.trim().trim_start_matches('.');
+  if trimmed.is_empty() {
+    "unknown".to_string()
+  } else {
+    trimmed.to_string()
+  }
+}
+
+/// 같은 이름의 파일이 있으면 "이름 (1).확장자" 형태로 겹치지 않는 경로를 만든다.
+fn unique_destination(dir: &Path, file_name: &str) -> PathBuf {
+  let candidate = dir.join(file_name);
+  if !candidate.exists() && !partial_path(&candidate).exists() {
+    return candidate;
+  }
+
+  let path = Path::new(file_name);
+  let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+  let extension = path.extension().map(|s| s.to_string_lossy().to_string());
+
+  (1..)
+    .map(|n| match &extension {
+      Some(ext) => dir.join(format!("{stem} ({n}).{ext}")),
+      None => dir.join(format!("{stem} ({n})")),
+    })
+    .find(|candidate| !candidate.exists() && !partial_path(candidate).exists())
+    .unwrap_or(candidate)
+}
+
+fn partial_path(destination: &Path) -> PathBuf {
+  let mut name = destination.as_os_str().to_os_string();
+  name.push(".part");
+  PathBuf::from(name)
+}
+
+/// 전송 데이터가 놓이는 파일 목록(경로, 크기). 단일 파일은 하나, 폴더는 매니페스트 순서대로다.
+/// 청크 오프셋은 이 파일들을 이어 붙인 바이트열 기준이다.
+fn transfer_layout(transfer: &FileTransfer, root: &Path) -> Vec<(PathBuf, u64)> {
+  match &transfer.manifest {
+    Some(manifest) => manifest
+      .iter()
+      .filter_map(|entry| safe_relative_path(&entry.path).map(|rel| (root.join(rel), entry.size)))
+      .collect(),
+    None => vec![(root.to_path_buf(), transfer.fileSize)],
+  }
+}
+
+async fn read_span(layout: &[(PathBuf, u64)], offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+  let mut filled = 0usize;
+  let mut start = 0u64;
+
+  for (path, size) in layout {
+    let end = start + size;
+    let position = offset + filled as u64;
+    if filled < buf.len() && position < end {
+      let mut file = tokio::fs::File::open(path).await?;
+      file.seek(std::io::SeekFrom::Start(position - start)).await?;
+      let want = ((end - position) as usize).min(buf.len() - filled);
+      file.read_exact(&mut buf[filled..filled + want]).await?;
+      filled += want;
+    }
+    start = end;
+  }
+
+  Ok(filled)
+}
+
+async fn write_span(layout: &[(PathBuf, u64)], offset: u64, data: &[u8]) -> std::io::Result<()> {
+  let mut written = 0usize;
+  let mut start = 0u64;
+
+  for (path, size) in layout {
+    let end = start + size;
+    let position = offset + written as u64;
+    if written < data.len() && position < end {
+      let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+      file.seek(std::io::SeekFrom::Start(position - start)).await?;
+      let take = ((end - position) as usize).min(data.len() - written);
+      file.write_all(&data[written..written + take]).await?;
+      file.flush().await?;
+      written += take;
+    }
+    start = end;
+  }
+
+  Ok(())
+}
+
+/// 수신 측 부분 파일(단일 파일) 또는 스테이징 폴더와 그 안의 빈 파일들을 만든다.
+async fn prepare_partial(transfer: &FileTransfer, destination: &Path) -> std::io::Result<()> {
+  let partial = partial_path(destination);
+  if transfer.manifest.is_none() {
+    tokio::fs::File::create(&partial).await?;
+    return Ok(());
+  }
+
+  tokio::fs::create_dir_all(&partial).await?;
+  for (path, _) in transfer_layout(transfer, &partial) {
+    if let Some(parent) = path.parent() {
+      tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::File::create(&path).await?;
+  }
+  Ok(())
+}
+
+async fn remove_partial(transfer: &FileTransfer, destination: &Path) {
+  let partial = partial_path(destination);
+  if transfer.manifest.is_some() {
+    let _ = tokio::fs::remove_dir_all(partial).await;
+  } else {
+    let _ = tokio::fs::remove_file(partial).await;
+  }
+}
+
+/// 매니페스트 경로를 안전한 상대 경로로 바꾼다. 절대 경로, "..", 드라이브 문자 등은 거부한다.
+fn safe_relative_path(path: &str) -> Option<PathBuf> {
+  if path.is_empty() || path.contains('\\') || path.contains(':') || path.starts_with('/') {
+    return None;
+  }
+
+  let mut relative = PathBuf::new();
+  for part in path.split('/') {
+    if part.is_empty() || part == "." || part == ".." {
+      return None;
+    }
+    relative.push(part);
+  }
+
+  relative
+    .components()
+    .all(|component| matches!(component, std::path::Component::Normal(_)))
+    .then_some(relative)
+}
+
+/// 폴더를 재귀적으로 훑어 매니페스트를 만든다. 심볼릭 링크는 따라가지 않는다.
+fn collect_manifest(root: &Path) -> std::io::Result<Vec<ManifestEntry>> {
+  fn walk(root: &Path, dir: &Path, entries: &mut Vec<ManifestEntry>) -> std::io::Result<()> {
+    let mut children = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+      let path = child.path();
+      let metadata = std::fs::symlink_metadata(&path)?;
+      if metadata.is_dir() {
+        walk(root, &path, entries)?;
+      } else if metadata.is_file() {
+        let relative = path
+          .strip_prefix(root)
+          .map_err(std::io::Error::other)?
+          .components()
+          .map(|component| component.as_os_str().to_string_lossy().to_string())
+          .collect::<Vec<_>>()
+          .join("/");
+        entries.push(ManifestEntry {
+          path: relative,
+          size: metadata.len(),
+          hash: hash_file(&path)?,
+        });
+      }
+    }
+    Ok(())
+  }
+
+  if !std::fs::metadata(root)?.is_dir() {
+    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a folder"));
+  }
+
+  let mut entries = Vec::new();
+  walk(root, root, &mut entries)?;
+  Ok(entries)
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+  let mut file = std::fs::File::open(path)?;
+  let mut hasher = sha2::Sha256::new();
+  std::io::copy(&mut file, &mut hasher)?;
+  Ok(hex::encode(hasher.finalize()))
+}
+
+/// 해시가 매니페스트와 다른 파일들의 (전송 내 오프셋, 크기).
+fn corrupted_entries(root: &Path, manifest: &[ManifestEntry]) -> Vec<(u64, u64)> {
+  let mut corrupted = Vec::new();
+  let mut offset = 0u64;
+  for entry in manifest {
+    let matches = safe_relative_path(&entry.path)
+      .and_then(|rel| hash_file(&root.join(rel)).ok())
+      .map(|hash| hash == entry.hash)
+      .unwrap_or(false);
+    if !matches {
+      corrupted.push((offset, entry.size));
+    }
+    offset += entry.size;
+  }
+  corrupted
+}
+
+async fn send_message(channel: &mut P2PChannel, message: &Value) -> Result<(), String> {
+  match timeout(Duration::from_secs(10), channel.send(message)).await {
+    Ok(Ok(())) => Ok(()),
+    Ok(Err(err)) => Err(err.to_string()),
+    Err(_) => Err("timed out writing to peer".to_string()),
+  }
+}
+
+async fn send_file_chunk(channel: &mut P2PChannel, header: &Value, data: &[u8]) -> Result<(), String> {
+  match timeout(Duration::from_secs(10), channel.send_file_chunk(header, data)).await {
+    Ok(Ok(())) => Ok(()),
+    Ok(Err(err)) => Err(err.to_string()),
+    Err(_) => Err("timed out writing to peer".to_string()),
+  }
+}
+
+async fn recv_message(channel: &mut P2PChannel) -> Result<Value, String> {
+  match timeout(Duration::from_secs(30), channel.recv()).await {
+    Ok(Ok(Some(Incoming::Message(message)))) => Ok(message),
+    Ok(Ok(Some(Incoming::FileChunk(..)))) => Err("unexpected file chunk from peer".to_string()),
+    Ok(Ok(None)) => Err("peer closed the connection".to_string()),
+    Ok(Err(err)) => Err(err.to_string()),
+    Err(_) => Err("timed out waiting for peer".to_string()),
+  }
+}
+
+fn new_chunk_bitmap(total_chunks: u64) -> Vec<u8> {
+  vec![0u8; total_chunks.div_ceil(8) as usize]
+}
+
+fn chunk_received(bitmap: &[u8], index: u64) -> bool {
+  bitmap
+    .get((index / 8) as usize)
+    .map(|byte| byte & (1 << (index % 8)) != 0)
+    .unwrap_or(false)
+}
+
+fn mark_chunk_received(bitmap: &mut [u8], index: u64) {
+  if let Some(byte) = bitmap.get_mut((index / 8) as usize) {
+    *byte |= 1 << (index % 8);
   }
+}
 
-  async fn update_peer_presence(&self, user_id: &str, ip_address: &str) {
-    let mut state = self.state.lock().await;
-    for peer in state.peers.values_mut() {
-      if peer.userId == user_id {
-        peer.isOnline = true;
-        peer.ipAddress = ip_address.to_string();
-        peer.lastSeen = now_iso();
-        let _ = self.app.emit("p2p:peer-online", peer.clone());
-        break;
-      }
+fn clear_chunk_range(bitmap: &mut [u8], offset: u64, size: u64) {
+  let first = offset / FILE_CHUNK_SIZE;
+  let last = (offset + size.max(1) - 1) / FILE_CHUNK_SIZE;
+  for index in first..=last {
+    if let Some(byte) = bitmap.get_mut((index / 8) as usize) {
+      *byte &= !(1 << (index % 8));
     }
   }
+}
 
-  async fn discovery_broadcast_loop(&self, discovery_port: u16, token: CancellationToken) {
-    let mut interval = tokio::time::interval(Duration::from_secs(30));
+/// 비트맵에 표시된 청크들의 실제 바이트 수. 마지막 청크는 64 KiB보다 작을 수 있다.
+fn received_bytes(bitmap: &[u8], total_chunks: u64, file_size: u64) -> u64 {
+  (0..total_chunks)
+    .filter(|index| chunk_received(bitmap, *index))
+    .map(|index| FILE_CHUNK_SIZE.min(file_size.saturating_sub(index * FILE_CHUNK_SIZE)))
+    .sum()
+}
 
-    loop {
-      tokio::select! {
-        _ = token.cancelled() => break,
-        _ = interval.tick() => {
-          let _ = self.broadcast_discovery(discovery_port).await;
-        }
-      }
-    }
-  }
+/// 진행률(%)이 바뀌었을 때만 true를 돌려 이벤트와 DB 기록 횟수를 줄인다.
+fn update_progress(transfer: &mut FileTransfer, bytes: u64) -> bool {
+  transfer.bytesTransferred = bytes;
+  let progress = bytes
+    .saturating_mul(100)
+    .checked_div(transfer.fileSize)
+    .map_or(100, |pct| pct.min(100) as u8);
+
+  let changed = progress != transfer.progress;
+  transfer.progress = progress;
+  changed
+}
 
-  async fn broadcast_discovery(&self, discovery_port: u16) -> bool {
-    let (peer_id, user_id, user_name, school_id) = {
-      let state = self.state.lock().await;
-      (
-        state.my_peer_id.clone(),
-        state.my_user_id.clone(),
-        state.my_user_name.clone(),
-        state.my_school_id.clone(),
-      )
-    };
+/// 설정의 downloadPath(없으면 저장소가 정한 폴더, 그것도 없으면 시스템 다운로드 폴더 아래 edulinker_file)를 수신 파일 저장 위치로 사용한다.
+fn download_dir_for(app: &CoreHost) -> PathBuf {
+  let configured = app
+    .open_db()
+    .ok()
+    .and_then(|conn| {
+      conn
+        .query_row(
+          "SELECT value FROM app_settings WHERE key = 'downloadPath'",
+          [],
+          |row| row.get::<_, String>(0),
+        )
+        .ok()
+    })
+    .filter(|path| !path.is_empty());
 
-    if user_id.is_empty() {
-      return false;
-    }
+  configured
+    .map(PathBuf::from)
+    .or_else(|| app.download_dir())
+    .or_else(|| dirs::download_dir().map(|dir| dir.join(DOWNLOAD_FOLDER_NAME)))
+    .unwrap_or_else(|| PathBuf::from(DOWNLOAD_FOLDER_NAME))
+}
 
-    let message = json!({
-      "type": "discovery",
-      "peerId": peer_id,
-      "userId": user_id,
-      "userName": user_name,
-      "schoolId": school_id,
-      "hostname": get_hostname(),
-      "platform": std::env::consts::OS,
-      "timestamp": now_iso()
-    });
+fn map_file_transfer(row: &rusqlite::Row) -> rusqlite::Result<(FileTransfer, Vec<u8>)> {
+  let transfer = FileTransfer {
+    id: row.get(0)?,
+    peerId: row.get(1)?,
+    peerName: row.get(2)?,
+    fileName: row.get(3)?,
+    fileSize: row.get::<_, i64>(4)? as u64,
+    totalChunks: row.get::<_, i64>(5)? as u64,
+    direction: row.get(6)?,
+    status: row.get(7)?,
+    filePath: row.get(8)?,
+    bytesTransferred: row.get::<_, i64>(9)? as u64,
+    progress: row.get::<_, i64>(10)? as u8,
+    kind: row.get::<_, Option<String>>(12)?.unwrap_or_else(|| "file".to_string()),
+    manifest: row
+      .get::<_, Option<String>>(13)?
+      .and_then(|text| serde_json::from_str(&text).ok()),
+  };
+  let received = row.get::<_, Option<Vec<u8>>>(11)?.unwrap_or_default();
+  Ok((transfer, received))
+}
 
-    let data = match serde_json::to_vec(&message) {
-      Ok(data) => data,
-      Err(_) => return false,
-    };
+const FILE_TRANSFER_COLUMNS: &str = "transfer_id, peer_id, peer_name, file_name, file_size, total_chunks, direction, status, file_path, bytes_transferred, progress, received_chunks, kind, manifest";
 
-    let socket = match UdpSocket::bind("0.0.0.0:0").await {
-      Ok(socket) => socket,
-      Err(_) => return false,
-    };
+fn load_file_transfer(app: &CoreHost, transfer_id: &str) -> Option<(FileTransfer, Vec<u8>)> {
+  let conn = app.open_db().ok()?;
+  conn
+    .query_row(
+      &format!("SELECT {FILE_TRANSFER_COLUMNS} FROM p2p_file_transfers WHERE transfer_id = ?1"),
+      params![transfer_id],
+      map_file_transfer,
+    )
+    .ok()
+}
 
-    let _ = socket.set_broadcast(true);
+fn save_file_transfer(app: &CoreHost, transfer: &FileTransfer, received: Option<&[u8]>) {
+  let Ok(conn) = app.open_db() else { return; };
 
-    let targets = broadcast_addresses();
-    for addr in targets {
-      let _ = socket.send_to(&data, (addr.as_str(), discovery_port)).await;
-    }
+  let now = now_iso();
+  let _ = conn.execute(
+    "INSERT INTO p2p_file_transfers (transfer_id, peer_id, peer_name, file_name, file_size, total_chunks, direction, status, file_path, bytes_transferred, progress, received_chunks, kind, manifest, created_at, updated_at)
+     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?15)
+     ON CONFLICT(transfer_id) DO UPDATE SET
+       status = excluded.status,
+       file_path = excluded.file_path,
+       bytes_transferred = excluded.bytes_transferred,
+       progress = excluded.progress,
+       received_chunks = COALESCE(excluded.received_chunks, received_chunks),
+       updated_at = excluded.updated_at",
+    params![
+      transfer.id,
+      transfer.peerId,
+      transfer.peerName,
+      transfer.fileName,
+      transfer.fileSize as i64,
+      transfer.totalChunks as i64,
+      transfer.direction,
+      transfer.status,
+      transfer.filePath,
+      transfer.bytesTransferred as i64,
+      transfer.progress as i64,
+      received,
+      transfer.kind,
+      transfer.manifest.as_ref().and_then(|manifest| serde_json::to_string(manifest).ok()),
+      now
+    ],
+  );
+}
 
-    true
-  }
+fn delete_file_transfer(app: &CoreHost, transfer_id: &str) {
+  let Ok(conn) = app.open_db() else { return; };
+  let _ = conn.execute("DELETE FROM p2p_file_transfers WHERE transfer_id = ?1", params![transfer_id]);
+}
 
-  async fn cleanup_loop(&self, token: CancellationToken) {
-    let mut interval = tokio::time::interval(Duration::from_secs(60));
+fn list_file_transfers(app: &CoreHost) -> Vec<FileTransfer> {
+  let Ok(conn) = app.open_db() else { return Vec::new(); };
+  let Ok(mut stmt) = conn.prepare(&format!(
+    "SELECT {FILE_TRANSFER_COLUMNS} FROM p2p_file_transfers ORDER BY created_at DESC"
+  )) else {
+    return Vec::new();
+  };
+
+  stmt
+    .query_map([], map_file_transfer)
+    .map(|rows| rows.filter_map(|row| row.ok()).map(|(transfer, _)| transfer).collect())
+    .unwrap_or_default()
+}
 
-    loop {
-      tokio::select! {
-        _ = token.cancelled() => break,
-        _ = interval.tick() => {
-          let mut state = self.state.lock().await;
-          let now = now_unix_ms();
-          for peer in state.peers.values_mut() {
-            if peer.isOnline {
-              if let Ok(last_seen) = parse_iso(&peer.lastSeen) {
-                if now.saturating_sub(last_seen) > 5 * 60 * 1000 {
-                  peer.isOnline = false;
-                  let _ = self.app.emit("p2p:peer-offline", peer.clone());
-                }
-              }
-            }
-          }
-        }
-      }
-    }
+/// 키가 준비되어 있으면 TCP 채널에 Noise 세션을 쓴다.
+async fn connect_tcp(ip: &str, port: u16, bind_ip: Option<IpAddr>) -> Result<TcpStream, String> {
+  let target = net_interfaces::socket_addr(ip, port).ok_or_else(|| format!("invalid address {ip}"))?;
+  let socket = if target.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() }
+    .map_err(|e| format!("failed to connect to {ip}: {e}"))?;
+  if bind_ip.is_some() {
+    socket
+      .bind(net_interfaces::local_for(&target, bind_ip))
+      .map_err(|e| format!("failed to connect to {ip}: {e}"))?;
+  }
+  match timeout(Duration::from_secs(5), socket.connect(target)).await {
+    Ok(Ok(stream)) => Ok(stream),
+    Ok(Err(err)) => Err(format!("failed to connect to {ip}: {err}")),
+    Err(_) => Err(format!("timed out connecting to {ip}")),
   }
+}
 
-  async fn heartbeat_loop(&self, token: CancellationToken) {
-    let mut interval = tokio::time::interval(Duration::from_secs(60));
+/// 알림이 `capability`를 광고했는지
+fn advertises(message: &Value, capability: &str) -> bool {
+  message
+    .get("capabilities")
+    .and_then(|v| v.as_array())
+    .is_some_and(|capabilities| capabilities.iter().any(|c| c.as_str() == Some(capability)))
+}
 
-    loop {
-      tokio::select! {
-        _ = token.cancelled() => break,
-        _ = interval.tick() => {
-          let peers = {
-            let state = self.state.lock().await;
-            state.peers.values().cloned().collect::<Vec<_>>()
-          };
-
-          for peer in peers {
-            if peer.isOnline {
-              let ping = json!({
-                "id": uuid::Uuid::new_v4().to_string(),
-                "type": "ping",
-                "senderId": self.my_user_id().await,
-                "receiverId": peer.userId,
-                "timestamp": now_iso()
-              });
-              let _ = self.send_udp_message(&peer.ipAddress, &ping).await;
-            }
-          }
-        }
+fn secure_config(state: &InternalP2PState) -> Option<SecureConfig> {
+  Some(SecureConfig::new(state.chat_keys.as_ref()?, state.identity_key.as_ref()?))
+}
+
+fn app_setting(app: &CoreHost, key: &str) -> Option<String> {
+  let conn = app.open_db().ok()?;
+  conn
+    .query_row("SELECT value FROM app_settings WHERE key = ?1", params![key], |row| row.get::<_, String>(0))
+    .ok()
+}
+
+fn save_setting(app: &CoreHost, key: &str, value: &str) -> bool {
+  let Ok(conn) = app.open_db() else { return false; };
+  conn
+    .execute(
+      "INSERT INTO app_settings (key, value, updated_at) VALUES (?1, ?2, ?3)
+       ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+      params![key, value, now_iso()],
+    )
+    .is_ok()
+}
+
+/// 저장된 시간표로 본 지금 교시. 시간표가 없으면 `None`.
+fn current_class_period(app: &CoreHost) -> Option<String> {
+  let settings = app_setting(app, p2p_presence::TIMETABLE_SETTING)?;
+  p2p_presence::class_period(&settings, p2p_presence::local_minutes())
+}
+
+/// 이 설치의 장기 X25519 키와 Ed25519 신원 키를 불러오고, 없으면 만들어 저장한다.
+fn load_or_create_keys(app: &CoreHost) -> Option<(ChatKeys, IdentityKey)> {
+  let conn = app.open_db().ok()?;
+
+  let (x25519, ed25519) = conn
+    .query_row("SELECT x25519_secret, ed25519_secret FROM p2p_identity WHERE id = 1", [], |row| {
+      Ok((row.get::<_, Option<Vec<u8>>>(0)?, row.get::<_, Option<Vec<u8>>>(1)?))
+    })
+    .unwrap_or((None, None));
+
+  let chat_keys = x25519
+    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+    .map(ChatKeys::from_bytes)
+    .unwrap_or_else(ChatKeys::generate);
+  let identity_key = ed25519
+    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+    .map(IdentityKey::from_bytes)
+    .unwrap_or_else(IdentityKey::generate);
+
+  conn
+    .execute(
+      "INSERT INTO p2p_identity (id, x25519_secret, ed25519_secret, created_at) VALUES (1, ?1, ?2, ?3)
+       ON CONFLICT(id) DO UPDATE SET x25519_secret = excluded.x25519_secret, ed25519_secret = excluded.ed25519_secret",
+      params![chat_keys.secret_bytes().to_vec(), identity_key.secret_bytes().to_vec(), now_iso()],
+    )
+    .ok()?;
+  Some((chat_keys, identity_key))
+}
+
+fn load_peer_key(app: &CoreHost, user_id: &str) -> Option<PeerKeyRecord> {
+  let conn = app.open_db().ok()?;
+  let mut record = conn
+    .query_row(
+      "SELECT user_id, identity_key, pending_key, verified FROM p2p_peer_keys WHERE user_id = ?1",
+      params![user_id],
+      |row| {
+        Ok(PeerKeyRecord {
+          user_id: row.get(0)?,
+          identity_key: row.get(1)?,
+          pending_key: row.get(2)?,
+          verified: row.get::<_, i64>(3)? != 0,
+          device_keys: Vec::new(),
+        })
+      },
+    )
+    .ok()?;
+  record.device_keys = device_keys(&conn, user_id);
+  Some(record)
+}
+
+fn device_keys(conn: &Connection, user_id: &str) -> Vec<String> {
+  let Ok(mut stmt) = conn.prepare("SELECT identity_key FROM p2p_peer_devices WHERE user_id = ?1") else {
+    return Vec::new();
+  };
+  stmt
+    .query_map(params![user_id], |row| row.get::<_, String>(0))
+    .map(|rows| rows.filter_map(|row| row.ok()).collect())
+    .unwrap_or_default()
+}
+
+fn pin_peer_key(app: &CoreHost, user_id: &str, identity_key: &str) -> KeyPin {
+  let Ok(conn) = app.open_db() else { return KeyPin::Unpinned; };
+  let now = now_iso();
+
+  let pinned = conn
+    .query_row(
+      "SELECT identity_key, pending_key FROM p2p_peer_keys WHERE user_id = ?1",
+      params![user_id],
+      |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)),
+    )
+    .ok();
+
+  match pinned {
+    None => {
+      let inserted = conn.execute(
+        "INSERT INTO p2p_peer_keys (user_id, identity_key, verified, first_seen, updated_at) VALUES (?1, ?2, 0, ?3, ?3)",
+        params![user_id, identity_key, now],
+      );
+      if inserted.is_ok() { KeyPin::Pinned } else { KeyPin::Unpinned }
+    }
+    Some((pinned, _)) if pinned == identity_key => KeyPin::Pinned,
+    Some(_) if device_keys(&conn, user_id).iter().any(|key| key == identity_key) => KeyPin::Pinned,
+    Some((previous, pending)) => {
+      let first_seen = pending.as_deref() != Some(identity_key);
+      if first_seen {
+        let _ = conn.execute(
+          "UPDATE p2p_peer_keys SET pending_key = ?2, updated_at = ?3 WHERE user_id = ?1",
+          params![user_id, identity_key, now],
+        );
       }
+      KeyPin::Changed { previous, first_seen }
     }
   }
 }
 
-pub fn requested_discovery_port() -> u16 {
-  parse_port(std::env::var("INTERNAL_P2P_DISCOVERY_PORT").ok(), 41235)
+fn set_peer_key_verified(app: &CoreHost, user_id: &str, verified: bool) -> bool {
+  let Ok(conn) = app.open_db() else { return false; };
+  conn
+    .execute(
+      "UPDATE p2p_peer_keys SET verified = ?2, updated_at = ?3 WHERE user_id = ?1",
+      params![user_id, verified as i64, now_iso()],
+    )
+    .map(|count| count > 0)
+    .unwrap_or(false)
 }
 
-pub fn requested_udp_message_port() -> u16 {
-  parse_port(std::env::var("INTERNAL_P2P_MESSAGE_PORT").ok(), 41236)
+fn accept_pending_peer_key(app: &CoreHost, user_id: &str) -> bool {
+  let Ok(conn) = app.open_db() else { return false; };
+  conn
+    .execute(
+      "UPDATE p2p_peer_keys SET identity_key = pending_key, pending_key = NULL, verified = 0, updated_at = ?2
+       WHERE user_id = ?1 AND pending_key IS NOT NULL",
+      params![user_id, now_iso()],
+    )
+    .map(|count| count > 0)
+    .unwrap_or(false)
 }
 
-pub fn requested_tcp_message_port() -> u16 {
-  parse_port(std::env::var("INTERNAL_P2P_TCP_PORT").ok(), 41237)
+/// 바뀐 것으로 보였던 키를 기존 키와 함께 같은 사용자의 다른 기기 키로 고정한다.
+fn accept_pending_peer_device(app: &CoreHost, user_id: &str) -> bool {
+  let Ok(mut conn) = app.open_db() else { return false; };
+  let Ok(tx) = conn.transaction() else { return false; };
+
+  let added = tx
+    .execute(
+      "INSERT OR IGNORE INTO p2p_peer_devices (user_id, identity_key, added_at)
+       SELECT user_id, pending_key, ?2 FROM p2p_peer_keys WHERE user_id = ?1 AND pending_key IS NOT NULL",
+      params![user_id, now_iso()],
+    )
+    .unwrap_or(0);
+  let cleared = tx.execute(
+    "UPDATE p2p_peer_keys SET pending_key = NULL, updated_at = ?2 WHERE user_id = ?1",
+    params![user_id, now_iso()],
+  );
+  added > 0 && cleared.is_ok() && tx.commit().is_ok()
 }
 
-fn parse_port(value: Option<String>, fallback: u16) -> u16 {
-  value
-    .and_then(|v| v.parse::<u16>().ok())
-    .unwrap_or(fallback)
+fn queue_expiry_ms(conn: &Connection) -> i64 {
+  let hours = conn
+    .query_row(
+      "SELECT value FROM app_settings WHERE key = 'p2pQueueExpiryHours'",
+      [],
+      |row| row.get::<_, String>(0),
+    )
+    .ok()
+    .and_then(|value| value.trim().parse::<i64>().ok())
+    .filter(|hours| *hours > 0)
+    .unwrap_or(DEFAULT_QUEUE_EXPIRY_HOURS);
+  hours.saturating_mul(60 * 60 * 1000)
 }
 
-fn generate_peer_id() -> String {
-  let mac = mac_address::get_mac_address()
-    .ok()
-    .flatten()
-    .map(|m| m.to_string())
+/// 받은 메시지 ID를 기억하고 그 타임스탬프를 받아들이는 기간. 대기열과 중계 허브가
+/// 만료 전까지 원래 타임스탬프로 다시 보내므로 대기열 만료 시간에 시계 오차를 더한다.
+fn seen_window_ms(conn: &Connection) -> i64 {
+  queue_expiry_ms(conn).saturating_add(MAX_CLOCK_SKEW_MS)
+}
+
+const QUEUE_COLUMNS: &str =
+  "message_id, receiver_id, message_type, payload, attempts, next_attempt_at, expires_at, last_error, created_at, relay_holder, device";
+
+fn map_queued_message(row: &rusqlite::Row) -> rusqlite::Result<QueuedMessage> {
+  let payload: String = row.get(3)?;
+  Ok(QueuedMessage {
+    message_id: row.get(0)?,
+    receiver_id: row.get(1)?,
+    message_type: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+    attempts: row.get::<_, i64>(4)?.max(0) as u32,
+    next_attempt_at: row.get(5)?,
+    expires_at: row.get(6)?,
+    last_error: row.get(7)?,
+    created_at: row.get(8)?,
+    relay_holder: row.get(9)?,
+    device: Some(row.get::<_, String>(10)?).filter(|device| !device.is_empty()),
+    message: serde_json::from_str(&payload).unwrap_or(Value::Null),
+  })
+}
+
+fn insert_queued_message(app: &CoreHost, receiver_id: &str, device: Option<&str>, message: &Value) {
+  let Ok(conn) = app.open_db() else { return; };
+
+  let message_id = message
+    .get("id")
+    .and_then(|v| v.as_str())
+    .map(|s| s.to_string())
     .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+  let message_type = message.get("type").and_then(|v| v.as_str()).unwrap_or("");
+  let now = now_unix_ms();
+  let expires_at = now.saturating_add(queue_expiry_ms(&conn));
 
-  let mut hasher = sha2::Sha256::new();
-  hasher.update(format!("{}{}", mac, now_unix_ms()));
-  let result = hasher.finalize();
-  let hex = hex::encode(result);
-  hex.chars().take(16).collect()
+  let _ = conn.execute(
+    "INSERT INTO p2p_outbound_queue (message_id, receiver_id, message_type, payload, attempts, next_attempt_at, expires_at, created_at, device)
+     VALUES (?1, ?2, ?3, ?4, 0, ?5, ?6, ?7, ?8)
+     ON CONFLICT(message_id, device) DO UPDATE SET
+       payload = excluded.payload,
+       next_attempt_at = excluded.next_attempt_at",
+    params![
+      message_id,
+      receiver_id,
+      message_type,
+      message.to_string(),
+      now,
+      expires_at,
+      now_iso(),
+      device.unwrap_or("")
+    ],
+  );
 }
 
-fn get_local_ip() -> String {
-  match local_ip_address::local_ip() {
-    Ok(ip) => ip.to_string(),
-    Err(_) => "127.0.0.1".to_string(),
-  }
+/// 한 메시지의 대기 항목들. 기기마다 따로 대기시켰으면 여러 개다.
+fn load_queued_messages(app: &CoreHost, message_id: &str) -> Vec<QueuedMessage> {
+  let Ok(conn) = app.open_db() else { return Vec::new(); };
+  let Ok(mut stmt) = conn.prepare(&format!(
+    "SELECT {QUEUE_COLUMNS} FROM p2p_outbound_queue WHERE message_id = ?1 ORDER BY rowid ASC"
+  )) else {
+    return Vec::new();
+  };
+
+  stmt
+    .query_map(params![message_id], map_queued_message)
+    .map(|rows| rows.filter_map(|row| row.ok()).collect())
+    .unwrap_or_default()
 }
 
-fn get_hostname() -> String {
-  hostname::get()
-    .ok()
-    .and_then(|h| h.into_string().ok())
-    .unwrap_or_else(|| "unknown".to_string())
+fn list_queued_messages(app: &CoreHost, receiver_id: Option<&str>) -> Vec<QueuedMessage> {
+  let Ok(conn) = app.open_db() else { return Vec::new(); };
+  let Ok(mut stmt) = conn.prepare(&format!(
+    "SELECT {QUEUE_COLUMNS} FROM p2p_outbound_queue
+     WHERE ?1 IS NULL OR receiver_id = ?1
+     ORDER BY rowid ASC"
+  )) else {
+    return Vec::new();
+  };
+
+  stmt
+    .query_map(params![receiver_id], map_queued_message)
+    .map(|rows| rows.filter_map(|row| row.ok()).collect())
+    .unwrap_or_default()
 }
 
-fn broadcast_addresses() -> Vec<String> {
-  let mut addresses = Vec::new();
-  if let Ok(ip) = local_ip_address::local_ip() {
-    if let std::net::IpAddr::V4(ipv4) = ip {
-      let octets = ipv4.octets();
-      addresses.push(format!("{}.{}.{}.255", octets[0], octets[1], octets[2]));
-    }
+fn update_queued_message(app: &CoreHost, entry: &QueuedMessage, attempts: u32, next_attempt_at: i64, error: Option<&str>) {
+  let Ok(conn) = app.open_db() else { return; };
+  let _ = conn.execute(
+    "UPDATE p2p_outbound_queue SET attempts = ?3, next_attempt_at = ?4, last_error = ?5
+     WHERE message_id = ?1 AND device = ?2",
+    params![
+      entry.message_id,
+      entry.device.as_deref().unwrap_or(""),
+      attempts as i64,
+      next_attempt_at,
+      error
+    ],
+  );
+}
+
+fn delete_queued_message(app: &CoreHost, message_id: &str) -> bool {
+  let Ok(conn) = app.open_db() else { return false; };
+  conn
+    .execute("DELETE FROM p2p_outbound_queue WHERE message_id = ?1", params![message_id])
+    .map(|count| count > 0)
+    .unwrap_or(false)
+}
+
+/// 그 기기 앞 사본 하나만 뺀다.
+fn delete_queued_entry(app: &CoreHost, entry: &QueuedMessage) -> bool {
+  let Ok(conn) = app.open_db() else { return false; };
+  conn
+    .execute(
+      "DELETE FROM p2p_outbound_queue WHERE message_id = ?1 AND device = ?2",
+      params![entry.message_id, entry.device.as_deref().unwrap_or("")],
+    )
+    .map(|count| count > 0)
+    .unwrap_or(false)
+}
+
+/// 받는 사람이 `receiver_id`일 때만 대기열에서 뺀다. 수신 확인은 받은 사람만 보낼 수 있다.
+/// 수신 확인으로는 어느 기기가 받았는지 알 수 없으므로 기기를 정한 사본은 남긴다.
+fn delete_queued_message_to(app: &CoreHost, message_id: &str, receiver_id: &str) -> bool {
+  let Ok(conn) = app.open_db() else { return false; };
+  conn
+    .execute(
+      "DELETE FROM p2p_outbound_queue WHERE message_id = ?1 AND receiver_id = ?2 AND device = ''",
+      params![message_id, receiver_id],
+    )
+    .map(|count| count > 0)
+    .unwrap_or(false)
+}
+
+/// 이 메시지를 `user_id`에게 보냈는지. 보낸 기록이나 대기열에서 찾는다.
+fn was_sent_to(app: &CoreHost, message_id: &str, user_id: &str) -> bool {
+  if message_id.is_empty() || user_id.is_empty() {
+    return false;
   }
+  let Ok(conn) = app.open_db() else { return false; };
+  conn
+    .query_row(
+      "SELECT EXISTS(SELECT 1 FROM messages WHERE message_id = ?1 AND recipient_id = ?2)
+           OR EXISTS(SELECT 1 FROM p2p_outbound_queue WHERE message_id = ?1 AND receiver_id = ?2)",
+      params![message_id, user_id],
+      |row| row.get::<_, bool>(0),
+    )
+    .unwrap_or(false)
+}
+
+/// 이 주소의 피어가 알린 UDP/TCP 메시지 포트. 모르는 주소면 우리와 같은 포트를 쓴다고 본다.
+fn peer_ports(state: &InternalP2PState, ip: &str) -> (u16, u16) {
+  state
+    .peers
+    .values()
+    .find(|peer| peer.has_address(ip))
+    .map(|peer| (peer.port, peer.tcpPort))
+    .unwrap_or((state.udp_message_port, state.tcp_message_port))
+}
+
+/// 알림에 포트가 없으면(이전 버전) 우리 포트를 쓴다.
+fn announced_port(message: &Value, key: &str, default: u16) -> u16 {
+  message
+    .get(key)
+    .and_then(|v| v.as_u64())
+    .and_then(|v| u16::try_from(v).ok())
+    .filter(|port| *port != 0)
+    .unwrap_or(default)
+}
 
-  if addresses.is_empty() {
-    addresses.push("255.255.255.255".to_string());
+/// 만료되지 않았고 다음 홉이 온라인인 경로
+fn live_routes(state: &InternalP2PState) -> Vec<RouteInfo> {
+  let now = now_unix_ms();
+  state
+    .routes
+    .values()
+    .filter(|route| now - route.updated_at <= ROUTE_TTL_MS)
+    .filter(|route| state.peers.values().any(|peer| peer.userId == route.via && peer.isOnline))
+    .cloned()
+    .collect()
+}
+
+/// 목적지로 가는 다음 홉 (사용자 ID, 주소). 직접 보이면 목적지 자신이다.
+/// `exclude`는 메시지를 넘겨준 이웃으로, 되돌려 보내지 않는다.
+fn next_hop(state: &InternalP2PState, destination_id: &str, exclude: Option<&str>) -> Option<(String, String)> {
+  let online = |user_id: &str| {
+    state
+      .peers
+      .values()
+      .find(|peer| peer.userId == user_id && peer.isOnline)
+      .map(|peer| peer.ipAddress.clone())
+  };
+
+  if let Some(ip) = online(destination_id) {
+    return Some((destination_id.to_string(), ip));
   }
+  let route = state.routes.get(destination_id)?;
+  if now_unix_ms() - route.updated_at > ROUTE_TTL_MS || Some(route.via.as_str()) == exclude {
+    return None;
+  }
+  online(&route.via).map(|ip| (route.via.clone(), ip))
+}
 
-  addresses
+/// 개봉할 때 받아들일 보낸 사람의 공개키들. 직접 보이는 기기마다 하나씩이고, 경로 광고에서 배운 키도 포함한다.
+fn known_public_keys(state: &InternalP2PState, user_id: &str) -> Vec<String> {
+  let mut keys = state
+    .peers
+    .values()
+    .filter(|peer| peer.userId == user_id)
+    .filter_map(|peer| peer.publicKey.clone())
+    .collect::<Vec<_>>();
+  if let Some(key) = state.routes.get(user_id).and_then(|route| route.public_key.clone()) {
+    keys.push(key);
+  }
+  keys
 }
 
-fn now_iso() -> String {
-  chrono::Utc::now().to_rfc3339()
+/// 봉인할 상대 공개키. `target_ip`에 있는 그 사용자의 기기가 우선이고, 없으면 직접 보이는 기기, 경로 광고 순이다.
+fn public_key_for(state: &InternalP2PState, user_id: &str, target_ip: Option<&str>) -> Option<String> {
+  let devices = || state.peers.values().filter(|peer| peer.userId == user_id);
+  target_ip
+    .and_then(|ip| devices().find(|peer| peer.ipAddress == ip && peer.publicKey.is_some()))
+    .or_else(|| devices().find(|peer| peer.isOnline && peer.publicKey.is_some()))
+    .or_else(|| devices().find(|peer| peer.publicKey.is_some()))
+    .and_then(|peer| peer.publicKey.clone())
+    .or_else(|| state.routes.get(user_id).and_then(|route| route.public_key.clone()))
 }
 
-fn now_unix_ms() -> i64 {
-  SystemTime::now()
-    .duration_since(UNIX_EPOCH)
-    .unwrap_or_default()
-    .as_millis() as i64
+/// 사용자가 로그인한 기기의 (주소, [`device_key`]). 재시작해 `peerId`만 바뀐 항목은 주소가 같으므로 한 번만 센다.
+/// 온라인인 기기가 있으면 그 기기들만, 없으면 알던 기기 모두다.
+fn user_devices(state: &InternalP2PState, user_id: &str) -> Vec<(String, String)> {
+  let mut devices = state
+    .peers
+    .values()
+    .filter(|peer| peer.userId == user_id)
+    .collect::<Vec<_>>();
+  if devices.iter().any(|peer| peer.isOnline) {
+    devices.retain(|peer| peer.isOnline);
+  }
+
+  let mut found = Vec::<(String, String)>::new();
+  for peer in devices {
+    if !found.iter().any(|(ip, _)| ip == &peer.ipAddress) {
+      found.push((peer.ipAddress.clone(), device_key(peer)));
+    }
+  }
+  found
 }
 
-fn parse_iso(value: &str) -> Result<i64, ()> {
-  let parsed = chrono::DateTime::parse_from_rfc3339(value).map_err(|_| ())?;
-  Ok(parsed.timestamp_millis())
+/// 대기열이 기기를 가리킬 때 쓰는 키. `peerId`는 재시작마다 바뀌므로 고정된 신원 키를 쓴다.
+fn device_key(peer: &PeerInfo) -> String {
+  peer.identityKey.clone().unwrap_or_else(|| peer.peerId.clone())
 }
 
+/// 대기 항목을 보낼 온라인 기기의 주소. 기기를 정한 사본은 그 기기에만 보낸다.
+fn queued_target(state: &InternalP2PState, entry: &QueuedMessage) -> Option<String> {
+  state
+    .peers
+    .values()
+    .filter(|peer| peer.userId == entry.receiver_id && peer.isOnline)
+    .find(|peer| entry.device.as_ref().is_none_or(|device| *device == device_key(peer)))
+    .map(|peer| peer.ipAddress.clone())
+}
 
+fn set_queue_relay_holder(app: &CoreHost, message_id: &str, holder: &str) -> bool {
+  let Ok(conn) = app.open_db() else { return false; };
+  conn
+    .execute(
+      "UPDATE p2p_outbound_queue SET relay_holder = ?2, last_error = NULL WHERE message_id = ?1 AND device = ''",
+      params![message_id, holder],
+    )
+    .map(|count| count > 0)
+    .unwrap_or(false)
+}
 
+/// 허브 역할: 맡은 메시지를 보관한다. 같은 메시지를 다시 맡으면 덮어쓴다.
+fn insert_relayed_message(app: &CoreHost, sender_id: &str, payload: &Value) -> bool {
+  let Ok(conn) = app.open_db() else { return false; };
+
+  let expires_at = now_unix_ms().saturating_add(queue_expiry_ms(&conn));
+  conn
+    .execute(
+      "INSERT INTO p2p_relay_store (message_id, recipient_id, sender_id, message_type, payload, expires_at, created_at)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+       ON CONFLICT(message_id) DO UPDATE SET payload = excluded.payload",
+      params![
+        payload.get("id").and_then(|v| v.as_str()),
+        payload.get("receiverId").and_then(|v| v.as_str()),
+        sender_id,
+        payload.get("type").and_then(|v| v.as_str()),
+        payload.to_string(),
+        expires_at,
+        now_iso()
+      ],
+    )
+    .is_ok()
+}
 
+fn list_relayed_messages(app: &CoreHost, recipient_id: &str) -> Vec<(String, Value)> {
+  let Ok(conn) = app.open_db() else { return Vec::new(); };
+  let Ok(mut stmt) = conn.prepare(
+    "SELECT message_id, payload FROM p2p_relay_store
+     WHERE recipient_id = ?1 AND expires_at > ?2
+     ORDER BY rowid ASC",
+  ) else {
+    return Vec::new();
+  };
+
+  stmt
+    .query_map(params![recipient_id, now_unix_ms()], |row| {
+      let payload: String = row.get(1)?;
+      Ok((row.get::<_, String>(0)?, serde_json::from_str(&payload).unwrap_or(Value::Null)))
+    })
+    .map(|rows| rows.filter_map(|row| row.ok()).collect())
+    .unwrap_or_default()
+}
 
+fn delete_relayed_message(app: &CoreHost, message_id: &str) {
+  let Ok(conn) = app.open_db() else { return; };
+  let _ = conn.execute("DELETE FROM p2p_relay_store WHERE message_id = ?1", params![message_id]);
+}
 
+fn purge_expired_relayed_messages(app: &CoreHost) {
+  let Ok(conn) = app.open_db() else { return; };
+  let _ = conn.execute("DELETE FROM p2p_relay_store WHERE expires_at <= ?1", params![now_unix_ms()]);
+}
 
+/// 넘치면 오래된 절반을 버린다. 버린 ID도 `p2p_seen_messages`에 남아 있다.
+fn remember_seen(seen: &mut HashMap<String, i64>, key: String, now: i64) {
+  if seen.len() >= SEEN_CACHE_LIMIT {
+    let mut times = seen.values().copied().collect::<Vec<_>>();
+    times.sort_unstable();
+    let cutoff = times[times.len() / 2];
+    seen.retain(|_, seen_at| *seen_at > cutoff);
+  }
+  seen.insert(key, now);
+}
 
+/// 처음 보는 메시지면 기록한다.
+fn record_seen_message(
+  app: &CoreHost,
+  sender_id: &str,
+  message_id: &str,
+  is_chat: bool,
+  now: i64,
+) -> Sighting {
+  let Ok(conn) = app.open_db() else { return Sighting::First; };
+
+  let inserted = conn
+    .execute(
+      "INSERT OR IGNORE INTO p2p_seen_messages (sender_id, message_id, seen_at) VALUES (?1, ?2, ?3)",
+      params![sender_id, message_id, now],
+    )
+    .unwrap_or(1);
+  if inserted == 0 {
+    return Sighting::Duplicate;
+  }
 
+  // 이 표가 생기기 전에 받아 저장한 채팅
+  let stored = is_chat
+    && conn
+      .query_row(
+        "SELECT 1 FROM messages WHERE message_id = ?1 AND sender_id = ?2 LIMIT 1",
+        params![message_id, sender_id],
+        |_| Ok(()),
+      )
+      .is_ok();
+  if stored {
+    Sighting::Duplicate
+  } else {
+    Sighting::First
+  }
+}
 
+/// 신선도 창을 지난 기록은 타임스탬프 확인으로 걸러지므로 지운다.
+/// 받은 시각보다 빠른 타임스탬프를 붙인 메시지도 있으므로 시계 오차만큼 더 둔다.
+fn purge_seen_messages(app: &CoreHost) {
+  let Ok(conn) = app.open_db() else { return; };
+  // 시계가 빠른 피어의 메시지는 받은 시각보다 늦게 낡으므로 그만큼 더 둔다.
+  let window = seen_window_ms(&conn).saturating_add(MAX_CLOCK_SKEW_MS);
+  let _ = conn.execute(
+    "DELETE FROM p2p_seen_messages WHERE seen_at < ?1",
+    params![now_unix_ms().saturating_sub(window)],
+  );
+}
 
-fn db_path_for(app: &AppHandle) -> Option<PathBuf> {
-  app.path().app_data_dir().ok().map(|dir| dir.join("local.db"))
+/// 앱이 꺼지기 전에 진행 중이던 전송은 재개 가능한 상태로 되돌린다.
+fn interrupt_stale_file_transfers(app: &CoreHost) {
+  let Ok(conn) = app.open_db() else { return; };
+  let _ = conn.execute(
+    "UPDATE p2p_file_transfers SET status = 'interrupted', updated_at = ?1 WHERE status IN ('accepted', 'transferring')",
+    params![now_iso()],
+  );
 }
 
-fn store_message(app: &AppHandle, message: Value, delivered: bool, is_read: bool) {
-  let Some(path) = db_path_for(app) else { return; };
-  let Ok(conn) = Connection::open(path) else { return; };
+fn store_message(app: &CoreHost, message: Value, delivered: bool, is_read: bool) {
+  let Ok(conn) = app.open_db() else { return; };
 
   let message_id = message.get("id").and_then(|v| v.as_str()).unwrap_or("");
   if message_id.is_empty() {
@@ -1293,8 +4852,8 @@ fn store_message(app: &AppHandle, message: Value, delivered: bool, is_read: bool
   let read_at = if is_read { Some(now_iso()) } else { None };
 
   let _ = conn.execute(
-    "INSERT INTO messages (message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, delivered_at, read_at, synced)
-     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, 0)",
+    "INSERT INTO messages (message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, delivered_at, read_at, synced, seq, received_at)
+     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, 0, ?11, ?12)",
     params![
       message_id,
       sender_id,
@@ -1305,14 +4864,174 @@ fn store_message(app: &AppHandle, message: Value, delivered: bool, is_read: bool
       if is_read { 1 } else { 0 },
       if delivered { 1 } else { 0 },
       delivered_at,
-      read_at
+      read_at,
+      p2p_order::seq_of(&message),
+      now_unix_ms()
     ],
   );
 }
 
-fn update_message_status(app: &AppHandle, message_id: &str, delivered: bool, is_read: bool) {
-  let Some(path) = db_path_for(app) else { return; };
-  let Ok(conn) = Connection::open(path) else { return; };
+/// 두 사람 사이의 최근 1:1 채팅을 `chat` 메시지 모양으로 (오래된 것부터)
+fn conversation_history(app: &CoreHost, my_user_id: &str, peer_id: &str) -> Vec<Value> {
+  let Ok(conn) = app.open_db() else { return Vec::new(); };
+  let Ok(mut stmt) = conn.prepare(
+    "SELECT message_id, sender_id, recipient_id, content, timestamp, seq FROM messages
+     WHERE message_id IS NOT NULL
+       AND ((sender_id = ?1 AND recipient_id = ?2) OR (sender_id = ?2 AND recipient_id = ?1))
+     ORDER BY COALESCE(seq, 0) DESC, received_at DESC, id DESC
+     LIMIT ?3",
+  ) else {
+    return Vec::new();
+  };
+
+  let mut history = stmt
+    .query_map(params![my_user_id, peer_id, SYNC_HISTORY_LIMIT as i64], |row| {
+      Ok(json!({
+        "id": row.get::<_, String>(0)?,
+        "type": "chat",
+        "senderId": row.get::<_, Option<String>>(1)?,
+        "receiverId": row.get::<_, Option<String>>(2)?,
+        "content": row.get::<_, Option<String>>(3)?,
+        "timestamp": row.get::<_, Option<String>>(4)?,
+        "seq": row.get::<_, Option<i64>>(5)?
+      }))
+    })
+    .map(|rows| rows.filter_map(|row| row.ok()).collect::<Vec<_>>())
+    .unwrap_or_default();
+  history.reverse();
+  history
+}
+
+fn history_ids(history: &[Value]) -> Vec<String> {
+  history
+    .iter()
+    .filter_map(|entry| entry.get("id").and_then(|v| v.as_str()).map(|s| s.to_string()))
+    .collect()
+}
+
+/// 순서와 상관없이 같은 메시지 집합이면 같은 값
+fn history_digest(history: &[Value]) -> String {
+  let mut ids = history_ids(history);
+  ids.sort();
+  hex::encode(sha2::Sha256::digest(ids.join("\n").as_bytes()))
+}
+
+/// 프레임 한도를 넘지 않도록 [`SYNC_BATCH_BYTES`]씩 나눈다.
+fn sync_batches(entries: Vec<Value>) -> Vec<Vec<Value>> {
+  let mut batches = Vec::new();
+  let mut batch = Vec::new();
+  let mut size = 0;
+  for entry in entries {
+    let len = entry.to_string().len();
+    if !batch.is_empty() && size + len > SYNC_BATCH_BYTES {
+      batches.push(std::mem::take(&mut batch));
+      size = 0;
+    }
+    size += len;
+    batch.push(entry);
+  }
+  if !batch.is_empty() {
+    batches.push(batch);
+  }
+  batches
+}
+
+/// 기록을 보낸 피어가 나에게 보낸 채팅인지
+fn is_sync_entry(entry: &Value, my_user_id: &str, peer_id: &str) -> bool {
+  let field = |key: &str| entry.get(key).and_then(|v| v.as_str()).unwrap_or("");
+  field("type") == "chat" && !field("id").is_empty() && field("senderId") == peer_id && field("receiverId") == my_user_id
+}
+
+/// 없던 메시지면 `network_type = 'p2p-sync'`로 저장하고 대화 카운터를 맞춘다.
+fn insert_synced_message(app: &CoreHost, entry: &Value, peer_id: &str) -> bool {
+  let Ok(conn) = app.open_db() else { return false; };
+
+  let field = |key: &str| entry.get(key).and_then(|v| v.as_str()).unwrap_or("");
+  let seq = p2p_order::seq_of(entry);
+  let inserted = conn
+    .execute(
+      "INSERT INTO messages (message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, synced, seq, received_at, network_type)
+       SELECT ?1, ?2, ?3, ?4, 'chat', ?5, 0, 1, 0, ?6, ?7, 'p2p-sync'
+       WHERE NOT EXISTS (SELECT 1 FROM messages WHERE message_id = ?1)",
+      params![
+        field("id"),
+        field("senderId"),
+        field("receiverId"),
+        field("content"),
+        if field("timestamp").is_empty() { now_iso() } else { field("timestamp").to_string() },
+        seq,
+        now_unix_ms()
+      ],
+    )
+    .map(|count| count > 0)
+    .unwrap_or(false);
+  drop(conn);
+
+  if inserted {
+    observe_sequence(app, &format!("direct:{peer_id}"), seq);
+  }
+  inserted
+}
+
+/// 보낼 메시지의 `seq`와, 이 대화에서 바로 전에 보낸 메시지의 `seq`(`prevSeq`, 없으면 0)
+fn next_sequence(app: &CoreHost, conversation_id: &str) -> (i64, i64) {
+  let Ok(mut conn) = app.open_db() else { return (0, 0); };
+  let Ok(tx) = conn.transaction_with_behavior(TransactionBehavior::Immediate) else { return (0, 0); };
+
+  let (counter, last_sent) = tx
+    .query_row(
+      "SELECT counter, last_sent FROM p2p_conversation_clocks WHERE conversation_id = ?1",
+      params![conversation_id],
+      |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+    )
+    .unwrap_or((0, 0));
+  let seq = counter + 1;
+  let saved = tx.execute(
+    "INSERT INTO p2p_conversation_clocks (conversation_id, counter, last_sent) VALUES (?1, ?2, ?2)
+     ON CONFLICT(conversation_id) DO UPDATE SET counter = excluded.counter, last_sent = excluded.last_sent",
+    params![conversation_id, seq],
+  );
+  if saved.is_err() || tx.commit().is_err() {
+    return (0, 0);
+  }
+  (seq, last_sent)
+}
+
+/// 받은 메시지의 `seq`를 대화 카운터에 반영하고 저장할 `seq`를 돌려준다.
+/// `seq`가 없으면 카운터를 하나 올려 그 값을 쓴다.
+fn observe_sequence(app: &CoreHost, conversation_id: &str, seq: Option<i64>) -> i64 {
+  let Ok(mut conn) = app.open_db() else { return 0; };
+
+  if let Some(seq) = seq {
+    let _ = conn.execute(
+      "INSERT INTO p2p_conversation_clocks (conversation_id, counter, last_sent) VALUES (?1, ?2, 0)
+       ON CONFLICT(conversation_id) DO UPDATE SET counter = MAX(counter, excluded.counter)",
+      params![conversation_id, seq],
+    );
+    return seq;
+  }
+
+  let Ok(tx) = conn.transaction_with_behavior(TransactionBehavior::Immediate) else { return 0; };
+  let bumped = tx.execute(
+    "INSERT INTO p2p_conversation_clocks (conversation_id, counter, last_sent) VALUES (?1, 1, 0)
+     ON CONFLICT(conversation_id) DO UPDATE SET counter = counter + 1",
+    params![conversation_id],
+  );
+  let counter = tx
+    .query_row(
+      "SELECT counter FROM p2p_conversation_clocks WHERE conversation_id = ?1",
+      params![conversation_id],
+      |row| row.get::<_, i64>(0),
+    )
+    .unwrap_or(0);
+  if bumped.is_err() || tx.commit().is_err() {
+    return 0;
+  }
+  counter
+}
+
+fn update_message_status(app: &CoreHost, message_id: &str, delivered: bool, is_read: bool) {
+  let Ok(conn) = app.open_db() else { return; };
 
   let delivered_at = if delivered { Some(now_iso()) } else { None };
   let read_at = if is_read { Some(now_iso()) } else { None };
@@ -1333,3 +5052,44 @@ fn update_message_status(app: &AppHandle, message_id: &str, delivered: bool, is_
     ],
   );
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn safe_relative_path_accepts_nested_names() {
+    assert_eq!(safe_relative_path("수업/1학년/자료.pdf"), Some(PathBuf::from("수업").join("1학년").join("자료.pdf")));
+    assert_eq!(safe_relative_path("a..b/c"), Some(PathBuf::from("a..b").join("c")));
+  }
+
+  #[test]
+  fn safe_relative_path_rejects_parent_components() {
+    assert_eq!(safe_relative_path(".."), None);
+    assert_eq!(safe_relative_path("../secret.txt"), None);
+    assert_eq!(safe_relative_path("docs/../../secret.txt"), None);
+    assert_eq!(safe_relative_path("docs/.."), None);
+  }
+
+  #[test]
+  fn safe_relative_path_rejects_absolute_paths() {
+    assert_eq!(safe_relative_path("/etc/passwd"), None);
+    assert_eq!(safe_relative_path("\\Windows\\System32"), None);
+    assert_eq!(safe_relative_path("\\\\server\\share\\file"), None);
+  }
+
+  #[test]
+  fn safe_relative_path_rejects_drive_prefixes() {
+    assert_eq!(safe_relative_path("C:/Windows/win.ini"), None);
+    assert_eq!(safe_relative_path("C:secret.txt"), None);
+    assert_eq!(safe_relative_path("docs/D:evil"), None);
+  }
+
+  #[test]
+  fn safe_relative_path_rejects_empty_components() {
+    assert_eq!(safe_relative_path(""), None);
+    assert_eq!(safe_relative_path("docs//file.txt"), None);
+    assert_eq!(safe_relative_path("docs/"), None);
+    assert_eq!(safe_relative_path("./file.txt"), None);
+  }
+}
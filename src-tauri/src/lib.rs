@@ -0,0 +1,25 @@
+//! edulinker 코어
+//!
+//! P2P 메신저, 디스커버리, tus/Durable Streams 서버를 Tauri 없이 쓸 수 있게 묶는다.
+//! 앱(`main.rs`)과 헤드리스 데몬(`bin/edulinker-daemon.rs`)이 함께 쓴다.
+
+pub mod host;
+pub mod schema;
+
+pub mod server;
+pub mod streams;
+pub mod tus;
+
+pub mod internal_p2p;
+pub mod p2p_protocol;
+pub mod p2p_crypto;
+pub mod p2p_order;
+pub mod p2p_presence;
+pub mod p2p_pool;
+pub mod p2p_udp;
+
+pub mod network_discovery;
+pub mod unicast_discovery;
+pub mod net_interfaces;
+pub mod discovery_hub;
+pub mod mdns_discovery;
@@ -1,13 +1,6 @@
 // Prevent console window in addition to Tauri window in Windows release builds
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-mod server;
-mod streams;
-mod tus;
-mod internal_p2p;
-mod network_discovery;
-mod discovery_hub;
-
 use std::sync::Arc;
 use std::sync::Mutex as StdMutex;
 use tokio::sync::Mutex;
@@ -19,7 +12,9 @@ use tauri_plugin_notification::NotificationExt;
 use rusqlite::{params, Connection, OptionalExtension};
 use serde_json::{json, Value};
 
-use server::ServerManager;
+use edulinker_core::host::{CoreHost, DataDir, EventSink};
+use edulinker_core::{discovery_hub, internal_p2p, network_discovery, schema, streams};
+use edulinker_core::server::ServerManager;
 
 struct AppState {
   db: StdMutex<Connection>,
@@ -92,15 +87,25 @@ struct P2PState {
 }
 
 impl P2PState {
-  fn new(app: AppHandle) -> Self {
+  fn new(app: AppHandle, host: CoreHost) -> Self {
     Self {
-      hub: discovery_hub::DiscoveryHub::new(),
-      internal: internal_p2p::InternalP2PManager::new(app.clone()),
-      discovery: network_discovery::NetworkDiscoveryManager::new(app.clone()),
+      hub: discovery_hub::DiscoveryHub::new(host.clone()),
+      internal: internal_p2p::InternalP2PManager::new(host.clone()),
+      discovery: network_discovery::NetworkDiscoveryManager::new(host),
       device_registration: DeviceRegistrationManager::new(app),
     }
   }
 }
+
+/// 코어 이벤트를 Tauri 창으로 보낸다.
+struct TauriEventSink(AppHandle);
+
+impl EventSink for TauriEventSink {
+  fn emit(&self, event: &str, payload: Value) {
+    let _ = self.0.emit(event, payload);
+  }
+}
+
 fn now_ms() -> i64 {
   SystemTime::now()
     .duration_since(UNIX_EPOCH)
@@ -112,159 +117,6 @@ fn get_api_url() -> String {
   std::env::var("VITE_API_URL").unwrap_or_else(|_| "http://localhost:3000".to_string())
 }
 
-fn init_db(conn: &Connection) -> rusqlite::Result<()> {
-  conn.execute_batch(
-    "
-    CREATE TABLE IF NOT EXISTS auth_store (
-      id INTEGER PRIMARY KEY CHECK (id = 1),
-      token TEXT,
-      user_json TEXT,
-      expires_at INTEGER
-    );
-
-    CREATE TABLE IF NOT EXISTS offline_users (
-      id INTEGER PRIMARY KEY AUTOINCREMENT,
-      email TEXT UNIQUE,
-      password_hash TEXT,
-      name TEXT,
-      role TEXT,
-      region TEXT,
-      school TEXT,
-      grade TEXT,
-      class_name TEXT,
-      classroom TEXT,
-      workplace TEXT,
-      job_title TEXT,
-      admin_duties TEXT,
-      extension_number TEXT,
-      phone_number TEXT,
-      profile_completed INTEGER,
-      created_at INTEGER
-    );
-
-    CREATE TABLE IF NOT EXISTS offline_sessions (
-      token TEXT PRIMARY KEY,
-      user_id INTEGER,
-      expires_at INTEGER
-    );
-
-    CREATE TABLE IF NOT EXISTS address_book (
-      id INTEGER PRIMARY KEY AUTOINCREMENT,
-      user_id TEXT,
-      name TEXT,
-      email TEXT,
-      phone TEXT,
-      role TEXT,
-      school_id TEXT,
-      ip_address TEXT,
-      hostname TEXT,
-      os TEXT,
-      platform TEXT,
-      last_seen TEXT,
-      is_online INTEGER,
-      synced INTEGER,
-      created_at TEXT,
-      updated_at TEXT
-    );
-
-    CREATE TABLE IF NOT EXISTS messages (
-      id INTEGER PRIMARY KEY AUTOINCREMENT,
-      sender_id TEXT,
-      recipient_id TEXT,
-      content TEXT,
-      message_type TEXT,
-      timestamp TEXT,
-      is_read INTEGER,
-      delivered INTEGER,
-      synced INTEGER
-    );
-
-    CREATE TABLE IF NOT EXISTS group_messages (
-      id TEXT PRIMARY KEY,
-      content TEXT,
-      message_type TEXT,
-      timestamp TEXT,
-      sender_id TEXT,
-      recipients TEXT,
-      is_read INTEGER,
-      delivered INTEGER
-    );
-
-    CREATE TABLE IF NOT EXISTS device_info (
-      device_id TEXT PRIMARY KEY,
-      user_id TEXT,
-      hostname TEXT,
-      ip_address TEXT,
-      mac_address TEXT,
-      os TEXT,
-      platform TEXT,
-      installed_at TEXT,
-      last_seen TEXT,
-      synced INTEGER
-    );
-
-    CREATE TABLE IF NOT EXISTS error_report_images (
-      id INTEGER PRIMARY KEY AUTOINCREMENT,
-      session_id TEXT,
-      file_name TEXT,
-      file_data BLOB,
-      mime_type TEXT,
-      created_at TEXT
-    );
-
-    CREATE TABLE IF NOT EXISTS shared_folders (
-      id TEXT PRIMARY KEY,
-      name TEXT,
-      path TEXT,
-      encrypted INTEGER,
-      password TEXT,
-      created_at TEXT
-    );
-
-    CREATE TABLE IF NOT EXISTS p2p_messages (
-      id INTEGER PRIMARY KEY AUTOINCREMENT,
-      message_id TEXT UNIQUE,
-      sender_id TEXT,
-      recipient_id TEXT,
-      content TEXT,
-      message_type TEXT,
-      timestamp TEXT,
-      is_read INTEGER DEFAULT 0,
-      delivered INTEGER DEFAULT 0,
-      read_at TEXT,
-      delivered_at TEXT,
-      network_type TEXT DEFAULT 'p2p'
-    );
-
-    CREATE TABLE IF NOT EXISTS discovered_devices (
-      device_id TEXT PRIMARY KEY,
-      hostname TEXT,
-      ip_address TEXT,
-      mac_address TEXT,
-      os TEXT,
-      platform TEXT,
-      user_id TEXT,
-      last_seen TEXT,
-      discovery_version TEXT
-    );
-
-    CREATE TABLE IF NOT EXISTS app_settings (
-      key TEXT PRIMARY KEY,
-      value TEXT,
-      updated_at TEXT
-    );
-
-    CREATE UNIQUE INDEX IF NOT EXISTS idx_address_book_user_id ON address_book(user_id);
-    CREATE INDEX IF NOT EXISTS idx_p2p_messages_sender ON p2p_messages(sender_id);
-    CREATE INDEX IF NOT EXISTS idx_p2p_messages_recipient ON p2p_messages(recipient_id);
-    CREATE INDEX IF NOT EXISTS idx_p2p_messages_timestamp ON p2p_messages(timestamp);
-    ")?;
-  ensure_message_columns(conn)?;
-
-  Ok(())
-}
-
-
 fn read_auth(conn: &Connection) -> Option<(String, Value, i64)> {
   let row = conn
     .query_row(
@@ -404,7 +256,17 @@ async fn ipc(app: AppHandle, state: State<'_, AppState>, p2p: State<'_, P2PState
     "internal-p2p:offer-file" => internal_p2p_offer_file(p2p, args).await,
     "internal-p2p:accept-file" => internal_p2p_accept_file(p2p, args).await,
     "internal-p2p:reject-file" => internal_p2p_reject_file(p2p, args).await,
+    "internal-p2p:offer-folder" => internal_p2p_offer_folder(p2p, args).await,
+    "internal-p2p:resume-file" => internal_p2p_resume_file(p2p, args).await,
     "internal-p2p:get-file-transfers" => internal_p2p_get_file_transfers(p2p).await,
+    "internal-p2p:get-queued-messages" => internal_p2p_get_queued_messages(p2p, args).await,
+    "internal-p2p:retry-queued-message" => internal_p2p_retry_queued_message(p2p, args).await,
+    "internal-p2p:cancel-queued-message" => internal_p2p_cancel_queued_message(p2p, args).await,
+    "internal-p2p:get-safety-number" => internal_p2p_get_safety_number(p2p, args).await,
+    "internal-p2p:verify-peer-key" => internal_p2p_verify_peer_key(p2p, args).await,
+    "internal-p2p:trust-peer-key" => internal_p2p_trust_peer_key(p2p, args).await,
+    "internal-p2p:trust-peer-device" => internal_p2p_trust_peer_device(p2p, args).await,
+    "internal-p2p:set-presence" => internal_p2p_set_presence(p2p, args).await,
     "internal-p2p:send-group-message" => internal_p2p_send_group_message(p2p, args).await,
     "internal-p2p:broadcast-group-create" => internal_p2p_broadcast_group_create(p2p, args).await,
     "internal-p2p:broadcast-group-member-change" => internal_p2p_broadcast_group_member_change(p2p, args).await,
@@ -417,8 +279,8 @@ async fn ipc(app: AppHandle, state: State<'_, AppState>, p2p: State<'_, P2PState
     "network-discovery:save-device" => network_discovery_save_device(state, args),
     "network-discovery:sync-databases" => network_discovery_sync_databases(state),
 
-    "p2p:initiate-transfer" => not_implemented(&channel),
-    "p2p:accept-transfer" => not_implemented(&channel),
+    "p2p:initiate-transfer" => internal_p2p_offer_file(p2p, args).await,
+    "p2p:accept-transfer" => internal_p2p_accept_file(p2p, args).await,
 
     "p2p-messaging:send" => p2p_messaging_send(p2p, args).await,
 
@@ -1362,7 +1224,9 @@ fn messaging_get_offline(state: State<'_, AppState>, args: Value) -> Result<Valu
       "isRead": row.get::<_, Option<i64>>(7)?.unwrap_or(0) == 1,
       "delivered": row.get::<_, Option<i64>>(8)?.unwrap_or(0) == 1,
       "readAt": row.get::<_, Option<String>>(9)?,
-      "deliveredAt": row.get::<_, Option<String>>(10)?
+      "deliveredAt": row.get::<_, Option<String>>(10)?,
+      "seq": row.get::<_, Option<i64>>(11)?,
+      "networkType": row.get::<_, Option<String>>(12)?
     }))
   };
 
@@ -1370,9 +1234,9 @@ fn messaging_get_offline(state: State<'_, AppState>, args: Value) -> Result<Valu
 
   if let Some(other) = other_user_id {
     let mut stmt = conn.prepare(
-      "SELECT id, message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, read_at, delivered_at FROM messages
+      "SELECT id, message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, read_at, delivered_at, seq, network_type FROM messages
        WHERE (sender_id = ?1 AND recipient_id = ?2) OR (sender_id = ?2 AND recipient_id = ?1)
-       ORDER BY timestamp DESC",
+       ORDER BY COALESCE(seq, 0) DESC, received_at DESC, id DESC",
     ).map_err(|e| e.to_string())?;
 
     let rows = stmt.query_map(params![user_id, other], map_row).map_err(|e| e.to_string())?;
@@ -1381,9 +1245,9 @@ fn messaging_get_offline(state: State<'_, AppState>, args: Value) -> Result<Valu
     }
   } else {
     let mut stmt = conn.prepare(
-      "SELECT id, message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, read_at, delivered_at FROM messages
+      "SELECT id, message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, read_at, delivered_at, seq, network_type FROM messages
        WHERE sender_id = ?1 OR recipient_id = ?1
-       ORDER BY timestamp DESC",
+       ORDER BY timestamp DESC, received_at DESC, id DESC",
     ).map_err(|e| e.to_string())?;
 
     let rows = stmt.query_map(params![user_id], map_row).map_err(|e| e.to_string())?;
@@ -1780,16 +1644,31 @@ async fn internal_p2p_start(p2p: State<'_, P2PState>, args: Value) -> Result<Val
   let user_id = args.get("userId").and_then(|v| v.as_str()).ok_or("missing userId")?.to_string();
   let user_name = args.get("userName").and_then(|v| v.as_str()).unwrap_or("").to_string();
   let school_id = args.get("schoolId").and_then(|v| v.as_str()).map(|s| s.to_string());
-  let discovery_port = args.get("discoveryPort").and_then(|v| v.as_u64()).unwrap_or(41235) as u16;
+  let requested_port = args.get("discoveryPort").and_then(|v| v.as_u64()).unwrap_or(41235) as u16;
+  let discovery_port = p2p
+    .hub
+    .ensure_started(requested_port, p2p.internal.clone(), p2p.discovery.clone())
+    .await?;
   p2p.internal.start(user_id, user_name, school_id, discovery_port).await
 }
 
 async fn internal_p2p_stop(p2p: State<'_, P2PState>) -> Result<Value, String> {
-  p2p.internal.stop().await
+  let result = p2p.internal.stop().await;
+  stop_discovery_hub_if_idle(&p2p).await;
+  result
 }
 
 async fn internal_p2p_status(p2p: State<'_, P2PState>) -> Result<Value, String> {
-  Ok(p2p.internal.status().await)
+  let mut status = p2p.internal.status().await;
+  status["discoveryBackends"] = json!(p2p.hub.backends().await);
+  Ok(status)
+}
+
+/// 디스커버리 허브는 두 관리자가 함께 쓰므로 둘 다 멈췄을 때만 닫는다.
+async fn stop_discovery_hub_if_idle(p2p: &P2PState) {
+  if !p2p.internal.is_running().await && !p2p.discovery.is_running().await {
+    p2p.hub.stop().await;
+  }
 }
 
 async fn internal_p2p_get_peers(p2p: State<'_, P2PState>) -> Result<Value, String> {
@@ -1820,7 +1699,8 @@ fn internal_p2p_get_messages(state: State<'_, AppState>, args: Value) -> Result<
       "delivered": row.get::<_, Option<i64>>(8)?.unwrap_or(0) == 1,
       "readAt": row.get::<_, Option<String>>(9)?,
       "deliveredAt": row.get::<_, Option<String>>(10)?,
-      "networkType": row.get::<_, Option<String>>(11)?
+      "networkType": row.get::<_, Option<String>>(11)?,
+      "seq": row.get::<_, Option<i64>>(12)?
     }))
   };
 
@@ -1828,9 +1708,9 @@ fn internal_p2p_get_messages(state: State<'_, AppState>, args: Value) -> Result<
 
   if let Some(other) = other_user_id {
     let mut stmt = conn.prepare(
-      "SELECT id, message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, read_at, delivered_at, network_type FROM p2p_messages
+      "SELECT id, message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, read_at, delivered_at, network_type, seq FROM messages
        WHERE (sender_id = ?1 AND recipient_id = ?2) OR (sender_id = ?2 AND recipient_id = ?1)
-       ORDER BY timestamp DESC LIMIT ?3",
+       ORDER BY COALESCE(seq, 0) DESC, received_at DESC, id DESC LIMIT ?3",
     ).map_err(|e| e.to_string())?;
 
     let rows = stmt.query_map(params![user_id, other, limit], map_row).map_err(|e| e.to_string())?;
@@ -1839,9 +1719,9 @@ fn internal_p2p_get_messages(state: State<'_, AppState>, args: Value) -> Result<
     }
   } else {
     let mut stmt = conn.prepare(
-      "SELECT id, message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, read_at, delivered_at, network_type FROM p2p_messages
+      "SELECT id, message_id, sender_id, recipient_id, content, message_type, timestamp, is_read, delivered, read_at, delivered_at, network_type, seq FROM messages
        WHERE sender_id = ?1 OR recipient_id = ?1
-       ORDER BY timestamp DESC LIMIT ?2",
+       ORDER BY timestamp DESC, received_at DESC, id DESC LIMIT ?2",
     ).map_err(|e| e.to_string())?;
 
     let rows = stmt.query_map(params![user_id, limit], map_row).map_err(|e| e.to_string())?;
@@ -1860,13 +1740,13 @@ fn internal_p2p_get_unread_count(state: State<'_, AppState>, args: Value) -> Res
   let conn = state.db.lock().map_err(|_| "db lock")?;
   let count: i64 = if let Some(other) = other_user_id {
     conn.query_row(
-      "SELECT COUNT(*) FROM p2p_messages WHERE recipient_id = ?1 AND sender_id = ?2 AND is_read = 0",
+      "SELECT COUNT(*) FROM messages WHERE recipient_id = ?1 AND sender_id = ?2 AND is_read = 0",
       params![user_id, other],
       |row| row.get(0),
     ).unwrap_or(0)
   } else {
     conn.query_row(
-      "SELECT COUNT(*) FROM p2p_messages WHERE recipient_id = ?1 AND is_read = 0",
+      "SELECT COUNT(*) FROM messages WHERE recipient_id = ?1 AND is_read = 0",
       params![user_id],
       |row| row.get(0),
     ).unwrap_or(0)
@@ -1887,20 +1767,93 @@ async fn internal_p2p_offer_file(p2p: State<'_, P2PState>, args: Value) -> Resul
   p2p.internal.offer_file(args).await
 }
 
+async fn internal_p2p_offer_folder(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
+  p2p.internal.offer_folder(args).await
+}
+
 async fn internal_p2p_accept_file(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
-  let transfer_id = args.get("transferId").and_then(|v| v.as_str()).ok_or("missing transferId")?.to_string();
+  let transfer_id = transfer_id_arg(&args)?;
   p2p.internal.accept_file(transfer_id).await
 }
 
 async fn internal_p2p_reject_file(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
-  let transfer_id = args.get("transferId").and_then(|v| v.as_str()).ok_or("missing transferId")?.to_string();
+  let transfer_id = transfer_id_arg(&args)?;
   p2p.internal.reject_file(transfer_id).await
 }
 
+// 프론트엔드는 transferId 문자열만 넘기기도 하고 {transferId} 객체를 넘기기도 한다.
+fn transfer_id_arg(args: &Value) -> Result<String, String> {
+  args
+    .as_str()
+    .or_else(|| args.get("transferId").and_then(|v| v.as_str()))
+    .map(|s| s.to_string())
+    .ok_or_else(|| "missing transferId".to_string())
+}
+
+async fn internal_p2p_resume_file(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
+  let transfer_id = transfer_id_arg(&args)?;
+  p2p.internal.resume_file(transfer_id).await
+}
+
 async fn internal_p2p_get_file_transfers(p2p: State<'_, P2PState>) -> Result<Value, String> {
   Ok(p2p.internal.get_file_transfers().await)
 }
 
+fn message_id_arg(args: &Value) -> Result<String, String> {
+  args
+    .as_str()
+    .or_else(|| args.get("messageId").and_then(|v| v.as_str()))
+    .map(|s| s.to_string())
+    .ok_or_else(|| "missing messageId".to_string())
+}
+
+async fn internal_p2p_get_queued_messages(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
+  Ok(p2p.internal.get_queued_messages(args).await)
+}
+
+async fn internal_p2p_retry_queued_message(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
+  let message_id = message_id_arg(&args)?;
+  p2p.internal.retry_queued_message(message_id).await
+}
+
+async fn internal_p2p_cancel_queued_message(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
+  let message_id = message_id_arg(&args)?;
+  p2p.internal.cancel_queued_message(message_id).await
+}
+
+fn user_id_arg(args: &Value) -> Result<String, String> {
+  args
+    .as_str()
+    .or_else(|| args.get("userId").and_then(|v| v.as_str()))
+    .map(|s| s.to_string())
+    .ok_or_else(|| "missing userId".to_string())
+}
+
+async fn internal_p2p_get_safety_number(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
+  let user_id = user_id_arg(&args)?;
+  p2p.internal.get_safety_number(user_id).await
+}
+
+async fn internal_p2p_verify_peer_key(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
+  let user_id = user_id_arg(&args)?;
+  let verified = args.get("verified").and_then(|v| v.as_bool()).unwrap_or(true);
+  p2p.internal.verify_peer_key(user_id, verified).await
+}
+
+async fn internal_p2p_trust_peer_key(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
+  let user_id = user_id_arg(&args)?;
+  p2p.internal.trust_peer_key(user_id).await
+}
+
+async fn internal_p2p_trust_peer_device(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
+  let user_id = user_id_arg(&args)?;
+  p2p.internal.trust_peer_device(user_id).await
+}
+
+async fn internal_p2p_set_presence(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
+  p2p.internal.set_presence(args).await
+}
+
 async fn internal_p2p_send_group_message(p2p: State<'_, P2PState>, args: Value) -> Result<Value, String> {
   p2p.internal.send_group_message(args).await
 }
@@ -1926,13 +1879,18 @@ async fn internal_p2p_send_group_typing(p2p: State<'_, P2PState>, args: Value) -
 // ============================================
 
 async fn network_discovery_start(p2p: State<'_, P2PState>) -> Result<Value, String> {
-  let port = 41236u16;
-  let requested_port = 41236u16;
+  let requested_port = network_discovery::requested_discovery_port();
+  let port = p2p
+    .hub
+    .ensure_started(requested_port, p2p.internal.clone(), p2p.discovery.clone())
+    .await?;
   p2p.discovery.start(port, requested_port).await
 }
 
 async fn network_discovery_stop(p2p: State<'_, P2PState>) -> Result<Value, String> {
-  p2p.discovery.stop().await
+  let result = p2p.discovery.stop().await;
+  stop_discovery_hub_if_idle(&p2p).await;
+  result
 }
 
 async fn network_discovery_get_devices(p2p: State<'_, P2PState>) -> Result<Value, String> {
@@ -2447,15 +2405,20 @@ fn main() {
       // 데이터베이스 초기화
       let db_path = db_path_for(&app.handle())?;
       let conn = Connection::open(&db_path).map_err(|e| e.to_string())?;
-      init_db(&conn).map_err(|e| e.to_string())?;
+      schema::init_db(&conn).map_err(|e| e.to_string())?;
       app.manage(AppState { db: StdMutex::new(conn) });
-      app.manage(P2PState::new(app.handle().clone()));
 
-      // 서버 매니저 생성 및 시작
       let app_data_dir = app
         .path()
         .app_data_dir()
         .map_err(|e| format!("Failed to get app data dir: {}", e))?;
+      let host = CoreHost::new(
+        Arc::new(TauriEventSink(app.handle().clone())),
+        Arc::new(DataDir::new(app_data_dir.clone())),
+      );
+      app.manage(P2PState::new(app.handle().clone(), host));
+
+      // 서버 매니저 생성 및 시작
 
       let server_manager = Arc::new(ServerManager::new(41234));
 
@@ -2492,36 +2455,21 @@ fn main() {
       // 폴더 열기
       open_folder,
     ])
-    .run(tauri::generate_context!())
-    .expect("error while running tauri application");
+    .build(tauri::generate_context!())
+    .expect("error while running tauri application")
+    .run(|app, event| {
+      // 종료할 때 피어들에게 goodbye를 보내 바로 오프라인으로 보이게 한다.
+      if let tauri::RunEvent::Exit = event {
+        let p2p = app.state::<P2PState>();
+        let _ = tauri::async_runtime::block_on(p2p.internal.stop());
+      }
+    });
 }
 
 
 
 
 
-fn ensure_message_columns(conn: &Connection) -> rusqlite::Result<()> {
-  let mut stmt = conn.prepare("PRAGMA table_info(messages)")?;
-  let column_iter = stmt.query_map([], |row| row.get::<_, String>(1))?;
-
-  let mut columns = Vec::new();
-  for col in column_iter {
-    columns.push(col?);
-  }
-
-  if !columns.iter().any(|c| c == "message_id") {
-    conn.execute("ALTER TABLE messages ADD COLUMN message_id TEXT", [])?;
-  }
-  if !columns.iter().any(|c| c == "read_at") {
-    conn.execute("ALTER TABLE messages ADD COLUMN read_at TEXT", [])?;
-  }
-  if !columns.iter().any(|c| c == "delivered_at") {
-    conn.execute("ALTER TABLE messages ADD COLUMN delivered_at TEXT", [])?;
-  }
-
-  Ok(())
-}
-
 // Settings functions
 fn settings_get(state: State<'_, AppState>, args: Value) -> Result<Value, String> {
   let key = args
@@ -2760,7 +2708,7 @@ fn file_create_download_folder(args: Value) -> Result<Value, String> {
     .ok_or("missing parentPath")?;
 
   // edulinker_file 폴더 경로 생성
-  let download_folder = std::path::Path::new(parent_path).join("edulinker_file");
+  let download_folder = std::path::Path::new(parent_path).join(internal_p2p::DOWNLOAD_FOLDER_NAME);
   let folder_path = download_folder.to_string_lossy().to_string();
 
   // 폴더가 없으면 생성
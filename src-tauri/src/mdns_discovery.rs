@@ -0,0 +1,205 @@
+//! mDNS(DNS-SD) 디스커버리
+//!
+//! 브로드캐스트를 막지만 mDNS는 통과시키는 AP나 관리형 스위치가 있어
+//! `_edulinker._tcp.local.` 서비스를 광고하고 탐색한다. TXT 레코드에는 서명된
+//! 디스커버리 알림의 필드와 메시지 포트가 들어가므로, 받은 쪽은 TXT에서
+//! `discovery-response`를 다시 만들어 브로드캐스트와 같은 검증을 거친다.
+
+use async_trait::async_trait;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::discovery_hub::{DiscoveryBackend, DiscoverySink};
+use crate::net_interfaces;
+
+pub const SERVICE_TYPE: &str = "_edulinker._tcp.local.";
+
+/// 사용자 정보가 바뀌었는지 이 주기로 확인해 다시 광고한다.
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+/// 받는 쪽은 오래된 서명 시각의 알림을 버리므로, 바뀐 것이 없어도 이 주기로 새로 서명해 등록한다.
+const RESIGN_INTERVAL: Duration = Duration::from_secs(120);
+
+/// 서명 대상에 들어가는 문자열 필드
+const STRING_FIELDS: &[&str] = &[
+  "peerId",
+  "userId",
+  "userName",
+  "schoolId",
+  "hostname",
+  "platform",
+  "publicKey",
+  "identityKey",
+  "timestamp",
+  "signature",
+];
+const PORT_FIELDS: &[&str] = &["udpPort", "tcpPort", "discoveryPort"];
+
+pub struct MdnsBackend {
+  daemon: ServiceDaemon,
+}
+
+/// 등록한 서비스. `stable`은 서명과 시각을 뺀 TXT 속성이다.
+struct Registration {
+  fullname: String,
+  stable: Vec<(String, String)>,
+  registered_at: Instant,
+}
+
+impl MdnsBackend {
+  pub fn new() -> Result<Self, String> {
+    let daemon = ServiceDaemon::new().map_err(|e| format!("failed to start mDNS daemon: {e}"))?;
+    Ok(Self { daemon })
+  }
+
+  /// 알림이 바뀌었으면 다시 등록한다. 서명과 시각만 바뀐 경우는 [`RESIGN_INTERVAL`]마다만 등록한다.
+  fn announce(&self, announcement: Option<Value>, registered: &mut Option<Registration>) {
+    let Some(announcement) = announcement else {
+      if let Some(registration) = registered.take() {
+        let _ = self.daemon.unregister(&registration.fullname);
+      }
+      return;
+    };
+
+    let properties = txt_properties(&announcement);
+    let stable = properties
+      .iter()
+      .filter(|(key, _)| key != "timestamp" && key != "signature")
+      .cloned()
+      .collect::<Vec<_>>();
+    if registered
+      .as_ref()
+      .is_some_and(|previous| previous.stable == stable && previous.registered_at.elapsed() < RESIGN_INTERVAL)
+    {
+      return;
+    }
+
+    let peer_id = announcement.get("peerId").and_then(|v| v.as_str()).unwrap_or_default();
+    let port = announcement.get("tcpPort").and_then(|v| v.as_u64()).unwrap_or(0) as u16;
+    let host_name = format!("edulinker-{peer_id}.local.");
+    let info = match ServiceInfo::new(SERVICE_TYPE, peer_id, &host_name, (), port, &properties[..]) {
+      Ok(info) => info.enable_addr_auto(),
+      Err(_) => return,
+    };
+    let fullname = info.get_fullname().to_string();
+
+    if let Some(previous) = registered.take() {
+      if previous.fullname != fullname {
+        let _ = self.daemon.unregister(&previous.fullname);
+      }
+    }
+    if self.daemon.register(info).is_ok() {
+      *registered = Some(Registration {
+        fullname,
+        stable,
+        registered_at: Instant::now(),
+      });
+    }
+  }
+}
+
+#[async_trait]
+impl DiscoveryBackend for MdnsBackend {
+  fn name(&self) -> &'static str {
+    "mdns"
+  }
+
+  async fn run(self: Box<Self>, sink: DiscoverySink, token: CancellationToken) {
+    let receiver = match self.daemon.browse(SERVICE_TYPE) {
+      Ok(receiver) => receiver,
+      Err(_) => {
+        let _ = self.daemon.shutdown();
+        return;
+      }
+    };
+
+    let mut registered = None;
+    let mut interval = tokio::time::interval(REANNOUNCE_INTERVAL);
+
+    loop {
+      tokio::select! {
+        _ = token.cancelled() => break,
+        _ = interval.tick() => {
+          self.announce(sink.announcement().await, &mut registered);
+        }
+        event = receiver.recv_async() => match event {
+          Ok(ServiceEvent::ServiceResolved(info)) => {
+            let Some(ip) = best_address(&info) else { continue; };
+            if let Some(message) = announcement_from_txt(&info) {
+              sink.deliver(&message, &ip).await;
+            }
+          }
+          Ok(_) => {}
+          Err(_) => break,
+        }
+      }
+    }
+
+    if let Some(Registration { fullname, .. }) = registered {
+      let _ = self.daemon.unregister(&fullname);
+    }
+    let _ = self.daemon.shutdown();
+  }
+}
+
+fn txt_properties(announcement: &Value) -> Vec<(String, String)> {
+  let mut properties = Vec::new();
+  for key in STRING_FIELDS {
+    if let Some(value) = announcement.get(*key).and_then(|v| v.as_str()) {
+      properties.push((key.to_string(), value.to_string()));
+    }
+  }
+  if let Some(version) = announcement.get("protocolVersion").and_then(|v| v.as_u64()) {
+    properties.push(("protocolVersion".to_string(), version.to_string()));
+  }
+  if let Some(capabilities) = announcement.get("capabilities").and_then(|v| v.as_array()) {
+    let capabilities = capabilities.iter().filter_map(|v| v.as_str()).collect::<Vec<_>>();
+    properties.push(("capabilities".to_string(), capabilities.join(",")));
+  }
+  for key in PORT_FIELDS {
+    if let Some(port) = announcement.get(*key).and_then(|v| v.as_u64()) {
+      properties.push((key.to_string(), port.to_string()));
+    }
+  }
+  properties
+}
+
+/// TXT 레코드에서 서명 검증이 가능한 `discovery-response`를 다시 만든다.
+/// 숫자와 배열 필드는 브로드캐스트 패킷과 같은 JSON 타입으로 되돌린다.
+fn announcement_from_txt(info: &ServiceInfo) -> Option<Value> {
+  let properties = info.get_properties();
+  properties.get_property_val_str("peerId")?;
+  properties.get_property_val_str("userId")?;
+
+  let mut message = json!({ "type": "discovery-response" });
+  for key in STRING_FIELDS {
+    if let Some(value) = properties.get_property_val_str(key) {
+      message[*key] = json!(value);
+    }
+  }
+  if let Some(version) = properties.get_property_val_str("protocolVersion").and_then(|v| v.parse::<u64>().ok()) {
+    message["protocolVersion"] = json!(version);
+  }
+  if let Some(capabilities) = properties.get_property_val_str("capabilities") {
+    let capabilities = capabilities.split(',').filter(|c| !c.is_empty()).collect::<Vec<_>>();
+    message["capabilities"] = json!(capabilities);
+  }
+  for key in PORT_FIELDS {
+    if let Some(port) = properties.get_property_val_str(key).and_then(|v| v.parse::<u16>().ok()) {
+      message[*key] = json!(port);
+    }
+  }
+  Some(message)
+}
+
+/// 광고된 주소 중 되돌려 보낼 수 있는 가장 좋은 주소.
+/// mDNS 응답의 링크 로컬 IPv6에는 인터페이스 번호가 없어 쓰지 않는다.
+fn best_address(info: &ServiceInfo) -> Option<String> {
+  info
+    .get_addresses()
+    .iter()
+    .map(|ip| ip.to_string())
+    .filter(|ip| net_interfaces::address_rank(ip) < 2)
+    .min_by_key(|ip| (net_interfaces::address_rank(ip), ip.clone()))
+}
@@ -0,0 +1,224 @@
+//! 네트워크 인터페이스 열거
+//!
+//! 디스커버리는 인터페이스마다 넷마스크로 계산한 지정 브로드캐스트 주소로 나간다.
+//! 유선과 무선을 함께 쓰는 PC나 /22 같은 넓은 대역에서도 모든 구간에 닿도록 하고,
+//! 루프백과 가상 어댑터(도커, VM, VPN 등)는 제외한다.
+//! IPv6에서는 링크 로컬 멀티캐스트 그룹으로 디스커버리를 보내고, 메시지 소켓은
+//! IPv4와 IPv6를 함께 받는 듀얼 스택으로 연다.
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// IPv6 디스커버리 멀티캐스트 그룹 (링크 로컬 범위, 끝 두 그룹은 "EDLK")
+pub const DISCOVERY_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x4544, 0x4c4b);
+
+/// 이 이름으로 시작하는 인터페이스는 가상 어댑터로 보고 제외한다. (Linux/macOS)
+const VIRTUAL_NAME_PREFIXES: &[&str] = &[
+  "docker", "br-", "veth", "virbr", "vmnet", "vboxnet", "utun", "tun", "tap", "wg", "tailscale", "zt", "awdl", "llw",
+];
+/// 이 단어가 들어간 인터페이스도 제외한다. (Windows 어댑터 이름)
+const VIRTUAL_NAME_KEYWORDS: &[&str] = &["virtualbox", "vmware", "hyper-v", "vethernet", "wsl", "tailscale", "zerotier"];
+
+/// 디스커버리에 쓸 수 있는 IPv4 인터페이스
+#[derive(Clone, Debug)]
+pub struct Ipv4Interface {
+  pub name: String,
+  pub ip: Ipv4Addr,
+  pub prefix_len: u8,
+  pub broadcast: Ipv4Addr,
+}
+
+/// 루프백, 링크 로컬, 가상 어댑터를 뺀 IPv4 인터페이스 목록
+pub fn ipv4_interfaces() -> Vec<Ipv4Interface> {
+  let Ok(interfaces) = if_addrs::get_if_addrs() else { return Vec::new(); };
+
+  interfaces
+    .into_iter()
+    .filter(|iface| !iface.is_loopback() && !is_virtual_adapter(&iface.name))
+    .filter_map(|iface| match iface.addr {
+      if_addrs::IfAddr::V4(addr) => Some((iface.name, addr)),
+      _ => None,
+    })
+    .filter(|(_, addr)| !addr.ip.is_link_local() && !addr.ip.is_unspecified())
+    .map(|(name, addr)| {
+      let mask = u32::from(addr.netmask);
+      Ipv4Interface {
+        name,
+        ip: addr.ip,
+        prefix_len: mask.count_ones() as u8,
+        broadcast: Ipv4Addr::from(u32::from(addr.ip) | !mask),
+      }
+    })
+    .collect()
+}
+
+/// 디스커버리에 쓸 수 있는 IPv6 인터페이스
+#[derive(Clone, Debug)]
+pub struct Ipv6Interface {
+  pub name: String,
+  pub ip: Ipv6Addr,
+  pub index: u32,
+}
+
+/// 루프백과 가상 어댑터를 뺀 IPv6 인터페이스 목록
+pub fn ipv6_interfaces() -> Vec<Ipv6Interface> {
+  let Ok(interfaces) = if_addrs::get_if_addrs() else { return Vec::new(); };
+
+  interfaces
+    .into_iter()
+    .filter(|iface| !iface.is_loopback() && !is_virtual_adapter(&iface.name))
+    .filter_map(|iface| match (iface.addr, iface.index) {
+      (if_addrs::IfAddr::V6(addr), Some(index)) => Some(Ipv6Interface { name: iface.name, ip: addr.ip, index }),
+      _ => None,
+    })
+    .collect()
+}
+
+/// 인터페이스별 지정 브로드캐스트 주소. 찾지 못하면 제한 브로드캐스트 주소를 쓴다.
+pub fn broadcast_addresses() -> Vec<Ipv4Addr> {
+  let mut addresses = Vec::new();
+  for iface in ipv4_interfaces() {
+    // /31, /32는 브로드캐스트가 없다.
+    if iface.prefix_len >= 31 || addresses.contains(&iface.broadcast) {
+      continue;
+    }
+    addresses.push(iface.broadcast);
+  }
+
+  if addresses.is_empty() {
+    addresses.push(Ipv4Addr::BROADCAST);
+  }
+
+  addresses
+}
+
+/// 각 IPv6 인터페이스의 멀티캐스트 그룹으로 디스커버리 패킷을 보낸다.
+pub async fn send_multicast_v6(data: &[u8], port: u16) -> usize {
+  let Ok(socket) = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await else { return 0; };
+
+  let mut indexes = ipv6_interfaces().into_iter().map(|iface| iface.index).collect::<Vec<_>>();
+  indexes.sort_unstable();
+  indexes.dedup();
+
+  let mut sent = 0;
+  for index in indexes {
+    let target = SocketAddrV6::new(DISCOVERY_MULTICAST_V6, port, 0, index);
+    if socket.send_to(data, target).await.is_ok() {
+      sent += 1;
+    }
+  }
+  sent
+}
+
+/// 디스커버리 허브용 IPv6 소켓. IPv4 소켓과 같은 포트를 쓰도록 IPv6 전용으로 열고
+/// 모든 IPv6 인터페이스에서 멀티캐스트 그룹에 가입한다.
+pub fn bind_discovery_v6(port: u16) -> std::io::Result<UdpSocket> {
+  let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+  socket.set_only_v6(true)?;
+  socket.set_reuse_address(true)?;
+  socket.bind(&SockAddr::from(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))))?;
+  socket.set_nonblocking(true)?;
+
+  let socket = UdpSocket::from_std(socket.into())?;
+  let mut joined = false;
+  for iface in ipv6_interfaces() {
+    joined |= socket.join_multicast_v6(&DISCOVERY_MULTICAST_V6, iface.index).is_ok();
+  }
+  if !joined {
+    return Err(std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "no IPv6 interface"));
+  }
+  Ok(socket)
+}
+
+/// IPv4와 IPv6를 함께 받는 UDP 소켓. IPv6를 쓸 수 없는 시스템에서는 IPv4로 연다.
+/// `bind_ip`가 있으면 그 주소에서만 받는다.
+pub async fn bind_udp(bind_ip: Option<IpAddr>, port: u16) -> std::io::Result<UdpSocket> {
+  if let Some(ip) = bind_ip {
+    return UdpSocket::bind((ip, port)).await;
+  }
+  match dual_stack_socket(Type::DGRAM, Protocol::UDP, port) {
+    Ok(socket) => UdpSocket::from_std(socket.into()),
+    Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await,
+  }
+}
+
+/// IPv4와 IPv6를 함께 받는 TCP 리스너. IPv6를 쓸 수 없는 시스템에서는 IPv4로 연다.
+/// `bind_ip`가 있으면 그 주소에서만 받는다.
+pub async fn bind_tcp(bind_ip: Option<IpAddr>, port: u16) -> std::io::Result<TcpListener> {
+  if let Some(ip) = bind_ip {
+    return TcpListener::bind((ip, port)).await;
+  }
+  let dual = dual_stack_socket(Type::STREAM, Protocol::TCP, port).and_then(|socket| {
+    socket.listen(1024)?;
+    Ok(socket)
+  });
+  match dual {
+    Ok(socket) => TcpListener::from_std(socket.into()),
+    Err(_) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await,
+  }
+}
+
+fn dual_stack_socket(kind: Type, protocol: Protocol, port: u16) -> std::io::Result<Socket> {
+  let socket = Socket::new(Domain::IPV6, kind, Some(protocol))?;
+  socket.set_only_v6(false)?;
+  if kind == Type::STREAM {
+    socket.set_reuse_address(true)?;
+  }
+  socket.bind(&SockAddr::from(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))))?;
+  socket.set_nonblocking(true)?;
+  Ok(socket)
+}
+
+/// 대상과 같은 주소 체계의 임시 포트 주소. `bind_ip`가 같은 체계면 그 주소에서 보낸다.
+pub fn local_for(target: &SocketAddr, bind_ip: Option<IpAddr>) -> SocketAddr {
+  match (target, bind_ip) {
+    (SocketAddr::V4(_), Some(ip @ IpAddr::V4(_))) | (SocketAddr::V6(_), Some(ip @ IpAddr::V6(_))) => SocketAddr::from((ip, 0)),
+    (SocketAddr::V4(_), _) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+    (SocketAddr::V6(_), _) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+  }
+}
+
+/// 수신 주소를 피어 주소 문자열로 바꾼다. IPv4 매핑 주소는 IPv4로 풀고,
+/// 링크 로컬 IPv6는 되돌려 보낼 수 있도록 `%인터페이스 번호`를 붙인다.
+pub fn peer_ip(addr: &SocketAddr) -> String {
+  match addr {
+    SocketAddr::V4(addr) => addr.ip().to_string(),
+    SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+      Some(ipv4) => ipv4.to_string(),
+      None if is_link_local_v6(addr.ip()) && addr.scope_id() != 0 => format!("{}%{}", addr.ip(), addr.scope_id()),
+      None => addr.ip().to_string(),
+    },
+  }
+}
+
+/// `peer_ip` 형식의 주소를 소켓 주소로 바꾼다.
+pub fn socket_addr(ip: &str, port: u16) -> Option<SocketAddr> {
+  if let Ok(ip) = ip.parse::<IpAddr>() {
+    return Some(SocketAddr::new(ip, port));
+  }
+  let (ip, scope) = ip.split_once('%')?;
+  let ip = ip.parse::<Ipv6Addr>().ok()?;
+  let scope = scope.parse::<u32>().ok()?;
+  Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope)))
+}
+
+/// 주소 선호 순위 (작을수록 우선). IPv4, 전역/ULA IPv6, 링크 로컬 IPv6 순이다.
+pub fn address_rank(ip: &str) -> u8 {
+  match socket_addr(ip, 0).map(|addr| addr.ip()) {
+    Some(IpAddr::V4(ip)) if !ip.is_link_local() => 0,
+    Some(IpAddr::V6(ip)) if !is_link_local_v6(&ip) => 1,
+    Some(_) => 2,
+    None => 3,
+  }
+}
+
+fn is_link_local_v6(ip: &Ipv6Addr) -> bool {
+  (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+fn is_virtual_adapter(name: &str) -> bool {
+  let name = name.to_lowercase();
+  VIRTUAL_NAME_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+    || VIRTUAL_NAME_KEYWORDS.iter().any(|keyword| name.contains(keyword))
+}
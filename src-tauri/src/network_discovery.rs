@@ -3,11 +3,14 @@ use serde_json::{json, Value};
 use sha2::Digest;
 use std::collections::HashMap;
 use std::time::Duration;
-use tauri::{AppHandle, Emitter};
 use tokio::net::UdpSocket;
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 
+use crate::host::CoreHost;
+use crate::net_interfaces;
+use crate::unicast_discovery::{self, UnicastTargets};
+
 const DISCOVERY_MESSAGE: &str = "EDULINKER_DISCOVERY";
 const DISCOVERY_VERSION: &str = "1.0";
 
@@ -36,13 +39,13 @@ struct NetworkDiscoveryState {
 
 #[derive(Clone)]
 pub struct NetworkDiscoveryManager {
-  app: AppHandle,
+  app: CoreHost,
   state: std::sync::Arc<Mutex<NetworkDiscoveryState>>,
 }
 
 impl NetworkDiscoveryManager {
-  pub fn new(app: AppHandle) -> Self {
-    let requested_port = requested_discovery_port();
+  pub fn new(app: CoreHost) -> Self {
+    let requested_port = app.net().discovery_port;
     let state = NetworkDiscoveryState {
       running: false,
       requested_port,
@@ -159,18 +162,22 @@ impl NetworkDiscoveryManager {
 
   async fn broadcast_loop(&self, port: u16, token: CancellationToken) {
     let mut interval = tokio::time::interval(Duration::from_secs(30));
+    let mut sweep_interval = tokio::time::interval(unicast_discovery::SWEEP_INTERVAL);
 
     loop {
       tokio::select! {
         _ = token.cancelled() => break,
         _ = interval.tick() => {
-          let _ = self.broadcast_once(port).await;
+          let _ = self.broadcast_once(port, false).await;
+        }
+        _ = sweep_interval.tick() => {
+          let _ = self.broadcast_once(port, true).await;
         }
       }
     }
   }
 
-  async fn broadcast_once(&self, port: u16) -> bool {
+  async fn broadcast_once(&self, port: u16, sweep: bool) -> bool {
     let (device_id, hostname, ip_address, mac_address) = {
       let state = self.state.lock().await;
       (
@@ -210,9 +217,16 @@ impl NetworkDiscoveryManager {
 
     let _ = socket.set_broadcast(true);
 
-    let targets = broadcast_addresses();
-    for addr in targets {
-      let _ = socket.send_to(&data, (addr.as_str(), port)).await;
+    for addr in net_interfaces::broadcast_addresses() {
+      let _ = socket.send_to(&data, (addr, port)).await;
+    }
+    net_interfaces::send_multicast_v6(&data, port).await;
+
+    let unicast = UnicastTargets::load(&self.app).await;
+    unicast_discovery::send_to_hosts(&socket, &data, &unicast.hosts, port).await;
+    if sweep {
+      let own_ip = ip_address.parse().ok();
+      unicast_discovery::sweep(&socket, &data, &unicast.sweep, port, own_ip).await;
     }
 
     true
@@ -259,20 +273,3 @@ fn get_mac_address() -> String {
     .map(|m| m.to_string())
     .unwrap_or_else(|| "00:00:00:00:00:00".to_string())
 }
-
-fn broadcast_addresses() -> Vec<String> {
-  let mut addresses = Vec::new();
-  if let Ok(ip) = local_ip_address::local_ip() {
-    if let std::net::IpAddr::V4(ipv4) = ip {
-      let octets = ipv4.octets();
-      addresses.push(format!("{}.{}.{}.255", octets[0], octets[1], octets[2]));
-    }
-  }
-
-  if addresses.is_empty() {
-    addresses.push("255.255.255.255".to_string());
-  }
-
-  addresses
-}
-
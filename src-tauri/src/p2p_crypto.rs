@@ -0,0 +1,356 @@
+//! 내부 P2P 종단간 암호화와 디스커버리 서명
+//!
+//! 설치마다 장기 X25519 키를 두고 공개키를 디스커버리로 알린다.
+//! 두 사용자의 X25519 공유 비밀에서 HKDF-SHA256으로 대화별 세션 키를 만들고,
+//! `content`를 ChaCha20-Poly1305로 봉인한 `encrypted` 봉투로 보낸다.
+//! 디스커버리 패킷은 설치별 Ed25519 신원 키로 서명해 사용자 사칭을 막는다.
+
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const ENVELOPE_VERSION: u64 = 1;
+const ENVELOPE_ALG: &str = "x25519-hkdf-sha256-chacha20poly1305";
+
+/// 이 설치의 장기 X25519 키 쌍
+#[derive(Clone)]
+pub struct ChatKeys {
+  secret: StaticSecret,
+  public: PublicKey,
+}
+
+impl ChatKeys {
+  pub fn generate() -> Self {
+    Self::from_secret(StaticSecret::random_from_rng(OsRng))
+  }
+
+  pub fn from_bytes(bytes: [u8; 32]) -> Self {
+    Self::from_secret(StaticSecret::from(bytes))
+  }
+
+  fn from_secret(secret: StaticSecret) -> Self {
+    let public = PublicKey::from(&secret);
+    Self { secret, public }
+  }
+
+  pub fn secret_bytes(&self) -> [u8; 32] {
+    self.secret.to_bytes()
+  }
+
+  pub fn public_bytes(&self) -> [u8; 32] {
+    self.public.to_bytes()
+  }
+
+  /// 디스커버리에 싣는 base64 공개키
+  pub fn public_key(&self) -> String {
+    base64::engine::general_purpose::STANDARD.encode(self.public.as_bytes())
+  }
+
+  /// `plaintext`를 상대 공개키로 봉인한 봉투를 만든다. `aad`는 메시지 메타데이터를 묶는다.
+  pub fn seal(
+    &self,
+    peer_public_key: &str,
+    my_user_id: &str,
+    peer_user_id: &str,
+    aad: &[u8],
+    plaintext: &[u8],
+  ) -> Result<Value, String> {
+    let peer_public = decode_public_key(peer_public_key)?;
+    let cipher = self.session_cipher(&peer_public, my_user_id, peer_user_id)?;
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+      .encrypt(&nonce, Payload { msg: plaintext, aad })
+      .map_err(|_| "encryption failed".to_string())?;
+
+    let b64 = base64::engine::general_purpose::STANDARD;
+    Ok(json!({
+      "v": ENVELOPE_VERSION,
+      "alg": ENVELOPE_ALG,
+      "senderKey": self.public_key(),
+      "nonce": b64.encode(nonce),
+      "ciphertext": b64.encode(ciphertext)
+    }))
+  }
+
+  /// 봉투를 연다. `peer_public_key`는 보낸 사람의 공개키다.
+  pub fn open(
+    &self,
+    envelope: &Value,
+    peer_public_key: &str,
+    my_user_id: &str,
+    peer_user_id: &str,
+    aad: &[u8],
+  ) -> Result<Vec<u8>, String> {
+    if envelope.get("v").and_then(|v| v.as_u64()) != Some(ENVELOPE_VERSION) {
+      return Err("unsupported envelope version".to_string());
+    }
+
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let nonce = envelope
+      .get("nonce")
+      .and_then(|v| v.as_str())
+      .and_then(|v| b64.decode(v).ok())
+      .filter(|v| v.len() == 12)
+      .ok_or("malformed nonce")?;
+    let ciphertext = envelope
+      .get("ciphertext")
+      .and_then(|v| v.as_str())
+      .and_then(|v| b64.decode(v).ok())
+      .ok_or("malformed ciphertext")?;
+
+    let peer_public = decode_public_key(peer_public_key)?;
+    let cipher = self.session_cipher(&peer_public, my_user_id, peer_user_id)?;
+    cipher
+      .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad })
+      .map_err(|_| "authentication failed".to_string())
+  }
+
+  /// 대화(두 사용자 + 두 공개키)마다 고유한 세션 키
+  fn session_cipher(&self, peer_public: &PublicKey, my_user_id: &str, peer_user_id: &str) -> Result<ChaCha20Poly1305, String> {
+    let shared = self.secret.diffie_hellman(peer_public);
+    if !shared.was_contributory() {
+      return Err("invalid peer public key".to_string());
+    }
+
+    let mut users = [my_user_id, peer_user_id];
+    users.sort();
+    let mut keys = [self.public.as_bytes().as_slice(), peer_public.as_bytes().as_slice()];
+    keys.sort();
+
+    let salt = format!("edulinker-p2p-chat|{}|{}", users[0], users[1]);
+    let info = [b"conversation-key".as_slice(), keys[0], keys[1]].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(salt.as_bytes()), shared.as_bytes())
+      .expand(&info, &mut key)
+      .map_err(|_| "key derivation failed".to_string())?;
+
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+  }
+}
+
+/// 이 설치의 장기 Ed25519 신원 키
+#[derive(Clone)]
+pub struct IdentityKey {
+  signing: SigningKey,
+}
+
+impl IdentityKey {
+  pub fn generate() -> Self {
+    Self { signing: SigningKey::generate(&mut OsRng) }
+  }
+
+  pub fn from_bytes(bytes: [u8; 32]) -> Self {
+    Self { signing: SigningKey::from_bytes(&bytes) }
+  }
+
+  pub fn secret_bytes(&self) -> [u8; 32] {
+    self.signing.to_bytes()
+  }
+
+  /// 디스커버리에 싣는 base64 공개키
+  pub fn public_key(&self) -> String {
+    base64::engine::general_purpose::STANDARD.encode(self.signing.verifying_key().as_bytes())
+  }
+
+  /// 디스커버리 패킷에 `identityKey`와 `signature`를 붙인다.
+  pub fn sign_announcement(&self, message: &mut Value) {
+    message["identityKey"] = json!(self.public_key());
+    let signature = self.signing.sign(&announcement_bytes(message));
+    message["signature"] = json!(base64::engine::general_purpose::STANDARD.encode(signature.to_bytes()));
+  }
+
+  /// Noise 정적 키가 이 신원 키의 것임을 증명하는 서명. 핸드셰이크 페이로드로 보낸다.
+  pub fn sign_static(&self, static_public: &[u8]) -> Value {
+    let signature = self.signing.sign(&static_binding_bytes(static_public));
+    json!({
+      "identityKey": self.public_key(),
+      "signature": base64::engine::general_purpose::STANDARD.encode(signature.to_bytes())
+    })
+  }
+}
+
+/// 핸드셰이크 페이로드의 서명을 검증하고 상대 신원 키를 돌려준다.
+pub fn verify_static_binding(binding: &Value, static_public: &[u8]) -> Result<String, String> {
+  let identity_key = binding.get("identityKey").and_then(|v| v.as_str()).ok_or("missing identityKey")?;
+  let verifying_key = decode_identity_key(identity_key)?;
+  let signature: [u8; 64] = binding
+    .get("signature")
+    .and_then(|v| v.as_str())
+    .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok())
+    .and_then(|bytes| bytes.try_into().ok())
+    .ok_or("malformed signature")?;
+
+  verifying_key
+    .verify(&static_binding_bytes(static_public), &Signature::from_bytes(&signature))
+    .map_err(|_| "invalid static key binding".to_string())?;
+  Ok(identity_key.to_string())
+}
+
+fn static_binding_bytes(static_public: &[u8]) -> Vec<u8> {
+  [b"edulinker-noise-static-v1".as_slice(), static_public].concat()
+}
+
+/// 디스커버리 패킷의 서명을 검증하고 서명한 신원 키를 돌려준다.
+pub fn verify_announcement(message: &Value) -> Result<String, String> {
+  let b64 = base64::engine::general_purpose::STANDARD;
+  let identity_key = message.get("identityKey").and_then(|v| v.as_str()).ok_or("missing identityKey")?;
+  let verifying_key = decode_identity_key(identity_key)?;
+  let signature: [u8; 64] = message
+    .get("signature")
+    .and_then(|v| v.as_str())
+    .and_then(|v| b64.decode(v).ok())
+    .and_then(|bytes| bytes.try_into().ok())
+    .ok_or("malformed signature")?;
+
+  verifying_key
+    .verify(&announcement_bytes(message), &Signature::from_bytes(&signature))
+    .map_err(|_| "invalid signature".to_string())?;
+  Ok(identity_key.to_string())
+}
+
+/// 서명 대상. 필드 순서가 직렬화 방식에 좌우되지 않도록 배열로 고정한다.
+fn announcement_bytes(message: &Value) -> Vec<u8> {
+  let field = |name: &str| message.get(name).cloned().unwrap_or(Value::Null);
+  json!([
+    "edulinker-discovery-v1",
+    field("type"),
+    field("peerId"),
+    field("userId"),
+    field("userName"),
+    field("schoolId"),
+    field("hostname"),
+    field("platform"),
+    field("protocolVersion"),
+    field("capabilities"),
+    field("publicKey"),
+    field("identityKey"),
+    field("udpPort"),
+    field("tcpPort"),
+    field("discoveryPort"),
+    field("presence"),
+    field("timestamp")
+  ])
+  .to_string()
+  .into_bytes()
+}
+
+/// 두 사용자의 신원 키로 만든 60자리 안전 번호. 양쪽에서 같은 값이 나온다.
+pub fn safety_number(my_user_id: &str, my_key: &str, peer_user_id: &str, peer_key: &str) -> Result<String, String> {
+  let my_bytes = decode_identity_key(my_key)?.to_bytes();
+  let peer_bytes = decode_identity_key(peer_key)?.to_bytes();
+
+  let mut parties = [(my_user_id, my_bytes), (peer_user_id, peer_bytes)];
+  parties.sort();
+
+  let mut hasher = Sha512::new();
+  hasher.update(b"edulinker-safety-number-v1");
+  for (user_id, key) in &parties {
+    hasher.update((user_id.len() as u32).to_be_bytes());
+    hasher.update(user_id.as_bytes());
+    hasher.update(key);
+  }
+  let digest = hasher.finalize();
+
+  let groups = digest[..60]
+    .chunks(5)
+    .map(|chunk| {
+      let value = chunk.iter().fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+      format!("{:05}", value % 100_000)
+    })
+    .collect::<Vec<_>>();
+  Ok(groups.join(" "))
+}
+
+fn decode_identity_key(value: &str) -> Result<VerifyingKey, String> {
+  let bytes: [u8; 32] = base64::engine::general_purpose::STANDARD
+    .decode(value)
+    .ok()
+    .and_then(|bytes| bytes.try_into().ok())
+    .ok_or("malformed identity key")?;
+  VerifyingKey::from_bytes(&bytes).map_err(|_| "malformed identity key".to_string())
+}
+
+/// 봉투에 묶는 메시지 메타데이터. 타임스탬프를 묶어 신선도 검사를 피하려고 바꿔 쓰지 못하게 한다.
+pub fn message_aad(message: &Value) -> Vec<u8> {
+  let field = |name: &str| message.get(name).and_then(|v| v.as_str()).unwrap_or("").to_string();
+  format!(
+    "{}|{}|{}|{}|{}",
+    field("id"),
+    field("type"),
+    field("senderId"),
+    field("receiverId"),
+    field("timestamp")
+  )
+  .into_bytes()
+}
+
+fn decode_public_key(value: &str) -> Result<PublicKey, String> {
+  let bytes: [u8; 32] = base64::engine::general_purpose::STANDARD
+    .decode(value)
+    .ok()
+    .and_then(|bytes| bytes.try_into().ok())
+    .ok_or("malformed public key")?;
+  Ok(PublicKey::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn chat(timestamp: &str) -> Value {
+    json!({
+      "id": "m1",
+      "type": "chat",
+      "senderId": "alice",
+      "receiverId": "bob",
+      "timestamp": timestamp
+    })
+  }
+
+  #[test]
+  fn sealed_content_is_bound_to_its_timestamp() {
+    let (alice, bob) = (ChatKeys::generate(), ChatKeys::generate());
+    let message = chat("2026-03-02T08:30:00+00:00");
+    let envelope = alice
+      .seal(&bob.public_key(), "alice", "bob", &message_aad(&message), b"hello")
+      .unwrap();
+
+    let opened = bob.open(&envelope, &alice.public_key(), "bob", "alice", &message_aad(&message));
+    assert_eq!(opened.unwrap(), b"hello");
+
+    // 신선도 검사를 피하려고 타임스탬프만 바꾸면 열리지 않는다.
+    let rewritten = chat("2026-03-05T08:30:00+00:00");
+    assert!(bob
+      .open(&envelope, &alice.public_key(), "bob", "alice", &message_aad(&rewritten))
+      .is_err());
+  }
+
+  #[test]
+  fn announcement_signature_covers_ports_and_presence() {
+    let identity = IdentityKey::generate();
+    let mut announcement = json!({
+      "type": "discovery",
+      "peerId": "p1",
+      "userId": "alice",
+      "udpPort": 47236,
+      "tcpPort": 47237,
+      "discoveryPort": 47235,
+      "presence": {"status": "available", "automatic": false},
+      "timestamp": "2026-03-02T08:30:00+00:00"
+    });
+    identity.sign_announcement(&mut announcement);
+    assert!(verify_announcement(&announcement).is_ok());
+
+    // 알림을 전하는 쪽이 포트나 상태를 바꾸면 서명이 맞지 않는다.
+    for (field, value) in [("tcpPort", json!(4444)), ("presence", json!({"status": "busy", "automatic": false}))] {
+      let mut rewritten = announcement.clone();
+      rewritten[field] = value;
+      assert!(verify_announcement(&rewritten).is_err(), "{field} is not signed");
+    }
+  }
+}
@@ -0,0 +1,126 @@
+//! 대화별 순서 번호와 수신 쪽 재정렬 버퍼
+//!
+//! `chat`과 `group_chat`에는 대화별 램포트 카운터 `seq`와, 보낸 사람이 같은 대화에서 바로 전에
+//! 보낸 메시지의 `seq`인 `prevSeq`가 붙는다. 학교 PC끼리 시계가 어긋나도 저장과 조회는
+//! `seq` 순서이고, 같으면 받은 시각 순이다.
+//! 재전송이나 대기열 때문에 앞 메시지가 늦으면 뒤 메시지를 잠깐 들고 있다가 순서대로 내보낸다.
+
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// 빠진 메시지를 이만큼 기다린 뒤에는 들고 있던 메시지를 그냥 내보낸다.
+pub const REORDER_WAIT: Duration = Duration::from_secs(2);
+/// 보낸 사람마다 들고 있을 최대 메시지 수
+const REORDER_LIMIT: usize = 64;
+
+/// 1:1 대화는 `direct:<상대 ID>`, 그룹은 `group:<그룹 ID>`
+pub fn conversation_id(message: &Value, my_user_id: &str) -> Option<String> {
+  let field = |key: &str| message.get(key).and_then(|v| v.as_str()).filter(|s| !s.is_empty());
+  match field("type")? {
+    "chat" => {
+      let sender_id = field("senderId")?;
+      let other = if sender_id == my_user_id { field("receiverId")? } else { sender_id };
+      Some(format!("direct:{other}"))
+    }
+    "group_chat" => Some(format!("group:{}", field("groupId")?)),
+    _ => None,
+  }
+}
+
+/// 이전 버전 피어가 보낸 메시지에는 없다.
+pub fn seq_of(message: &Value) -> Option<i64> {
+  message.get("seq").and_then(|v| v.as_i64()).filter(|seq| *seq > 0)
+}
+
+fn prev_seq_of(message: &Value) -> i64 {
+  message.get("prevSeq").and_then(|v| v.as_i64()).unwrap_or(0)
+}
+
+#[derive(Default)]
+struct SenderSlot {
+  /// 지금까지 내보낸 가장 큰 `seq`
+  delivered: i64,
+  held: BTreeMap<i64, Value>,
+}
+
+impl SenderSlot {
+  fn release(&mut self, message: Value, ready: &mut Vec<Value>) {
+    self.delivered = self.delivered.max(seq_of(&message).unwrap_or(0));
+    ready.push(message);
+  }
+
+  /// 앞 메시지가 도착해 이어지는 것들을 내보낸다.
+  fn release_contiguous(&mut self, ready: &mut Vec<Value>) {
+    while let Some(entry) = self.held.first_entry() {
+      if prev_seq_of(entry.get()) > self.delivered {
+        break;
+      }
+      let message = entry.remove();
+      self.release(message, ready);
+    }
+  }
+
+  fn release_all(&mut self, ready: &mut Vec<Value>) {
+    for message in std::mem::take(&mut self.held).into_values() {
+      self.release(message, ready);
+    }
+  }
+}
+
+/// 대화와 보낸 사람별로 `prevSeq`가 이어지는지 보고 순서를 맞춘다.
+#[derive(Default)]
+pub struct ReorderBuffer {
+  slots: HashMap<String, SenderSlot>,
+}
+
+impl ReorderBuffer {
+  /// `(대화, 보낸 사람)` 키
+  pub fn key(conversation_id: &str, sender_id: &str) -> String {
+    format!("{conversation_id}|{sender_id}")
+  }
+
+  /// 지금 내보낼 메시지를 순서대로 돌려준다. 비어 있으면 들고 있는 것이므로
+  /// [`REORDER_WAIT`] 뒤에 [`ReorderBuffer::flush`]를 부른다.
+  pub fn push(&mut self, key: &str, message: Value) -> Vec<Value> {
+    let mut ready = Vec::new();
+    let Some(slot) = self.slots.get_mut(key) else {
+      // 시작한 뒤 이 보낸 사람에게서 처음 받은 메시지는 기준으로 삼는다.
+      let mut slot = SenderSlot::default();
+      slot.release(message, &mut ready);
+      self.slots.insert(key.to_string(), slot);
+      return ready;
+    };
+
+    if prev_seq_of(&message) > slot.delivered {
+      slot.held.insert(seq_of(&message).unwrap_or(0), message);
+      if slot.held.len() > REORDER_LIMIT {
+        slot.release_all(&mut ready);
+      }
+      return ready;
+    }
+
+    slot.release(message, &mut ready);
+    slot.release_contiguous(&mut ready);
+    ready
+  }
+
+  /// 기다려도 앞 메시지가 오지 않았으면 들고 있던 메시지를 순서대로 내보낸다.
+  pub fn flush(&mut self, key: &str) -> Vec<Value> {
+    let mut ready = Vec::new();
+    if let Some(slot) = self.slots.get_mut(key) {
+      slot.release_all(&mut ready);
+    }
+    ready
+  }
+
+  /// 멈출 때 들고 있던 메시지를 모두 내보내고 비운다.
+  pub fn drain(&mut self) -> Vec<Value> {
+    let mut ready = Vec::new();
+    for slot in self.slots.values_mut() {
+      slot.release_all(&mut ready);
+    }
+    self.slots.clear();
+    ready
+  }
+}
@@ -0,0 +1,150 @@
+//! 내부 P2P 피어별 TCP 연결 풀
+//!
+//! 피어 주소마다 협상이 끝난 채널 하나를 유지해 그룹 팬아웃이나 대기열 재전송처럼
+//! 연달아 보내는 메시지가 매번 연결을 새로 맺지 않도록 한다.
+//! 연결에 실패한 피어는 지수 백오프가 끝날 때까지 다시 연결하지 않는다.
+
+use crate::p2p_protocol::P2PChannel;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+/// 이 시간 동안 쓰지 않은 연결은 닫는다.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct PooledPeer {
+  channel: Option<P2PChannel>,
+  last_used: Option<Instant>,
+  failures: u32,
+  retry_at: Option<Instant>,
+}
+
+impl PooledPeer {
+  fn record_failure(&mut self) {
+    self.channel = None;
+    self.failures = self.failures.saturating_add(1);
+    let backoff = BACKOFF_BASE
+      .saturating_mul(1u32 << self.failures.saturating_sub(1).min(16))
+      .min(BACKOFF_MAX);
+    self.retry_at = Some(Instant::now() + backoff);
+  }
+
+  fn record_success(&mut self, channel: P2PChannel) {
+    self.channel = Some(channel);
+    self.last_used = Some(Instant::now());
+    self.failures = 0;
+    self.retry_at = None;
+  }
+
+  fn is_idle(&self) -> bool {
+    self
+      .last_used
+      .map(|used| used.elapsed() >= IDLE_TIMEOUT)
+      .unwrap_or(true)
+  }
+}
+
+#[derive(Default)]
+pub struct ConnectionPool {
+  peers: std::sync::Mutex<HashMap<String, Arc<Mutex<PooledPeer>>>>,
+}
+
+impl ConnectionPool {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// 풀의 연결로 메시지를 보낸다. 보관된 연결이 끊겼으면 `open`으로 새 연결을 맺는다.
+  /// 같은 피어로의 전송은 순서대로 처리되고, 다른 피어로의 전송은 서로 막지 않는다.
+  pub async fn send<F, Fut>(&self, key: &str, message: &Value, open: F) -> Result<(), String>
+  where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<P2PChannel, String>>,
+  {
+    let entry = self.entry(key);
+    let mut peer = entry.lock().await;
+
+    if let Some(retry_at) = peer.retry_at {
+      if Instant::now() < retry_at {
+        return Err(format!("backing off from {key}"));
+      }
+    }
+
+    if let Some(mut channel) = peer.channel.take() {
+      if !peer.is_idle() && !channel.is_closed().await {
+        if let Ok(Ok(())) = timeout(SEND_TIMEOUT, channel.send(message)).await {
+          peer.record_success(channel);
+          return Ok(());
+        }
+      }
+    }
+
+    let mut channel = match open().await {
+      Ok(channel) => channel,
+      Err(err) => {
+        peer.record_failure();
+        return Err(err);
+      }
+    };
+
+    match timeout(SEND_TIMEOUT, channel.send(message)).await {
+      Ok(Ok(())) => {
+        peer.record_success(channel);
+        Ok(())
+      }
+      Ok(Err(err)) => {
+        peer.record_failure();
+        Err(err.to_string())
+      }
+      Err(_) => {
+        peer.record_failure();
+        Err(format!("timed out writing to {key}"))
+      }
+    }
+  }
+
+  /// 피어가 다시 보이면 백오프를 풀어 바로 재연결할 수 있게 한다.
+  pub fn reset_backoff(&self, key: &str) {
+    let entry = self.peers.lock().unwrap().get(key).cloned();
+    if let Some(entry) = entry {
+      if let Ok(mut peer) = entry.try_lock() {
+        peer.failures = 0;
+        peer.retry_at = None;
+      }
+    }
+  }
+
+  /// 오래 쓰지 않은 연결을 닫는다.
+  pub fn prune_idle(&self) {
+    self.peers.lock().unwrap().retain(|_, entry| match entry.try_lock() {
+      Ok(peer) => !peer.is_idle() || peer.retry_at.is_some_and(|at| Instant::now() < at),
+      Err(_) => true,
+    });
+  }
+
+  pub fn remove(&self, key: &str) {
+    self.peers.lock().unwrap().remove(key);
+  }
+
+  pub fn clear(&self) {
+    self.peers.lock().unwrap().clear();
+  }
+
+  fn entry(&self, key: &str) -> Arc<Mutex<PooledPeer>> {
+    self
+      .peers
+      .lock()
+      .unwrap()
+      .entry(key.to_string())
+      .or_default()
+      .clone()
+  }
+}
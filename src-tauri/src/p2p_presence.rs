@@ -0,0 +1,111 @@
+//! 교사 상태 (재석, 자리 비움, 바쁨, 수업 중, 퇴근)
+//!
+//! 사용자가 고른 상태는 `p2pPresence` 설정에 남는다. 디스커버리 알림과 하트비트(`ping`/`pong`)에
+//! `presence`로 실어 보내고, 받은 쪽은 피어 정보에 두었다가 바뀌면 `p2p:presence-changed`를 낸다.
+//! 재석이나 자리 비움일 때 `messageSettings`의 수업 시간에 들어가면 저절로 수업 중이 된다.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// 사용자가 고른 상태를 담는 설정 키
+pub const PRESENCE_SETTING: &str = "p2pPresence";
+/// 프론트엔드가 수업 시간표(`classTimes`)를 저장하는 설정 키
+pub const TIMETABLE_SETTING: &str = "messageSettings";
+/// 상태 메시지 최대 글자 수
+const MAX_MESSAGE_CHARS: usize = 80;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PresenceStatus {
+  #[default]
+  Available,
+  Away,
+  Busy,
+  InClass,
+  OffDuty,
+}
+
+impl PresenceStatus {
+  /// `available`, `away`, `busy`, `in-class`, `off-duty`
+  pub fn parse(value: &str) -> Option<Self> {
+    serde_json::from_value(Value::String(value.trim().to_string())).ok()
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Presence {
+  pub status: PresenceStatus,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub message: Option<String>,
+  /// 시간표 때문에 저절로 수업 중이 된 경우
+  #[serde(default)]
+  pub automatic: bool,
+}
+
+impl Presence {
+  pub fn new(status: PresenceStatus, message: Option<&str>) -> Self {
+    Self {
+      status,
+      message: clean_message(message),
+      automatic: false,
+    }
+  }
+
+  /// 피어가 보낸 `presence`. 없거나 알 수 없는 상태면 `None`.
+  pub fn from_value(value: Option<&Value>) -> Option<Self> {
+    let mut presence = serde_json::from_value::<Self>(value?.clone()).ok()?;
+    presence.message = clean_message(presence.message.as_deref());
+    Some(presence)
+  }
+
+  /// 지금 수업 중이면(`period`) 재석과 자리 비움은 수업 중으로 바꾼다.
+  /// 바쁨과 퇴근은 사용자가 고른 그대로 둔다.
+  pub fn effective(&self, period: Option<&str>) -> Self {
+    match period {
+      Some(label) if matches!(self.status, PresenceStatus::Available | PresenceStatus::Away) => Self {
+        status: PresenceStatus::InClass,
+        message: self.message.clone().or_else(|| clean_message(Some(label))),
+        automatic: true,
+      },
+      _ => Self {
+        automatic: false,
+        ..self.clone()
+      },
+    }
+  }
+}
+
+/// 자정부터 센 `minutes`(분)가 시간표의 어느 수업에 들어가면 그 교시 이름(없으면 빈 문자열).
+/// 시작은 포함하고 끝은 포함하지 않는다. 프론트엔드의 `isClassTime`과 같다.
+pub fn class_period(settings: &str, minutes: u32) -> Option<String> {
+  let settings = serde_json::from_str::<Value>(settings).ok()?;
+  let times = settings
+    .get("classTimes")
+    .or_else(|| settings.get("breakTimes"))
+    .and_then(|v| v.as_array())?;
+
+  times.iter().find_map(|range| {
+    let start = parse_minutes(range.get("start")?.as_str()?)?;
+    let end = parse_minutes(range.get("end")?.as_str()?)?;
+    (start <= minutes && minutes < end).then(|| range.get("label").and_then(|v| v.as_str()).unwrap_or("").to_string())
+  })
+}
+
+/// 이 PC 시계로 자정부터 지난 분
+pub fn local_minutes() -> u32 {
+  use chrono::Timelike;
+  let now = chrono::Local::now();
+  now.hour() * 60 + now.minute()
+}
+
+/// `HH:mm`. 하루 끝을 나타내는 `24:00`도 받는다.
+fn parse_minutes(value: &str) -> Option<u32> {
+  let (hours, minutes) = value.trim().split_once(':')?;
+  let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+  (hours <= 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+fn clean_message(message: Option<&str>) -> Option<String> {
+  let message = message?.trim();
+  (!message.is_empty()).then(|| message.chars().take(MAX_MESSAGE_CHARS).collect())
+}
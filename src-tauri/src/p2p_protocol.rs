@@ -0,0 +1,466 @@
+//! 내부 P2P TCP 채널 프로토콜
+//!
+//! 프레임 = 길이(u32, big-endian, 타입 바이트 포함) + 프레임 타입(u8) + 페이로드.
+//! 연결은 HELLO 프레임으로 시작해 프로토콜 버전과 기능(capabilities)을 교환한다.
+//! 양쪽이 `noise`를 광고하면 이어서 Noise XX 핸드셰이크를 하고, 이후 프레임은
+//! 타입 바이트부터 암호화한다. 정적 키는 Ed25519 신원 키 서명으로 묶는다.
+//! 이전 버전 앱은 줄바꿈 구분 JSON만 이해하므로, 상대가 v2를 광고하지 않으면
+//! 같은 인터페이스로 레거시 모드를 사용한다.
+
+use crate::p2p_crypto::{self, ChatKeys, IdentityKey};
+use base64::Engine;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// 현재 프로토콜 버전. 디스커버리 패킷의 `protocolVersion`으로도 광고한다.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// 줄바꿈 구분 JSON을 쓰는 이전 버전.
+pub const LEGACY_PROTOCOL_VERSION: u16 = 1;
+/// 프레임(또는 레거시 한 줄)의 최대 크기.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+/// 이 빌드가 지원하는 기능 목록.
+pub const CAPABILITIES: &[&str] = &["chat", "receipts", "file-chunks", "noise"];
+
+const FRAME_HELLO: u8 = 1;
+const FRAME_MESSAGE: u8 = 2;
+const FRAME_FILE_CHUNK: u8 = 3;
+const FRAME_HANDSHAKE: u8 = 4;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+const NOISE_MAX_MESSAGE: usize = 65_535;
+const NOISE_TAG_SIZE: usize = 16;
+const NOISE_SEGMENT_SIZE: usize = NOISE_MAX_MESSAGE - NOISE_TAG_SIZE;
+/// 암호화된 프레임은 세그먼트마다 길이(u16)와 인증 태그가 더 붙는다.
+const MAX_SECURE_FRAME_SIZE: usize = MAX_FRAME_SIZE + (MAX_FRAME_SIZE / NOISE_SEGMENT_SIZE + 1) * (2 + NOISE_TAG_SIZE);
+
+/// Noise 핸드셰이크에 쓰는 이 설치의 키
+#[derive(Clone)]
+pub struct SecureConfig {
+  static_secret: [u8; 32],
+  static_public: [u8; 32],
+  identity: IdentityKey,
+}
+
+impl SecureConfig {
+  /// 정적 키는 채팅 암호화용 X25519 키를 그대로 쓴다.
+  pub fn new(chat_keys: &ChatKeys, identity: &IdentityKey) -> Self {
+    Self {
+      static_secret: chat_keys.secret_bytes(),
+      static_public: chat_keys.public_bytes(),
+      identity: identity.clone(),
+    }
+  }
+}
+
+/// 채널에서 받은 항목
+pub enum Incoming {
+  /// 일반 JSON 메시지 (채팅, 영수증, 제어 메시지 등)
+  Message(Value),
+  /// 파일 청크 헤더(JSON)와 원본 바이트
+  FileChunk(Value, Vec<u8>),
+}
+
+enum Mode {
+  Legacy,
+  Framed,
+}
+
+/// 한 TCP 연결 위의 메시지 채널
+pub struct P2PChannel {
+  reader: BufReader<OwnedReadHalf>,
+  writer: OwnedWriteHalf,
+  mode: Mode,
+  peer_addr: SocketAddr,
+  transport: Option<snow::TransportState>,
+  /// 협상된 프로토콜 버전
+  pub version: u16,
+  /// 양쪽이 모두 지원하는 기능
+  pub capabilities: Vec<String>,
+  /// 상대가 HELLO에 담아 보낸 정보 (레거시 모드에서는 Null)
+  pub peer_hello: Value,
+  /// 핸드셰이크로 확인한 상대의 신원 키 (암호화하지 않은 채널은 None)
+  pub remote_identity: Option<String>,
+}
+
+impl P2PChannel {
+  /// 레거시(줄바꿈 JSON) 채널
+  pub fn legacy(stream: TcpStream) -> std::io::Result<Self> {
+    let peer_addr = stream.peer_addr()?;
+    let (read_half, writer) = stream.into_split();
+    Ok(Self {
+      reader: BufReader::new(read_half),
+      writer,
+      mode: Mode::Legacy,
+      peer_addr,
+      transport: None,
+      version: LEGACY_PROTOCOL_VERSION,
+      capabilities: Vec::new(),
+      peer_hello: Value::Null,
+      remote_identity: None,
+    })
+  }
+
+  /// 연결을 연 쪽: HELLO를 보내고 상대의 HELLO를 기다린 뒤, 가능하면 핸드셰이크를 시작한다.
+  pub async fn connect(stream: TcpStream, hello: Value, secure: Option<&SecureConfig>) -> std::io::Result<Self> {
+    let mut channel = Self::legacy(stream)?;
+    channel.mode = Mode::Framed;
+    let hello_bytes = serde_json::to_vec(&hello)?;
+    channel.write_frame(FRAME_HELLO, &hello_bytes).await?;
+
+    let peer_hello = channel.expect_frame(FRAME_HELLO).await?;
+    channel.apply_hello(&hello, serde_json::from_slice(&peer_hello)?)?;
+
+    if let Some(secure) = secure.filter(|_| channel.supports("noise")) {
+      let prologue = [hello_bytes.as_slice(), peer_hello.as_slice()].concat();
+      channel.handshake_initiator(secure, &prologue).await?;
+    }
+    Ok(channel)
+  }
+
+  /// 연결을 받은 쪽: 첫 바이트로 레거시 여부를 판단하고, 프레임 모드면 HELLO에 응답한다.
+  pub async fn accept(stream: TcpStream, hello: Value, secure: Option<&SecureConfig>) -> std::io::Result<Self> {
+    let mut first = [0u8; 1];
+    let peeked = timeout(HANDSHAKE_TIMEOUT, stream.peek(&mut first))
+      .await
+      .map_err(|_| invalid_data("handshake timed out"))??;
+
+    let mut channel = Self::legacy(stream)?;
+    if peeked == 0 || first[0] == b'{' {
+      return Ok(channel);
+    }
+
+    channel.mode = Mode::Framed;
+    let peer_hello = channel.expect_frame(FRAME_HELLO).await?;
+    let hello_bytes = serde_json::to_vec(&hello)?;
+    channel.write_frame(FRAME_HELLO, &hello_bytes).await?;
+    channel.apply_hello(&hello, serde_json::from_slice(&peer_hello)?)?;
+
+    if let Some(secure) = secure.filter(|_| channel.supports("noise")) {
+      let prologue = [peer_hello.as_slice(), hello_bytes.as_slice()].concat();
+      channel.handshake_responder(secure, &prologue).await?;
+    }
+    Ok(channel)
+  }
+
+  pub fn peer_addr(&self) -> SocketAddr {
+    self.peer_addr
+  }
+
+  pub fn supports(&self, capability: &str) -> bool {
+    self.capabilities.iter().any(|c| c == capability)
+  }
+
+  /// Noise 세션으로 암호화된 채널인지
+  pub fn is_secure(&self) -> bool {
+    self.transport.is_some()
+  }
+
+  pub async fn send(&mut self, message: &Value) -> std::io::Result<()> {
+    let payload = serde_json::to_vec(message)?;
+    match self.mode {
+      Mode::Legacy => self.write_line(payload).await,
+      Mode::Framed => self.write_frame(FRAME_MESSAGE, &payload).await,
+    }
+  }
+
+  /// 파일 청크 전송. 바이너리 프레임을 쓸 수 없는 상대에게는 base64로 `data`에 담는다.
+  pub async fn send_file_chunk(&mut self, header: &Value, data: &[u8]) -> std::io::Result<()> {
+    if matches!(self.mode, Mode::Framed) && self.supports("file-chunks") {
+      let header = serde_json::to_vec(header)?;
+      let mut payload = Vec::with_capacity(4 + header.len() + data.len());
+      payload.extend_from_slice(&(header.len() as u32).to_be_bytes());
+      payload.extend_from_slice(&header);
+      payload.extend_from_slice(data);
+      return self.write_frame(FRAME_FILE_CHUNK, &payload).await;
+    }
+
+    let mut message = header.clone();
+    message["data"] = json!(base64::engine::general_purpose::STANDARD.encode(data));
+    self.send(&message).await
+  }
+
+  /// 다음 항목을 읽는다. 연결이 정상 종료되면 `None`.
+  pub async fn recv(&mut self) -> std::io::Result<Option<Incoming>> {
+    loop {
+      let item = match self.mode {
+        Mode::Legacy => self.read_line().await?.map(|payload| (FRAME_MESSAGE, payload)),
+        Mode::Framed => self.read_frame().await?,
+      };
+      let Some((frame_type, payload)) = item else { return Ok(None); };
+
+      match frame_type {
+        FRAME_MESSAGE => {
+          // 파싱할 수 없는 줄은 기존 동작처럼 건너뛴다.
+          let Ok(message) = serde_json::from_slice::<Value>(&payload) else { continue; };
+          if message.get("type").and_then(|v| v.as_str()) == Some("file_chunk") {
+            let data = message
+              .get("data")
+              .and_then(|v| v.as_str())
+              .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok())
+              .unwrap_or_default();
+            return Ok(Some(Incoming::FileChunk(message, data)));
+          }
+          return Ok(Some(Incoming::Message(message)));
+        }
+        FRAME_FILE_CHUNK => {
+          if payload.len() < 4 {
+            return Err(invalid_data("truncated file chunk frame"));
+          }
+          let header_len = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+          if payload.len() < 4 + header_len {
+            return Err(invalid_data("truncated file chunk header"));
+          }
+          let header = serde_json::from_slice::<Value>(&payload[4..4 + header_len])?;
+          return Ok(Some(Incoming::FileChunk(header, payload[4 + header_len..].to_vec())));
+        }
+        // 알 수 없는 프레임은 이후 버전과의 호환을 위해 무시한다.
+        _ => continue,
+      }
+    }
+  }
+
+  /// 상대가 연결을 닫았는지 확인한다. 풀에 보관한 채널을 재사용하기 전에 쓴다.
+  pub async fn is_closed(&mut self) -> bool {
+    if !self.reader.buffer().is_empty() {
+      return false;
+    }
+    let mut byte = [0u8; 1];
+    matches!(
+      timeout(Duration::ZERO, self.reader.get_mut().peek(&mut byte)).await,
+      Ok(Ok(0)) | Ok(Err(_))
+    )
+  }
+
+  pub async fn shutdown(&mut self) {
+    let _ = self.writer.shutdown().await;
+  }
+
+  /// 기능은 양쪽 HELLO가 모두 광고한 것만 남긴다.
+  fn apply_hello(&mut self, hello: &Value, peer_hello: Value) -> std::io::Result<()> {
+    if peer_hello.get("protocol").and_then(|v| v.as_str()) != Some("edulinker-p2p") {
+      return Err(invalid_data("unknown protocol"));
+    }
+
+    let peer_version = peer_hello
+      .get("version")
+      .and_then(|v| v.as_u64())
+      .map(|v| v.min(u16::MAX as u64) as u16)
+      .unwrap_or(LEGACY_PROTOCOL_VERSION);
+    self.version = peer_version.min(PROTOCOL_VERSION);
+
+    let peer_capabilities = hello_capabilities(&peer_hello);
+    self.capabilities = hello_capabilities(hello)
+      .into_iter()
+      .filter(|c| CAPABILITIES.contains(&c.as_str()) && peer_capabilities.contains(c))
+      .collect();
+
+    self.peer_hello = peer_hello;
+    Ok(())
+  }
+
+  async fn handshake_initiator(&mut self, secure: &SecureConfig, prologue: &[u8]) -> std::io::Result<()> {
+    let mut handshake = noise_builder(secure, prologue)?.build_initiator().map_err(noise_error)?;
+    let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+
+    // -> e
+    let len = handshake.write_message(&[], &mut buf).map_err(noise_error)?;
+    self.write_frame(FRAME_HANDSHAKE, &buf[..len]).await?;
+
+    // <- e, ee, s, es + 상대 신원 서명
+    let message = self.expect_frame(FRAME_HANDSHAKE).await?;
+    let len = handshake.read_message(&message, &mut buf).map_err(noise_error)?;
+    let remote_static = handshake.get_remote_static().ok_or_else(|| invalid_data("missing remote static key"))?;
+    let remote_identity = verify_binding(&buf[..len], remote_static)?;
+
+    // -> s, se + 내 신원 서명
+    let binding = serde_json::to_vec(&secure.identity.sign_static(&secure.static_public))?;
+    let len = handshake.write_message(&binding, &mut buf).map_err(noise_error)?;
+    self.write_frame(FRAME_HANDSHAKE, &buf[..len]).await?;
+
+    self.transport = Some(handshake.into_transport_mode().map_err(noise_error)?);
+    self.remote_identity = Some(remote_identity);
+    Ok(())
+  }
+
+  async fn handshake_responder(&mut self, secure: &SecureConfig, prologue: &[u8]) -> std::io::Result<()> {
+    let mut handshake = noise_builder(secure, prologue)?.build_responder().map_err(noise_error)?;
+    let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+
+    let message = self.expect_frame(FRAME_HANDSHAKE).await?;
+    handshake.read_message(&message, &mut buf).map_err(noise_error)?;
+
+    let binding = serde_json::to_vec(&secure.identity.sign_static(&secure.static_public))?;
+    let len = handshake.write_message(&binding, &mut buf).map_err(noise_error)?;
+    self.write_frame(FRAME_HANDSHAKE, &buf[..len]).await?;
+
+    let message = self.expect_frame(FRAME_HANDSHAKE).await?;
+    let len = handshake.read_message(&message, &mut buf).map_err(noise_error)?;
+    let remote_static = handshake.get_remote_static().ok_or_else(|| invalid_data("missing remote static key"))?;
+    let remote_identity = verify_binding(&buf[..len], remote_static)?;
+
+    self.transport = Some(handshake.into_transport_mode().map_err(noise_error)?);
+    self.remote_identity = Some(remote_identity);
+    Ok(())
+  }
+
+  /// 핸드셰이크 중에는 정해진 프레임만 받는다.
+  async fn expect_frame(&mut self, expected: u8) -> std::io::Result<Vec<u8>> {
+    let (frame_type, payload) = timeout(HANDSHAKE_TIMEOUT, self.read_frame())
+      .await
+      .map_err(|_| invalid_data("handshake timed out"))??
+      .ok_or_else(|| invalid_data("connection closed during handshake"))?;
+    if frame_type != expected {
+      return Err(invalid_data("unexpected frame during handshake"));
+    }
+    Ok(payload)
+  }
+
+  async fn write_frame(&mut self, frame_type: u8, payload: &[u8]) -> std::io::Result<()> {
+    if payload.len() + 1 > MAX_FRAME_SIZE {
+      return Err(invalid_data("frame too large"));
+    }
+
+    let mut plain = Vec::with_capacity(1 + payload.len());
+    plain.push(frame_type);
+    plain.extend_from_slice(payload);
+
+    // Noise 메시지는 64 KiB를 넘을 수 없어 세그먼트(u16 길이 + 암호문)로 나눈다.
+    let body = match self.transport.as_mut() {
+      None => plain,
+      Some(transport) => {
+        let mut body = Vec::with_capacity(plain.len() + 2 + NOISE_TAG_SIZE);
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+        for segment in plain.chunks(NOISE_SEGMENT_SIZE) {
+          let len = transport.write_message(segment, &mut buf).map_err(noise_error)?;
+          body.extend_from_slice(&(len as u16).to_be_bytes());
+          body.extend_from_slice(&buf[..len]);
+        }
+        body
+      }
+    };
+
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    self.writer.write_all(&frame).await
+  }
+
+  async fn read_frame(&mut self) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+    let mut len_buf = [0u8; 4];
+    match self.reader.read_exact(&mut len_buf).await {
+      Ok(_) => {}
+      Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+      Err(err) => return Err(err),
+    }
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    let max_len = if self.transport.is_some() { MAX_SECURE_FRAME_SIZE } else { MAX_FRAME_SIZE };
+    if len == 0 || len > max_len {
+      return Err(invalid_data("invalid frame length"));
+    }
+
+    let mut body = vec![0u8; len];
+    self.reader.read_exact(&mut body).await?;
+
+    let mut frame = match self.transport.as_mut() {
+      None => body,
+      Some(transport) => {
+        let mut plain = Vec::with_capacity(len);
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+        let mut rest = body.as_slice();
+        while !rest.is_empty() {
+          let segment_len = match rest {
+            [hi, lo, ..] => u16::from_be_bytes([*hi, *lo]) as usize,
+            _ => return Err(invalid_data("truncated encrypted frame")),
+          };
+          let segment = rest
+            .get(2..2 + segment_len)
+            .ok_or_else(|| invalid_data("truncated encrypted frame"))?;
+          let read = transport.read_message(segment, &mut buf).map_err(noise_error)?;
+          plain.extend_from_slice(&buf[..read]);
+          rest = &rest[2 + segment_len..];
+        }
+        plain
+      }
+    };
+
+    if frame.is_empty() || frame.len() > MAX_FRAME_SIZE {
+      return Err(invalid_data("invalid frame length"));
+    }
+    let payload = frame.split_off(1);
+    Ok(Some((frame[0], payload)))
+  }
+
+  async fn write_line(&mut self, mut payload: Vec<u8>) -> std::io::Result<()> {
+    if payload.len() + 1 > MAX_FRAME_SIZE {
+      return Err(invalid_data("message too large"));
+    }
+    payload.push(b'\n');
+    self.writer.write_all(&payload).await
+  }
+
+  async fn read_line(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = (&mut self.reader)
+      .take(MAX_FRAME_SIZE as u64)
+      .read_until(b'\n', &mut line)
+      .await?;
+    if read == 0 {
+      return Ok(None);
+    }
+    if line.last() != Some(&b'\n') && read as u64 >= MAX_FRAME_SIZE as u64 {
+      return Err(invalid_data("line too long"));
+    }
+    Ok(Some(line))
+  }
+}
+
+/// HELLO 프레임 본문. 키가 준비되지 않았으면 `noise`를 광고하지 않는다.
+pub fn hello_payload(peer_id: &str, user_id: &str, secure: bool) -> Value {
+  let capabilities = CAPABILITIES
+    .iter()
+    .filter(|c| secure || **c != "noise")
+    .collect::<Vec<_>>();
+  json!({
+    "protocol": "edulinker-p2p",
+    "version": PROTOCOL_VERSION,
+    "capabilities": capabilities,
+    "peerId": peer_id,
+    "userId": user_id
+  })
+}
+
+fn hello_capabilities(hello: &Value) -> Vec<String> {
+  hello
+    .get("capabilities")
+    .and_then(|v| v.as_array())
+    .map(|caps| caps.iter().filter_map(|c| c.as_str().map(|c| c.to_string())).collect())
+    .unwrap_or_default()
+}
+
+/// 양쪽 HELLO를 prologue로 묶어, 중간에서 협상 내용을 바꾸면 핸드셰이크가 실패하게 한다.
+fn noise_builder<'a>(secure: &'a SecureConfig, prologue: &'a [u8]) -> std::io::Result<snow::Builder<'a>> {
+  let params = NOISE_PARAMS.parse().map_err(noise_error)?;
+  Ok(snow::Builder::new(params).local_private_key(&secure.static_secret).prologue(prologue))
+}
+
+/// 상대 정적 키에 대한 신원 키 서명을 확인하고 신원 키를 돌려준다.
+fn verify_binding(payload: &[u8], remote_static: &[u8]) -> std::io::Result<String> {
+  let binding = serde_json::from_slice::<Value>(payload)?;
+  p2p_crypto::verify_static_binding(&binding, remote_static).map_err(|err| invalid_data(&err))
+}
+
+fn noise_error(err: snow::Error) -> std::io::Error {
+  invalid_data(&format!("noise: {err}"))
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
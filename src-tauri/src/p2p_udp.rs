@@ -0,0 +1,327 @@
+//! 내부 P2P UDP 신뢰 전송
+//!
+//! TCP가 실패했을 때 쓰는 UDP 경로다. 메시지를 `udp_frag` 조각으로 나눠 보내고,
+//! 수신 측은 조각이 모두 모이면 재조립한 뒤 `udp_ack`를 돌려준다.
+//! 송신 측은 ack를 받을 때까지 재전송 타이머를 늘려 가며 다시 보낸다.
+
+use base64::Engine;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time::timeout_at;
+
+/// 조각 하나에 담는 원본 바이트 수. base64와 헤더를 더해도 일반적인 MTU 안에 들어간다.
+pub const FRAGMENT_SIZE: usize = 800;
+/// 한 메시지의 최대 조각 수 (약 800 KiB)
+pub const MAX_FRAGMENTS: usize = 1024;
+/// 수신 버퍼 크기. UDP 데이터그램 최대 크기와 같다.
+pub const MAX_DATAGRAM_SIZE: usize = 65_536;
+
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+const MAX_ATTEMPTS: u32 = 4;
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+const SEEN_TTL: Duration = Duration::from_secs(120);
+const MAX_PENDING: usize = 256;
+
+/// 조각 수신 결과
+pub enum Received {
+  /// 마지막 조각까지 모여 재조립된 메시지. ack를 보내고 처리한다.
+  Complete(String, Vec<u8>),
+  /// 이미 처리한 메시지의 재전송. ack만 다시 보낸다.
+  Duplicate(String),
+  /// 아직 모이지 않았거나 잘못된 조각
+  Partial,
+}
+
+struct Pending {
+  count: usize,
+  fragments: BTreeMap<usize, Vec<u8>>,
+  started: Instant,
+}
+
+/// 송신자별 조각 재조립기
+#[derive(Default)]
+pub struct Reassembler {
+  pending: HashMap<(IpAddr, String), Pending>,
+  seen: HashMap<(IpAddr, String), Instant>,
+}
+
+impl Reassembler {
+  pub fn accept(&mut self, ip: IpAddr, fragment: &Value) -> Received {
+    self.expire();
+
+    let Some(udp_id) = fragment.get("udpId").and_then(|v| v.as_str()) else { return Received::Partial; };
+    let index = fragment.get("index").and_then(|v| v.as_u64()).unwrap_or(u64::MAX) as usize;
+    let count = fragment.get("count").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+    let data = fragment
+      .get("data")
+      .and_then(|v| v.as_str())
+      .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok());
+    let Some(data) = data else { return Received::Partial; };
+    if count == 0 || count > MAX_FRAGMENTS || index >= count || data.len() > FRAGMENT_SIZE {
+      return Received::Partial;
+    }
+
+    let key = (ip, udp_id.to_string());
+    if self.seen.contains_key(&key) {
+      return Received::Duplicate(key.1);
+    }
+
+    if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING {
+      return Received::Partial;
+    }
+    let pending = self.pending.entry(key.clone()).or_insert_with(|| Pending {
+      count,
+      fragments: BTreeMap::new(),
+      started: Instant::now(),
+    });
+    if pending.count != count {
+      return Received::Partial;
+    }
+    pending.fragments.insert(index, data);
+    if pending.fragments.len() < pending.count {
+      return Received::Partial;
+    }
+
+    let Some(pending) = self.pending.remove(&key) else { return Received::Partial; };
+    let payload = pending.fragments.into_values().flatten().collect();
+    self.seen.insert(key.clone(), Instant::now());
+    Received::Complete(key.1, payload)
+  }
+
+  fn expire(&mut self) {
+    self.pending.retain(|_, pending| pending.started.elapsed() < REASSEMBLY_TIMEOUT);
+    self.seen.retain(|_, at| at.elapsed() < SEEN_TTL);
+  }
+}
+
+/// 메시지를 조각 데이터그램으로 나눈다.
+pub fn fragments(udp_id: &str, payload: &[u8]) -> Vec<Vec<u8>> {
+  let chunks: Vec<&[u8]> = if payload.is_empty() {
+    vec![&[]]
+  } else {
+    payload.chunks(FRAGMENT_SIZE).collect()
+  };
+  let count = chunks.len();
+
+  chunks
+    .into_iter()
+    .enumerate()
+    .map(|(index, chunk)| {
+      json!({
+        "type": "udp_frag",
+        "udpId": udp_id,
+        "index": index,
+        "count": count,
+        "data": base64::engine::general_purpose::STANDARD.encode(chunk)
+      })
+      .to_string()
+      .into_bytes()
+    })
+    .collect()
+}
+
+pub fn ack(udp_id: &str) -> Vec<u8> {
+  json!({"type": "udp_ack", "udpId": udp_id}).to_string().into_bytes()
+}
+
+/// 메시지를 조각으로 `local`에서 보내고 수신 측의 ack를 기다린다. ack를 받았을 때만 `true`.
+pub async fn send_reliable(local: SocketAddr, target: SocketAddr, message: &Value) -> bool {
+  let Ok(payload) = serde_json::to_vec(message) else { return false; };
+  let udp_id = uuid::Uuid::new_v4().to_string();
+  let datagrams = fragments(&udp_id, &payload);
+  if datagrams.len() > MAX_FRAGMENTS {
+    return false;
+  }
+
+  let Ok(socket) = UdpSocket::bind(local).await else { return false; };
+
+  let mut buf = vec![0u8; 1024];
+  let mut rto = INITIAL_RTO;
+  for _ in 0..MAX_ATTEMPTS {
+    for datagram in &datagrams {
+      if socket.send_to(datagram, target).await.is_err() {
+        return false;
+      }
+    }
+
+    let deadline = tokio::time::Instant::now() + rto;
+    while let Ok(Ok((len, from))) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+      if from.ip() != target.ip() {
+        continue;
+      }
+      let Ok(reply) = serde_json::from_slice::<Value>(&buf[..len]) else { continue; };
+      if reply.get("type").and_then(|v| v.as_str()) == Some("udp_ack")
+        && reply.get("udpId").and_then(|v| v.as_str()) == Some(udp_id.as_str())
+      {
+        return true;
+      }
+    }
+
+    rto *= 2;
+  }
+
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::Ipv4Addr;
+
+  const SENDER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+  fn parsed(udp_id: &str, payload: &[u8]) -> Vec<Value> {
+    fragments(udp_id, payload)
+      .iter()
+      .map(|datagram| serde_json::from_slice(datagram).unwrap())
+      .collect()
+  }
+
+  fn payload_of(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+  }
+
+  #[test]
+  fn reassembles_fragments_received_out_of_order() {
+    let payload = payload_of(FRAGMENT_SIZE * 3 + 17);
+    let mut frags = parsed("out-of-order", &payload);
+    assert_eq!(frags.len(), 4);
+    frags.reverse();
+
+    let mut reassembler = Reassembler::default();
+    for fragment in &frags[..3] {
+      assert!(matches!(reassembler.accept(SENDER, fragment), Received::Partial));
+    }
+    match reassembler.accept(SENDER, &frags[3]) {
+      Received::Complete(udp_id, data) => {
+        assert_eq!(udp_id, "out-of-order");
+        assert_eq!(data, payload);
+      }
+      _ => panic!("expected a complete message"),
+    }
+  }
+
+  #[test]
+  fn duplicate_fragments_are_absorbed_and_resends_reported() {
+    let payload = payload_of(FRAGMENT_SIZE * 2);
+    let frags = parsed("duplicate", &payload);
+
+    let mut reassembler = Reassembler::default();
+    assert!(matches!(reassembler.accept(SENDER, &frags[0]), Received::Partial));
+    assert!(matches!(reassembler.accept(SENDER, &frags[0]), Received::Partial));
+    assert!(matches!(reassembler.accept(SENDER, &frags[1]), Received::Complete(_, ref data) if *data == payload));
+
+    // ack를 잃어 송신 측이 다시 보낸 조각은 처리하지 않고 ack만 다시 보낸다.
+    for fragment in &frags {
+      assert!(matches!(reassembler.accept(SENDER, fragment), Received::Duplicate(ref id) if id == "duplicate"));
+    }
+  }
+
+  #[test]
+  fn large_payload_round_trips() {
+    let payload = payload_of(20_000);
+    let datagrams = fragments("large", &payload);
+    assert_eq!(datagrams.len(), 20_000usize.div_ceil(FRAGMENT_SIZE));
+    assert!(datagrams.iter().all(|datagram| datagram.len() < 1400));
+
+    let mut reassembler = Reassembler::default();
+    let mut complete = None;
+    for datagram in &datagrams {
+      let fragment = serde_json::from_slice::<Value>(datagram).unwrap();
+      if let Received::Complete(_, data) = reassembler.accept(SENDER, &fragment) {
+        complete = Some(data);
+      }
+    }
+    assert_eq!(complete, Some(payload));
+  }
+
+  #[test]
+  fn fragments_from_different_senders_are_kept_apart() {
+    let frags = parsed("shared-id", &payload_of(FRAGMENT_SIZE * 2));
+    let other = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+    let mut reassembler = Reassembler::default();
+    assert!(matches!(reassembler.accept(SENDER, &frags[0]), Received::Partial));
+    assert!(matches!(reassembler.accept(other, &frags[1]), Received::Partial));
+  }
+
+  #[test]
+  fn malformed_fragments_are_ignored() {
+    let mut reassembler = Reassembler::default();
+    let data = base64::engine::general_purpose::STANDARD.encode(b"x");
+    let bad = [
+      json!({"type": "udp_frag", "index": 0, "count": 1, "data": data}),
+      json!({"type": "udp_frag", "udpId": "a", "index": 1, "count": 1, "data": data}),
+      json!({"type": "udp_frag", "udpId": "a", "index": 0, "count": 0, "data": data}),
+      json!({"type": "udp_frag", "udpId": "a", "index": 0, "count": MAX_FRAGMENTS + 1, "data": data}),
+      json!({"type": "udp_frag", "udpId": "a", "index": 0, "count": 1, "data": "not base64!"}),
+    ];
+    for fragment in &bad {
+      assert!(matches!(reassembler.accept(SENDER, fragment), Received::Partial));
+    }
+  }
+
+  /// 받은 조각을 재조립해 `drop_rounds`번째 완성까지는 ack하지 않는 수신 측
+  async fn receiver(drop_rounds: usize) -> (SocketAddr, tokio::task::JoinHandle<Option<Vec<u8>>>) {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let task = tokio::spawn(async move {
+      let mut reassembler = Reassembler::default();
+      let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+      let mut rounds = 0;
+      let mut received = None;
+      loop {
+        let Ok(Ok((len, from))) = tokio::time::timeout(Duration::from_secs(10), socket.recv_from(&mut buf)).await else {
+          return received;
+        };
+        let fragment = serde_json::from_slice::<Value>(&buf[..len]).unwrap();
+        let udp_id = match reassembler.accept(from.ip(), &fragment) {
+          Received::Complete(udp_id, data) => {
+            received = Some(data);
+            udp_id
+          }
+          Received::Duplicate(udp_id) => udp_id,
+          Received::Partial => continue,
+        };
+        rounds += 1;
+        if rounds > drop_rounds {
+          socket.send_to(&ack(&udp_id), from).await.unwrap();
+          return received;
+        }
+      }
+    });
+    (addr, task)
+  }
+
+  fn local() -> SocketAddr {
+    SocketAddr::new(SENDER, 0)
+  }
+
+  #[tokio::test]
+  async fn send_reliable_succeeds_once_acknowledged() {
+    let (target, task) = receiver(0).await;
+    let message = json!({"type": "chat", "content": "a".repeat(10_000)});
+    assert!(send_reliable(local(), target, &message).await);
+    let received = task.await.unwrap().unwrap();
+    assert_eq!(serde_json::from_slice::<Value>(&received).unwrap(), message);
+  }
+
+  #[tokio::test]
+  async fn send_reliable_retransmits_until_acknowledged() {
+    // 첫 번째 전송의 ack를 잃은 것과 같다.
+    let (target, task) = receiver(1).await;
+    let message = json!({"type": "chat", "content": "다시 보내기"});
+    assert!(send_reliable(local(), target, &message).await);
+    assert!(task.await.unwrap().is_some());
+  }
+
+  #[tokio::test]
+  async fn send_reliable_fails_without_an_ack() {
+    let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let target = silent.local_addr().unwrap();
+    assert!(!send_reliable(local(), target, &json!({"type": "chat", "content": "응답 없음"})).await);
+  }
+}
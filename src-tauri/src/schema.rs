@@ -0,0 +1,320 @@
+//! 로컬 SQLite 스키마
+//!
+//! 앱과 헤드리스 데몬이 같은 `local.db`를 쓰므로 스키마와 마이그레이션을 한곳에 둔다.
+
+use rusqlite::Connection;
+
+pub fn init_db(conn: &Connection) -> rusqlite::Result<()> {
+  conn.execute_batch(
+    "
+    CREATE TABLE IF NOT EXISTS auth_store (
+      id INTEGER PRIMARY KEY CHECK (id = 1),
+      token TEXT,
+      user_json TEXT,
+      expires_at INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS offline_users (
+      id INTEGER PRIMARY KEY AUTOINCREMENT,
+      email TEXT UNIQUE,
+      password_hash TEXT,
+      name TEXT,
+      role TEXT,
+      region TEXT,
+      school TEXT,
+      grade TEXT,
+      class_name TEXT,
+      classroom TEXT,
+      workplace TEXT,
+      job_title TEXT,
+      admin_duties TEXT,
+      extension_number TEXT,
+      phone_number TEXT,
+      profile_completed INTEGER,
+      created_at INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS offline_sessions (
+      token TEXT PRIMARY KEY,
+      user_id INTEGER,
+      expires_at INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS address_book (
+      id INTEGER PRIMARY KEY AUTOINCREMENT,
+      user_id TEXT,
+      name TEXT,
+      email TEXT,
+      phone TEXT,
+      role TEXT,
+      school_id TEXT,
+      ip_address TEXT,
+      hostname TEXT,
+      os TEXT,
+      platform TEXT,
+      last_seen TEXT,
+      is_online INTEGER,
+      synced INTEGER,
+      created_at TEXT,
+      updated_at TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS messages (
+      id INTEGER PRIMARY KEY AUTOINCREMENT,
+      sender_id TEXT,
+      recipient_id TEXT,
+      content TEXT,
+      message_type TEXT,
+      timestamp TEXT,
+      is_read INTEGER,
+      delivered INTEGER,
+      synced INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS group_messages (
+      id TEXT PRIMARY KEY,
+      content TEXT,
+      message_type TEXT,
+      timestamp TEXT,
+      sender_id TEXT,
+      recipients TEXT,
+      is_read INTEGER,
+      delivered INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS device_info (
+      device_id TEXT PRIMARY KEY,
+      user_id TEXT,
+      hostname TEXT,
+      ip_address TEXT,
+      mac_address TEXT,
+      os TEXT,
+      platform TEXT,
+      installed_at TEXT,
+      last_seen TEXT,
+      synced INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS error_report_images (
+      id INTEGER PRIMARY KEY AUTOINCREMENT,
+      session_id TEXT,
+      file_name TEXT,
+      file_data BLOB,
+      mime_type TEXT,
+      created_at TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS shared_folders (
+      id TEXT PRIMARY KEY,
+      name TEXT,
+      path TEXT,
+      encrypted INTEGER,
+      password TEXT,
+      created_at TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS p2p_messages (
+      id INTEGER PRIMARY KEY AUTOINCREMENT,
+      message_id TEXT UNIQUE,
+      sender_id TEXT,
+      recipient_id TEXT,
+      content TEXT,
+      message_type TEXT,
+      timestamp TEXT,
+      is_read INTEGER DEFAULT 0,
+      delivered INTEGER DEFAULT 0,
+      read_at TEXT,
+      delivered_at TEXT,
+      network_type TEXT DEFAULT 'p2p'
+    );
+
+    CREATE TABLE IF NOT EXISTS p2p_file_transfers (
+      transfer_id TEXT PRIMARY KEY,
+      peer_id TEXT,
+      peer_name TEXT,
+      file_name TEXT,
+      file_size INTEGER,
+      total_chunks INTEGER,
+      direction TEXT,
+      status TEXT,
+      file_path TEXT,
+      bytes_transferred INTEGER DEFAULT 0,
+      progress INTEGER DEFAULT 0,
+      received_chunks BLOB,
+      kind TEXT DEFAULT 'file',
+      manifest TEXT,
+      created_at TEXT,
+      updated_at TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS p2p_identity (
+      id INTEGER PRIMARY KEY CHECK (id = 1),
+      x25519_secret BLOB,
+      ed25519_secret BLOB,
+      created_at TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS p2p_peer_keys (
+      user_id TEXT PRIMARY KEY,
+      identity_key TEXT,
+      pending_key TEXT,
+      verified INTEGER DEFAULT 0,
+      first_seen TEXT,
+      updated_at TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS p2p_peer_devices (
+      user_id TEXT NOT NULL,
+      identity_key TEXT NOT NULL,
+      added_at TEXT,
+      PRIMARY KEY (user_id, identity_key)
+    );
+
+    CREATE TABLE IF NOT EXISTS p2p_outbound_queue (
+      message_id TEXT NOT NULL,
+      receiver_id TEXT,
+      message_type TEXT,
+      payload TEXT,
+      attempts INTEGER DEFAULT 0,
+      next_attempt_at INTEGER,
+      expires_at INTEGER,
+      last_error TEXT,
+      created_at TEXT,
+      relay_holder TEXT,
+      device TEXT NOT NULL DEFAULT '',
+      PRIMARY KEY (message_id, device)
+    );
+
+    CREATE TABLE IF NOT EXISTS p2p_relay_store (
+      message_id TEXT PRIMARY KEY,
+      recipient_id TEXT,
+      sender_id TEXT,
+      message_type TEXT,
+      payload TEXT,
+      expires_at INTEGER,
+      created_at TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS p2p_seen_messages (
+      sender_id TEXT NOT NULL,
+      message_id TEXT NOT NULL,
+      seen_at INTEGER NOT NULL,
+      PRIMARY KEY (sender_id, message_id)
+    );
+
+    CREATE TABLE IF NOT EXISTS p2p_conversation_clocks (
+      conversation_id TEXT PRIMARY KEY,
+      counter INTEGER NOT NULL DEFAULT 0,
+      last_sent INTEGER NOT NULL DEFAULT 0
+    );
+
+    CREATE TABLE IF NOT EXISTS discovered_devices (
+      device_id TEXT PRIMARY KEY,
+      hostname TEXT,
+      ip_address TEXT,
+      mac_address TEXT,
+      os TEXT,
+      platform TEXT,
+      user_id TEXT,
+      last_seen TEXT,
+      discovery_version TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS app_settings (
+      key TEXT PRIMARY KEY,
+      value TEXT,
+      updated_at TEXT
+    );
+
+    CREATE UNIQUE INDEX IF NOT EXISTS idx_address_book_user_id ON address_book(user_id);
+    CREATE INDEX IF NOT EXISTS idx_p2p_messages_sender ON p2p_messages(sender_id);
+    CREATE INDEX IF NOT EXISTS idx_p2p_messages_recipient ON p2p_messages(recipient_id);
+    CREATE INDEX IF NOT EXISTS idx_p2p_messages_timestamp ON p2p_messages(timestamp);
+    CREATE INDEX IF NOT EXISTS idx_p2p_relay_store_recipient ON p2p_relay_store(recipient_id);
+    CREATE INDEX IF NOT EXISTS idx_p2p_seen_messages_seen_at ON p2p_seen_messages(seen_at);
+    ")?;
+  ensure_message_columns(conn)?;
+  ensure_queue_columns(conn)?;
+
+  Ok(())
+}
+
+fn ensure_message_columns(conn: &Connection) -> rusqlite::Result<()> {
+  let mut stmt = conn.prepare("PRAGMA table_info(messages)")?;
+  let column_iter = stmt.query_map([], |row| row.get::<_, String>(1))?;
+
+  let mut columns = Vec::new();
+  for col in column_iter {
+    columns.push(col?);
+  }
+
+  if !columns.iter().any(|c| c == "message_id") {
+    conn.execute("ALTER TABLE messages ADD COLUMN message_id TEXT", [])?;
+  }
+  if !columns.iter().any(|c| c == "read_at") {
+    conn.execute("ALTER TABLE messages ADD COLUMN read_at TEXT", [])?;
+  }
+  if !columns.iter().any(|c| c == "delivered_at") {
+    conn.execute("ALTER TABLE messages ADD COLUMN delivered_at TEXT", [])?;
+  }
+  // 대화별 순서 번호와 받은 시각(Unix ms). 이전에 받은 메시지는 둘 다 비어 있다.
+  if !columns.iter().any(|c| c == "seq") {
+    conn.execute("ALTER TABLE messages ADD COLUMN seq INTEGER", [])?;
+  }
+  if !columns.iter().any(|c| c == "received_at") {
+    conn.execute("ALTER TABLE messages ADD COLUMN received_at INTEGER", [])?;
+  }
+  // 다른 피어에게서 기록을 받아 채운 메시지는 `p2p-sync`
+  if !columns.iter().any(|c| c == "network_type") {
+    conn.execute("ALTER TABLE messages ADD COLUMN network_type TEXT DEFAULT 'p2p'", [])?;
+  }
+
+  Ok(())
+}
+
+fn ensure_queue_columns(conn: &Connection) -> rusqlite::Result<()> {
+  let mut stmt = conn.prepare("PRAGMA table_info(p2p_outbound_queue)")?;
+  let column_iter = stmt.query_map([], |row| row.get::<_, String>(1))?;
+
+  let mut columns = Vec::new();
+  for col in column_iter {
+    columns.push(col?);
+  }
+
+  if !columns.iter().any(|c| c == "relay_holder") {
+    conn.execute("ALTER TABLE p2p_outbound_queue ADD COLUMN relay_holder TEXT", [])?;
+  }
+
+  // 한 메시지를 받는 사람의 기기마다 따로 대기시킬 수 있도록 기본 키에 기기를 넣는다.
+  // 기존 행은 아무 기기에나 보내는 항목(`device = ''`)이 된다.
+  if !columns.iter().any(|c| c == "device") {
+    conn.execute_batch(
+      "
+      BEGIN;
+      ALTER TABLE p2p_outbound_queue RENAME TO p2p_outbound_queue_old;
+      CREATE TABLE p2p_outbound_queue (
+        message_id TEXT NOT NULL,
+        receiver_id TEXT,
+        message_type TEXT,
+        payload TEXT,
+        attempts INTEGER DEFAULT 0,
+        next_attempt_at INTEGER,
+        expires_at INTEGER,
+        last_error TEXT,
+        created_at TEXT,
+        relay_holder TEXT,
+        device TEXT NOT NULL DEFAULT '',
+        PRIMARY KEY (message_id, device)
+      );
+      INSERT INTO p2p_outbound_queue
+        (message_id, receiver_id, message_type, payload, attempts, next_attempt_at, expires_at, last_error, created_at, relay_holder)
+        SELECT message_id, receiver_id, message_type, payload, attempts, next_attempt_at, expires_at, last_error, created_at, relay_holder
+        FROM p2p_outbound_queue_old;
+      DROP TABLE p2p_outbound_queue_old;
+      COMMIT;
+      ",
+    )?;
+  }
+
+  Ok(())
+}
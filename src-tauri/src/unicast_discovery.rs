@@ -0,0 +1,155 @@
+//! 브로드캐스트가 막힌 네트워크를 위한 유니캐스트 디스커버리
+//!
+//! VLAN이나 무선 AP 격리로 브로드캐스트가 다른 구간에 닿지 않는 학교가 많다.
+//! 설정의 고정 피어/허브 주소(`p2pStaticPeers`)와 스윕 대역(`p2pDiscoverySweep`)으로
+//! 디스커버리 패킷을 직접 보내, 다른 구간의 피어도 응답을 돌려주고 피어 목록에 나타나게 한다.
+
+use ipnet::Ipv4Net;
+use rusqlite::params;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::host::CoreHost;
+use crate::net_interfaces;
+
+/// 쉼표나 공백으로 구분한 `host`, `host:port`, `ip`, `ip:port` 목록
+pub const STATIC_PEERS_SETTING: &str = "p2pStaticPeers";
+/// 쉼표나 공백으로 구분한 CIDR 목록 (예: `10.20.0.0/22`)
+pub const SWEEP_SETTING: &str = "p2pDiscoverySweep";
+/// 대역 스윕은 고정 주소보다 드물게 보낸다.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const STATIC_PEERS_ENV: &str = "INTERNAL_P2P_STATIC_PEERS";
+const SWEEP_ENV: &str = "INTERNAL_P2P_DISCOVERY_SWEEP";
+/// /20보다 넓은 대역은 스윕하지 않는다.
+const MIN_SWEEP_PREFIX: u8 = 20;
+const SWEEP_BATCH: usize = 64;
+const SWEEP_BATCH_DELAY: Duration = Duration::from_millis(20);
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 유니캐스트로 디스커버리를 보낼 대상
+#[derive(Clone, Default)]
+pub struct UnicastTargets {
+  pub hosts: Vec<String>,
+  pub sweep: Vec<Ipv4Net>,
+}
+
+impl UnicastTargets {
+  /// 앱 설정과 환경 변수에서 대상을 읽는다. 설정은 매번 다시 읽으므로 재시작 없이 반영된다.
+  pub async fn load(app: &CoreHost) -> Self {
+    let app = app.clone();
+    tokio::task::spawn_blocking(move || {
+      let static_peers = [app_setting(&app, STATIC_PEERS_SETTING), std::env::var(STATIC_PEERS_ENV).ok()];
+      let sweep = [app_setting(&app, SWEEP_SETTING), std::env::var(SWEEP_ENV).ok()];
+      Self::parse(
+        &static_peers.into_iter().flatten().collect::<Vec<_>>().join(","),
+        &sweep.into_iter().flatten().collect::<Vec<_>>().join(","),
+      )
+    })
+    .await
+    .unwrap_or_default()
+  }
+
+  pub fn parse(static_peers: &str, sweep: &str) -> Self {
+    let mut hosts = Vec::new();
+    for host in split_list(static_peers) {
+      if !hosts.contains(&host) {
+        hosts.push(host);
+      }
+    }
+
+    let mut nets = Vec::new();
+    for net in split_list(sweep).filter_map(|cidr| cidr.parse::<Ipv4Net>().ok()) {
+      let net = net.trunc();
+      if net.prefix_len() >= MIN_SWEEP_PREFIX && !nets.contains(&net) {
+        nets.push(net);
+      }
+    }
+
+    Self { hosts, sweep: nets }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.hosts.is_empty() && self.sweep.is_empty()
+  }
+}
+
+/// 고정 주소로 패킷을 보낸다. 이름은 매번 해석하므로 허브의 DHCP 주소가 바뀌어도 따라간다.
+/// `socket`은 IPv4 소켓이고, IPv6 주소로는 필요할 때 따로 연 소켓으로 보낸다.
+pub async fn send_to_hosts(socket: &UdpSocket, data: &[u8], hosts: &[String], default_port: u16) -> usize {
+  let mut socket_v6 = None;
+  let mut sent = 0;
+  for host in hosts {
+    for addr in resolve(host, default_port).await {
+      let result = if addr.is_ipv4() {
+        socket.send_to(data, addr).await
+      } else {
+        if socket_v6.is_none() {
+          socket_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+        }
+        let Some(socket_v6) = &socket_v6 else { continue; };
+        socket_v6.send_to(data, addr).await
+      };
+      if result.is_ok() {
+        sent += 1;
+      }
+    }
+  }
+  sent
+}
+
+/// 대역의 모든 호스트 주소로 패킷을 보낸다. 스위치에 부담을 주지 않도록 나눠서 보낸다.
+pub async fn sweep(socket: &UdpSocket, data: &[u8], nets: &[Ipv4Net], port: u16, own_ip: Option<IpAddr>) -> usize {
+  let mut sent = 0;
+  for net in nets {
+    for host in net.hosts() {
+      if own_ip == Some(IpAddr::V4(host)) {
+        continue;
+      }
+      if socket.send_to(data, (host, port)).await.is_ok() {
+        sent += 1;
+      }
+      if sent % SWEEP_BATCH == 0 {
+        tokio::time::sleep(SWEEP_BATCH_DELAY).await;
+      }
+    }
+  }
+  sent
+}
+
+async fn resolve(host: &str, default_port: u16) -> Vec<SocketAddr> {
+  if let Ok(addr) = host.parse::<SocketAddr>() {
+    return vec![addr];
+  }
+  if let Some(addr) = net_interfaces::socket_addr(host, default_port) {
+    return vec![addr];
+  }
+
+  match host.rsplit_once(':') {
+    Some((_, port)) if port.parse::<u16>().is_ok() => match timeout(RESOLVE_TIMEOUT, tokio::net::lookup_host(host)).await {
+      Ok(Ok(addrs)) => addrs.collect(),
+      _ => Vec::new(),
+    },
+    _ => match timeout(RESOLVE_TIMEOUT, tokio::net::lookup_host((host, default_port))).await {
+      Ok(Ok(addrs)) => addrs.collect(),
+      _ => Vec::new(),
+    },
+  }
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = String> + '_ {
+  value
+    .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+    .map(|item| item.trim())
+    .filter(|item| !item.is_empty())
+    .map(|item| item.to_string())
+}
+
+fn app_setting(app: &CoreHost, key: &str) -> Option<String> {
+  let conn = app.open_db().ok()?;
+  conn
+    .query_row("SELECT value FROM app_settings WHERE key = ?1", params![key], |row| row.get::<_, String>(0))
+    .ok()
+}
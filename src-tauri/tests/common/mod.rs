@@ -0,0 +1,307 @@
+//! 루프백 P2P 테스트 하니스
+//!
+//! 127.0.0.x 주소마다 P2P 인스턴스를 하나씩 띄운다. 포트는 `INTERNAL_P2P_DISCOVERY_PORT`,
+//! `INTERNAL_P2P_MESSAGE_PORT`, `INTERNAL_P2P_TCP_PORT`를 따르고, 없으면 앱과 겹치지 않는
+//! 테스트 포트를 쓴다. 모든 인스턴스가 같은 포트를 쓰고 주소로 구분되므로 실제 교실과 같다.
+//! 디스커버리는 다른 인스턴스 주소를 `p2pStaticPeers`로 넣어 유니캐스트로 한다.
+//!
+//! 127.0.0.1 외의 루프백 주소를 쓸 수 없는 시스템(macOS 기본 설정)에서는 테스트를 건너뛴다.
+
+#![allow(dead_code)]
+
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use edulinker_core::discovery_hub::DiscoveryHub;
+use edulinker_core::host::{CoreHost, NetConfig, RecordingSink, TempDataDir};
+use edulinker_core::internal_p2p::InternalP2PManager;
+use edulinker_core::network_discovery::NetworkDiscoveryManager;
+use edulinker_core::schema;
+
+pub const SCHOOL_ID: &str = "loopback-test";
+/// 손실과 재시작이 있어도 대기열 재시도(15초) 한 번은 기다린다.
+pub const WAIT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// 같은 프로세스의 테스트끼리 주소가 겹치지 않도록 나눠 준다.
+static NEXT_HOST: AtomicU8 = AtomicU8::new(10);
+
+pub struct Peer {
+  pub user_id: String,
+  pub ip: IpAddr,
+  pub events: Arc<RecordingSink>,
+  pub internal: InternalP2PManager,
+  hub: DiscoveryHub,
+  discovery: NetworkDiscoveryManager,
+  host: CoreHost,
+  storage: Arc<TempDataDir>,
+}
+
+impl Peer {
+  fn new(user_id: &str, ip: IpAddr, net: NetConfig, static_peers: &[IpAddr]) -> Self {
+    let events = Arc::new(RecordingSink::new());
+    let storage = Arc::new(TempDataDir::new(user_id).expect("temp data dir"));
+    let host = CoreHost::new(events.clone(), storage.clone()).with_net(net);
+
+    let conn = host.open_db().expect("open db");
+    schema::init_db(&conn).expect("init db");
+    let static_peers = static_peers.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(",");
+    set_setting(&conn, "p2pStaticPeers", &static_peers);
+    set_setting(&conn, "p2pDiscoveryBackend", "broadcast");
+
+    Self {
+      user_id: user_id.to_string(),
+      ip,
+      events,
+      internal: InternalP2PManager::new(host.clone()),
+      hub: DiscoveryHub::new(host.clone()),
+      discovery: NetworkDiscoveryManager::new(host.clone()),
+      host,
+      storage,
+    }
+  }
+
+  pub async fn start(&self) {
+    let port = self
+      .hub
+      .ensure_started(self.host.net().discovery_port, self.internal.clone(), self.discovery.clone())
+      .await
+      .expect("discovery hub");
+    self
+      .internal
+      .start(self.user_id.clone(), self.user_id.clone(), Some(SCHOOL_ID.to_string()), port)
+      .await
+      .expect("p2p start");
+  }
+
+  pub async fn stop(&self) {
+    let _ = self.internal.stop().await;
+    self.hub.stop().await;
+    // 취소된 수신 태스크가 포트를 놓을 때까지 기다린다.
+    tokio::time::sleep(Duration::from_millis(300)).await;
+  }
+
+  /// 프로세스를 다시 띄운 것처럼 멈췄다가 같은 데이터 폴더로 다시 시작한다.
+  pub async fn restart(&self) {
+    self.stop().await;
+    self.start().await;
+  }
+
+  pub fn db(&self) -> Connection {
+    self.host.open_db().expect("open db")
+  }
+
+  pub fn set_setting(&self, key: &str, value: &str) {
+    set_setting(&self.db(), key, value);
+  }
+
+  /// 다른 주소의 누군가가 이 인스턴스의 메시지 포트로 JSON 데이터그램을 보낸다.
+  pub fn send_raw_udp(&self, message: &Value) {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind raw socket");
+    let target = (self.ip, self.host.net().udp_message_port);
+    socket.send_to(message.to_string().as_bytes(), target).expect("send raw datagram");
+  }
+
+  /// `from` 주소에서 이 인스턴스의 디스커버리 포트로 알림을 보낸다.
+  pub fn send_raw_discovery(&self, from: IpAddr, message: &Value) {
+    let socket = std::net::UdpSocket::bind((from, 0)).expect("bind raw socket");
+    let target = (self.ip, self.host.net().discovery_port);
+    socket.send_to(message.to_string().as_bytes(), target).expect("send raw datagram");
+  }
+
+  /// 결과가 한 행 한 열인 쿼리
+  pub fn query_i64(&self, sql: &str, args: &[&dyn rusqlite::ToSql]) -> i64 {
+    self.db().query_row(sql, args, |row| row.get(0)).expect(sql)
+  }
+
+  /// 결과가 한 행 한 열인 텍스트 쿼리
+  pub fn query_text(&self, sql: &str, args: &[&dyn rusqlite::ToSql]) -> String {
+    self.db().query_row(sql, args, |row| row.get(0)).expect(sql)
+  }
+
+  pub fn download_dir(&self) -> std::path::PathBuf {
+    self.host.download_dir().expect("download dir")
+  }
+
+  pub async fn online_peers(&self) -> Vec<String> {
+    let peers = self.internal.get_peers().await;
+    peers
+      .get("onlinePeers")
+      .and_then(|v| v.as_array())
+      .map(|list| {
+        list
+          .iter()
+          .filter_map(|peer| peer.get("userId").and_then(|v| v.as_str()).map(|s| s.to_string()))
+          .collect()
+      })
+      .unwrap_or_default()
+  }
+
+  /// `event` 중 `matches`를 만족하는 첫 페이로드를 기다린다.
+  pub async fn wait_for_event(&self, event: &str, matches: impl Fn(&Value) -> bool) -> Value {
+    let events = self.events.clone();
+    wait_until(&format!("{event} on {}", self.user_id), || {
+      let found = events.payloads(event).into_iter().find(|payload| matches(payload));
+      async move { found }
+    })
+    .await
+  }
+
+  /// `event`를 정확히 `count`번 받을 때까지 기다린다.
+  pub async fn wait_for_count(&self, event: &str, count: usize) -> Vec<Value> {
+    let events = self.events.clone();
+    wait_until(&format!("{count} x {event} on {}", self.user_id), || {
+      let payloads = events.payloads(event);
+      async move { (payloads.len() >= count).then_some(payloads) }
+    })
+    .await
+  }
+}
+
+pub struct Cluster {
+  pub peers: Vec<Peer>,
+}
+
+impl Cluster {
+  /// 이름마다 인스턴스를 하나씩 띄우고 서로 온라인으로 보일 때까지 기다린다.
+  /// 루프백 주소를 여러 개 쓸 수 없으면 `None`.
+  pub async fn start(names: &[&str]) -> Option<Self> {
+    Self::start_with(names, |_| 0).await
+  }
+
+  /// `loss`는 인스턴스별 수신 손실률(%)
+  pub async fn start_with(names: &[&str], loss: impl Fn(&str) -> u8) -> Option<Self> {
+    let cluster = Self::launch(names, loss).await?;
+    cluster.wait_until_meshed().await;
+    Some(cluster)
+  }
+
+  /// 인스턴스를 띄우기만 하고 서로 보일 때까지 기다리지 않는다.
+  /// 같은 이름을 여러 번 주면 한 사용자가 여러 기기에 로그인한 것이다.
+  /// `loss`는 `names` 순서대로 인스턴스마다 한 번 불린다.
+  pub async fn launch(names: &[&str], loss: impl Fn(&str) -> u8) -> Option<Self> {
+    Self::launch_inner(names, loss, |_, _| true).await
+  }
+
+  /// `linked(a, b)`인 인스턴스끼리만 서로의 주소를 안다. 서로 다른 서브넷처럼
+  /// 직접 찾을 수 없는 피어 사이의 경로를 시험할 때 쓴다. 서로 보일 때까지 기다리지 않는다.
+  pub async fn launch_linked(names: &[&str], linked: impl Fn(&str, &str) -> bool) -> Option<Self> {
+    Self::launch_inner(names, |_| 0, linked).await
+  }
+
+  async fn launch_inner(
+    names: &[&str],
+    loss: impl Fn(&str) -> u8,
+    linked: impl Fn(&str, &str) -> bool,
+  ) -> Option<Self> {
+    let first = NEXT_HOST.fetch_add(names.len() as u8, Ordering::SeqCst);
+    let ips = (0..names.len())
+      .map(|i| IpAddr::V4(Ipv4Addr::new(127, 0, 0, first + i as u8)))
+      .collect::<Vec<_>>();
+    if ips.iter().any(|ip| std::net::UdpSocket::bind((*ip, 0)).is_err()) {
+      eprintln!("skipping: loopback aliases 127.0.0.x are not available");
+      return None;
+    }
+
+    let base = base_net();
+    let peers = names
+      .iter()
+      .zip(&ips)
+      .map(|(name, ip)| {
+        let others = names
+          .iter()
+          .zip(&ips)
+          .filter(|(other_name, other)| *other != ip && (linked(name, other_name) || linked(other_name, name)))
+          .map(|(_, other)| *other)
+          .collect::<Vec<_>>();
+        let net = NetConfig {
+          bind_ip: Some(*ip),
+          inbound_loss_percent: loss(name),
+          ..base
+        };
+        Peer::new(name, *ip, net, &others)
+      })
+      .collect::<Vec<_>>();
+
+    // 먼저 뜬 인스턴스는 나중에 뜬 인스턴스의 디스커버리에 응답하면서 서로 알게 된다.
+    for peer in &peers {
+      peer.start().await;
+    }
+
+    Some(Self { peers })
+  }
+
+  pub fn peer(&self, name: &str) -> &Peer {
+    self.peers.iter().find(|peer| peer.user_id == name).expect(name)
+  }
+
+  /// 같은 사용자로 띄운 인스턴스들 (띄운 순서)
+  pub fn devices(&self, name: &str) -> Vec<&Peer> {
+    self.peers.iter().filter(|peer| peer.user_id == name).collect()
+  }
+
+  /// 모든 인스턴스가 나머지 전부를 온라인으로 볼 때까지
+  pub async fn wait_until_meshed(&self) {
+    for peer in &self.peers {
+      let expected = self.peers.len() - 1;
+      wait_until(&format!("{} to see {expected} peers", peer.user_id), || async move {
+        (peer.online_peers().await.len() >= expected).then_some(())
+      })
+      .await;
+    }
+  }
+
+  pub async fn stop(&self) {
+    for peer in &self.peers {
+      peer.stop().await;
+    }
+  }
+}
+
+/// `check`가 값을 돌려줄 때까지 폴링한다. [`WAIT_TIMEOUT`]이 지나면 실패한다.
+pub async fn wait_until<T, F, Fut>(what: &str, mut check: F) -> T
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Option<T>>,
+{
+  let deadline = tokio::time::Instant::now() + WAIT_TIMEOUT;
+  loop {
+    if let Some(value) = check().await {
+      return value;
+    }
+    if tokio::time::Instant::now() >= deadline {
+      panic!("timed out waiting for {what}");
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+}
+
+/// 환경 변수로 준 포트, 없으면 앱 기본 포트(41235~41237)와 겹치지 않는 테스트 포트
+fn base_net() -> NetConfig {
+  let port = |name: &str, default: u16| {
+    std::env::var(name)
+      .ok()
+      .and_then(|value| value.trim().parse::<u16>().ok())
+      .unwrap_or(default)
+  };
+  NetConfig {
+    discovery_port: port("INTERNAL_P2P_DISCOVERY_PORT", 47235),
+    udp_message_port: port("INTERNAL_P2P_MESSAGE_PORT", 47236),
+    tcp_message_port: port("INTERNAL_P2P_TCP_PORT", 47237),
+    bind_ip: None,
+    inbound_loss_percent: 0,
+  }
+}
+
+fn set_setting(conn: &Connection, key: &str, value: &str) {
+  conn
+    .execute(
+      "INSERT OR REPLACE INTO app_settings (key, value, updated_at) VALUES (?1, ?2, datetime('now'))",
+      params![key, value],
+    )
+    .expect("set setting");
+}
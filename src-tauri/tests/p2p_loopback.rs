@@ -0,0 +1,1152 @@
+//! 여러 P2P 인스턴스를 루프백에 띄워 디스커버리, 채팅, 수신 확인, 그룹, 파일 제안을
+//! 끝까지 돌려 보고, 내보낸 이벤트와 SQLite 상태를 확인한다.
+
+mod common;
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use common::{wait_until, Cluster};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_discover_each_other() {
+  let Some(cluster) = Cluster::start(&["alice", "bob", "carol"]).await else { return; };
+
+  for peer in &cluster.peers {
+    let mut online = peer.online_peers().await;
+    online.sort();
+    let mut expected = cluster
+      .peers
+      .iter()
+      .filter(|other| other.user_id != peer.user_id)
+      .map(|other| other.user_id.clone())
+      .collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(online, expected, "{} sees the wrong peers", peer.user_id);
+    assert_eq!(peer.events.payloads("p2p:peer-discovered").len(), 2);
+    assert_eq!(
+      peer.query_i64("SELECT COUNT(*) FROM p2p_peer_keys", &[]),
+      2,
+      "{} should pin both identity keys",
+      peer.user_id
+    );
+  }
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replayed_announcement_does_not_move_a_peer() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  // alice의 서명된 알림을 누군가 엿들었다. bob은 원래 주소에서 온 것을 먼저 받는다.
+  let captured = alice.internal.discovery_announcement().await.unwrap();
+  bob.send_raw_discovery(alice.ip, &captured);
+  tokio::time::sleep(Duration::from_millis(500)).await;
+
+  // 같은 알림을 다른 주소에서 다시 보내도 alice의 주소가 바뀌지 않는다.
+  bob.send_raw_discovery(IpAddr::V4(Ipv4Addr::LOCALHOST), &captured);
+  tokio::time::sleep(Duration::from_secs(1)).await;
+  let peers = bob.internal.get_peers().await;
+  let seen = peers["onlinePeers"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .find(|peer| peer["userId"] == "alice")
+    .cloned()
+    .unwrap();
+  assert_eq!(seen["ipAddress"], alice.ip.to_string());
+  assert!(!seen["addresses"].as_array().unwrap().contains(&json!("127.0.0.1")), "{seen}");
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chat_with_delivery_and_read_receipts() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  let sent = alice
+    .internal
+    .send_message(json!({"receiverId": "bob", "content": "안녕하세요"}))
+    .await
+    .unwrap();
+  assert!(sent.get("error").is_none(), "direct send failed: {sent}");
+  let message_id = sent["messageId"].as_str().unwrap().to_string();
+
+  let received = bob
+    .wait_for_event("messaging:received", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  assert_eq!(received["senderId"], "alice");
+  assert_eq!(received["content"], "안녕하세요");
+
+  alice
+    .wait_for_event("messaging:delivery-receipt", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  wait_until("alice to mark the message delivered", || async {
+    let delivered = alice.query_i64(
+      "SELECT COALESCE(MAX(delivered), 0) FROM messages WHERE message_id = ?1",
+      &[&message_id],
+    );
+    (delivered == 1).then_some(())
+  })
+  .await;
+  assert_eq!(
+    bob.query_i64("SELECT COUNT(*) FROM messages WHERE message_id = ?1 AND sender_id = 'alice'", &[&message_id]),
+    1
+  );
+
+  bob
+    .internal
+    .send_read_receipt(json!({"messageId": message_id, "senderId": "alice"}))
+    .await
+    .unwrap();
+  alice
+    .wait_for_event("messaging:read-receipt", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  wait_until("alice to mark the message read", || async {
+    let read = alice.query_i64(
+      "SELECT COALESCE(MAX(is_read), 0) FROM messages WHERE message_id = ?1",
+      &[&message_id],
+    );
+    (read == 1).then_some(())
+  })
+  .await;
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn plaintext_chat_from_a_peer_with_a_key_is_rejected() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let bob = cluster.peer("bob");
+
+  // alice는 공개키를 광고했으므로 alice 이름의 평문 채팅은 위조다.
+  let forged = json!({
+    "id": "forged-plaintext-chat",
+    "type": "chat",
+    "senderId": "alice",
+    "receiverId": "bob",
+    "timestamp": chrono::Utc::now().to_rfc3339(),
+    "content": "비밀번호를 알려 주세요"
+  });
+  bob.send_raw_udp(&forged);
+
+  let failed = bob
+    .wait_for_event("p2p:decrypt-failed", |payload| payload["messageId"] == "forged-plaintext-chat")
+    .await;
+  assert_eq!(failed["senderId"], "alice");
+  assert!(bob.events.payloads("messaging:received").is_empty());
+  assert_eq!(
+    bob.query_i64("SELECT COUNT(*) FROM messages WHERE message_id = 'forged-plaintext-chat'", &[]),
+    0
+  );
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_known_peer_is_not_downgraded_to_plaintext() {
+  // bob의 리스너는 연결을 받자마자 끊으므로 HELLO도 Noise도 끝나지 않는다.
+  let Some(cluster) = Cluster::launch(&["alice", "bob"], |name| if name == "bob" { 100 } else { 0 }).await else {
+    return;
+  };
+  let alice = cluster.peer("alice");
+  wait_until("alice to see bob", || async {
+    alice.online_peers().await.contains(&"bob".to_string()).then_some(())
+  })
+  .await;
+
+  // 엄격 모드가 아니어도 신원 키가 고정된 피어에게 줄바꿈 JSON으로 보내지 않고 대기열에 남긴다.
+  let sent = alice
+    .internal
+    .send_message(json!({"receiverId": "bob", "content": "평문으로 가면 안 되는 메시지"}))
+    .await
+    .unwrap();
+  assert_eq!(sent["error"], "Message queued (peer offline)");
+  assert_eq!(
+    alice.query_i64(
+      "SELECT COUNT(*) FROM p2p_outbound_queue WHERE message_id = ?1",
+      &[&sent["messageId"].as_str().unwrap()],
+    ),
+    1
+  );
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn routed_receipts_must_come_from_the_recipient() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  bob.stop().await;
+  wait_until("alice to see bob leave", || async {
+    (!alice.online_peers().await.contains(&"bob".to_string())).then_some(())
+  })
+  .await;
+  let queued = alice
+    .internal
+    .send_message(json!({"receiverId": "bob", "content": "bob만 확인할 수 있는 메시지"}))
+    .await
+    .unwrap();
+  assert!(queued.get("error").is_some(), "message should be queued: {queued}");
+  let message_id = queued["messageId"].as_str().unwrap().to_string();
+
+  // 경로 봉투 안의 senderId는 서명되지 않는다.
+  let routed_receipt = |id: &str, sender_id: &str| {
+    let now = chrono::Utc::now().to_rfc3339();
+    json!({
+      "id": format!("{id}-envelope"),
+      "type": "route",
+      "senderId": "mallory",
+      "receiverId": "alice",
+      "destinationId": "alice",
+      "ttl": 3,
+      "timestamp": now,
+      "payload": {
+        "id": id,
+        "type": "delivery_receipt",
+        "senderId": sender_id,
+        "receiverId": "alice",
+        "timestamp": now,
+        "messageId": message_id,
+        "deliveredAt": now
+      }
+    })
+  };
+  alice.send_raw_udp(&routed_receipt("forged-receipt", "mallory"));
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  assert!(alice.events.payloads("messaging:delivery-receipt").is_empty());
+  assert_eq!(
+    alice.query_i64("SELECT COUNT(*) FROM p2p_outbound_queue WHERE message_id = ?1", &[&message_id]),
+    1
+  );
+
+  alice.send_raw_udp(&routed_receipt("recipient-receipt", "bob"));
+  alice
+    .wait_for_event("messaging:delivery-receipt", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  wait_until("alice to dequeue the message", || async {
+    let left = alice.query_i64("SELECT COUNT(*) FROM p2p_outbound_queue WHERE message_id = ?1", &[&message_id]);
+    (left == 0).then_some(())
+  })
+  .await;
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_older_than_the_seen_window_are_dropped() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let bob = cluster.peer("bob");
+
+  // 받은 ID를 기억하는 기간(대기열 만료 72시간 + 시계 오차)보다 오래된 패킷은 다시 보낸 것이다.
+  let typing = |id: &str, timestamp: String| {
+    json!({
+      "id": id,
+      "type": "typing",
+      "senderId": "alice",
+      "receiverId": "bob",
+      "timestamp": timestamp,
+      "content": "typing"
+    })
+  };
+  let stale = (chrono::Utc::now() - chrono::Duration::hours(80)).to_rfc3339();
+  bob.send_raw_udp(&typing("replayed-typing", stale));
+  bob.send_raw_udp(&typing("fresh-typing", chrono::Utc::now().to_rfc3339()));
+
+  bob.wait_for_count("messaging:typing", 1).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  assert_eq!(bob.events.payloads("messaging:typing").len(), 1);
+  assert_eq!(
+    bob.query_i64("SELECT COUNT(*) FROM p2p_seen_messages WHERE message_id = 'replayed-typing'", &[]),
+    0
+  );
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unsealed_history_from_a_peer_with_a_key_is_rejected() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let bob = cluster.peer("bob");
+
+  // alice 이름으로 bob이 보낸 적 없는 "bob이 보낸 메시지"를 기록 동기화로 밀어 넣으려 한다.
+  let entries = json!([{
+    "id": "forged-sync-entry",
+    "type": "chat",
+    "senderId": "bob",
+    "receiverId": "alice",
+    "content": "제가 보낸 적 없는 말",
+    "timestamp": chrono::Utc::now().to_rfc3339()
+  }]);
+  bob.send_raw_udp(&json!({
+    "id": "forged-sync-batch",
+    "type": "sync_messages",
+    "senderId": "alice",
+    "receiverId": "bob",
+    "timestamp": chrono::Utc::now().to_rfc3339(),
+    "content": entries.to_string()
+  }));
+
+  bob
+    .wait_for_event("p2p:decrypt-failed", |payload| payload["messageId"] == "forged-sync-batch")
+    .await;
+  assert!(bob.events.payloads("messaging:history-synced").is_empty());
+  assert_eq!(
+    bob.query_i64("SELECT COUNT(*) FROM messages WHERE message_id = 'forged-sync-entry'", &[]),
+    0
+  );
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn group_messages_reach_every_member() {
+  let Some(cluster) = Cluster::start(&["alice", "bob", "carol"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let members = json!(["alice", "bob", "carol"]);
+
+  alice
+    .internal
+    .broadcast_group_create(json!({"groupId": "g-1", "groupName": "3학년 2반", "memberIds": members}))
+    .await
+    .unwrap();
+  for name in ["bob", "carol"] {
+    let created = cluster
+      .peer(name)
+      .wait_for_event("group:created", |payload| payload["groupId"] == "g-1")
+      .await;
+    assert_eq!(created["groupName"], "3학년 2반");
+  }
+
+  let sent = alice
+    .internal
+    .send_group_message(json!({"groupId": "g-1", "groupName": "3학년 2반", "memberIds": members, "content": "조회 시작"}))
+    .await
+    .unwrap();
+  assert_eq!(sent["failedRecipients"], json!([]));
+  let message_id = sent["messageId"].as_str().unwrap().to_string();
+
+  for name in ["bob", "carol"] {
+    let received = cluster
+      .peer(name)
+      .wait_for_event("group:message-received", |payload| payload["id"] == message_id.as_str())
+      .await;
+    assert_eq!(received["content"], "조회 시작");
+    assert_eq!(received["senderId"], "alice");
+  }
+
+  let receipts = alice.wait_for_count("group:delivery-receipt", 2).await;
+  let from = receipts
+    .iter()
+    .filter_map(|receipt| receipt["senderId"].as_str())
+    .collect::<HashSet<_>>();
+  assert_eq!(from, HashSet::from(["bob", "carol"]));
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn conversations_are_ordered_by_sequence_number() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  for i in 1..=3 {
+    alice
+      .internal
+      .send_message(json!({"receiverId": "bob", "content": format!("질문 {i}")}))
+      .await
+      .unwrap();
+  }
+  let received = bob.wait_for_count("messaging:received", 3).await;
+  let seqs = received.iter().map(|payload| payload["seq"].as_i64()).collect::<Vec<_>>();
+  assert_eq!(seqs, vec![Some(1), Some(2), Some(3)]);
+
+  // 답장은 받은 번호보다 뒤에 온다. 보낸 쪽 시계와 상관없이 양쪽 저장 순서가 같다.
+  let reply = bob
+    .internal
+    .send_message(json!({"receiverId": "alice", "content": "답변"}))
+    .await
+    .unwrap();
+  let reply_id = reply["messageId"].as_str().unwrap().to_string();
+  let delivered = alice
+    .wait_for_event("messaging:received", |payload| payload["messageId"] == reply_id.as_str())
+    .await;
+  assert_eq!(delivered["seq"], 4);
+
+  for peer in [alice, bob] {
+    wait_until("both sides to store the conversation", || async {
+      (peer.query_i64("SELECT COUNT(*) FROM messages", &[]) == 4).then_some(())
+    })
+    .await;
+    let contents = peer
+      .db()
+      .prepare("SELECT content FROM messages ORDER BY COALESCE(seq, 0), received_at")
+      .unwrap()
+      .query_map([], |row| row.get::<_, String>(0))
+      .unwrap()
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap();
+    assert_eq!(contents, ["질문 1", "질문 2", "질문 3", "답변"], "{} stored the wrong order", peer.user_id);
+  }
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn file_offer_is_accepted_and_transferred() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  // 청크 여러 개에 걸치도록 충분히 크게 만든다.
+  let contents = (0..3_000_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+  let source = tempfile_path(alice, "수행평가.bin");
+  std::fs::write(&source, &contents).unwrap();
+
+  let offered = alice
+    .internal
+    .offer_file(json!({"receiverId": "bob", "filePath": source.to_string_lossy()}))
+    .await
+    .unwrap();
+  let transfer_id = offered["transfer"]["id"].as_str().unwrap().to_string();
+
+  let offer = bob
+    .wait_for_event("p2p:file-offer", |payload| payload["id"] == transfer_id.as_str())
+    .await;
+  assert_eq!(offer["fileName"], "수행평가.bin");
+  assert_eq!(offer["fileSize"], contents.len());
+
+  let accepted = bob.internal.accept_file(transfer_id.clone()).await.unwrap();
+  assert_eq!(accepted["success"], true);
+
+  let complete = bob
+    .wait_for_event("p2p:file-complete", |payload| payload["id"] == transfer_id.as_str())
+    .await;
+  let destination = complete["filePath"].as_str().unwrap();
+  assert!(destination.starts_with(&*bob.download_dir().to_string_lossy()));
+  assert_eq!(std::fs::read(destination).unwrap(), contents);
+
+  alice
+    .wait_for_event("p2p:file-complete", |payload| payload["id"] == transfer_id.as_str())
+    .await;
+  assert_eq!(
+    bob.query_i64(
+      "SELECT COUNT(*) FROM p2p_file_transfers WHERE transfer_id = ?1 AND status = 'completed'",
+      &[&transfer_id]
+    ),
+    1
+  );
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn file_accept_from_another_address_is_ignored() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  let source = tempfile_path(alice, "성적표.xlsx");
+  std::fs::write(&source, b"confidential").unwrap();
+  let offered = alice
+    .internal
+    .offer_file(json!({"receiverId": "bob", "filePath": source.to_string_lossy()}))
+    .await
+    .unwrap();
+  let transfer_id = offered["transfer"]["id"].as_str().unwrap().to_string();
+  bob
+    .wait_for_event("p2p:file-offer", |payload| payload["id"] == transfer_id.as_str())
+    .await;
+
+  // 전송 ID를 알아낸 누군가가 bob인 척 수락해도 파일을 보내지 않는다.
+  alice.send_raw_udp(&json!({
+    "id": "spoofed-accept",
+    "type": "file_accept",
+    "senderId": "bob",
+    "receiverId": "alice",
+    "timestamp": chrono::Utc::now().to_rfc3339(),
+    "messageId": transfer_id
+  }));
+  tokio::time::sleep(Duration::from_secs(1)).await;
+  assert!(alice.events.payloads("p2p:file-progress").is_empty());
+  assert_eq!(
+    alice.query_i64(
+      "SELECT COUNT(*) FROM p2p_file_transfers WHERE transfer_id = ?1 AND status = 'pending'",
+      &[&transfer_id]
+    ),
+    1
+  );
+
+  bob.internal.accept_file(transfer_id.clone()).await.unwrap();
+  alice
+    .wait_for_event("p2p:file-complete", |payload| payload["id"] == transfer_id.as_str())
+    .await;
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn file_reject_is_reported_to_the_sender() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  let source = tempfile_path(alice, "과제.hwp");
+  std::fs::write(&source, b"draft").unwrap();
+  let offered = alice
+    .internal
+    .offer_file(json!({"receiverId": "bob", "filePath": source.to_string_lossy()}))
+    .await
+    .unwrap();
+  let transfer_id = offered["transfer"]["id"].as_str().unwrap().to_string();
+  bob
+    .wait_for_event("p2p:file-offer", |payload| payload["id"] == transfer_id.as_str())
+    .await;
+
+  bob.internal.reject_file(transfer_id.clone()).await.unwrap();
+  let rejected = alice
+    .wait_for_event("p2p:file-rejected", |payload| payload["transferId"] == transfer_id.as_str())
+    .await;
+  assert_eq!(rejected["receiverId"], "bob");
+  assert!(alice.events.payloads("p2p:file-complete").is_empty());
+  assert_eq!(
+    alice.query_i64("SELECT COUNT(*) FROM p2p_file_transfers WHERE transfer_id = ?1", &[&transfer_id]),
+    0
+  );
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupted_file_resumes_from_received_chunks() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  let contents = (0..8_000_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+  let source = tempfile_path(alice, "수업영상.mp4");
+  std::fs::write(&source, &contents).unwrap();
+
+  let offered = alice
+    .internal
+    .offer_file(json!({"receiverId": "bob", "filePath": source.to_string_lossy()}))
+    .await
+    .unwrap();
+  let transfer_id = offered["transfer"]["id"].as_str().unwrap().to_string();
+  bob
+    .wait_for_event("p2p:file-offer", |payload| payload["id"] == transfer_id.as_str())
+    .await;
+  bob.internal.accept_file(transfer_id.clone()).await.unwrap();
+
+  // 일부를 받은 상태에서 수신 측 프로세스가 내려간다.
+  bob
+    .wait_for_event("p2p:file-progress", |payload| {
+      payload["transferId"] == transfer_id.as_str() && payload["progress"].as_u64() >= Some(5)
+    })
+    .await;
+  bob.stop().await;
+  alice
+    .wait_for_event("p2p:file-interrupted", |payload| payload["id"] == transfer_id.as_str())
+    .await;
+
+  let received_before = bob.query_i64(
+    "SELECT bytes_transferred FROM p2p_file_transfers WHERE transfer_id = ?1",
+    &[&transfer_id],
+  ) as u64;
+  assert!(received_before > 0 && received_before < contents.len() as u64);
+
+  bob.start().await;
+  cluster.wait_until_meshed().await;
+  alice.events.clear();
+  let resumed = bob.internal.resume_file(transfer_id.clone()).await.unwrap();
+  assert_eq!(resumed["success"], true);
+
+  let complete = bob
+    .wait_for_event("p2p:file-complete", |payload| payload["id"] == transfer_id.as_str())
+    .await;
+  alice
+    .wait_for_event("p2p:file-complete", |payload| payload["id"] == transfer_id.as_str())
+    .await;
+
+  // 송신 측은 수신 측 비트맵에 있는 청크를 다시 보내지 않는다.
+  let sent = alice
+    .events
+    .payloads("p2p:file-progress")
+    .into_iter()
+    .filter(|payload| payload["transferId"] == transfer_id.as_str())
+    .map(|payload| payload["bytesTransferred"].as_u64().unwrap())
+    .collect::<Vec<_>>();
+  assert!(!sent.is_empty());
+  assert!(sent.iter().all(|bytes| *bytes >= received_before), "resent chunks before {received_before}: {sent:?}");
+
+  let destination = complete["filePath"].as_str().unwrap();
+  assert_eq!(
+    hex::encode(Sha256::digest(std::fs::read(destination).unwrap())),
+    hex::encode(Sha256::digest(&contents))
+  );
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn folder_offer_escaping_the_download_folder_is_rejected() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let bob = cluster.peer("bob");
+  let download_dir = bob.download_dir();
+  let outside = download_dir.parent().unwrap().join("escaped.txt");
+
+  bob.send_raw_udp(&json!({
+    "id": "escaping-folder",
+    "type": "folder_offer",
+    "senderId": "alice",
+    "receiverId": "bob",
+    "timestamp": chrono::Utc::now().to_rfc3339(),
+    "fileName": "과제",
+    "fileSize": 10,
+    "totalChunks": 1,
+    "manifest": [
+      {"path": "ok.txt", "size": 5, "hash": ""},
+      {"path": "../escaped.txt", "size": 5, "hash": ""}
+    ]
+  }));
+
+  let rejected = bob
+    .wait_for_event("p2p:folder-rejected", |payload| payload["transferId"] == "escaping-folder")
+    .await;
+  assert_eq!(rejected["path"], "../escaped.txt");
+  assert!(bob.events.payloads("p2p:file-offer").is_empty());
+  let accepted = bob.internal.accept_file("escaping-folder".to_string()).await.unwrap();
+  assert_eq!(accepted["success"], false);
+  assert!(!outside.exists());
+  assert!(std::fs::read_dir(&download_dir).map(|mut dir| dir.next().is_none()).unwrap_or(true));
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chat_survives_inbound_packet_loss() {
+  let Some(cluster) = Cluster::start_with(&["alice", "bob"], |name| if name == "bob" { 30 } else { 0 }).await else {
+    return;
+  };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  let mut sent = HashSet::new();
+  for i in 0..10 {
+    let result = alice
+      .internal
+      .send_message(json!({"receiverId": "bob", "content": format!("message {i}")}))
+      .await
+      .unwrap();
+    sent.insert(result["messageId"].as_str().unwrap().to_string());
+  }
+
+  // 재전송이나 대기열 재시도가 있어도 메시지마다 정확히 한 번 받아야 한다.
+  let received = bob.wait_for_count("messaging:received", sent.len()).await;
+  let ids = received
+    .iter()
+    .filter_map(|payload| payload["messageId"].as_str().map(|s| s.to_string()))
+    .collect::<HashSet<_>>();
+  assert_eq!(ids, sent);
+  assert_eq!(received.len(), sent.len(), "duplicate deliveries");
+  // 저장은 이벤트 뒤에 따로 끝난다.
+  wait_until("bob to store every message", || async {
+    let stored = bob.query_i64("SELECT COUNT(*) FROM messages WHERE sender_id = 'alice'", &[]);
+    (stored == sent.len() as i64).then_some(())
+  })
+  .await;
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resent_messages_are_acknowledged_but_not_received_twice() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  let sent = alice
+    .internal
+    .send_message(json!({"receiverId": "bob", "content": "한 번만"}))
+    .await
+    .unwrap();
+  let message_id = sent["messageId"].as_str().unwrap().to_string();
+  bob
+    .wait_for_event("messaging:received", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  alice.wait_for_count("messaging:delivery-receipt", 1).await;
+
+  // 같은 ID로 다시 보내면 수신 확인은 오지만 받는 쪽 이벤트와 행은 늘지 않는다.
+  // 재시작한 뒤에도 DB에 남은 기록으로 걸러낸다.
+  let resend = json!({"receiverId": "bob", "content": "한 번만", "messageId": message_id});
+  alice.internal.send_message(resend.clone()).await.unwrap();
+  alice.wait_for_count("messaging:delivery-receipt", 2).await;
+  bob.restart().await;
+  cluster.wait_until_meshed().await;
+  alice.internal.send_message(resend).await.unwrap();
+  alice.wait_for_count("messaging:delivery-receipt", 3).await;
+
+  assert_eq!(bob.events.payloads("messaging:received").len(), 1);
+  assert_eq!(
+    bob.query_i64("SELECT COUNT(*) FROM messages WHERE message_id = ?1", &[&message_id]),
+    1
+  );
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn queued_messages_are_delivered_after_restart() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  bob.stop().await;
+  let queued = alice
+    .internal
+    .send_message(json!({"receiverId": "bob", "content": "꺼져 있는 동안 보낸 메시지"}))
+    .await
+    .unwrap();
+  assert!(queued.get("error").is_some(), "message should be queued: {queued}");
+  let message_id = queued["messageId"].as_str().unwrap().to_string();
+  assert_eq!(
+    alice.query_i64("SELECT COUNT(*) FROM p2p_outbound_queue WHERE message_id = ?1", &[&message_id]),
+    1
+  );
+  assert!(bob.events.payloads("messaging:received").is_empty());
+
+  bob.restart().await;
+  cluster.wait_until_meshed().await;
+
+  bob
+    .wait_for_event("messaging:received", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  wait_until("alice to drain the queue", || async {
+    let left = alice.query_i64("SELECT COUNT(*) FROM p2p_outbound_queue WHERE message_id = ?1", &[&message_id]);
+    (left == 0).then_some(())
+  })
+  .await;
+  assert_eq!(bob.events.payloads("messaging:received").len(), 1);
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn queued_chat_is_handed_to_the_hub_sealed() {
+  let Some(cluster) = Cluster::start(&["alice", "bob", "hub"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+  let hub = cluster.peer("hub");
+
+  hub.set_setting("p2pRelayHub", "true");
+  hub.stop().await;
+  bob.stop().await;
+  let queued = alice
+    .internal
+    .send_message(json!({"receiverId": "bob", "content": "허브가 읽으면 안 되는 내용"}))
+    .await
+    .unwrap();
+  assert!(queued.get("error").is_some(), "message should be queued: {queued}");
+  let message_id = queued["messageId"].as_str().unwrap().to_string();
+
+  // 허브가 나중에 뜨면 대기열 재시도가 원문이 아닌 봉인한 사본을 맡긴다.
+  hub.start().await;
+  alice
+    .wait_for_event("p2p:message-relayed", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  let stored = hub.query_text("SELECT payload FROM p2p_relay_store WHERE message_id = ?1", &[&message_id]);
+  let stored = serde_json::from_str::<serde_json::Value>(&stored).unwrap();
+  assert_eq!(stored["content"], "");
+  assert!(stored["encrypted"].is_object());
+  assert!(!stored.to_string().contains("허브가 읽으면 안 되는 내용"));
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hub_forwards_messages_and_receipts_between_offline_peers() {
+  let Some(cluster) = Cluster::start(&["alice", "bob", "hub"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+  let hub = cluster.peer("hub");
+
+  hub.set_setting("p2pRelayHub", "true");
+  hub.restart().await;
+  for peer in [alice, bob] {
+    wait_until(&format!("{} to see the relay hub", peer.user_id), || async move {
+      let peers = peer.internal.get_peers().await;
+      let online = peers["onlinePeers"].as_array().cloned().unwrap_or_default();
+      online
+        .iter()
+        .any(|other| other["userId"] == "hub" && other["isRelayHub"] == true)
+        .then_some(())
+    })
+    .await;
+  }
+
+  bob.stop().await;
+  // 허브를 거치는 경로도 거둬져야 대기열로 간다.
+  wait_until("alice to lose every way to bob", || async {
+    let peers = alice.internal.get_peers().await;
+    let routed = peers["routes"].as_array().is_some_and(|routes| routes.iter().any(|r| r["userId"] == "bob"));
+    (!routed && !alice.online_peers().await.contains(&"bob".to_string())).then_some(())
+  })
+  .await;
+
+  let queued = alice
+    .internal
+    .send_message(json!({"receiverId": "bob", "content": "내일 회의 자료입니다"}))
+    .await
+    .unwrap();
+  assert!(queued.get("error").is_some(), "message should be queued: {queued}");
+  let message_id = queued["messageId"].as_str().unwrap().to_string();
+  alice
+    .wait_for_event("p2p:message-relayed", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+
+  // 보낸 사람과 받는 사람이 한 번도 같이 온라인이 아니어도 허브가 전달한다.
+  alice.stop().await;
+  bob.start().await;
+  let received = bob
+    .wait_for_event("messaging:received", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  assert_eq!(received["content"], "내일 회의 자료입니다");
+
+  // 수신 확인은 오프라인인 alice 대신 허브가 맡는다.
+  wait_until("the hub to hold bob's delivery receipt", || async {
+    let held = hub.query_i64(
+      "SELECT COUNT(*) FROM p2p_relay_store WHERE recipient_id = 'alice' AND message_type = 'delivery_receipt'",
+      &[],
+    );
+    let pending = hub.query_i64("SELECT COUNT(*) FROM p2p_relay_store WHERE message_id = ?1", &[&message_id]);
+    (held == 1 && pending == 0).then_some(())
+  })
+  .await;
+
+  bob.stop().await;
+  alice.start().await;
+  let receipt = alice
+    .wait_for_event("messaging:delivery-receipt", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  assert_eq!(receipt["relayedBy"], "hub");
+  wait_until("alice to mark the message delivered and dequeue it", || async {
+    let delivered = alice.query_i64(
+      "SELECT COALESCE(MAX(delivered), 0) FROM messages WHERE message_id = ?1",
+      &[&message_id],
+    );
+    let queued = alice.query_i64("SELECT COUNT(*) FROM p2p_outbound_queue WHERE message_id = ?1", &[&message_id]);
+    (delivered == 1 && queued == 0).then_some(())
+  })
+  .await;
+  wait_until("the hub to hand over the receipt", || async {
+    (hub.query_i64("SELECT COUNT(*) FROM p2p_relay_store", &[]) == 0).then_some(())
+  })
+  .await;
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lost_history_is_backfilled_after_reconnect() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  alice
+    .internal
+    .send_message(json!({"receiverId": "bob", "content": "내일 회의"}))
+    .await
+    .unwrap();
+  bob.wait_for_count("messaging:received", 1).await;
+  bob
+    .internal
+    .send_message(json!({"receiverId": "alice", "content": "알겠습니다"}))
+    .await
+    .unwrap();
+  alice.wait_for_count("messaging:received", 1).await;
+
+  // 대기열에 남지 않은 채 기록이 사라진 경우
+  bob.stop().await;
+  bob.db().execute("DELETE FROM messages", []).unwrap();
+  bob.start().await;
+  cluster.wait_until_meshed().await;
+
+  // alice가 보낸 메시지만 돌려받는다. bob이 보낸 것이라며 alice가 주는 기록은 지어낸 것일 수 있다.
+  let synced = bob.wait_for_event("messaging:history-synced", |payload| payload["userId"] == "alice").await;
+  assert_eq!(synced["count"], 1);
+  assert_eq!(
+    bob.query_i64("SELECT COUNT(*) FROM messages WHERE network_type = 'p2p-sync'", &[]),
+    1
+  );
+  assert_eq!(
+    bob.query_i64("SELECT COUNT(*) FROM messages WHERE content = '내일 회의' AND sender_id = 'alice' AND seq = 1", &[]),
+    1
+  );
+  assert_eq!(bob.query_i64("SELECT COUNT(*) FROM messages WHERE sender_id = 'bob'", &[]), 0);
+  assert!(alice.events.payloads("messaging:history-synced").is_empty());
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_reach_every_device_of_a_user() {
+  let Some(cluster) = Cluster::launch(&["alice", "bob", "bob"], |_| 0).await else { return; };
+  let alice = cluster.peer("alice");
+  let devices = cluster.devices("bob");
+  let (bob_desk, bob_laptop) = (devices[0], devices[1]);
+
+  // 두 번째 기기의 키는 바뀐 키처럼 보이므로 같은 사람의 기기임을 확인해 준다.
+  alice.wait_for_event("p2p:peer-key-changed", |payload| payload["userId"] == "bob").await;
+  alice.internal.trust_peer_device("bob".to_string()).await.unwrap();
+  cluster.wait_until_meshed().await;
+
+  let sent = alice
+    .internal
+    .send_message(json!({"receiverId": "bob", "content": "두 기기 모두"}))
+    .await
+    .unwrap();
+  assert_eq!(sent["success"], true);
+  for device in [bob_desk, bob_laptop] {
+    let received = device.wait_for_count("messaging:received", 1).await;
+    assert_eq!(received[0]["content"], "두 기기 모두");
+  }
+
+  bob_desk
+    .internal
+    .send_message(json!({"receiverId": "alice", "content": "책상에서 답장"}))
+    .await
+    .unwrap();
+  alice.wait_for_count("messaging:received", 1).await;
+  let synced = bob_laptop.wait_for_count("messaging:device-sent", 1).await;
+  assert_eq!(synced[0]["content"], "책상에서 답장");
+  wait_until("the laptop to store the reply", || async {
+    let stored = bob_laptop.query_i64(
+      "SELECT COUNT(*) FROM messages WHERE content = '책상에서 답장' AND sender_id = 'bob'",
+      &[],
+    );
+    (stored == 1).then_some(())
+  })
+  .await;
+
+  let message_id = sent["messageId"].as_str().unwrap().to_string();
+  bob_desk
+    .internal
+    .send_read_receipt(json!({"messageId": message_id, "senderId": "alice"}))
+    .await
+    .unwrap();
+  bob_laptop.wait_for_event("messaging:device-read", |payload| payload["messageId"] == message_id.as_str()).await;
+  wait_until("the laptop to mark the message read", || async {
+    let read = bob_laptop.query_i64(
+      "SELECT COALESCE(MAX(is_read), 0) FROM messages WHERE message_id = ?1",
+      &[&message_id],
+    );
+    (read == 1).then_some(())
+  })
+  .await;
+  alice.wait_for_event("messaging:read-receipt", |payload| payload["messageId"] == message_id.as_str()).await;
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_device_that_misses_a_message_gets_its_own_queue_entry() {
+  // 노트북(세 번째)은 디스커버리로는 보이지만 메시지 포트로 오는 것을 모두 버린다.
+  let launched = std::cell::Cell::new(0);
+  let Some(cluster) = Cluster::launch(&["alice", "bob", "bob"], |_| {
+    launched.set(launched.get() + 1);
+    if launched.get() == 3 { 100 } else { 0 }
+  })
+  .await
+  else {
+    return;
+  };
+  let alice = cluster.peer("alice");
+  let bob_desk = cluster.devices("bob")[0];
+
+  alice.wait_for_event("p2p:peer-key-changed", |payload| payload["userId"] == "bob").await;
+  alice.internal.trust_peer_device("bob".to_string()).await.unwrap();
+  wait_until("alice to see both of bob's devices", || async {
+    let online = alice.online_peers().await;
+    (online.iter().filter(|user_id| *user_id == "bob").count() == 2).then_some(())
+  })
+  .await;
+
+  let sent = alice
+    .internal
+    .send_message(json!({"receiverId": "bob", "content": "노트북도 받아야 하는 메시지"}))
+    .await
+    .unwrap();
+  assert!(sent.get("error").is_none(), "the desk should accept the message: {sent}");
+  let message_id = sent["messageId"].as_str().unwrap().to_string();
+  bob_desk.wait_for_count("messaging:received", 1).await;
+
+  // 노트북 앞으로만 한 줄이 남는다. 피어 ID는 재시작마다 바뀌므로 신원 키로 가린다.
+  let laptop_ip = cluster.devices("bob")[1].ip.to_string();
+  let peers = alice.internal.get_peers().await;
+  let laptop = peers["onlinePeers"].as_array().unwrap().iter().find(|peer| peer["ipAddress"] == laptop_ip.as_str());
+  assert_eq!(
+    alice.query_text(
+      "SELECT device FROM p2p_outbound_queue WHERE message_id = ?1 AND receiver_id = 'bob'",
+      &[&message_id],
+    ),
+    laptop.unwrap()["identityKey"].as_str().unwrap()
+  );
+  assert_eq!(
+    alice.query_i64("SELECT COUNT(*) FROM p2p_outbound_queue WHERE message_id = ?1", &[&message_id]),
+    1
+  );
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn presence_changes_reach_peers() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  assert!(alice.internal.set_presence(json!({"status": "lunch"})).await.is_err());
+
+  let set = alice
+    .internal
+    .set_presence(json!({"status": "busy", "message": "학부모 상담"}))
+    .await
+    .unwrap();
+  assert_eq!(set["presence"]["status"], "busy");
+  let changed = bob
+    .wait_for_event("p2p:presence-changed", |payload| {
+      payload["userId"] == "alice" && payload["presence"]["status"] == "busy"
+    })
+    .await;
+  assert_eq!(changed["presence"]["message"], "학부모 상담");
+  let peers = bob.internal.get_peers().await;
+  assert_eq!(peers["onlinePeers"][0]["presence"]["status"], "busy");
+
+  // 하루 종일 수업인 시간표에서는 재석이 수업 중으로 바뀐다.
+  alice.set_setting(
+    "messageSettings",
+    &json!({"classTimes": [{"start": "00:00", "end": "24:00", "label": "보충 수업"}]}).to_string(),
+  );
+  let set = alice.internal.set_presence(json!({"status": "available"})).await.unwrap();
+  assert_eq!(set["presence"]["status"], "in-class");
+  assert_eq!(set["presence"]["automatic"], true);
+  let changed = bob
+    .wait_for_event("p2p:presence-changed", |payload| payload["presence"]["status"] == "in-class")
+    .await;
+  assert_eq!(changed["presence"]["message"], "보충 수업");
+
+  // 퇴근은 수업 시간에도 그대로다.
+  let set = alice.internal.set_presence(json!({"status": "off-duty"})).await.unwrap();
+  assert_eq!(set["presence"]["status"], "off-duty");
+  bob
+    .wait_for_event("p2p:presence-changed", |payload| payload["presence"]["status"] == "off-duty")
+    .await;
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stopping_says_goodbye() {
+  let Some(cluster) = Cluster::start(&["alice", "bob"]).await else { return; };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+
+  // 하트비트 타임아웃(기본 30초)을 기다리지 않고 바로 오프라인이 된다.
+  alice.internal.stop().await.unwrap();
+  let started = tokio::time::Instant::now();
+  bob.wait_for_event("p2p:peer-offline", |payload| payload["userId"] == "alice").await;
+  assert!(started.elapsed() < Duration::from_secs(5));
+  assert!(bob.online_peers().await.is_empty());
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn silent_peers_go_offline_after_missed_pongs() {
+  // alice는 메시지 포트로 오는 것을 모두 버리므로 디스커버리로는 보여도 ping에 답하지 않는다.
+  let Some(cluster) = Cluster::launch(&["alice", "bob"], |name| if name == "alice" { 100 } else { 0 }).await else {
+    return;
+  };
+  let bob = cluster.peer("bob");
+  bob.set_setting("p2pHeartbeatSeconds", "1");
+  bob.set_setting("p2pOfflineSeconds", "3");
+  bob.events.clear();
+  bob.restart().await;
+
+  bob.wait_for_event("p2p:peer-discovered", |payload| payload["userId"] == "alice").await;
+  let started = tokio::time::Instant::now();
+  bob.wait_for_event("p2p:peer-offline", |payload| payload["userId"] == "alice").await;
+  assert!(started.elapsed() < Duration::from_secs(10));
+
+  cluster.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chat_is_routed_through_a_bridging_peer() {
+  // bob만 두 서브넷에 걸쳐 있어 alice와 carol은 서로를 직접 찾지 못한다.
+  let Some(cluster) = Cluster::launch_linked(&["alice", "bob", "carol"], |a, b| a == "bob" || b == "bob").await else {
+    return;
+  };
+  let alice = cluster.peer("alice");
+  let bob = cluster.peer("bob");
+  let carol = cluster.peer("carol");
+
+  wait_until("alice to learn a route to carol", || async {
+    let peers = alice.internal.get_peers().await;
+    let routes = peers["routes"].as_array().cloned().unwrap_or_default();
+    routes
+      .iter()
+      .any(|route| route["userId"] == "carol" && route["via"] == "bob")
+      .then_some(())
+  })
+  .await;
+  assert!(!alice.online_peers().await.contains(&"carol".to_string()));
+
+  let sent = alice
+    .internal
+    .send_message(json!({"receiverId": "carol", "content": "교무실에서 보냅니다"}))
+    .await
+    .unwrap();
+  assert_eq!(sent["routed"], true, "message should be routed: {sent}");
+  let message_id = sent["messageId"].as_str().unwrap().to_string();
+
+  let received = carol
+    .wait_for_event("messaging:received", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  // carol은 경로 광고로 alice의 키를 알므로 봉인되지 않은 채팅이었다면 거절했다.
+  assert_eq!(received["content"], "교무실에서 보냅니다");
+  assert!(carol.events.payloads("p2p:decrypt-failed").is_empty());
+
+  // 수신 확인도 같은 경로로 돌아온다.
+  let receipt = alice
+    .wait_for_event("messaging:delivery-receipt", |payload| payload["messageId"] == message_id.as_str())
+    .await;
+  assert_eq!(receipt["routedVia"], "bob");
+
+  // 중계한 bob은 봉인된 본문을 넘기기만 한다.
+  assert!(bob.events.payloads("messaging:received").is_empty());
+  assert!(bob.events.payloads("p2p:decrypt-failed").is_empty());
+  assert_eq!(
+    bob.query_i64("SELECT COUNT(*) FROM messages WHERE message_id = ?1", &[&message_id]),
+    0
+  );
+
+  cluster.stop().await;
+}
+
+fn tempfile_path(peer: &common::Peer, name: &str) -> std::path::PathBuf {
+  let dir = peer.download_dir().with_file_name("outbox");
+  std::fs::create_dir_all(&dir).unwrap();
+  dir.join(name)
+}
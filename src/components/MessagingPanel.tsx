@@ -328,6 +328,32 @@ export default function MessagingPanel() {
       });
     };
 
+    // 상대가 파일 전송을 거절
+    const handleFileRejected = (transfer: any) => {
+      setPendingFileTransfers(prev => {
+        const newMap = new Map(prev);
+        newMap.delete(transfer.transferId);
+        return newMap;
+      });
+
+      addNotification({
+        title: '파일 전송 거절',
+        message: `${getContactName(transfer.receiverId)}님이 ${transfer.fileName} 파일을 거절했습니다.`,
+        type: 'warning',
+      });
+    };
+
+    // 잘못된 폴더 제안을 자동으로 거절함
+    const handleFolderRejected = (data: { senderId: string; path: string | null }) => {
+      addNotification({
+        title: '폴더 전송 차단',
+        message: data.path
+          ? `${getContactName(data.senderId)}님이 보낸 폴더에 허용되지 않는 경로(${data.path})가 있어 거절했습니다.`
+          : `${getContactName(data.senderId)}님이 보낸 폴더 정보가 올바르지 않아 거절했습니다.`,
+        type: 'error',
+      });
+    };
+
     // 이벤트 리스너 등록
     window.electronAPI?.onInternalPeerDiscovered?.(handlePeerDiscovered);
     window.electronAPI?.onInternalPeerOnline?.(handlePeerOnline);
@@ -339,6 +365,8 @@ export default function MessagingPanel() {
     window.electronAPI?.onInternalFileOffer?.(handleFileOffer);
     window.electronAPI?.onInternalFileProgress?.(handleFileProgress);
     window.electronAPI?.onInternalFileComplete?.(handleFileComplete);
+    window.electronAPI?.onInternalFileRejected?.(handleFileRejected);
+    window.electronAPI?.onInternalFolderRejected?.(handleFolderRejected);
 
     return () => {
       window.electronAPI?.removeInternalP2PListeners?.();
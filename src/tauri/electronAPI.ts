@@ -99,6 +99,16 @@ export function createElectronAPI() {
       void addListener('messaging:received', callback);
     },
     removeMessageListener: () => removeListeners('messaging:received'),
+    onMessageSentFromOtherDevice: (callback: (message: any) => void) => {
+      void addListener('messaging:device-sent', callback);
+    },
+    onReadOnOtherDevice: (callback: (receipt: any) => void) => {
+      void addListener('messaging:device-read', callback);
+    },
+    removeDeviceSyncListeners: () => {
+      removeListeners('messaging:device-sent');
+      removeListeners('messaging:device-read');
+    },
     onReadReceipt: (callback: (receipt: any) => void) => {
       void addListener('messaging:read-receipt', callback);
     },
@@ -170,9 +180,23 @@ export function createElectronAPI() {
       ipcInvoke('internal-p2p:send-typing', data),
     offerInternalFile: (data: { receiverId: string; fileName: string; fileSize: number; filePath: string }) =>
       ipcInvoke('internal-p2p:offer-file', data),
+    offerInternalFolder: (data: { receiverId: string; folderPath: string }) =>
+      ipcInvoke('internal-p2p:offer-folder', data),
     acceptInternalFile: (transferId: string) => ipcInvoke('internal-p2p:accept-file', transferId),
     rejectInternalFile: (transferId: string) => ipcInvoke('internal-p2p:reject-file', transferId),
+    resumeInternalFile: (transferId: string) => ipcInvoke('internal-p2p:resume-file', transferId),
     getInternalFileTransfers: () => ipcInvoke('internal-p2p:get-file-transfers'),
+    getInternalQueuedMessages: (data?: { receiverId?: string }) =>
+      ipcInvoke('internal-p2p:get-queued-messages', data ?? {}),
+    retryInternalQueuedMessage: (messageId: string) => ipcInvoke('internal-p2p:retry-queued-message', messageId),
+    cancelInternalQueuedMessage: (messageId: string) => ipcInvoke('internal-p2p:cancel-queued-message', messageId),
+    getInternalSafetyNumber: (userId: string) => ipcInvoke('internal-p2p:get-safety-number', userId),
+    verifyInternalPeerKey: (data: { userId: string; verified?: boolean }) =>
+      ipcInvoke('internal-p2p:verify-peer-key', data),
+    trustInternalPeerKey: (userId: string) => ipcInvoke('internal-p2p:trust-peer-key', userId),
+    trustInternalPeerDevice: (userId: string) => ipcInvoke('internal-p2p:trust-peer-device', userId),
+    setInternalPresence: (data: { status: string; message?: string }) =>
+      ipcInvoke('internal-p2p:set-presence', data),
     onInternalP2PStarted: (callback: (info: any) => void) => {
       void addListener('p2p:started', callback);
     },
@@ -200,6 +224,30 @@ export function createElectronAPI() {
     onInternalFileComplete: (callback: (transfer: any) => void) => {
       void addListener('p2p:file-complete', callback);
     },
+    onInternalFileInterrupted: (callback: (transfer: any) => void) => {
+      void addListener('p2p:file-interrupted', callback);
+    },
+    onInternalFileRejected: (callback: (transfer: any) => void) => {
+      void addListener('p2p:file-rejected', callback);
+    },
+    onInternalFolderRejected: (callback: (data: any) => void) => {
+      void addListener('p2p:folder-rejected', callback);
+    },
+    onInternalMessageFailed: (callback: (data: any) => void) => {
+      void addListener('p2p:message-failed', callback);
+    },
+    onInternalDecryptFailed: (callback: (data: any) => void) => {
+      void addListener('p2p:decrypt-failed', callback);
+    },
+    onInternalPeerKeyChanged: (callback: (data: any) => void) => {
+      void addListener('p2p:peer-key-changed', callback);
+    },
+    onInternalMessageRelayed: (callback: (data: any) => void) => {
+      void addListener('p2p:message-relayed', callback);
+    },
+    onInternalPresenceChanged: (callback: (data: any) => void) => {
+      void addListener('p2p:presence-changed', callback);
+    },
     removeInternalP2PListeners: () => {
       removeListeners('p2p:started');
       removeListeners('p2p:stopped');
@@ -210,6 +258,14 @@ export function createElectronAPI() {
       removeListeners('p2p:file-offer');
       removeListeners('p2p:file-progress');
       removeListeners('p2p:file-complete');
+      removeListeners('p2p:file-interrupted');
+      removeListeners('p2p:file-rejected');
+      removeListeners('p2p:folder-rejected');
+      removeListeners('p2p:message-failed');
+      removeListeners('p2p:decrypt-failed');
+      removeListeners('p2p:peer-key-changed');
+      removeListeners('p2p:message-relayed');
+      removeListeners('p2p:presence-changed');
     },
 
     // Group Chat
@@ -241,6 +297,9 @@ export function createElectronAPI() {
     onGroupTyping: (callback: (data: any) => void) => {
       void addListener('group:typing', callback);
     },
+    onGroupMessageSentFromOtherDevice: (callback: (message: any) => void) => {
+      void addListener('group:device-sent', callback);
+    },
     removeGroupListeners: () => {
       removeListeners('group:message-received');
       removeListeners('group:created');
@@ -248,6 +307,7 @@ export function createElectronAPI() {
       removeListeners('group:read-receipt');
       removeListeners('group:delivery-receipt');
       removeListeners('group:typing');
+      removeListeners('group:device-sent');
     },
 
     // Settings
@@ -1,3 +1,12 @@
+/** 내부망 P2P 상태. `in-class`는 수업 시간표에 따라 저절로 바뀌기도 한다. */
+export type PresenceStatus = 'available' | 'away' | 'busy' | 'in-class' | 'off-duty';
+
+export interface Presence {
+  status: PresenceStatus;
+  message?: string;
+  automatic: boolean;
+}
+
 export interface ElectronAPI {
   on?: (event: string, callback: (payload: any) => void) => void;
   removeAllListeners?: (event?: string) => void;
@@ -47,6 +56,9 @@ export interface ElectronAPI {
   sendMessage: (message: any) => Promise<any>;
   onMessageReceived: (callback: (message: any) => void) => void;
   removeMessageListener: () => void;
+  onMessageSentFromOtherDevice?: (callback: (message: any) => void) => void;
+  onReadOnOtherDevice?: (callback: (receipt: { messageId: string; senderId: string; readAt: string }) => void) => void;
+  removeDeviceSyncListeners?: () => void;
   onReadReceipt: (callback: (receipt: any) => void) => void;
   onDeliveryReceipt: (callback: (receipt: any) => void) => void;
   removeReceiptListeners: () => void;
@@ -96,9 +108,19 @@ export interface ElectronAPI {
   sendInternalReadReceipt?: (data: { messageId: string; senderId: string }) => Promise<any>;
   sendInternalTyping?: (data: { receiverId: string; isTyping: boolean }) => Promise<any>;
   offerInternalFile?: (data: { receiverId: string; fileName: string; fileSize: number; filePath: string }) => Promise<any>;
+  offerInternalFolder?: (data: { receiverId: string; folderPath: string }) => Promise<any>;
   acceptInternalFile?: (transferId: string) => Promise<any>;
   rejectInternalFile?: (transferId: string) => Promise<any>;
+  resumeInternalFile?: (transferId: string) => Promise<any>;
   getInternalFileTransfers?: () => Promise<any>;
+  getInternalQueuedMessages?: (data?: { receiverId?: string }) => Promise<any>;
+  retryInternalQueuedMessage?: (messageId: string) => Promise<any>;
+  cancelInternalQueuedMessage?: (messageId: string) => Promise<any>;
+  getInternalSafetyNumber?: (userId: string) => Promise<any>;
+  verifyInternalPeerKey?: (data: { userId: string; verified?: boolean }) => Promise<any>;
+  trustInternalPeerKey?: (userId: string) => Promise<any>;
+  trustInternalPeerDevice?: (userId: string) => Promise<any>;
+  setInternalPresence?: (data: { status: PresenceStatus; message?: string }) => Promise<any>;
   onInternalP2PStarted?: (callback: (info: any) => void) => void;
   onInternalP2PStopped?: (callback: () => void) => void;
   onInternalPeerDiscovered?: (callback: (peer: any) => void) => void;
@@ -108,6 +130,14 @@ export interface ElectronAPI {
   onInternalFileOffer?: (callback: (transfer: any) => void) => void;
   onInternalFileProgress?: (callback: (data: any) => void) => void;
   onInternalFileComplete?: (callback: (transfer: any) => void) => void;
+  onInternalFileInterrupted?: (callback: (transfer: any) => void) => void;
+  onInternalFileRejected?: (callback: (transfer: any) => void) => void;
+  onInternalFolderRejected?: (callback: (data: { transferId: string; senderId: string; path: string | null; error: string }) => void) => void;
+  onInternalMessageFailed?: (callback: (data: any) => void) => void;
+  onInternalDecryptFailed?: (callback: (data: any) => void) => void;
+  onInternalPeerKeyChanged?: (callback: (data: any) => void) => void;
+  onInternalMessageRelayed?: (callback: (data: any) => void) => void;
+  onInternalPresenceChanged?: (callback: (data: { peerId: string; userId: string; userName?: string; presence: Presence | null; isSelf: boolean }) => void) => void;
   removeInternalP2PListeners?: () => void;
 
   // Group Chat
@@ -122,6 +152,7 @@ export interface ElectronAPI {
   onGroupReadReceipt?: (callback: (data: any) => void) => void;
   onGroupDeliveryReceipt?: (callback: (data: any) => void) => void;
   onGroupTyping?: (callback: (data: any) => void) => void;
+  onGroupMessageSentFromOtherDevice?: (callback: (message: any) => void) => void;
   removeGroupListeners?: () => void;
 
   // Settings
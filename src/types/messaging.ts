@@ -14,6 +14,8 @@ export interface PeerInfo {
   userId: string;
   userName?: string;
   ipAddress: string;
+  addresses?: string[];
+  isRelayHub?: boolean;
   isOnline: boolean;
   lastSeen: Date;
 }